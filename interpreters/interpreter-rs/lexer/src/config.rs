@@ -0,0 +1,347 @@
+use crate::interner::{Interner, Symbol};
+use crate::with_opt_iterator::{Loc, Tag, Token, Tokenizer, KEYWORDS, LOX_PLUS_KEYWORDS};
+use std::collections::HashMap;
+
+/// Operators beyond plain Lox, all disabled by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Operators {
+    /// `+=`, `-=`, `*=` and `/=`
+    pub compound_assignment: bool,
+    /// `->`
+    pub arrow: bool,
+    /// `%`
+    pub percent: bool,
+    /// `&&` and `||`
+    pub logical: bool,
+}
+
+/// Comment styles, by default only `//` line comments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comments {
+    /// `// ...` up to the end of the line
+    pub double_slash: bool,
+    /// `# ...` up to the end of the line
+    pub hash: bool,
+    /// `/* ... */`, not nested
+    pub block: bool,
+}
+
+impl Default for Comments {
+    fn default() -> Self {
+        Comments {
+            double_slash: true,
+            hash: false,
+            block: false,
+        }
+    }
+}
+
+/// Lexical dialect: the keyword set, extra operators and comment styles.
+///
+/// Build one at startup and hand out [`ConfiguredTokenizer`]s from it. A config equal to
+/// [`LexerConfig::lox`] tokenizes through the plain [`Tokenizer`] without any extra checks.
+///
+/// ```
+/// use lexer::config::{LexerConfig, Operators};
+/// use lexer::with_opt_iterator::Tag;
+///
+/// let config = LexerConfig::lox()
+///     .keyword("let", Tag::KeywordVar)
+///     .without_keyword("print")
+///     .operators(Operators { compound_assignment: true, ..Operators::default() });
+/// let tags: Vec<Tag> = config.tokenizer(b"let print += 1;").map(|t| t.tag).collect();
+/// assert_eq!(
+///     tags,
+///     [Tag::KeywordVar, Tag::Identifier, Tag::PlusEqual, Tag::Number, Tag::Semicolon]
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexerConfig {
+    keywords: HashMap<Box<str>, Tag>,
+    operators: Operators,
+    comments: Comments,
+}
+
+impl Default for LexerConfig {
+    fn default() -> Self {
+        Self::lox()
+    }
+}
+
+impl LexerConfig {
+    /// The standard Lox lexer.
+    pub fn lox() -> Self {
+        LexerConfig {
+            keywords: KEYWORDS
+                .iter()
+                .map(|&(word, tag)| (word.into(), tag))
+                .collect(),
+            operators: Operators::default(),
+            comments: Comments::default(),
+        }
+    }
+
+    /// The Lox+ lexer: Lox with the keywords for imports and exceptions.
+    pub fn lox_plus() -> Self {
+        LOX_PLUS_KEYWORDS
+            .iter()
+            .fold(Self::lox(), |config, &(word, tag)| {
+                config.keyword(word, tag)
+            })
+    }
+
+    /// Adds `word` as a keyword producing `tag`, which may alias an existing keyword or be
+    /// [`Tag::KeywordExtra`] for one the parser handles by its text.
+    pub fn keyword(mut self, word: &str, tag: Tag) -> Self {
+        self.keywords.insert(word.into(), tag);
+        self
+    }
+
+    /// Turns `word` back into a plain identifier.
+    pub fn without_keyword(mut self, word: &str) -> Self {
+        self.keywords.remove(word);
+        self
+    }
+
+    pub fn operators(mut self, operators: Operators) -> Self {
+        self.operators = operators;
+        self
+    }
+
+    pub fn comments(mut self, comments: Comments) -> Self {
+        self.comments = comments;
+        self
+    }
+
+    pub fn keyword_tag(&self, word: &str) -> Option<Tag> {
+        self.keywords.get(word).copied()
+    }
+
+    /// Whether this config tokenizes exactly like plain Lox.
+    pub fn is_lox(&self) -> bool {
+        *self == Self::lox()
+    }
+
+    pub fn tokenizer<'a>(&'a self, buffer: &'a [u8]) -> ConfiguredTokenizer<'a> {
+        ConfiguredTokenizer {
+            inner: Tokenizer::new(buffer),
+            config: self,
+            plain: self.is_lox(),
+        }
+    }
+}
+
+/// A [`Tokenizer`] following a [`LexerConfig`]. Anything the config does not change is
+/// scanned by the plain tokenizer.
+pub struct ConfiguredTokenizer<'a> {
+    inner: Tokenizer<'a>,
+    config: &'a LexerConfig,
+    plain: bool,
+}
+
+impl Iterator for ConfiguredTokenizer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.next_token()?;
+        match token.tag {
+            Tag::Eof => None,
+            _ => Some(token),
+        }
+    }
+}
+
+impl ConfiguredTokenizer<'_> {
+    /// Line of the most recently consumed byte, 1-based.
+    pub fn line(&self) -> usize {
+        self.inner.line
+    }
+
+    pub fn next_token(&mut self) -> Option<Token> {
+        if self.plain {
+            return self.inner.next_token();
+        }
+        self.skip_trivia();
+
+        let operators = self.config.operators;
+        let t = &mut self.inner;
+        let start = t.index;
+        let tag = match (t.peek(), t.peek_next()) {
+            (Some(b'+'), Some(b'=')) if operators.compound_assignment => Tag::PlusEqual,
+            (Some(b'-'), Some(b'=')) if operators.compound_assignment => Tag::MinusEqual,
+            (Some(b'*'), Some(b'=')) if operators.compound_assignment => Tag::StarEqual,
+            (Some(b'/'), Some(b'=')) if operators.compound_assignment => Tag::SlashEqual,
+            (Some(b'-'), Some(b'>')) if operators.arrow => Tag::Arrow,
+            (Some(b'&'), Some(b'&')) if operators.logical => Tag::AmpAmp,
+            (Some(b'|'), Some(b'|')) if operators.logical => Tag::PipePipe,
+            (Some(b'%'), _) if operators.percent => {
+                t.index += 1;
+                return Some(Token {
+                    tag: Tag::Percent,
+                    loc: Loc {
+                        start,
+                        end: t.index,
+                    },
+                });
+            }
+            (Some(b'a'..=b'z' | b'A'..=b'Z' | b'_'), _) => return Some(self.identifier(start)),
+            _ => return t.scan_token(),
+        };
+
+        t.index += 2;
+        Some(Token {
+            tag,
+            loc: Loc {
+                start,
+                end: t.index,
+            },
+        })
+    }
+
+    /// Like [`Tokenizer::next_token_interned`]. Keywords are recognized through the config, so
+    /// `interner` may be any interner.
+    pub fn next_token_interned(
+        &mut self,
+        interner: &mut Interner,
+    ) -> Option<(Token, Option<Symbol>)> {
+        let token = self.next_token()?;
+        let buffer = self.inner.buffer;
+        let symbol = match (token.tag, buffer.get(token.loc.start)) {
+            (Tag::String, _) => {
+                std::str::from_utf8(&buffer[token.loc.start + 1..token.loc.end - 1])
+                    .ok()
+                    .map(|text| interner.intern(text))
+            }
+            (_, Some(b'a'..=b'z' | b'A'..=b'Z' | b'_')) => {
+                std::str::from_utf8(&buffer[token.loc.start..token.loc.end])
+                    .ok()
+                    .map(|text| interner.intern(text))
+            }
+            _ => None,
+        };
+        Some((token, symbol))
+    }
+
+    fn identifier(&mut self, start: usize) -> Token {
+        let t = &mut self.inner;
+        while let Some(b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_') = t.peek() {
+            t.index += 1;
+        }
+
+        let text = unsafe { std::str::from_utf8_unchecked(&t.buffer[start..t.index]) };
+        Token {
+            tag: self.config.keyword_tag(text).unwrap_or(Tag::Identifier),
+            loc: Loc {
+                start,
+                end: t.index,
+            },
+        }
+    }
+
+    fn skip_trivia(&mut self) {
+        let comments = self.config.comments;
+        let t = &mut self.inner;
+        while let Some(c) = t.peek() {
+            match (c, t.peek_next()) {
+                (b' ' | b'\r' | b'\t' | b'\n', _) => t.advance(),
+                (b'/', Some(b'/')) if comments.double_slash => skip_line(t),
+                (b'#', _) if comments.hash => skip_line(t),
+                (b'/', Some(b'*')) if comments.block => {
+                    t.index += 2;
+                    // An unterminated block comment runs to the end of the buffer
+                    while t.peek().is_some() {
+                        if t.peek() == Some(b'*') && t.peek_next() == Some(b'/') {
+                            t.index += 2;
+                            break;
+                        }
+                        t.advance();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+fn skip_line(t: &mut Tokenizer) {
+    while let Some(c) = t.peek() {
+        if c == b'\n' {
+            break;
+        }
+        t.index += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operators_keywords_and_comments() {
+        let config = LexerConfig::lox()
+            .keyword("fn", Tag::KeywordFun)
+            .keyword("match", Tag::KeywordExtra)
+            .without_keyword("class")
+            .operators(Operators {
+                compound_assignment: true,
+                arrow: true,
+                percent: true,
+                logical: true,
+            })
+            .comments(Comments {
+                double_slash: false,
+                hash: true,
+                block: true,
+            });
+        assert!(!config.is_lox());
+        assert!(LexerConfig::default().is_lox());
+
+        let source = b"fn class match # note\n/* a\nb */ x -= 1 % 2 -> a && b || c /= d";
+        let mut tokenizer = config.tokenizer(source);
+        let tags: Vec<Tag> = tokenizer.by_ref().map(|t| t.tag).collect();
+        assert_eq!(
+            tags,
+            [
+                Tag::KeywordFun,
+                Tag::Identifier,
+                Tag::KeywordExtra,
+                Tag::Identifier,
+                Tag::MinusEqual,
+                Tag::Number,
+                Tag::Percent,
+                Tag::Number,
+                Tag::Arrow,
+                Tag::Identifier,
+                Tag::AmpAmp,
+                Tag::Identifier,
+                Tag::PipePipe,
+                Tag::Identifier,
+                Tag::SlashEqual,
+                Tag::Identifier,
+            ]
+        );
+        assert_eq!(tokenizer.line(), 3);
+
+        // `//` is not a comment in this dialect and the default tokenizer ignores the extras
+        let tags: Vec<Tag> = config.tokenizer(b"a // b").map(|t| t.tag).collect();
+        assert_eq!(
+            tags,
+            [Tag::Identifier, Tag::Slash, Tag::Slash, Tag::Identifier]
+        );
+        let tags: Vec<Tag> = Tokenizer::new(b"a += b").map(|t| t.tag).collect();
+        assert_eq!(
+            tags,
+            [Tag::Identifier, Tag::Plus, Tag::Equal, Tag::Identifier]
+        );
+
+        // Lox+ keywords are names in plain Lox
+        let source = b"try throw x";
+        let tags: Vec<Tag> = LexerConfig::lox_plus()
+            .tokenizer(source)
+            .map(|t| t.tag)
+            .collect();
+        assert_eq!(tags, [Tag::KeywordTry, Tag::KeywordThrow, Tag::Identifier]);
+        let tags: Vec<Tag> = Tokenizer::new(source).map(|t| t.tag).collect();
+        assert_eq!(tags, [Tag::Identifier; 3]);
+    }
+}
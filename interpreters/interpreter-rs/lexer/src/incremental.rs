@@ -0,0 +1,149 @@
+use crate::with_opt_iterator::{Loc, Tag, Token, Tokenizer, TokenizerState};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineToken {
+    pub token: Token,
+    /// Line the token starts on.
+    pub line: usize,
+}
+
+/// A text edit in byte offsets: `start..old_end` of the old buffer was replaced by what is now
+/// `start..new_end` of the new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edit {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+impl Edit {
+    /// Maps an offset at or after `old_end` in the old buffer to the new buffer.
+    fn shift(&self, offset: usize) -> usize {
+        offset - self.old_end + self.new_end
+    }
+}
+
+/// Tokenizes the whole buffer, ending with the `Eof` token.
+pub fn tokenize(source: &[u8]) -> Vec<LineToken> {
+    let mut tokenizer = Tokenizer::new(source);
+    let mut tokens = Vec::new();
+    while let Some(token) = tokenizer.next_token() {
+        let eof = token.tag == Tag::Eof;
+        tokens.push(LineToken {
+            line: start_line(source, &token, tokenizer.line()),
+            token,
+        });
+        if eof {
+            break;
+        }
+    }
+    tokens
+}
+
+/// Updates `old`, the tokens of a buffer before `edit`, to the tokens of `source`, the buffer
+/// after it. Tokenizing restarts shortly before the edit and stops as soon as a token lines up
+/// with an old one past the edit, from where on the old tokens are reused with shifted `Loc`s and
+/// lines. The result equals `tokenize(source)`.
+pub fn relex(old: &[LineToken], source: &[u8], edit: Edit) -> Vec<LineToken> {
+    // Tokens ending before the edit are unchanged, but the last of them may still merge with the
+    // edited text (`1.` followed by an inserted `5`), so back up one more
+    let kept = old
+        .partition_point(|t| t.token.loc.end < edit.start)
+        .saturating_sub(1);
+    let state = match kept.checked_sub(1).map(|last| &old[last]) {
+        Some(last) => TokenizerState {
+            index: last.token.loc.end,
+            line: last.line + newlines(&source[last.token.loc.start..last.token.loc.end]),
+        },
+        None => TokenizerState { index: 0, line: 1 },
+    };
+
+    let mut tokens = old[..kept].to_vec();
+    let mut tokenizer = Tokenizer::resume(source, state);
+    // First old token that could still line up with a new one
+    let mut next_old = old.partition_point(|t| t.token.loc.start < edit.old_end);
+
+    while let Some(token) = tokenizer.next_token() {
+        let line = start_line(source, &token, tokenizer.line());
+
+        if token.loc.start >= edit.new_end {
+            let old_start = token.loc.start - edit.new_end + edit.old_end;
+            while next_old < old.len() && old[next_old].token.loc.start < old_start {
+                next_old += 1;
+            }
+            // The bytes from here on are the same as before the edit, and so are the tokens
+            if old
+                .get(next_old)
+                .is_some_and(|t| t.token.loc.start == old_start)
+            {
+                let line_shift = line as isize - old[next_old].line as isize;
+                tokens.extend(old[next_old..].iter().map(|t| LineToken {
+                    token: Token {
+                        tag: t.token.tag,
+                        loc: Loc {
+                            start: edit.shift(t.token.loc.start),
+                            end: edit.shift(t.token.loc.end),
+                        },
+                    },
+                    line: (t.line as isize + line_shift) as usize,
+                }));
+                return tokens;
+            }
+        }
+
+        let eof = token.tag == Tag::Eof;
+        tokens.push(LineToken { token, line });
+        if eof {
+            break;
+        }
+    }
+    tokens
+}
+
+/// Line `token` starts on, given the tokenizer's line right after scanning it.
+fn start_line(source: &[u8], token: &Token, line_after: usize) -> usize {
+    line_after - newlines(&source[token.loc.start..token.loc.end])
+}
+
+fn newlines(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&c| c == b'\n').count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(source: &str, start: usize, end: usize, text: &str) -> (String, Edit) {
+        let edited = format!("{}{}{}", &source[..start], text, &source[end..]);
+        let edit = Edit {
+            start,
+            old_end: end,
+            new_end: start + text.len(),
+        };
+        (edited, edit)
+    }
+
+    #[test]
+    fn test_relex_matches_full_tokenize() {
+        let source = "var a = 1.;\nprint \"x\ny\" + a; // note\nfun f() { return a; }\n";
+        let old = tokenize(source.as_bytes());
+
+        let edits = [
+            (10, 10, "5"),         // `1.` becomes `1.5`
+            (4, 5, "longer_name"), // rename
+            (0, 0, "\n\n"),        // lines shift for everything after
+            (18, 18, "\""),        // opens a string running to the end
+            (24, 34, ""),          // deletes across tokens and the comment
+            (source.len(), source.len(), "a"),
+        ];
+        for (start, end, text) in edits {
+            let (edited, edit) = apply(source, start, end, text);
+            assert_eq!(
+                relex(&old, edited.as_bytes(), edit),
+                tokenize(edited.as_bytes()),
+                "{:?}",
+                edited
+            );
+        }
+    }
+}
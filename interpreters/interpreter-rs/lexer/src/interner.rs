@@ -0,0 +1,173 @@
+/// Stable id for an interned string. Ids are dense and handed out in interning order, so they
+/// can index side tables directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+impl Symbol {
+    #[inline(always)]
+    pub const fn from_index(index: usize) -> Symbol {
+        Symbol(index as u32)
+    }
+
+    #[inline(always)]
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+const EMPTY: u32 = u32::MAX;
+const INITIAL_CAPACITY: usize = 64;
+
+/// FNV-1a, shared with the runtime's string table so both agree on string hashes.
+#[inline(always)]
+pub fn hash(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for &b in bytes {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+/// Maps strings to [`Symbol`]s with an open-addressing, linear-probing table.
+///
+/// All interned text lives in one growing buffer and the table only stores symbol ids, so
+/// interning an already known string never allocates.
+pub struct Interner {
+    /// Symbol ids, `EMPTY` for unused slots. The length is always a power of two.
+    table: Vec<u32>,
+    /// `(start, end)` of every symbol's text in `text`.
+    spans: Vec<(u32, u32)>,
+    hashes: Vec<u32>,
+    text: String,
+}
+
+impl Default for Interner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Interner {
+            table: vec![EMPTY; INITIAL_CAPACITY],
+            spans: Vec::new(),
+            hashes: Vec::new(),
+            text: String::new(),
+        }
+    }
+
+    /// Creates an interner whose first symbols are `words`, in order.
+    pub fn with_words(words: &[&str]) -> Self {
+        let mut interner = Self::new();
+        for word in words {
+            interner.intern(word);
+        }
+        interner
+    }
+
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    pub fn intern(&mut self, s: &str) -> Symbol {
+        self.intern_with_hash(s, hash(s.as_bytes()))
+    }
+
+    pub fn intern_with_hash(&mut self, s: &str, hash: u32) -> Symbol {
+        let slot = self.find_slot(s, hash);
+        if self.table[slot] != EMPTY {
+            return Symbol(self.table[slot]);
+        }
+
+        let symbol = self.spans.len() as u32;
+        let start = self.text.len() as u32;
+        self.text.push_str(s);
+        self.spans.push((start, self.text.len() as u32));
+        self.hashes.push(hash);
+        self.table[slot] = symbol;
+
+        // Keep the load factor under 3/4 like clox's tables
+        if self.spans.len() * 4 > self.table.len() * 3 {
+            self.grow();
+        }
+        Symbol(symbol)
+    }
+
+    pub fn get(&self, s: &str) -> Option<Symbol> {
+        let slot = self.find_slot(s, hash(s.as_bytes()));
+        match self.table[slot] {
+            EMPTY => None,
+            symbol => Some(Symbol(symbol)),
+        }
+    }
+
+    #[inline(always)]
+    pub fn resolve(&self, symbol: Symbol) -> &str {
+        let (start, end) = self.spans[symbol.index()];
+        &self.text[start as usize..end as usize]
+    }
+
+    #[inline(always)]
+    pub fn hash_of(&self, symbol: Symbol) -> u32 {
+        self.hashes[symbol.index()]
+    }
+
+    /// Returns the slot holding `s`, or the empty slot where it would go.
+    #[inline(always)]
+    fn find_slot(&self, s: &str, hash: u32) -> usize {
+        let mask = self.table.len() - 1;
+        let mut slot = hash as usize & mask;
+        loop {
+            match self.table[slot] {
+                EMPTY => return slot,
+                symbol
+                    if self.hashes[symbol as usize] == hash
+                        && self.resolve(Symbol(symbol)) == s =>
+                {
+                    return slot
+                }
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+
+    fn grow(&mut self) {
+        let capacity = self.table.len() * 2;
+        let mut table = vec![EMPTY; capacity];
+        for (symbol, &hash) in self.hashes.iter().enumerate() {
+            let mut slot = hash as usize & (capacity - 1);
+            while table[slot] != EMPTY {
+                slot = (slot + 1) & (capacity - 1);
+            }
+            table[slot] = symbol as u32;
+        }
+        self.table = table;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern_and_resolve() {
+        let mut interner = Interner::with_words(&["and", "class"]);
+        assert_eq!(interner.get("class"), Some(Symbol::from_index(1)));
+
+        let symbols: Vec<Symbol> = (0..1000)
+            .map(|i| interner.intern(&format!("v{}", i)))
+            .collect();
+        for (i, &symbol) in symbols.iter().enumerate() {
+            assert_eq!(interner.intern(&format!("v{}", i)), symbol);
+            assert_eq!(interner.resolve(symbol), format!("v{}", i));
+        }
+        assert_eq!(interner.len(), 1002);
+        assert_eq!(interner.get("missing"), None);
+    }
+}
@@ -1,4 +1,8 @@
+pub mod config;
+pub mod incremental;
+pub mod interner;
 pub mod naive_zig_like;
+pub mod trivia;
 pub mod with_opt;
 pub mod with_opt_intermediate;
 pub mod with_opt_intermediate_zero_copy;
@@ -0,0 +1,206 @@
+use crate::config::LexerConfig;
+use crate::with_opt_iterator::{Loc, Tag, Token, Tokenizer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    /// Spaces, tabs and carriage returns
+    Whitespace,
+    Newline,
+    /// `// ...` without the line break
+    Comment,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub loc: Loc,
+}
+
+/// A token with the whitespace and comments around it. Trailing trivia runs up to the end of
+/// the token's line, everything after that leads the next token, so the `Eof` token carries
+/// whatever follows the last real token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TriviaToken {
+    pub token: Token,
+    pub leading: Vec<Trivia>,
+    pub trailing: Vec<Trivia>,
+}
+
+impl TriviaToken {
+    pub fn tag(&self) -> Tag {
+        self.token.tag
+    }
+
+    /// Span of the token including its trivia.
+    pub fn full_loc(&self) -> Loc {
+        Loc {
+            start: self
+                .leading
+                .first()
+                .map_or(self.token.loc.start, |t| t.loc.start),
+            end: self
+                .trailing
+                .last()
+                .map_or(self.token.loc.end, |t| t.loc.end),
+        }
+    }
+}
+
+/// Tokenizer that keeps trivia instead of skipping it. The full spans of its tokens cover the
+/// buffer without gaps or overlaps.
+pub struct TriviaTokenizer<'a> {
+    inner: Tokenizer<'a>,
+    /// Where keywords come from when they differ from plain Lox.
+    config: Option<&'a LexerConfig>,
+    done: bool,
+}
+
+impl<'a> TriviaTokenizer<'a> {
+    pub fn new(buffer: &'a [u8]) -> Self {
+        TriviaTokenizer {
+            inner: Tokenizer::new(buffer),
+            config: None,
+            done: false,
+        }
+    }
+
+    /// Recognizes the keywords of `config`. Its operators and comment styles don't apply.
+    pub fn with_config(buffer: &'a [u8], config: &'a LexerConfig) -> Self {
+        TriviaTokenizer {
+            inner: Tokenizer::new(buffer),
+            config: (!config.is_lox()).then_some(config),
+            done: false,
+        }
+    }
+
+    /// Line of the most recently consumed byte, 1-based.
+    pub fn line(&self) -> usize {
+        self.inner.line()
+    }
+
+    /// Returns tokens up to and including `Eof`, then `None`.
+    pub fn next_token(&mut self) -> Option<TriviaToken> {
+        if self.done {
+            return None;
+        }
+
+        let mut leading = Vec::new();
+        while let Some(trivia) = self.trivia(false) {
+            leading.push(trivia);
+        }
+        let mut token = self.inner.scan_token()?;
+        if let Some(config) = self.config {
+            let text = &self.inner.buffer[token.loc.start..token.loc.end];
+            if let [b'a'..=b'z' | b'A'..=b'Z' | b'_', ..] = text {
+                let word = unsafe { std::str::from_utf8_unchecked(text) };
+                token.tag = config.keyword_tag(word).unwrap_or(Tag::Identifier);
+            }
+        }
+        let mut trailing = Vec::new();
+        if token.tag == Tag::Eof {
+            self.done = true;
+        } else {
+            while let Some(trivia) = self.trivia(true) {
+                trailing.push(trivia);
+            }
+        }
+
+        Some(TriviaToken {
+            token,
+            leading,
+            trailing,
+        })
+    }
+
+    fn trivia(&mut self, stop_at_newline: bool) -> Option<Trivia> {
+        let t = &mut self.inner;
+        let start = t.index;
+        let kind = match (t.peek()?, t.peek_next()) {
+            (b'\n', _) if !stop_at_newline => {
+                t.advance();
+                TriviaKind::Newline
+            }
+            (b' ' | b'\r' | b'\t', _) => {
+                while let Some(b' ' | b'\r' | b'\t') = t.peek() {
+                    t.index += 1;
+                }
+                TriviaKind::Whitespace
+            }
+            (b'/', Some(b'/')) => {
+                while !matches!(t.peek(), None | Some(b'\n')) {
+                    t.index += 1;
+                }
+                TriviaKind::Comment
+            }
+            _ => return None,
+        };
+
+        Some(Trivia {
+            kind,
+            loc: Loc {
+                start,
+                end: t.index,
+            },
+        })
+    }
+}
+
+/// Tokenizes the whole buffer with trivia, ending with the `Eof` token.
+pub fn tokenize_with_trivia(source: &[u8]) -> Vec<TriviaToken> {
+    let mut tokenizer = TriviaTokenizer::new(source);
+    std::iter::from_fn(|| tokenizer.next_token()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trivia_covers_the_source() {
+        let source = b"  // header\nvar x = 1; // one\n\n\tprint x;\n// end";
+        let tokens = tokenize_with_trivia(source);
+
+        let mut end = 0;
+        for token in &tokens {
+            let loc = token.full_loc();
+            assert_eq!(loc.start, end);
+            end = loc.end;
+        }
+        assert_eq!(end, source.len());
+
+        let kinds = |trivia: &[Trivia]| trivia.iter().map(|t| t.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds(&tokens[0].leading),
+            [
+                TriviaKind::Whitespace,
+                TriviaKind::Comment,
+                TriviaKind::Newline
+            ]
+        );
+        assert_eq!(
+            kinds(&tokens[4].trailing),
+            [TriviaKind::Whitespace, TriviaKind::Comment]
+        );
+        assert_eq!(
+            kinds(&tokens[5].leading),
+            [
+                TriviaKind::Newline,
+                TriviaKind::Newline,
+                TriviaKind::Whitespace
+            ]
+        );
+        let eof = tokens.last().unwrap();
+        assert_eq!(eof.tag(), Tag::Eof);
+        assert_eq!(
+            kinds(&eof.leading),
+            [TriviaKind::Newline, TriviaKind::Comment]
+        );
+
+        let config = LexerConfig::lox_plus();
+        let mut tokenizer = TriviaTokenizer::with_config(b"try x", &config);
+        let tags: Vec<Tag> = std::iter::from_fn(|| tokenizer.next_token())
+            .map(|t| t.tag())
+            .collect();
+        assert_eq!(tags, [Tag::KeywordTry, Tag::Identifier, Tag::Eof]);
+    }
+}
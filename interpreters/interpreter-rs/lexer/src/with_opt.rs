@@ -262,6 +262,7 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    #[allow(dead_code)]
     #[inline(always)]
     fn peek_offset(&self, offset: usize) -> Option<u8> {
         if self.index + offset < self.buffer.len() {
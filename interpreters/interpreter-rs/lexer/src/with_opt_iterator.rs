@@ -1,9 +1,15 @@
+use crate::interner::{Interner, Symbol};
+
 #[rustfmt::skip]
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Tag {
     // Single-character tokens
     LeftParen, RightParen, LeftBrace, RightBrace, Comma, Dot, Minus, Plus, Semicolon, Slash, Star,
+    LeftBracket, RightBracket, Colon,
+
+    // Operators only produced under a `LexerConfig` that enables them
+    PlusEqual, MinusEqual, StarEqual, SlashEqual, Percent, Arrow, AmpAmp, PipePipe,
 
     // One or two character tokens
     Bang, BangEqual, Equal, EqualEqual, Greater, GreaterEqual, Less, LessEqual,
@@ -14,6 +20,10 @@ pub enum Tag {
     // Keywords
     KeywordAnd, KeywordClass, KeywordElse, KeywordFalse, KeywordFun, KeywordFor, KeywordIf, KeywordNil,
     KeywordOr, KeywordPrint, KeywordReturn, KeywordSuper, KeywordThis, KeywordTrue, KeywordVar, KeywordWhile,
+    // Keywords only in Lox+, see `LexerConfig::lox_plus`
+    KeywordImport, KeywordExport, KeywordTry, KeywordCatch, KeywordFinally, KeywordThrow,
+    // A keyword added by a `LexerConfig` that has no tag of its own
+    KeywordExtra,
 
     Eof,
     Invalid,
@@ -42,6 +52,51 @@ impl Tag {
             _ => None,
         }
     }
+
+    /// Symbol of this keyword in an interner created by [`keyword_interner`].
+    pub fn keyword_symbol(self) -> Option<Symbol> {
+        KEYWORDS
+            .iter()
+            .position(|&(_, tag)| tag == self)
+            .map(Symbol::from_index)
+    }
+}
+
+/// Keywords in interning order: the symbol with index `i` in a [`keyword_interner`] is
+/// `KEYWORDS[i]`, which turns keyword detection into a bounds check on the symbol.
+pub(crate) const KEYWORDS: [(&str, Tag); 16] = [
+    ("and", Tag::KeywordAnd),
+    ("class", Tag::KeywordClass),
+    ("else", Tag::KeywordElse),
+    ("false", Tag::KeywordFalse),
+    ("fun", Tag::KeywordFun),
+    ("for", Tag::KeywordFor),
+    ("if", Tag::KeywordIf),
+    ("nil", Tag::KeywordNil),
+    ("or", Tag::KeywordOr),
+    ("print", Tag::KeywordPrint),
+    ("return", Tag::KeywordReturn),
+    ("super", Tag::KeywordSuper),
+    ("this", Tag::KeywordThis),
+    ("true", Tag::KeywordTrue),
+    ("var", Tag::KeywordVar),
+    ("while", Tag::KeywordWhile),
+];
+
+/// Keywords Lox+ adds on top of [`KEYWORDS`].
+pub(crate) const LOX_PLUS_KEYWORDS: [(&str, Tag); 6] = [
+    ("import", Tag::KeywordImport),
+    ("export", Tag::KeywordExport),
+    ("try", Tag::KeywordTry),
+    ("catch", Tag::KeywordCatch),
+    ("finally", Tag::KeywordFinally),
+    ("throw", Tag::KeywordThrow),
+];
+
+/// Creates an interner seeded with the keywords, as required by
+/// [`Tokenizer::next_token_interned`].
+pub fn keyword_interner() -> Interner {
+    Interner::with_words(&KEYWORDS.map(|(keyword, _)| keyword))
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -56,10 +111,17 @@ pub struct Token {
     pub loc: Loc,
 }
 
+/// Everything a [`Tokenizer`] needs to continue from a token boundary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenizerState {
+    pub index: usize,
+    pub line: usize,
+}
+
 pub struct Tokenizer<'a> {
-    buffer: &'a [u8],
-    index: usize,
-    line: usize, // Optional: For better error reporting
+    pub(crate) buffer: &'a [u8],
+    pub(crate) index: usize,
+    pub(crate) line: usize, // Optional: For better error reporting
 }
 
 impl<'a> Iterator for Tokenizer<'a> {
@@ -101,8 +163,31 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    /// Continues tokenizing `buffer` from a state saved with [`Tokenizer::state`], possibly
+    /// taken on an earlier version of the buffer with the same bytes up to `state.index`.
+    pub fn resume(buffer: &'a [u8], state: TokenizerState) -> Self {
+        Tokenizer {
+            buffer,
+            index: state.index,
+            line: state.line,
+        }
+    }
+
+    pub fn state(&self) -> TokenizerState {
+        TokenizerState {
+            index: self.index,
+            line: self.line,
+        }
+    }
+
+    /// Line of the most recently consumed byte, 1-based.
+    #[inline(always)]
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
     #[inline(always)]
-    fn advance(&mut self) {
+    pub(crate) fn advance(&mut self) {
         if self.buffer[self.index] == b'\n' {
             self.line += 1;
         }
@@ -134,7 +219,7 @@ impl<'a> Tokenizer<'a> {
     }
 
     #[inline(always)]
-    fn number(&mut self, start: usize) -> Option<Token> {
+    pub(crate) fn number(&mut self, start: usize) -> Option<Token> {
         while matches!(self.peek(), Some(b'0'..=b'9')) {
             self.index += 1;
         }
@@ -158,7 +243,37 @@ impl<'a> Tokenizer<'a> {
     }
 
     #[inline(always)]
-    fn peek(&self) -> Option<u8> {
+    pub(crate) fn string(&mut self, start: usize) -> Option<Token> {
+        self.index += 1; // Skip the opening quote
+
+        while let Some(c) = self.peek() {
+            match c {
+                b'"' => {
+                    self.index += 1;
+                    return Some(Token {
+                        tag: Tag::String,
+                        loc: Loc {
+                            start,
+                            end: self.index,
+                        },
+                    });
+                }
+                _ => self.advance(),
+            }
+        }
+
+        // Unterminated string, the token runs to the end of the buffer
+        Some(Token {
+            tag: Tag::Invalid,
+            loc: Loc {
+                start,
+                end: self.index,
+            },
+        })
+    }
+
+    #[inline(always)]
+    pub(crate) fn peek(&self) -> Option<u8> {
         if self.index < self.buffer.len() {
             Some(self.buffer[self.index])
         } else {
@@ -167,7 +282,7 @@ impl<'a> Tokenizer<'a> {
     }
 
     #[inline(always)]
-    fn peek_next(&self) -> Option<u8> {
+    pub(crate) fn peek_next(&self) -> Option<u8> {
         if self.index + 1 < self.buffer.len() {
             Some(self.buffer[self.index + 1])
         } else {
@@ -197,9 +312,61 @@ impl<'a> Tokenizer<'a> {
         })
     }
 
+    /// Like [`Tokenizer::next_token`] but also interns identifiers and string literal contents
+    /// (without the quotes). `interner` must come from [`keyword_interner`].
     #[inline(always)]
-    fn next_token(&mut self) -> Option<Token> {
+    pub fn next_token_interned(
+        &mut self,
+        interner: &mut Interner,
+    ) -> Option<(Token, Option<Symbol>)> {
+        debug_assert_eq!(interner.get("and"), Some(Symbol::from_index(0)));
         self.skip_whitespace();
+        let start = self.index;
+
+        match self.peek() {
+            Some(b'a'..=b'z' | b'A'..=b'Z' | b'_') => {
+                while let Some(b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_') = self.peek() {
+                    self.index += 1;
+                }
+
+                let text =
+                    unsafe { std::str::from_utf8_unchecked(&self.buffer[start..self.index]) };
+                let symbol = interner.intern(text);
+                let tag = KEYWORDS
+                    .get(symbol.index())
+                    .map_or(Tag::Identifier, |&(_, tag)| tag);
+
+                let loc = Loc {
+                    start,
+                    end: self.index,
+                };
+                Some((Token { tag, loc }, Some(symbol)))
+            }
+            Some(b'"') => {
+                let token = self.string(start)?;
+                let symbol = match token.tag {
+                    Tag::String => {
+                        std::str::from_utf8(&self.buffer[token.loc.start + 1..token.loc.end - 1])
+                            .ok()
+                            .map(|text| interner.intern(text))
+                    }
+                    _ => None,
+                };
+                Some((token, symbol))
+            }
+            _ => self.next_token().map(|token| (token, None)),
+        }
+    }
+
+    #[inline(always)]
+    pub fn next_token(&mut self) -> Option<Token> {
+        self.skip_whitespace();
+        self.scan_token()
+    }
+
+    /// Scans the token starting at the current index, which must not be whitespace.
+    #[inline(always)]
+    pub(crate) fn scan_token(&mut self) -> Option<Token> {
         if self.index >= self.buffer.len() {
             return Some(Token {
                 tag: Tag::Eof,
@@ -254,6 +421,22 @@ impl<'a> Tokenizer<'a> {
                 self.advance();
                 Tag::Star
             }
+            b'[' => {
+                self.advance();
+                Tag::LeftBracket
+            }
+            b']' => {
+                self.advance();
+                Tag::RightBracket
+            }
+            b':' => {
+                self.advance();
+                Tag::Colon
+            }
+            b'/' => {
+                self.advance();
+                Tag::Slash
+            }
             b'!' => {
                 self.advance();
                 if matches!(self.peek(), Some(b'=')) {
@@ -290,6 +473,9 @@ impl<'a> Tokenizer<'a> {
                     Tag::Greater
                 }
             }
+            b'"' => {
+                return self.string(start);
+            }
             b'0'..=b'9' => {
                 return self.number(start);
             }
@@ -352,4 +538,55 @@ mod tests {
             assert_eq!(token.tag, expected_tag);
         }
     }
+
+    #[test]
+    fn test_strings_and_slash() {
+        let source = b"print \"a\nb\" / 2; // done\n\"open";
+        let mut tokenizer = Tokenizer::new(source);
+        let expected = [
+            (Tag::KeywordPrint, 1),
+            (Tag::String, 2),
+            (Tag::Slash, 2),
+            (Tag::Number, 2),
+            (Tag::Semicolon, 2),
+            (Tag::Invalid, 3),
+            (Tag::Eof, 3),
+        ];
+
+        for (expected_tag, expected_line) in expected {
+            let token = tokenizer.next_token().unwrap();
+            assert_eq!(token.tag, expected_tag);
+            assert_eq!(tokenizer.line(), expected_line);
+        }
+    }
+
+    #[test]
+    fn test_interned_tokens() {
+        let source = b"var name = \"name\"; print name;";
+        let mut tokenizer = Tokenizer::new(source);
+        let mut interner = keyword_interner();
+
+        let mut tokens = Vec::new();
+        while let Some((token, symbol)) = tokenizer.next_token_interned(&mut interner) {
+            if token.tag == Tag::Eof {
+                break;
+            }
+            tokens.push((token.tag, symbol));
+        }
+
+        let name = interner.get("name");
+        assert_eq!(
+            tokens,
+            [
+                (Tag::KeywordVar, Tag::KeywordVar.keyword_symbol()),
+                (Tag::Identifier, name),
+                (Tag::Equal, None),
+                (Tag::String, name),
+                (Tag::Semicolon, None),
+                (Tag::KeywordPrint, Tag::KeywordPrint.keyword_symbol()),
+                (Tag::Identifier, name),
+                (Tag::Semicolon, None),
+            ]
+        );
+    }
 }
@@ -0,0 +1,320 @@
+use lexer::interner::Symbol;
+use lexer::with_opt_iterator::Loc;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub text: Rc<str>,
+    pub symbol: Symbol,
+    pub loc: Loc,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Rc<str>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[rustfmt::skip]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add, Subtract, Multiply, Divide,
+    Equal, NotEqual, Greater, GreaterEqual, Less, LessEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub loc: Loc,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Literal(Literal),
+    Variable(Name),
+    Assign(Name, Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(Box<Expr>, BinaryOp, Box<Expr>),
+    Logical(Box<Expr>, LogicalOp, Box<Expr>),
+    Grouping(Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    Get(Box<Expr>, Name),
+    Set(Box<Expr>, Name, Box<Expr>),
+    This,
+    Super(Name),
+    // Lox+ only
+    List(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    Index(Box<Expr>, Box<Expr>),
+    SetIndex(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Expression(Expr),
+    Print(Expr),
+    Var(Name, Option<Expr>),
+    Block(Vec<Stmt>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    While(Expr, Box<Stmt>),
+    Function(Rc<Function>),
+    Return(Option<Expr>),
+    Class(Class),
+    /// `import "path";`, top level only.
+    Import(Rc<str>),
+    /// `export` before a top-level `var`, `fun` or `class` declaration.
+    Export(Box<Stmt>),
+    // Lox+ only
+    Throw(Expr),
+    Try(Try),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: Name,
+    pub params: Vec<Name>,
+    pub body: Vec<Stmt>,
+    /// Line of the closing brace, where the implicit return lives.
+    pub end_line: u32,
+}
+
+/// A `try` block with a `catch` clause, a `finally` block or both.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Try {
+    pub body: Vec<Stmt>,
+    /// Variable holding the thrown value, and the block handling it.
+    pub catch: Option<(Name, Vec<Stmt>)>,
+    pub finally: Option<Vec<Stmt>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Class {
+    pub name: Name,
+    pub superclass: Option<Name>,
+    pub methods: Vec<Rc<Function>>,
+}
+
+// S-expression printing, used by the REPL's `:ast` command
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Literal::Nil => write!(f, "nil"),
+            Literal::Bool(b) => write!(f, "{}", b),
+            Literal::Number(n) => write!(f, "{}", n),
+            Literal::String(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UnaryOp::Negate => "-",
+            UnaryOp::Not => "!",
+        })
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+        })
+    }
+}
+
+impl fmt::Display for LogicalOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogicalOp::And => "and",
+            LogicalOp::Or => "or",
+        })
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Literal(literal) => write!(f, "{}", literal),
+            ExprKind::Variable(name) => write!(f, "{}", name.text),
+            ExprKind::Assign(name, value) => write!(f, "(= {} {})", name.text, value),
+            ExprKind::Unary(op, right) => write!(f, "({} {})", op, right),
+            ExprKind::Binary(left, op, right) => write!(f, "({} {} {})", op, left, right),
+            ExprKind::Logical(left, op, right) => write!(f, "({} {} {})", op, left, right),
+            ExprKind::Grouping(inner) => write!(f, "(group {})", inner),
+            ExprKind::Call(callee, args) => {
+                write!(f, "(call {}", callee)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Get(object, name) => write!(f, "(. {} {})", object, name.text),
+            ExprKind::Set(object, name, value) => {
+                write!(f, "(= (. {} {}) {})", object, name.text, value)
+            }
+            ExprKind::This => write!(f, "this"),
+            ExprKind::Super(method) => write!(f, "(super {})", method.text),
+            ExprKind::List(items) => {
+                write!(f, "(list")?;
+                for item in items {
+                    write!(f, " {}", item)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Map(entries) => {
+                write!(f, "(map")?;
+                for (key, value) in entries {
+                    write!(f, " ({} {})", key, value)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Index(object, index) => write!(f, "([] {} {})", object, index),
+            ExprKind::SetIndex(object, index, value) => {
+                write!(f, "(= ([] {} {}) {})", object, index, value)
+            }
+        }
+    }
+}
+
+/// Statements print one per line, nested statements indented by two spaces.
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_indented(f, 0)
+    }
+}
+
+impl Stmt {
+    /// The variable, function or class a declaration introduces.
+    pub fn declared_name(&self) -> Option<&Name> {
+        match &self.kind {
+            StmtKind::Var(name, _) => Some(name),
+            StmtKind::Function(function) => Some(&function.name),
+            StmtKind::Class(class) => Some(&class.name),
+            StmtKind::Export(declaration) => declaration.declared_name(),
+            _ => None,
+        }
+    }
+
+    fn write_indented(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        write!(f, "{:width$}", "", width = depth * 2)?;
+        match &self.kind {
+            StmtKind::Expression(expr) => write!(f, "(; {})", expr),
+            StmtKind::Print(expr) => write!(f, "(print {})", expr),
+            StmtKind::Var(name, None) => write!(f, "(var {})", name.text),
+            StmtKind::Var(name, Some(init)) => write!(f, "(var {} {})", name.text, init),
+            StmtKind::Block(statements) => {
+                write!(f, "(block")?;
+                write_body(f, statements, depth)
+            }
+            StmtKind::If(condition, then_branch, else_branch) => {
+                writeln!(f, "(if {}", condition)?;
+                then_branch.write_indented(f, depth + 1)?;
+                if let Some(else_branch) = else_branch {
+                    writeln!(f)?;
+                    else_branch.write_indented(f, depth + 1)?;
+                }
+                write!(f, ")")
+            }
+            StmtKind::While(condition, body) => {
+                writeln!(f, "(while {}", condition)?;
+                body.write_indented(f, depth + 1)?;
+                write!(f, ")")
+            }
+            StmtKind::Function(function) => function.write_indented(f, depth),
+            StmtKind::Return(None) => write!(f, "(return)"),
+            StmtKind::Return(Some(value)) => write!(f, "(return {})", value),
+            StmtKind::Class(class) => {
+                write!(f, "(class {}", class.name.text)?;
+                if let Some(superclass) = &class.superclass {
+                    write!(f, " < {}", superclass.text)?;
+                }
+                for method in &class.methods {
+                    writeln!(f)?;
+                    write!(f, "{:width$}", "", width = (depth + 1) * 2)?;
+                    method.write_indented(f, depth + 1)?;
+                }
+                write!(f, ")")
+            }
+            StmtKind::Import(path) => write!(f, "(import {:?})", path),
+            StmtKind::Export(declaration) => {
+                writeln!(f, "(export")?;
+                declaration.write_indented(f, depth + 1)?;
+                write!(f, ")")
+            }
+            StmtKind::Throw(value) => write!(f, "(throw {})", value),
+            StmtKind::Try(handler) => {
+                write!(f, "(try")?;
+                for stmt in &handler.body {
+                    writeln!(f)?;
+                    stmt.write_indented(f, depth + 1)?;
+                }
+                let indent = (depth + 1) * 2;
+                if let Some((name, body)) = &handler.catch {
+                    write!(f, "\n{:indent$}(catch {}", "", name.text)?;
+                    write_body(f, body, depth + 1)?;
+                }
+                if let Some(body) = &handler.finally {
+                    write!(f, "\n{:indent$}(finally", "")?;
+                    write_body(f, body, depth + 1)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl Function {
+    fn write_indented(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        write!(f, "(fun {} (", self.name.text)?;
+        for (i, param) in self.params.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", param.text)?;
+        }
+        write!(f, ")")?;
+        write_body(f, &self.body, depth)
+    }
+}
+
+fn write_body(f: &mut fmt::Formatter, statements: &[Stmt], depth: usize) -> fmt::Result {
+    for stmt in statements {
+        writeln!(f)?;
+        stmt.write_indented(f, depth + 1)?;
+    }
+    write!(f, ")")
+}
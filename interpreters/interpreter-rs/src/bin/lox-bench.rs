@@ -0,0 +1,169 @@
+use interpreter_rs::parser::Dialect;
+use interpreter_rs::register_vm::RegisterVm;
+use interpreter_rs::vm::{InterpretError, Vm};
+use std::env;
+use std::io;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+/// Programs measured when no files are given, as `(name, source)`.
+const PROGRAMS: [(&str, &str); 5] = [
+    (
+        "fib",
+        "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(25);",
+    ),
+    (
+        "loop",
+        "var sum = 0; for (var i = 0; i < 1000000; i = i + 1) { sum = sum + i * 2 - 1; } print sum;",
+    ),
+    (
+        "closures",
+        "fun adder(n) { fun add(x) { return x + n; } return add; }
+         var total = 0;
+         for (var i = 0; i < 100000; i = i + 1) { total = adder(i)(total) / 2; }
+         print total;",
+    ),
+    (
+        "classes",
+        "class Point { init(x, y) { this.x = x; this.y = y; }
+           add(other) { return Point(this.x + other.x, this.y + other.y); } }
+         var p = Point(0, 0);
+         for (var i = 0; i < 100000; i = i + 1) { p = p.add(Point(1, 2)); }
+         print p.x + p.y;",
+    ),
+    (
+        "strings",
+        "var s = \"\"; for (var i = 0; i < 20000; i = i + 1) { s = s + \"x\"; if (len(s) > 100) s = \"\"; }
+         print len(s);",
+    ),
+];
+
+struct Measurement {
+    instructions: u64,
+    time: Duration,
+}
+
+/// Runs `source` `runs` times on fresh VMs, keeping the fastest time.
+fn measure<V>(
+    source: &[u8],
+    runs: usize,
+    new_vm: impl Fn() -> V,
+    run: impl Fn(&mut V, &[u8]) -> Result<u64, InterpretError>,
+) -> Result<Measurement, InterpretError> {
+    let mut best = Measurement {
+        instructions: 0,
+        time: Duration::MAX,
+    };
+    for _ in 0..runs {
+        let mut vm = new_vm();
+        let start = Instant::now();
+        best.instructions = run(&mut vm, source)?;
+        best.time = best.time.min(start.elapsed());
+    }
+    Ok(best)
+}
+
+fn stack_vm(dialect: Dialect) -> Vm {
+    let mut vm = Vm::new();
+    vm.set_output(Box::new(io::sink()));
+    vm.set_dialect(dialect);
+    vm
+}
+
+fn register_vm(dialect: Dialect) -> RegisterVm {
+    let mut vm = RegisterVm::new();
+    vm.set_output(Box::new(io::sink()));
+    vm.set_dialect(dialect);
+    vm
+}
+
+fn run() -> Result<(), ExitCode> {
+    let usage = || {
+        eprintln!("Usage: lox-bench [--plus] [--runs N] [file...]");
+        ExitCode::from(64)
+    };
+    let mut dialect = Dialect::Lox;
+    let mut runs = 5;
+    let mut paths = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--plus" => dialect = Dialect::LoxPlus,
+            "--runs" => {
+                runs = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(usage)?;
+            }
+            _ if !arg.starts_with("--") => paths.push(arg),
+            _ => return Err(usage()),
+        }
+    }
+
+    let mut programs = Vec::new();
+    for path in paths {
+        let source = std::fs::read(&path).map_err(|e| {
+            eprintln!("Error: {}: {}", path, e);
+            ExitCode::from(74)
+        })?;
+        programs.push((path, source));
+    }
+    if programs.is_empty() {
+        programs = PROGRAMS
+            .iter()
+            .map(|(name, source)| (name.to_string(), source.as_bytes().to_vec()))
+            .collect();
+    }
+
+    println!(
+        "{:<16} {:>14} {:>10} {:>15} {:>10} {:>8}",
+        "program", "stack instrs", "stack ms", "register instrs", "reg ms", "speedup"
+    );
+    for (name, source) in programs {
+        let stack = measure(
+            &source,
+            runs,
+            || stack_vm(dialect),
+            |vm, source| {
+                vm.interpret(source)?;
+                Ok(vm.instructions_executed())
+            },
+        );
+        let register = measure(
+            &source,
+            runs,
+            || register_vm(dialect),
+            |vm, source| {
+                vm.interpret(source)?;
+                Ok(vm.instructions_executed())
+            },
+        );
+        let (stack, register) = match (stack, register) {
+            (Ok(stack), Ok(register)) => (stack, register),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("{}: {}", name, e);
+                return Err(ExitCode::from(70));
+            }
+        };
+
+        let ms = |time: Duration| time.as_secs_f64() * 1000.0;
+        println!(
+            "{:<16} {:>14} {:>10.2} {:>15} {:>10.2} {:>7.2}x",
+            name,
+            stack.instructions,
+            ms(stack.time),
+            register.instructions,
+            ms(register.time),
+            stack.time.as_secs_f64() / register.time.as_secs_f64(),
+        );
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => code,
+    }
+}
@@ -0,0 +1,13 @@
+use interpreter_rs::dap;
+use std::io;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    match dap::run(io::stdin().lock(), io::stdout()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(74)
+        }
+    }
+}
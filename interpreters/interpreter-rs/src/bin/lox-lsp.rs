@@ -0,0 +1,15 @@
+use interpreter_rs::lsp;
+use std::io;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    match lsp::run(io::stdin().lock(), io::stdout().lock()) {
+        Ok(true) => ExitCode::SUCCESS,
+        // Exit without a shutdown request first
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(74)
+        }
+    }
+}
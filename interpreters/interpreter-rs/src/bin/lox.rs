@@ -0,0 +1,286 @@
+use interpreter_rs::debugger::{Console, Debugger};
+use interpreter_rs::golden;
+use interpreter_rs::lint::{self, Severity};
+use interpreter_rs::parser::Dialect;
+use interpreter_rs::profiler::Profiler;
+use interpreter_rs::repl::Repl;
+use interpreter_rs::vm::{InterpretError, Vm};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// Functions and lines listed by `--profile`.
+const PROFILE_TOP: usize = 20;
+
+/// Directories listed in `LOX_PATH`, where imports not found next to the importing file are
+/// looked up.
+fn search_path() -> Vec<PathBuf> {
+    env::var_os("LOX_PATH")
+        .map(|paths| env::split_paths(&paths).collect())
+        .unwrap_or_default()
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".lox_history"))
+}
+
+fn repl(vm: Vm) -> Result<(), ExitCode> {
+    let mut editor = DefaultEditor::new().map_err(|e| {
+        eprintln!("Error: {}", e);
+        ExitCode::from(74)
+    })?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // Missing on first run
+        let _ = editor.load_history(path);
+    }
+
+    println!("lox interpreter v0.0.1, :help for commands, Ctrl+D to exit");
+    let mut repl = Repl::new(vm);
+    loop {
+        let prompt = if repl.is_pending() { "... " } else { "> " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                repl.clear_pending();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                break;
+            }
+        };
+
+        let Some(entry) = repl.push_line(&line) else {
+            continue;
+        };
+        let _ = editor.add_history_entry(entry.trim_end());
+        if matches!(entry.trim(), ":quit" | ":q") {
+            break;
+        }
+        match repl.eval(&entry) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+    Ok(())
+}
+
+/// `lox lint [--plus] file...`: prints lints and fails if any of them is an error.
+fn lint(args: impl Iterator<Item = String>) -> Result<(), ExitCode> {
+    let mut dialect = Dialect::Lox;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--plus" => dialect = Dialect::LoxPlus,
+            _ if !arg.starts_with("--") => paths.push(arg),
+            _ => {
+                eprintln!("Usage: lox lint [--plus] file...");
+                return Err(ExitCode::from(64));
+            }
+        }
+    }
+    if paths.is_empty() {
+        eprintln!("Usage: lox lint [--plus] file...");
+        return Err(ExitCode::from(64));
+    }
+
+    let mut result = Ok(());
+    for path in paths {
+        let buffer = std::fs::read(&path).map_err(|e| {
+            eprintln!("Error: {}", e);
+            ExitCode::from(74)
+        })?;
+        match lint::lint(&buffer, dialect) {
+            Ok(lints) => {
+                for lint in &lints {
+                    println!("{}: {}", path, lint);
+                }
+                if lints.iter().any(|lint| lint.severity == Severity::Error) {
+                    result = result.and(Err(ExitCode::from(1)));
+                }
+            }
+            Err(diagnostics) => {
+                for diagnostic in diagnostics {
+                    eprintln!("{}: {}", path, diagnostic);
+                }
+                result = Err(ExitCode::from(65));
+            }
+        }
+    }
+    result
+}
+
+/// `lox test [path...]`: runs golden `.lox` files on every backend, `tests/lox` by default.
+fn test(args: impl Iterator<Item = String>) -> Result<(), ExitCode> {
+    let mut roots: Vec<PathBuf> = args.map(PathBuf::from).collect();
+    if roots.is_empty() {
+        roots.push(Path::new("tests").join("lox"));
+    }
+
+    let (mut passed, mut failed) = (0, 0);
+    for root in roots {
+        let files = golden::discover(&root).map_err(|e| {
+            eprintln!("Error: {}: {}", root.display(), e);
+            ExitCode::from(74)
+        })?;
+        for file in files {
+            let mismatches = golden::check_file(&file).map_err(|e| {
+                eprintln!("Error: {}: {}", file.display(), e);
+                ExitCode::from(74)
+            })?;
+            if mismatches.is_empty() {
+                passed += 1;
+            } else {
+                failed += 1;
+            }
+            for mismatch in mismatches {
+                println!("FAIL {} ({})", file.display(), mismatch.backend.name());
+                print!("{}", mismatch.diff);
+            }
+        }
+    }
+    println!("{} passed, {} failed", passed, failed);
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(ExitCode::from(1))
+    }
+}
+
+/// `lox debug [--plus] [--break LINE]... file`: runs a script under the console debugger,
+/// pausing on entry unless breakpoints are given.
+fn debug(mut args: impl Iterator<Item = String>) -> Result<(), ExitCode> {
+    let usage = || {
+        eprintln!("Usage: lox debug [--plus] [--break LINE]... file");
+        ExitCode::from(64)
+    };
+    let mut dialect = Dialect::Lox;
+    let mut breakpoints = Vec::new();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--plus" => dialect = Dialect::LoxPlus,
+            "--break" => {
+                let line = args.next().and_then(|line| line.parse().ok());
+                breakpoints.push(line.ok_or_else(usage)?);
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return Err(usage()),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+    let buffer = std::fs::read(&path).map_err(|e| {
+        eprintln!("Error: {}", e);
+        ExitCode::from(74)
+    })?;
+
+    let mut vm = Vm::new();
+    vm.set_dialect(dialect);
+    vm.set_script_path(&path);
+    vm.set_search_path(search_path());
+    let console = Console::new(io::stdin().lock(), io::stderr());
+    let mut debugger = Debugger::new(console, breakpoints.is_empty());
+    debugger.set_breakpoints(breakpoints);
+    vm.set_debugger(Some(debugger));
+    vm.interpret(&buffer).map_err(|e| {
+        eprintln!("{}", e);
+        match e {
+            InterpretError::Compile(_) => ExitCode::from(65),
+            InterpretError::Runtime(_) => ExitCode::from(70),
+        }
+    })
+}
+
+fn run() -> Result<(), ExitCode> {
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("lint") => return lint(args.skip(1)),
+        Some("test") => return test(args.skip(1)),
+        Some("debug") => return debug(args.skip(1)),
+        _ => {}
+    }
+
+    let mut stress_gc = false;
+    let mut optimize = true;
+    let mut dialect = Dialect::Lox;
+    let mut profile = false;
+    let mut cache = true;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--stress-gc" => stress_gc = true,
+            "--no-optimize" => optimize = false,
+            "--plus" => dialect = Dialect::LoxPlus,
+            "--profile" => profile = true,
+            "--no-cache" => cache = false,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => {
+                eprintln!("Usage: lox [--stress-gc] [--no-optimize] [--plus] [--profile] [--no-cache] [file] | lox lint [--plus] file... | lox test [path...] | lox debug [--plus] [--break LINE]... file");
+                return Err(ExitCode::from(64));
+            }
+        }
+    }
+
+    let mut vm = Vm::new();
+    vm.heap_mut().set_stress(stress_gc);
+    vm.set_dialect(dialect);
+    vm.set_optimize(optimize);
+    vm.set_search_path(search_path());
+
+    let Some(path) = path else {
+        return repl(vm);
+    };
+    let buffer = std::fs::read(&path).map_err(|e| {
+        eprintln!("Error: {}", e);
+        ExitCode::from(74)
+    })?;
+
+    vm.set_script_path(&path);
+    if profile {
+        vm.set_profiler(Some(Profiler::new()));
+    }
+    let result = if cache {
+        vm.interpret_cached(&buffer, &Path::new(&path).with_extension("loxc"))
+    } else {
+        vm.interpret(&buffer)
+    };
+    let result = result.map_err(|e| {
+        eprintln!("{}", e);
+        match e {
+            InterpretError::Compile(_) => ExitCode::from(65),
+            InterpretError::Runtime(_) => ExitCode::from(70),
+        }
+    });
+    if let Some(profiler) = vm.take_profiler() {
+        write_profile(&profiler, &Path::new(&path).with_extension("folded"))?;
+    }
+    result
+}
+
+/// Prints the heaviest functions and lines and writes the folded stacks next to the script.
+fn write_profile(profiler: &Profiler, folded: &Path) -> Result<(), ExitCode> {
+    eprint!("{}", profiler.report(PROFILE_TOP));
+    std::fs::write(folded, profiler.folded()).map_err(|e| {
+        eprintln!("Error: {}: {}", folded.display(), e);
+        ExitCode::from(74)
+    })?;
+    eprintln!("\nFolded stacks written to {}", folded.display());
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => code,
+    }
+}
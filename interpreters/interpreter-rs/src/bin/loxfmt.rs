@@ -0,0 +1,85 @@
+use interpreter_rs::formatter::format;
+use std::env;
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: loxfmt [--check] [file...]";
+
+/// Formats `source`, printing syntax errors prefixed with `name`.
+fn format_source(name: &str, source: &[u8]) -> Result<String, ExitCode> {
+    format(source).map_err(|errors| {
+        for error in errors {
+            eprintln!("{}: {}", name, error);
+        }
+        ExitCode::from(65)
+    })
+}
+
+fn run() -> Result<(), ExitCode> {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--check" => check = true,
+            _ if !arg.starts_with("--") => paths.push(arg),
+            _ => {
+                eprintln!("{}", USAGE);
+                return Err(ExitCode::from(64));
+            }
+        }
+    }
+
+    if paths.is_empty() {
+        let mut source = Vec::new();
+        io::stdin().read_to_end(&mut source).map_err(|e| {
+            eprintln!("Error: {}", e);
+            ExitCode::from(74)
+        })?;
+        let formatted = format_source("<stdin>", &source)?;
+        if check {
+            return if formatted.as_bytes() == source {
+                Ok(())
+            } else {
+                eprintln!("<stdin> is not formatted");
+                Err(ExitCode::FAILURE)
+            };
+        }
+        let _ = io::stdout().write_all(formatted.as_bytes());
+        return Ok(());
+    }
+
+    let mut result = Ok(());
+    for path in &paths {
+        let source = std::fs::read(path).map_err(|e| {
+            eprintln!("{}: {}", path, e);
+            ExitCode::from(74)
+        })?;
+        let formatted = match format_source(path, &source) {
+            Ok(formatted) => formatted,
+            Err(code) => {
+                result = Err(code);
+                continue;
+            }
+        };
+        if formatted.as_bytes() == source {
+            continue;
+        }
+        if check {
+            eprintln!("{} is not formatted", path);
+            result = result.and(Err(ExitCode::FAILURE));
+        } else {
+            std::fs::write(path, formatted).map_err(|e| {
+                eprintln!("{}: {}", path, e);
+                ExitCode::from(74)
+            })?;
+        }
+    }
+    result
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => code,
+    }
+}
@@ -1,13 +1,19 @@
 use lexer::with_opt_iterator_zero_copy::{Tag, Tokenizer};
 use std::env;
-use std::io::{self};
+use std::io::{self, Read};
 
 fn run_interpreter() -> io::Result<()> {
     println!("(zig-like) zlox interpreter v0.0.1");
     println!("Type your code below. Press Ctrl+D (Unix) or Ctrl+Z (Windows) to end input.");
 
-    let args: Vec<String> = env::args().collect();
-    let buffer = std::fs::read(&args[1])?;
+    let buffer = match env::args().nth(1) {
+        Some(path) => std::fs::read(path)?,
+        None => {
+            let mut buffer = Vec::new();
+            io::stdin().read_to_end(&mut buffer)?;
+            buffer
+        }
+    };
 
     for token in Tokenizer::new(&buffer) {
         match token.tag {
@@ -0,0 +1,149 @@
+use crate::value::Value;
+use std::rc::Rc;
+
+#[rustfmt::skip]
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    // Operands are noted as (width in bytes)
+    Constant,       // constant (2)
+    Nil, True, False, Pop,
+    GetLocal,       // slot (1)
+    SetLocal,       // slot (1)
+    GetGlobal,      // name constant (2)
+    DefineGlobal,   // name constant (2)
+    SetGlobal,      // name constant (2)
+    GetUpvalue,     // index (1)
+    SetUpvalue,     // index (1)
+    GetProperty,    // name constant (2)
+    SetProperty,    // name constant (2)
+    GetSuper,       // name constant (2)
+    Equal, Greater, Less, Add, Subtract, Multiply, Divide, Not, Negate,
+    Print,
+    Jump,           // forward offset (2)
+    JumpIfFalse,    // forward offset (2)
+    Loop,           // backward offset (2)
+    Call,           // argument count (1)
+    Invoke,         // name constant (2), argument count (1)
+    SuperInvoke,    // name constant (2), argument count (1)
+    Closure,        // function constant (2), then (is_local, index) pairs (1, 1) per upvalue
+    CloseUpvalue,
+    Return,
+    Class,          // name constant (2)
+    Inherit,
+    Method,         // name constant (2)
+    BuildList,      // element count (2)
+    BuildMap,       // entry count (2)
+    GetIndex, SetIndex,
+    Import,         // path constant (2)
+    FinishImport,
+    PushHandler,    // forward offset to the handler (2)
+    PopHandler, Throw,
+}
+
+impl OpCode {
+    const LAST: u8 = OpCode::Throw as u8;
+
+    #[inline(always)]
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        if byte <= Self::LAST {
+            // SAFETY: OpCode is repr(u8) with contiguous discriminants up to LAST
+            Some(unsafe { std::mem::transmute::<u8, OpCode>(byte) })
+        } else {
+            None
+        }
+    }
+
+    /// Bytes of operands after the opcode, not counting the upvalue pairs after a `Closure`,
+    /// which depend on its function.
+    pub fn operand_len(self) -> usize {
+        match self {
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call => 1,
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::PushHandler
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::Closure
+            | OpCode::BuildList
+            | OpCode::BuildMap
+            | OpCode::Import => 2,
+            OpCode::Invoke | OpCode::SuperInvoke => 3,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    /// Source line for every byte in `code`.
+    pub lines: Vec<u32>,
+    pub constants: Vec<Value>,
+    /// Local variable names for debuggers.
+    pub locals: Vec<LocalInfo>,
+    /// Name of every upvalue, by index.
+    pub upvalue_names: Vec<Rc<str>>,
+}
+
+/// A local variable living in `slot` while the code in `start..end` runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalInfo {
+    pub name: Rc<str>,
+    pub slot: u8,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline(always)]
+    pub fn write(&mut self, byte: u8, line: u32) {
+        self.code.push(byte);
+        self.lines.push(line);
+    }
+
+    /// Drops the code from `len` on, keeping the constants.
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
+    }
+
+    pub fn write_u16(&mut self, value: u16, line: u32) {
+        self.write((value >> 8) as u8, line);
+        self.write(value as u8, line);
+    }
+
+    #[inline(always)]
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        (self.code[offset] as u16) << 8 | self.code[offset + 1] as u16
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        // Reuse an identical constant so repeated names don't exhaust the table
+        let same = |&c: &Value| match (c, value) {
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            _ => c == value,
+        };
+        if let Some(index) = self.constants.iter().position(same) {
+            return index;
+        }
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+}
@@ -0,0 +1,1053 @@
+use crate::ast::*;
+use crate::chunk::{Chunk, LocalInfo, OpCode};
+use crate::diagnostic::Diagnostic;
+use crate::gc::{Heap, Trace};
+use crate::object::{Obj, ObjFunction, ObjRef};
+use crate::optimizer;
+use crate::value::Value;
+use lexer::interner::Symbol;
+use lexer::with_opt_iterator::{Loc, Tag};
+use std::rc::Rc;
+
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct Local {
+    /// `None` for the hidden callee slot of plain functions.
+    name: Option<Symbol>,
+    /// `None` while the variable's initializer is being compiled.
+    depth: Option<u32>,
+    is_captured: bool,
+    /// Index of the variable's entry in [`Chunk::locals`].
+    info: Option<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct UpvalueDesc {
+    index: u8,
+    is_local: bool,
+}
+
+/// A `try` statement whose body or `catch` block is being compiled.
+struct TryState {
+    /// Whether its handler is installed, which in a `catch` block it only is for `finally`.
+    handler: bool,
+    finally: Option<Vec<Stmt>>,
+}
+
+/// A function whose body is still being compiled.
+struct FunctionState {
+    kind: FunctionKind,
+    name: Option<ObjRef>,
+    arity: u8,
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueDesc>,
+    scope_depth: u32,
+    /// Enclosing `try` statements, innermost last, which a `return` leaves.
+    tries: Vec<TryState>,
+}
+
+impl FunctionState {
+    fn new(kind: FunctionKind, name: Option<ObjRef>) -> Self {
+        // Slot zero holds the callee, or the receiver for methods
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => Tag::KeywordThis.keyword_symbol(),
+            _ => None,
+        };
+        let mut chunk = Chunk::new();
+        if slot_zero.is_some() {
+            chunk.locals.push(LocalInfo {
+                name: "this".into(),
+                slot: 0,
+                start: 0,
+                end: usize::MAX,
+            });
+        }
+        FunctionState {
+            kind,
+            name,
+            arity: 0,
+            locals: vec![Local {
+                name: slot_zero,
+                depth: Some(0),
+                is_captured: false,
+                info: slot_zero.map(|_| 0),
+            }],
+            chunk,
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            tries: Vec::new(),
+        }
+    }
+}
+
+struct ClassState {
+    has_superclass: bool,
+}
+
+/// Compiles a parsed program into a top-level script function.
+///
+/// `roots` are the caller's GC roots. The compiler may collect before allocating, in which case it
+/// marks them together with the constants of every function still being compiled.
+pub fn compile(
+    program: &[Stmt],
+    heap: &mut Heap,
+    roots: &dyn Trace,
+) -> Result<ObjRef, Vec<Diagnostic>> {
+    compile_with(program, heap, roots, true)
+}
+
+/// Like [`compile`], but `optimize` can turn off constant folding, dead code elimination and
+/// jump threading. Both produce programs with the same behavior.
+pub fn compile_with(
+    program: &[Stmt],
+    heap: &mut Heap,
+    roots: &dyn Trace,
+    optimize: bool,
+) -> Result<ObjRef, Vec<Diagnostic>> {
+    compile_module(program, heap, roots, optimize, 0)
+}
+
+/// Like [`compile_with`], for a module other than the main script. Its functions use the
+/// globals of `module`.
+pub fn compile_module(
+    program: &[Stmt],
+    heap: &mut Heap,
+    roots: &dyn Trace,
+    optimize: bool,
+    module: usize,
+) -> Result<ObjRef, Vec<Diagnostic>> {
+    let mut compiler = Compiler {
+        heap,
+        roots,
+        states: vec![FunctionState::new(FunctionKind::Script, None)],
+        classes: Vec::new(),
+        errors: Vec::new(),
+        line: 1,
+        strings: Vec::new(),
+        optimize,
+        module,
+    };
+
+    compiler.statements(program);
+    compiler.emit_return();
+
+    let state = compiler.states.pop().unwrap();
+    if !compiler.errors.is_empty() {
+        return Err(compiler.errors);
+    }
+    Ok(compiler.finish(state))
+}
+
+struct Compiler<'a> {
+    heap: &'a mut Heap,
+    roots: &'a dyn Trace,
+    states: Vec<FunctionState>,
+    classes: Vec<ClassState>,
+    errors: Vec<Diagnostic>,
+    line: u32,
+    /// Heap string for every symbol seen so far, so each name is interned in the heap once.
+    strings: Vec<Option<ObjRef>>,
+    optimize: bool,
+    module: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn state(&mut self) -> &mut FunctionState {
+        self.states.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state().chunk
+    }
+
+    /// `finished` is a function that was just popped off the state stack but not allocated yet.
+    fn collect_if_needed(&mut self, finished: Option<&FunctionState>) {
+        if !self.heap.should_collect() {
+            return;
+        }
+        self.roots.trace(self.heap);
+        self.strings.trace(self.heap);
+        for state in self.states.iter().chain(finished) {
+            state.name.trace(self.heap);
+            state.chunk.constants.trace(self.heap);
+        }
+        self.heap.collect();
+    }
+
+    fn intern(&mut self, chars: &str) -> ObjRef {
+        self.collect_if_needed(None);
+        self.heap.intern(chars)
+    }
+
+    fn finish(&mut self, mut state: FunctionState) -> ObjRef {
+        if self.optimize {
+            optimizer::thread_jumps(&mut state.chunk, self.heap);
+        }
+        // Locals still in scope live until the function returns
+        let end = state.chunk.code.len();
+        for local in &mut state.chunk.locals {
+            local.end = local.end.min(end);
+        }
+        self.collect_if_needed(Some(&state));
+        self.heap.alloc(Obj::Function(ObjFunction {
+            name: state.name,
+            arity: state.arity,
+            upvalue_count: state.upvalues.len(),
+            chunk: Rc::new(state.chunk),
+            module: self.module,
+            registers: None,
+        }))
+    }
+
+    fn error(&mut self, name: &Name, message: &str) {
+        self.errors.push(Diagnostic {
+            line: name.line,
+            loc: name.loc,
+            at: format!(" at '{}'", name.text),
+            message: message.to_string(),
+        });
+    }
+
+    fn error_at_line(&mut self, message: &str) {
+        self.errors.push(Diagnostic {
+            line: self.line,
+            loc: Loc { start: 0, end: 0 },
+            at: String::new(),
+            message: message.to_string(),
+        });
+    }
+
+    fn emit(&mut self, byte: u8) {
+        let line = self.line;
+        self.chunk().write(byte, line);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        self.emit(op as u8);
+    }
+
+    fn emit_u16(&mut self, value: u16) {
+        let line = self.line;
+        self.chunk().write_u16(value, line);
+    }
+
+    fn emit_op_u16(&mut self, op: OpCode, operand: u16) {
+        self.emit_op(op);
+        self.emit_u16(operand);
+    }
+
+    fn emit_return(&mut self) {
+        self.emit_implicit_result();
+        self.emit_op(OpCode::Return);
+    }
+
+    /// Pushes what a `return` without a value returns.
+    fn emit_implicit_result(&mut self) {
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit_op(OpCode::GetLocal);
+            self.emit(0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+    }
+
+    fn make_constant(&mut self, value: Value) -> u16 {
+        let index = self.chunk().add_constant(value);
+        if index > u16::MAX as usize {
+            self.error_at_line("Too many constants in one chunk.");
+            return 0;
+        }
+        index as u16
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_op_u16(OpCode::Constant, constant);
+    }
+
+    fn name_string(&mut self, name: &Name) -> ObjRef {
+        let index = name.symbol.index();
+        if let Some(Some(string)) = self.strings.get(index) {
+            return *string;
+        }
+
+        let string = self.intern(&name.text);
+        if self.strings.len() <= index {
+            self.strings.resize(index + 1, None);
+        }
+        self.strings[index] = Some(string);
+        string
+    }
+
+    fn identifier_constant(&mut self, name: &Name) -> u16 {
+        let string = self.name_string(name);
+        self.make_constant(Value::Obj(string))
+    }
+
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_op_u16(op, u16::MAX);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself
+        let jump = self.chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error_at_line("Too much code to jump over.");
+        }
+        let code = &mut self.chunk().code;
+        code[offset] = (jump >> 8) as u8;
+        code[offset + 1] = jump as u8;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+        let offset = self.chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error_at_line("Loop body too large.");
+        }
+        self.emit_u16(offset as u16);
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.state();
+        state.scope_depth -= 1;
+        let depth = state.scope_depth;
+
+        while let Some(local) = self.state().locals.last() {
+            if local.depth.is_some_and(|d| d <= depth) {
+                break;
+            }
+            let op = if local.is_captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            };
+            let info = local.info;
+            self.emit_op(op);
+            let state = self.state();
+            if let Some(info) = info {
+                state.chunk.locals[info].end = state.chunk.code.len();
+            }
+            state.locals.pop();
+        }
+    }
+
+    /// Compiles code that can never run and throws its bytecode away, so it is still checked
+    /// for errors.
+    fn dead_code(&mut self, f: impl FnOnce(&mut Self)) {
+        let len = self.chunk().code.len();
+        f(self);
+        self.chunk().truncate(len);
+    }
+
+    fn statements(&mut self, stmts: &[Stmt]) {
+        for (i, stmt) in stmts.iter().enumerate() {
+            self.statement(stmt);
+            if self.optimize && matches!(stmt.kind, StmtKind::Return(_)) {
+                self.dead_code(|compiler| {
+                    for stmt in &stmts[i + 1..] {
+                        compiler.statement(stmt);
+                    }
+                });
+                return;
+            }
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        self.line = stmt.line;
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expression(expr);
+                self.emit_op(OpCode::Pop);
+            }
+            StmtKind::Print(expr) => {
+                self.expression(expr);
+                self.emit_op(OpCode::Print);
+            }
+            StmtKind::Var(name, initializer) => {
+                let global = self.parse_variable(name);
+                match initializer {
+                    Some(expr) => self.expression(expr),
+                    None => self.emit_op(OpCode::Nil),
+                }
+                self.line = stmt.line;
+                self.define_variable(global);
+            }
+            StmtKind::Block(statements) => self.block(statements),
+            StmtKind::If(condition, then_branch, else_branch) => {
+                if let Some(condition) = self.constant(condition) {
+                    let (live, dead) = match optimizer::is_truthy(&condition) {
+                        true => (Some(then_branch), else_branch.as_ref()),
+                        false => (else_branch.as_ref(), Some(then_branch)),
+                    };
+                    if let Some(dead) = dead {
+                        self.dead_code(|compiler| compiler.statement(dead));
+                    }
+                    if let Some(live) = live {
+                        self.statement(live);
+                    }
+                    return;
+                }
+
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(then_branch);
+
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit_op(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            StmtKind::While(condition, body) => {
+                let loop_start = self.chunk().code.len();
+                if let Some(condition) = self.constant(condition) {
+                    if optimizer::is_truthy(&condition) {
+                        self.statement(body);
+                        self.line = stmt.line;
+                        self.emit_loop(loop_start);
+                    } else {
+                        self.dead_code(|compiler| compiler.statement(body));
+                    }
+                    return;
+                }
+                self.expression(condition);
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(body);
+                self.line = stmt.line;
+                self.emit_loop(loop_start);
+                self.patch_jump(exit_jump);
+                self.emit_op(OpCode::Pop);
+            }
+            StmtKind::Function(function) => {
+                let global = self.parse_variable(&function.name);
+                // Allow the function to refer to itself
+                self.mark_initialized();
+                self.function(function, FunctionKind::Function);
+                self.define_variable(global);
+            }
+            StmtKind::Return(value) => {
+                let kind = self.state().kind;
+                if kind == FunctionKind::Script {
+                    self.error_at_line("Can't return from top-level code.");
+                }
+                match value {
+                    None => self.emit_implicit_result(),
+                    Some(value) => {
+                        if kind == FunctionKind::Initializer {
+                            self.error_at_line("Can't return a value from an initializer.");
+                        }
+                        self.expression(value);
+                    }
+                }
+                if !self.state().tries.is_empty() {
+                    // The result waits in a hidden slot while `finally` blocks run
+                    self.add_hidden_local();
+                    self.leave_tries();
+                    self.state().locals.pop();
+                }
+                self.emit_op(OpCode::Return);
+            }
+            StmtKind::Class(class) => self.class(class),
+            StmtKind::Import(path) => {
+                let path = self.intern(path);
+                let constant = self.make_constant(Value::Obj(path));
+                self.emit_op_u16(OpCode::Import, constant);
+                self.emit_op(OpCode::FinishImport);
+            }
+            StmtKind::Export(declaration) => self.statement(declaration),
+            StmtKind::Throw(value) => {
+                self.expression(value);
+                self.line = stmt.line;
+                self.emit_op(OpCode::Throw);
+            }
+            StmtKind::Try(handler) => self.try_statement(handler),
+        }
+    }
+
+    /// Compiles `try` into a handler around its body. A thrown value lands on the stack at the
+    /// handler's code, where it becomes the `catch` variable. `finally` blocks are copied to
+    /// every way out: the end of the body and of the `catch` block, each `return`, and a
+    /// handler that throws the value on after running the block.
+    fn try_statement(&mut self, handler: &Try) {
+        let finally = handler.finally.as_deref();
+        let catch_jump = self.emit_jump(OpCode::PushHandler);
+        self.guarded(true, finally, |compiler| compiler.block(&handler.body));
+        self.emit_op(OpCode::PopHandler);
+        self.finally_block(finally);
+        let body_exit = self.emit_jump(OpCode::Jump);
+        self.patch_jump(catch_jump);
+
+        let Some((name, body)) = &handler.catch else {
+            self.rethrow(finally.unwrap_or_default(), 1);
+            self.patch_jump(body_exit);
+            return;
+        };
+        self.begin_scope();
+        self.add_local(name);
+        self.mark_initialized();
+        let rethrow_jump = finally.map(|_| self.emit_jump(OpCode::PushHandler));
+        self.guarded(finally.is_some(), finally, |compiler| {
+            compiler.statements(body)
+        });
+        if finally.is_some() {
+            self.emit_op(OpCode::PopHandler);
+        }
+        self.end_scope();
+        self.finally_block(finally);
+        if let (Some(rethrow_jump), Some(finally)) = (rethrow_jump, finally) {
+            let catch_exit = self.emit_jump(OpCode::Jump);
+            self.patch_jump(rethrow_jump);
+            // The catch variable's slot is still below the thrown value
+            self.rethrow(finally, 2);
+            self.patch_jump(catch_exit);
+        }
+        self.patch_jump(body_exit);
+    }
+
+    /// Compiles code a `return` has to leave through `finally`, and the handler if installed.
+    fn guarded(&mut self, handler: bool, finally: Option<&[Stmt]>, f: impl FnOnce(&mut Self)) {
+        self.state().tries.push(TryState {
+            handler,
+            finally: finally.map(<[Stmt]>::to_vec),
+        });
+        f(self);
+        self.state().tries.pop();
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        self.begin_scope();
+        self.statements(stmts);
+        self.end_scope();
+    }
+
+    fn finally_block(&mut self, finally: Option<&[Stmt]>) {
+        if let Some(finally) = finally {
+            self.block(finally);
+        }
+    }
+
+    /// Handler code that runs `finally` and throws the value on top of the stack on, with
+    /// `hidden` values the compiler has no variables for on the stack.
+    fn rethrow(&mut self, finally: &[Stmt], hidden: usize) {
+        for _ in 0..hidden {
+            self.add_hidden_local();
+        }
+        self.block(finally);
+        self.emit_op(OpCode::Throw);
+        let locals = &mut self.state().locals;
+        locals.truncate(locals.len() - hidden);
+    }
+
+    /// Pops the handlers and runs the `finally` blocks of every enclosing `try`, innermost
+    /// first. Each block is compiled as if only the statements around it were enclosing.
+    fn leave_tries(&mut self) {
+        let Some(innermost) = self.state().tries.pop() else {
+            return;
+        };
+        if innermost.handler {
+            self.emit_op(OpCode::PopHandler);
+        }
+        self.finally_block(innermost.finally.as_deref());
+        self.leave_tries();
+        self.state().tries.push(innermost);
+    }
+
+    /// Takes a stack slot for a value no variable names.
+    fn add_hidden_local(&mut self) {
+        if self.state().locals.len() == MAX_LOCALS {
+            self.error_at_line("Too many local variables in function.");
+        }
+        let depth = self.state().scope_depth;
+        self.state().locals.push(Local {
+            name: None,
+            depth: Some(depth),
+            is_captured: false,
+            info: None,
+        });
+    }
+
+    fn class(&mut self, class: &Class) {
+        let name_constant = self.identifier_constant(&class.name);
+        self.declare_variable(&class.name);
+
+        self.emit_op_u16(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if let Some(superclass) = &class.superclass {
+            if superclass.symbol == class.name.symbol {
+                self.error(superclass, "A class can't inherit from itself.");
+            }
+            self.named_variable(superclass);
+
+            self.begin_scope();
+            self.add_local(&Self::synthetic(superclass, Tag::KeywordSuper));
+            self.mark_initialized();
+
+            self.named_variable(&class.name);
+            self.emit_op(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(&class.name);
+        for method in &class.methods {
+            let constant = self.identifier_constant(&method.name);
+            let kind = if &*method.name.text == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            self.function(method, kind);
+            self.line = method.end_line;
+            self.emit_op_u16(OpCode::Method, constant);
+        }
+        self.emit_op(OpCode::Pop);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn function(&mut self, function: &Function, kind: FunctionKind) {
+        let name = self.name_string(&function.name);
+        self.states.push(FunctionState::new(kind, Some(name)));
+        self.begin_scope();
+
+        if function.params.len() > u8::MAX as usize {
+            self.error(&function.params[0], "Can't have more than 255 parameters.");
+        }
+        self.state().arity = function.params.len().min(u8::MAX as usize) as u8;
+        for param in &function.params {
+            let constant = self.parse_variable(param);
+            self.define_variable(constant);
+        }
+
+        self.statements(&function.body);
+        self.line = function.end_line;
+        self.emit_return();
+
+        let state = self.states.pop().unwrap();
+        let upvalues = state.upvalues.clone();
+        let function = self.finish(state);
+
+        let constant = self.make_constant(Value::Obj(function));
+        self.emit_op_u16(OpCode::Closure, constant);
+        for upvalue in upvalues {
+            self.emit(upvalue.is_local as u8);
+            self.emit(upvalue.index);
+        }
+    }
+
+    fn parse_variable(&mut self, name: &Name) -> u16 {
+        self.declare_variable(name);
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+        self.identifier_constant(name)
+    }
+
+    fn declare_variable(&mut self, name: &Name) {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        let depth = state.scope_depth;
+        let duplicate = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d >= depth))
+            .any(|local| local.name == Some(name.symbol));
+        if duplicate {
+            self.error(name, "Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: &Name) {
+        if self.state().locals.len() == MAX_LOCALS {
+            self.error(name, "Too many local variables in function.");
+            return;
+        }
+        let state = self.state();
+        state.chunk.locals.push(LocalInfo {
+            name: name.text.clone(),
+            slot: state.locals.len() as u8,
+            start: usize::MAX,
+            end: usize::MAX,
+        });
+        let info = Some(state.chunk.locals.len() - 1);
+        state.locals.push(Local {
+            name: Some(name.symbol),
+            depth: None,
+            is_captured: false,
+            info,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let depth = state.scope_depth;
+        let local = state.locals.last_mut().unwrap();
+        local.depth = Some(depth);
+        if let Some(info) = local.info {
+            state.chunk.locals[info].start = state.chunk.code.len();
+        }
+    }
+
+    fn define_variable(&mut self, global: u16) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_op_u16(OpCode::DefineGlobal, global);
+    }
+
+    fn resolve_local(&mut self, state_index: usize, name: &Name) -> Option<u8> {
+        let state = &self.states[state_index];
+        let (slot, local) = state
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == Some(name.symbol))?;
+        if local.depth.is_none() {
+            self.error(name, "Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    fn resolve_upvalue(&mut self, state_index: usize, name: &Name) -> Option<u8> {
+        if state_index == 0 {
+            return None;
+        }
+
+        if let Some(local) = self.resolve_local(state_index - 1, name) {
+            self.states[state_index - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state_index, local, true, name));
+        }
+
+        let upvalue = self.resolve_upvalue(state_index - 1, name)?;
+        Some(self.add_upvalue(state_index, upvalue, false, name))
+    }
+
+    fn add_upvalue(&mut self, state_index: usize, index: u8, is_local: bool, name: &Name) -> u8 {
+        let desc = UpvalueDesc { index, is_local };
+        let upvalues = &mut self.states[state_index].upvalues;
+        if let Some(existing) = upvalues.iter().position(|&u| u == desc) {
+            return existing as u8;
+        }
+
+        if upvalues.len() == MAX_UPVALUES {
+            self.error(name, "Too many closure variables in function.");
+            return 0;
+        }
+        upvalues.push(desc);
+        let chunk = &mut self.states[state_index].chunk;
+        chunk.upvalue_names.push(name.text.clone());
+        (chunk.upvalue_names.len() - 1) as u8
+    }
+
+    fn named_variable(&mut self, name: &Name) {
+        let (get, operand) = self.resolve_variable(name);
+        match get {
+            OpCode::GetGlobal => self.emit_op_u16(get, operand),
+            _ => {
+                self.emit_op(get);
+                self.emit(operand as u8);
+            }
+        }
+    }
+
+    /// Returns the get opcode and operand for a variable.
+    fn resolve_variable(&mut self, name: &Name) -> (OpCode, u16) {
+        let current = self.states.len() - 1;
+        if let Some(slot) = self.resolve_local(current, name) {
+            (OpCode::GetLocal, slot as u16)
+        } else if let Some(index) = self.resolve_upvalue(current, name) {
+            (OpCode::GetUpvalue, index as u16)
+        } else {
+            (OpCode::GetGlobal, self.identifier_constant(name))
+        }
+    }
+
+    /// The value of `expr` if it is known at compile time and optimizing is on.
+    fn constant(&self, expr: &Expr) -> Option<Literal> {
+        if !self.optimize {
+            return None;
+        }
+        optimizer::fold(expr)
+    }
+
+    fn literal(&mut self, literal: &Literal) {
+        match literal {
+            Literal::Nil => self.emit_op(OpCode::Nil),
+            Literal::Bool(true) => self.emit_op(OpCode::True),
+            Literal::Bool(false) => self.emit_op(OpCode::False),
+            Literal::Number(n) => self.emit_constant(Value::Number(*n)),
+            Literal::String(s) => {
+                let string = self.intern(s);
+                self.emit_constant(Value::Obj(string));
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        self.line = expr.line;
+        if let ExprKind::Unary(..) | ExprKind::Binary(..) | ExprKind::Logical(..) = expr.kind {
+            if let Some(folded) = self.constant(expr) {
+                self.literal(&folded);
+                return;
+            }
+        }
+        match &expr.kind {
+            ExprKind::Literal(literal) => self.literal(literal),
+            ExprKind::Variable(name) => self.named_variable(name),
+            ExprKind::Assign(name, value) => {
+                self.expression(value);
+                self.line = expr.line;
+                let (get, operand) = self.resolve_variable(name);
+                let set = match get {
+                    OpCode::GetLocal => OpCode::SetLocal,
+                    OpCode::GetUpvalue => OpCode::SetUpvalue,
+                    _ => OpCode::SetGlobal,
+                };
+                if set == OpCode::SetGlobal {
+                    self.emit_op_u16(set, operand);
+                } else {
+                    self.emit_op(set);
+                    self.emit(operand as u8);
+                }
+            }
+            ExprKind::Unary(op, right) => {
+                self.expression(right);
+                self.line = expr.line;
+                match op {
+                    UnaryOp::Negate => self.emit_op(OpCode::Negate),
+                    UnaryOp::Not => self.emit_op(OpCode::Not),
+                }
+            }
+            ExprKind::Binary(left, op, right) => {
+                self.expression(left);
+                self.expression(right);
+                self.line = expr.line;
+                match op {
+                    BinaryOp::Add => self.emit_op(OpCode::Add),
+                    BinaryOp::Subtract => self.emit_op(OpCode::Subtract),
+                    BinaryOp::Multiply => self.emit_op(OpCode::Multiply),
+                    BinaryOp::Divide => self.emit_op(OpCode::Divide),
+                    BinaryOp::Equal => self.emit_op(OpCode::Equal),
+                    BinaryOp::NotEqual => {
+                        self.emit_op(OpCode::Equal);
+                        self.emit_op(OpCode::Not);
+                    }
+                    BinaryOp::Greater => self.emit_op(OpCode::Greater),
+                    BinaryOp::GreaterEqual => {
+                        self.emit_op(OpCode::Less);
+                        self.emit_op(OpCode::Not);
+                    }
+                    BinaryOp::Less => self.emit_op(OpCode::Less),
+                    BinaryOp::LessEqual => {
+                        self.emit_op(OpCode::Greater);
+                        self.emit_op(OpCode::Not);
+                    }
+                }
+            }
+            ExprKind::Logical(left, op, right) => {
+                self.expression(left);
+                self.line = expr.line;
+                match op {
+                    LogicalOp::And => {
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        self.emit_op(OpCode::Pop);
+                        self.expression(right);
+                        self.patch_jump(end_jump);
+                    }
+                    LogicalOp::Or => {
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        let end_jump = self.emit_jump(OpCode::Jump);
+                        self.patch_jump(else_jump);
+                        self.emit_op(OpCode::Pop);
+                        self.expression(right);
+                        self.patch_jump(end_jump);
+                    }
+                }
+            }
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Call(callee, arguments) => self.call(expr, callee, arguments),
+            ExprKind::Get(object, name) => {
+                self.expression(object);
+                self.line = expr.line;
+                let constant = self.identifier_constant(name);
+                self.emit_op_u16(OpCode::GetProperty, constant);
+            }
+            ExprKind::Set(object, name, value) => {
+                self.expression(object);
+                self.expression(value);
+                self.line = expr.line;
+                let constant = self.identifier_constant(name);
+                self.emit_op_u16(OpCode::SetProperty, constant);
+            }
+            ExprKind::This => {
+                if self.classes.is_empty() {
+                    self.error_at_line("Can't use 'this' outside of a class.");
+                    return;
+                }
+                self.this(expr);
+            }
+            ExprKind::Super(method) => {
+                if !self.check_super(method) {
+                    return;
+                }
+                let constant = self.identifier_constant(method);
+                self.this(expr);
+                self.named_variable(&Self::synthetic(method, Tag::KeywordSuper));
+                self.emit_op_u16(OpCode::GetSuper, constant);
+            }
+            ExprKind::List(items) => {
+                for item in items {
+                    self.expression(item);
+                }
+                self.line = expr.line;
+                let count = self.collection_size(items.len(), "elements in a list literal");
+                self.emit_op_u16(OpCode::BuildList, count);
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+                self.line = expr.line;
+                let count = self.collection_size(entries.len(), "entries in a map literal");
+                self.emit_op_u16(OpCode::BuildMap, count);
+            }
+            ExprKind::Index(object, index) => {
+                self.expression(object);
+                self.expression(index);
+                self.line = expr.line;
+                self.emit_op(OpCode::GetIndex);
+            }
+            ExprKind::SetIndex(object, index, value) => {
+                self.expression(object);
+                self.expression(index);
+                self.expression(value);
+                self.line = expr.line;
+                self.emit_op(OpCode::SetIndex);
+            }
+        }
+    }
+
+    fn collection_size(&mut self, len: usize, what: &str) -> u16 {
+        if len > u16::MAX as usize {
+            self.error_at_line(&format!("Can't have more than {} {}.", u16::MAX, what));
+            return 0;
+        }
+        len as u16
+    }
+
+    fn call(&mut self, expr: &Expr, callee: &Expr, arguments: &[Expr]) {
+        let argc = arguments.len().min(u8::MAX as usize) as u8;
+        match &callee.kind {
+            // Fuse property access and call so no bound method is allocated
+            ExprKind::Get(object, name) => {
+                self.expression(object);
+                let constant = self.identifier_constant(name);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.line = expr.line;
+                self.emit_op_u16(OpCode::Invoke, constant);
+                self.emit(argc);
+            }
+            ExprKind::Super(method) => {
+                if !self.check_super(method) {
+                    return;
+                }
+                let constant = self.identifier_constant(method);
+                self.this(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.line = expr.line;
+                self.named_variable(&Self::synthetic(method, Tag::KeywordSuper));
+                self.emit_op_u16(OpCode::SuperInvoke, constant);
+                self.emit(argc);
+            }
+            _ => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.line = expr.line;
+                self.emit_op(OpCode::Call);
+                self.emit(argc);
+            }
+        }
+    }
+
+    fn this(&mut self, expr: &Expr) {
+        let name = Name {
+            text: "this".into(),
+            symbol: Tag::KeywordThis.keyword_symbol().unwrap(),
+            loc: expr.loc,
+            line: expr.line,
+        };
+        self.named_variable(&name);
+    }
+
+    fn check_super(&mut self, method: &Name) -> bool {
+        match self.classes.last() {
+            None => {
+                self.error_at_line("Can't use 'super' outside of a class.");
+                false
+            }
+            Some(class) if !class.has_superclass => {
+                self.error(method, "Can't use 'super' in a class with no superclass.");
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// A name for the hidden `this` or `super` locals, located at `like`.
+    fn synthetic(like: &Name, keyword: Tag) -> Name {
+        let text = match keyword {
+            Tag::KeywordThis => "this",
+            _ => "super",
+        };
+        Name {
+            text: text.into(),
+            symbol: keyword.keyword_symbol().unwrap(),
+            ..like.clone()
+        }
+    }
+}
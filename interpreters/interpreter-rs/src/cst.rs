@@ -0,0 +1,603 @@
+use crate::parser::{Dialect, MAX_DEPTH};
+use lexer::trivia::{TriviaToken, TriviaTokenizer};
+use lexer::with_opt_iterator::Tag;
+
+#[rustfmt::skip]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntaxKind {
+    Program,
+
+    // Declarations and statements
+    ClassDecl, FunDecl, Function, ParamList, VarDecl, ImportDecl, ExportDecl,
+    Block, ExprStmt, PrintStmt, IfStmt, WhileStmt, ForStmt, ReturnStmt, TryStmt, ThrowStmt,
+
+    // Expressions
+    Literal, Name, Super, Grouping, Unary, Binary, Assign, Call, ArgList, Get, Index,
+    ListLit, MapLit, MapEntry,
+
+    /// Tokens that fit nowhere in the grammar
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Element {
+    Node(Node),
+    Token(TriviaToken),
+}
+
+/// Node of the lossless syntax tree. Every token of the source, trivia included, sits in the
+/// tree exactly once and in order, even for malformed input, so printing a tree gives back the
+/// source it was parsed from. Missing tokens are simply absent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub kind: SyntaxKind,
+    pub children: Vec<Element>,
+}
+
+impl Node {
+    /// Appends the source text of this node, trivia included.
+    pub fn write_text(&self, source: &[u8], out: &mut Vec<u8>) {
+        for child in &self.children {
+            match child {
+                Element::Node(node) => node.write_text(source, out),
+                Element::Token(token) => {
+                    let loc = token.full_loc();
+                    out.extend_from_slice(&source[loc.start..loc.end]);
+                }
+            }
+        }
+    }
+
+    pub fn text(&self, source: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_text(source, &mut out);
+        out
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.children.iter().filter_map(|child| match child {
+            Element::Node(node) => Some(node),
+            Element::Token(_) => None,
+        })
+    }
+
+    pub fn first_token(&self) -> Option<&TriviaToken> {
+        self.children.iter().find_map(|child| match child {
+            Element::Node(node) => node.first_token(),
+            Element::Token(token) => Some(token),
+        })
+    }
+
+    pub fn last_token(&self) -> Option<&TriviaToken> {
+        self.children.iter().rev().find_map(|child| match child {
+            Element::Node(node) => node.last_token(),
+            Element::Token(token) => Some(token),
+        })
+    }
+
+    pub fn tokens(&self) -> impl Iterator<Item = &TriviaToken> {
+        self.children.iter().filter_map(|child| match child {
+            Element::Token(token) => Some(token),
+            Element::Node(_) => None,
+        })
+    }
+}
+
+/// Parses `source` into a lossless tree rooted at a [`SyntaxKind::Program`] node, whose last
+/// child is the `Eof` token. Lox+ syntax is always accepted, but its keywords are only keywords
+/// under [`Dialect::LoxPlus`]. Never fails: whatever does not parse ends up in
+/// [`SyntaxKind::Error`] nodes, as does everything nested more than [`MAX_DEPTH`] deep.
+pub fn parse(source: &[u8], dialect: Dialect) -> Node {
+    let mut tokens = tokenize(source, dialect);
+    tokens.reverse();
+    let mut parser = CstParser {
+        tokens,
+        children: Vec::new(),
+        open: Vec::new(),
+        depth: 0,
+    };
+    parser.program();
+    match parser.children.pop() {
+        Some(Element::Node(program)) => program,
+        _ => unreachable!("program() leaves exactly the root node"),
+    }
+}
+
+/// Tokens with their trivia, keywords following [`Dialect::lexer_config`] like the parser.
+pub fn tokenize(source: &[u8], dialect: Dialect) -> Vec<TriviaToken> {
+    let config = dialect.lexer_config();
+    let mut tokenizer = TriviaTokenizer::with_config(source, &config);
+    std::iter::from_fn(|| tokenizer.next_token()).collect()
+}
+
+const BINARY_LEVELS: [&[Tag]; 6] = [
+    &[Tag::KeywordOr],
+    &[Tag::KeywordAnd],
+    &[Tag::BangEqual, Tag::EqualEqual],
+    &[Tag::Greater, Tag::GreaterEqual, Tag::Less, Tag::LessEqual],
+    &[Tag::Minus, Tag::Plus],
+    &[Tag::Slash, Tag::Star],
+];
+
+/// Error-tolerant recursive descent over the Lox grammar. Nodes are built on a stack: `start`
+/// opens a node whose children are everything pushed until the matching `finish`, and
+/// `start_at` opens one retroactively around children pushed since a checkpoint.
+struct CstParser {
+    /// Remaining tokens, reversed
+    tokens: Vec<TriviaToken>,
+    children: Vec<Element>,
+    open: Vec<(SyntaxKind, usize)>,
+    /// Nesting of the node being built, kept within [`MAX_DEPTH`].
+    depth: usize,
+}
+
+impl CstParser {
+    fn peek(&self) -> Tag {
+        self.tokens.last().map_or(Tag::Eof, |token| token.tag())
+    }
+
+    fn at(&self, tags: &[Tag]) -> bool {
+        tags.contains(&self.peek())
+    }
+
+    fn bump(&mut self) {
+        if let Some(token) = self.tokens.pop() {
+            self.children.push(Element::Token(token));
+        }
+    }
+
+    fn eat(&mut self, tag: Tag) -> bool {
+        let found = self.peek() == tag && tag != Tag::Eof;
+        if found {
+            self.bump();
+        }
+        found
+    }
+
+    fn checkpoint(&self) -> usize {
+        self.children.len()
+    }
+
+    fn start(&mut self, kind: SyntaxKind) {
+        self.open.push((kind, self.children.len()));
+    }
+
+    fn start_at(&mut self, checkpoint: usize, kind: SyntaxKind) {
+        self.open.push((kind, checkpoint));
+    }
+
+    fn finish(&mut self) {
+        let (kind, first) = self.open.pop().expect("finish without start");
+        let children = self.children.split_off(first);
+        self.children.push(Element::Node(Node { kind, children }));
+    }
+
+    fn error_token(&mut self) {
+        self.start(SyntaxKind::Error);
+        self.bump();
+        self.finish();
+    }
+
+    /// Runs `parse` and turns the next token into an error if it consumed nothing, so loops
+    /// over it always make progress.
+    fn progress(&mut self, parse: impl FnOnce(&mut Self)) {
+        let remaining = self.tokens.len();
+        parse(self);
+        if self.tokens.len() == remaining {
+            self.error_token();
+        }
+    }
+
+    /// Runs `parse` one level deeper, or past [`MAX_DEPTH`] turns the next token into an error
+    /// instead.
+    fn nested(&mut self, parse: impl FnOnce(&mut Self)) {
+        if self.depth >= MAX_DEPTH {
+            if self.peek() != Tag::Eof {
+                self.error_token();
+            }
+            return;
+        }
+        let depth = self.depth;
+        self.depth += 1;
+        parse(self);
+        self.depth = depth;
+    }
+
+    fn program(&mut self) {
+        self.start(SyntaxKind::Program);
+        while self.peek() != Tag::Eof {
+            self.progress(Self::declaration);
+        }
+        self.bump();
+        self.finish();
+    }
+
+    fn declaration(&mut self) {
+        match self.peek() {
+            Tag::KeywordClass => {
+                self.start(SyntaxKind::ClassDecl);
+                self.bump();
+                self.eat(Tag::Identifier);
+                if self.eat(Tag::Less) {
+                    self.eat(Tag::Identifier);
+                }
+                if self.eat(Tag::LeftBrace) {
+                    while !self.at(&[Tag::RightBrace, Tag::Eof]) {
+                        match self.peek() {
+                            Tag::Identifier => self.function(),
+                            _ => self.error_token(),
+                        }
+                    }
+                    self.eat(Tag::RightBrace);
+                }
+                self.finish();
+            }
+            Tag::KeywordFun => {
+                self.start(SyntaxKind::FunDecl);
+                self.bump();
+                self.function();
+                self.finish();
+            }
+            Tag::KeywordVar => self.var_declaration(),
+            Tag::KeywordImport => {
+                self.start(SyntaxKind::ImportDecl);
+                self.bump();
+                self.eat(Tag::String);
+                self.eat(Tag::Semicolon);
+                self.finish();
+            }
+            Tag::KeywordExport => {
+                self.start(SyntaxKind::ExportDecl);
+                self.bump();
+                if self.at(&[Tag::KeywordClass, Tag::KeywordFun, Tag::KeywordVar]) {
+                    self.declaration();
+                }
+                self.finish();
+            }
+            _ => self.statement(),
+        }
+    }
+
+    fn function(&mut self) {
+        self.start(SyntaxKind::Function);
+        self.eat(Tag::Identifier);
+        self.start(SyntaxKind::ParamList);
+        if self.eat(Tag::LeftParen) {
+            while self.eat(Tag::Identifier) && self.eat(Tag::Comma) {}
+            self.eat(Tag::RightParen);
+        }
+        self.finish();
+        if self.peek() == Tag::LeftBrace {
+            self.block();
+        }
+        self.finish();
+    }
+
+    fn var_declaration(&mut self) {
+        self.start(SyntaxKind::VarDecl);
+        self.bump();
+        self.eat(Tag::Identifier);
+        if self.eat(Tag::Equal) {
+            self.expression();
+        }
+        self.eat(Tag::Semicolon);
+        self.finish();
+    }
+
+    fn statement(&mut self) {
+        match self.peek() {
+            Tag::KeywordPrint => {
+                self.start(SyntaxKind::PrintStmt);
+                self.bump();
+                self.expression();
+                self.eat(Tag::Semicolon);
+                self.finish();
+            }
+            Tag::KeywordReturn => {
+                self.start(SyntaxKind::ReturnStmt);
+                self.bump();
+                if !self.at(&[Tag::Semicolon, Tag::RightBrace, Tag::Eof]) {
+                    self.expression();
+                }
+                self.eat(Tag::Semicolon);
+                self.finish();
+            }
+            Tag::KeywordIf => {
+                self.start(SyntaxKind::IfStmt);
+                self.bump();
+                self.condition();
+                self.nested(Self::statement);
+                if self.eat(Tag::KeywordElse) {
+                    self.nested(Self::statement);
+                }
+                self.finish();
+            }
+            Tag::KeywordWhile => {
+                self.start(SyntaxKind::WhileStmt);
+                self.bump();
+                self.condition();
+                self.nested(Self::statement);
+                self.finish();
+            }
+            Tag::KeywordFor => self.for_statement(),
+            Tag::KeywordTry => {
+                self.start(SyntaxKind::TryStmt);
+                self.bump();
+                self.try_block();
+                if self.eat(Tag::KeywordCatch) {
+                    self.eat(Tag::LeftParen);
+                    self.eat(Tag::Identifier);
+                    self.eat(Tag::RightParen);
+                    self.try_block();
+                }
+                if self.eat(Tag::KeywordFinally) {
+                    self.try_block();
+                }
+                self.finish();
+            }
+            Tag::KeywordThrow => {
+                self.start(SyntaxKind::ThrowStmt);
+                self.bump();
+                self.expression();
+                self.eat(Tag::Semicolon);
+                self.finish();
+            }
+            Tag::LeftBrace => self.block(),
+            Tag::RightBrace | Tag::Eof => {}
+            _ => {
+                self.start(SyntaxKind::ExprStmt);
+                self.expression();
+                self.eat(Tag::Semicolon);
+                self.finish();
+            }
+        }
+    }
+
+    fn try_block(&mut self) {
+        if self.peek() == Tag::LeftBrace {
+            self.block();
+        }
+    }
+
+    fn condition(&mut self) {
+        self.eat(Tag::LeftParen);
+        self.expression();
+        self.eat(Tag::RightParen);
+    }
+
+    fn for_statement(&mut self) {
+        self.start(SyntaxKind::ForStmt);
+        self.bump();
+        self.eat(Tag::LeftParen);
+        match self.peek() {
+            Tag::Semicolon => self.bump(),
+            Tag::KeywordVar => self.var_declaration(),
+            _ => {
+                self.start(SyntaxKind::ExprStmt);
+                self.expression();
+                self.eat(Tag::Semicolon);
+                self.finish();
+            }
+        }
+        if !self.at(&[Tag::Semicolon, Tag::RightParen]) {
+            self.expression();
+        }
+        self.eat(Tag::Semicolon);
+        if !self.at(&[Tag::RightParen, Tag::LeftBrace]) {
+            self.expression();
+        }
+        self.eat(Tag::RightParen);
+        self.nested(Self::statement);
+        self.finish();
+    }
+
+    fn block(&mut self) {
+        self.nested(|parser| {
+            parser.start(SyntaxKind::Block);
+            parser.bump();
+            while !parser.at(&[Tag::RightBrace, Tag::Eof]) {
+                parser.progress(Self::declaration);
+            }
+            parser.eat(Tag::RightBrace);
+            parser.finish();
+        });
+    }
+
+    fn expression(&mut self) {
+        self.nested(|parser| {
+            let checkpoint = parser.checkpoint();
+            parser.binary(0);
+            if parser.peek() == Tag::Equal {
+                parser.start_at(checkpoint, SyntaxKind::Assign);
+                parser.bump();
+                parser.expression();
+                parser.finish();
+            }
+        });
+    }
+
+    fn binary(&mut self, level: usize) {
+        let Some(operators) = BINARY_LEVELS.get(level) else {
+            return self.unary();
+        };
+        let (checkpoint, depth) = (self.checkpoint(), self.depth);
+        self.binary(level + 1);
+        // Each operator nests the operands before it, left over ones become errors
+        while self.at(operators) && self.depth < MAX_DEPTH {
+            self.depth += 1;
+            self.start_at(checkpoint, SyntaxKind::Binary);
+            self.bump();
+            self.binary(level + 1);
+            self.finish();
+        }
+        self.depth = depth;
+    }
+
+    fn unary(&mut self) {
+        if self.at(&[Tag::Bang, Tag::Minus]) {
+            self.start(SyntaxKind::Unary);
+            self.bump();
+            self.nested(Self::unary);
+            self.finish();
+        } else {
+            self.call();
+        }
+    }
+
+    fn call(&mut self) {
+        let (checkpoint, depth) = (self.checkpoint(), self.depth);
+        self.primary();
+        while self.depth < MAX_DEPTH {
+            self.depth += 1;
+            match self.peek() {
+                Tag::LeftParen => {
+                    self.start_at(checkpoint, SyntaxKind::Call);
+                    self.start(SyntaxKind::ArgList);
+                    self.bump();
+                    self.comma_list(Tag::RightParen, Self::expression);
+                    self.finish();
+                    self.finish();
+                }
+                Tag::Dot => {
+                    self.start_at(checkpoint, SyntaxKind::Get);
+                    self.bump();
+                    self.eat(Tag::Identifier);
+                    self.finish();
+                }
+                Tag::LeftBracket => {
+                    self.start_at(checkpoint, SyntaxKind::Index);
+                    self.bump();
+                    self.expression();
+                    self.eat(Tag::RightBracket);
+                    self.finish();
+                }
+                _ => break,
+            }
+        }
+        self.depth = depth;
+    }
+
+    /// Parses `item`s separated by commas, allowing a trailing one, up to and including `end`.
+    fn comma_list(&mut self, end: Tag, item: fn(&mut Self)) {
+        while !self.at(&[end, Tag::Eof]) {
+            let remaining = self.tokens.len();
+            item(self);
+            if !self.eat(Tag::Comma) || self.tokens.len() == remaining {
+                break;
+            }
+        }
+        self.eat(end);
+    }
+
+    fn map_entry(&mut self) {
+        self.start(SyntaxKind::MapEntry);
+        self.expression();
+        self.eat(Tag::Colon);
+        self.expression();
+        self.finish();
+    }
+
+    fn primary(&mut self) {
+        match self.peek() {
+            Tag::Number
+            | Tag::String
+            | Tag::KeywordTrue
+            | Tag::KeywordFalse
+            | Tag::KeywordNil
+            | Tag::KeywordThis => {
+                self.start(SyntaxKind::Literal);
+                self.bump();
+                self.finish();
+            }
+            Tag::Identifier => {
+                self.start(SyntaxKind::Name);
+                self.bump();
+                self.finish();
+            }
+            Tag::KeywordSuper => {
+                self.start(SyntaxKind::Super);
+                self.bump();
+                self.eat(Tag::Dot);
+                self.eat(Tag::Identifier);
+                self.finish();
+            }
+            Tag::LeftParen => {
+                self.start(SyntaxKind::Grouping);
+                self.bump();
+                self.expression();
+                self.eat(Tag::RightParen);
+                self.finish();
+            }
+            Tag::LeftBracket => {
+                self.start(SyntaxKind::ListLit);
+                self.bump();
+                self.comma_list(Tag::RightBracket, Self::expression);
+                self.finish();
+            }
+            Tag::LeftBrace => {
+                self.start(SyntaxKind::MapLit);
+                self.bump();
+                self.comma_list(Tag::RightBrace, Self::map_entry);
+                self.finish();
+            }
+            // Closers belong to an enclosing construct
+            Tag::RightParen | Tag::RightBracket | Tag::RightBrace | Tag::Semicolon | Tag::Eof => {}
+            _ => self.error_token(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trips_source() {
+        let sources: [&[u8]; 4] = [
+            b"// greeting\nclass A < B {\n  init(x) { this.x = x; } // keep\n}\n\nfun f(a, b,) {\n\treturn a + b * -c;\n}\nfor (var i = 0; i < 10; i = i + 1) print i;\n",
+            b"var m = {\"a\": [1, 2,], \"b\": nil}; m[\"a\"][0] = super.x(1)(2).y;\n// trailing",
+            b"if (a) { print ) ; } else while (\"open",
+            b"}} fun (  { var = ; class { 1 } @ # ",
+        ];
+
+        for source in sources {
+            let tree = parse(source, Dialect::LoxPlus);
+            assert_eq!(
+                String::from_utf8_lossy(&tree.text(source)),
+                String::from_utf8_lossy(source)
+            );
+        }
+
+        let tree = parse(sources[0], Dialect::LoxPlus);
+        let kinds: Vec<SyntaxKind> = tree.nodes().map(|node| node.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                SyntaxKind::ClassDecl,
+                SyntaxKind::FunDecl,
+                SyntaxKind::ForStmt
+            ]
+        );
+        let eof = tree.tokens().last().unwrap();
+        assert_eq!(eof.tag(), Tag::Eof);
+    }
+
+    #[test]
+    fn test_deep_nesting_stays_lossless() {
+        fn depth(node: &Node) -> usize {
+            1 + node.nodes().map(depth).max().unwrap_or(0)
+        }
+        let sources = [
+            format!("print {}1{};", "(".repeat(500), ")".repeat(500)),
+            format!("print {}1;", "-".repeat(100_000)),
+            format!("{}{}", "{".repeat(3000), "}".repeat(3000)),
+            format!("print 1{};", " + 1".repeat(100_000)),
+        ];
+        for source in sources {
+            let tree = parse(source.as_bytes(), Dialect::Lox);
+            assert_eq!(tree.text(source.as_bytes()), source.as_bytes());
+            assert!(depth(&tree) < 4 * MAX_DEPTH);
+            assert_eq!(tree.tokens().last().unwrap().tag(), Tag::Eof);
+        }
+    }
+}
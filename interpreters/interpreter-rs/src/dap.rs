@@ -0,0 +1,548 @@
+use crate::debugger::{Debugger, Frontend, PauseReason, Resume, Session, Variable};
+use crate::json::Json;
+use crate::lsp::{read_message, write_message};
+use crate::parser::Dialect;
+use crate::vm::{InterpretError, Vm};
+use std::cell::RefCell;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+/// The only thread, Lox has no concurrency.
+const THREAD_ID: usize = 1;
+/// Variable references per frame: locals, upvalues and globals.
+const SCOPES: usize = 3;
+
+/// Serves the Debug Adapter Protocol over `input` and `output` until the client disconnects.
+/// The program named in `launch` starts running once the client sends `configurationDone`.
+pub fn run(input: impl BufRead + 'static, output: impl Write + 'static) -> io::Result<()> {
+    let connection = Rc::new(RefCell::new(Connection {
+        input: Box::new(input),
+        output: Box::new(output),
+        seq: 0,
+        error: None,
+        disconnected: false,
+    }));
+    let mut launch = Launch::default();
+    loop {
+        let Some(request) = connection.borrow_mut().read_request()? else {
+            return Ok(());
+        };
+        let mut conn = connection.borrow_mut();
+        let start = request.get("command").as_str() == Some("configurationDone");
+        match request.get("command").as_str().unwrap_or("") {
+            "initialize" => {
+                conn.respond(
+                    &request,
+                    Json::object([
+                        ("supportsConfigurationDoneRequest", true.into()),
+                        ("supportsEvaluateForHovers", true.into()),
+                    ]),
+                )?;
+                conn.event("initialized", Json::object([]))?;
+            }
+            "launch" => {
+                let arguments = request.get("arguments");
+                launch.program = arguments.get("program").as_str().map(str::to_string);
+                launch.stop_on_entry = arguments.get("stopOnEntry").as_bool().unwrap_or(false);
+                launch.dialect = match arguments.get("dialect").as_str() {
+                    Some("lox+") => Dialect::LoxPlus,
+                    _ => Dialect::Lox,
+                };
+                conn.respond(&request, Json::Null)?;
+            }
+            "setBreakpoints" => {
+                launch.breakpoints = requested_lines(&request);
+                conn.respond(&request, verified(&launch.breakpoints))?;
+            }
+            "configurationDone" => conn.respond(&request, Json::Null)?,
+            "disconnect" | "terminate" => {
+                conn.respond(&request, Json::Null)?;
+                return Ok(());
+            }
+            _ => conn.common(&request)?,
+        }
+        drop(conn);
+        if start {
+            launch.start(&connection)?;
+        }
+        if connection.borrow().disconnected {
+            return Ok(());
+        }
+    }
+}
+
+#[derive(Default)]
+struct Launch {
+    program: Option<String>,
+    stop_on_entry: bool,
+    dialect: Dialect,
+    breakpoints: Vec<u32>,
+}
+
+impl Launch {
+    /// Runs the program to completion, pausing as the client asks.
+    fn start(&self, connection: &Rc<RefCell<Connection>>) -> io::Result<()> {
+        let Some(program) = &self.program else {
+            connection
+                .borrow_mut()
+                .output("stderr", "No program to launch.\n")?;
+            return connection.borrow_mut().exited(64);
+        };
+        let source = match std::fs::read(program) {
+            Ok(source) => source,
+            Err(e) => {
+                let message = format!("Error: {}: {}\n", program, e);
+                connection.borrow_mut().output("stderr", &message)?;
+                return connection.borrow_mut().exited(74);
+            }
+        };
+
+        let mut vm = Vm::new();
+        vm.set_dialect(self.dialect);
+        vm.set_script_path(program);
+        vm.set_output(Box::new(OutputEvents {
+            connection: connection.clone(),
+            line: Vec::new(),
+        }));
+        let mut debugger = Debugger::new(
+            Adapter {
+                connection: connection.clone(),
+                program: program.clone(),
+            },
+            self.stop_on_entry,
+        );
+        debugger.set_breakpoints(self.breakpoints.iter().copied());
+        vm.set_debugger(Some(debugger));
+        let result = vm.interpret(&source);
+        // Flushes the last line of output
+        drop(vm);
+
+        let mut conn = connection.borrow_mut();
+        if let Some(error) = conn.error.take() {
+            return Err(error);
+        }
+        let code = match result {
+            Ok(()) => 0,
+            Err(e) => {
+                conn.output("stderr", &format!("{}\n", e))?;
+                match e {
+                    InterpretError::Compile(_) => 65,
+                    InterpretError::Runtime(_) => 70,
+                }
+            }
+        };
+        conn.exited(code)
+    }
+}
+
+struct Connection {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    seq: usize,
+    /// I/O error hit while the program was running, reported once it stops.
+    error: Option<io::Error>,
+    disconnected: bool,
+}
+
+impl Connection {
+    fn read_request(&mut self) -> io::Result<Option<Json>> {
+        loop {
+            let Some(body) = read_message(&mut self.input)? else {
+                return Ok(None);
+            };
+            // Clients only send requests, anything unparsable is skipped
+            if let Ok(message) = Json::parse(&body) {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    fn send(&mut self, kind: &str, fields: Vec<(String, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        let mut message = vec![
+            ("seq".to_string(), self.seq.into()),
+            ("type".to_string(), kind.into()),
+        ];
+        message.extend(fields);
+        write_message(&mut self.output, &Json::Object(message))
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        self.send(
+            "response",
+            vec![
+                ("request_seq".to_string(), request.get("seq").clone()),
+                ("success".to_string(), true.into()),
+                ("command".to_string(), request.get("command").clone()),
+                ("body".to_string(), body),
+            ],
+        )
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> io::Result<()> {
+        self.send(
+            "response",
+            vec![
+                ("request_seq".to_string(), request.get("seq").clone()),
+                ("success".to_string(), false.into()),
+                ("command".to_string(), request.get("command").clone()),
+                ("message".to_string(), message.into()),
+            ],
+        )
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(
+            "event",
+            vec![
+                ("event".to_string(), event.into()),
+                ("body".to_string(), body),
+            ],
+        )
+    }
+
+    fn output(&mut self, category: &str, text: &str) -> io::Result<()> {
+        self.event(
+            "output",
+            Json::object([("category", category.into()), ("output", text.into())]),
+        )
+    }
+
+    fn exited(&mut self, code: usize) -> io::Result<()> {
+        self.event("exited", Json::object([("exitCode", code.into())]))?;
+        self.event("terminated", Json::object([]))
+    }
+
+    /// Requests answered the same way whether or not the program is paused.
+    fn common(&mut self, request: &Json) -> io::Result<()> {
+        match request.get("command").as_str().unwrap_or("") {
+            "threads" => self.respond(
+                request,
+                Json::object([(
+                    "threads",
+                    vec![Json::object([
+                        ("id", THREAD_ID.into()),
+                        ("name", "main".into()),
+                    ])]
+                    .into(),
+                )]),
+            ),
+            "stackTrace" | "scopes" | "variables" | "evaluate" => {
+                self.fail(request, "The program isn't paused.")
+            }
+            command => self.fail(request, &format!("Unsupported request '{}'.", command)),
+        }
+    }
+}
+
+fn requested_lines(request: &Json) -> Vec<u32> {
+    request
+        .at(&["arguments", "breakpoints"])
+        .as_array()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|breakpoint| breakpoint.get("line").as_usize())
+        .map(|line| line as u32)
+        .collect()
+}
+
+fn verified(lines: &[u32]) -> Json {
+    let breakpoints: Vec<Json> = lines
+        .iter()
+        .map(|&line| Json::object([("verified", true.into()), ("line", (line as usize).into())]))
+        .collect();
+    Json::object([("breakpoints", breakpoints.into())])
+}
+
+fn variables(variables: Vec<Variable>) -> Json {
+    let variables: Vec<Json> = variables
+        .into_iter()
+        .map(|variable| {
+            Json::object([
+                ("name", variable.name.into()),
+                ("value", variable.value.into()),
+                ("variablesReference", 0usize.into()),
+            ])
+        })
+        .collect();
+    Json::object([("variables", variables.into())])
+}
+
+/// Sends the program's `print` output to the client a line at a time.
+struct OutputEvents {
+    connection: Rc<RefCell<Connection>>,
+    line: Vec<u8>,
+}
+
+impl Write for OutputEvents {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        if self.line.ends_with(b"\n") {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.line.is_empty() {
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        self.connection.borrow_mut().output("stdout", &text)
+    }
+}
+
+impl Drop for OutputEvents {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Answers the client's requests while the program is paused.
+struct Adapter {
+    connection: Rc<RefCell<Connection>>,
+    program: String,
+}
+
+impl Adapter {
+    /// Handles one request, returning how to resume if it ends the pause.
+    fn handle(
+        &self,
+        conn: &mut Connection,
+        session: &mut Session,
+        request: &Json,
+    ) -> io::Result<Option<Resume>> {
+        let arguments = request.get("arguments");
+        match request.get("command").as_str().unwrap_or("") {
+            "stackTrace" => {
+                let frames: Vec<Json> = session
+                    .stack_trace()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, frame)| {
+                        Json::object([
+                            ("id", id.into()),
+                            ("name", frame.function.into()),
+                            ("line", (frame.line as usize).into()),
+                            ("column", 1usize.into()),
+                            (
+                                "source",
+                                Json::object([("path", self.program.as_str().into())]),
+                            ),
+                        ])
+                    })
+                    .collect();
+                let total = frames.len();
+                conn.respond(
+                    request,
+                    Json::object([
+                        ("stackFrames", frames.into()),
+                        ("totalFrames", total.into()),
+                    ]),
+                )?;
+            }
+            "scopes" => {
+                let frame = arguments.get("frameId").as_usize().unwrap_or(0);
+                let scopes: Vec<Json> = ["Locals", "Closure", "Globals"]
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, name)| {
+                        Json::object([
+                            ("name", name.into()),
+                            ("variablesReference", (frame * SCOPES + i + 1).into()),
+                            ("expensive", (name == "Globals").into()),
+                        ])
+                    })
+                    .collect();
+                conn.respond(request, Json::object([("scopes", scopes.into())]))?;
+            }
+            "variables" => {
+                let reference = arguments.get("variablesReference").as_usize().unwrap_or(0);
+                let frame = reference.saturating_sub(1) / SCOPES;
+                if reference == 0 || frame >= session.stack_trace().len() {
+                    conn.fail(request, "Unknown variables reference.")?;
+                    return Ok(None);
+                }
+                let list = match (reference - 1) % SCOPES {
+                    0 => session.frame(frame).locals,
+                    1 => session.frame(frame).upvalues,
+                    _ => session.globals(),
+                };
+                conn.respond(request, variables(list))?;
+            }
+            "evaluate" => {
+                let frame = arguments.get("frameId").as_usize().unwrap_or(0);
+                let expression = arguments.get("expression").as_str().unwrap_or("");
+                match session.evaluate(frame, expression) {
+                    Ok(result) => conn.respond(
+                        request,
+                        Json::object([
+                            ("result", result.into()),
+                            ("variablesReference", 0usize.into()),
+                        ]),
+                    )?,
+                    Err(message) => conn.fail(request, &message)?,
+                }
+            }
+            "setBreakpoints" => {
+                let lines = requested_lines(request);
+                *session.breakpoints_mut() = lines.iter().copied().collect();
+                conn.respond(request, verified(&lines))?;
+            }
+            command @ ("continue" | "next" | "stepIn" | "stepOut") => {
+                conn.respond(
+                    request,
+                    Json::object([("allThreadsContinued", true.into())]),
+                )?;
+                return Ok(Some(match command {
+                    "continue" => Resume::Continue,
+                    "next" => Resume::StepOver,
+                    "stepIn" => Resume::StepIn,
+                    _ => Resume::StepOut,
+                }));
+            }
+            "disconnect" | "terminate" => {
+                conn.respond(request, Json::Null)?;
+                conn.disconnected = true;
+                return Ok(Some(Resume::Stop));
+            }
+            _ => conn.common(request)?,
+        }
+        Ok(None)
+    }
+
+    fn pause(&self, session: &mut Session, reason: PauseReason) -> io::Result<Resume> {
+        let mut conn = self.connection.borrow_mut();
+        conn.event(
+            "stopped",
+            Json::object([
+                ("reason", reason.name().into()),
+                ("threadId", THREAD_ID.into()),
+                ("allThreadsStopped", true.into()),
+            ]),
+        )?;
+        loop {
+            let Some(request) = conn.read_request()? else {
+                conn.disconnected = true;
+                return Ok(Resume::Stop);
+            };
+            if let Some(resume) = self.handle(&mut conn, session, &request)? {
+                return Ok(resume);
+            }
+        }
+    }
+}
+
+impl Frontend for Adapter {
+    fn paused(&mut self, session: &mut Session, reason: PauseReason) -> Resume {
+        self.pause(session, reason).unwrap_or_else(|e| {
+            self.connection.borrow_mut().error = Some(e);
+            Resume::Stop
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::OutputBuffer;
+
+    fn request(seq: usize, command: &str, arguments: Json) -> Json {
+        Json::object([
+            ("seq", seq.into()),
+            ("type", "request".into()),
+            ("command", command.into()),
+            ("arguments", arguments),
+        ])
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let path = std::env::temp_dir().join(format!("lox-dap-{}.lox", std::process::id()));
+        std::fs::write(
+            &path,
+            "fun square(n) {\n  var result = n * n;\n  return result;\n}\nprint square(3);\n",
+        )
+        .unwrap();
+        let program = path.to_str().unwrap();
+
+        let mut input = Vec::new();
+        for message in [
+            request(1, "initialize", Json::object([])),
+            request(2, "launch", Json::object([("program", program.into())])),
+            request(
+                3,
+                "setBreakpoints",
+                Json::object([
+                    ("source", Json::object([("path", program.into())])),
+                    (
+                        "breakpoints",
+                        vec![Json::object([("line", 3usize.into())])].into(),
+                    ),
+                ]),
+            ),
+            request(4, "configurationDone", Json::Null),
+            request(5, "stackTrace", Json::object([("threadId", 1usize.into())])),
+            request(
+                6,
+                "variables",
+                Json::object([("variablesReference", 1usize.into())]),
+            ),
+            request(
+                7,
+                "evaluate",
+                Json::object([
+                    ("expression", "result + 1".into()),
+                    ("frameId", 0usize.into()),
+                ]),
+            ),
+            request(8, "continue", Json::object([("threadId", 1usize.into())])),
+            request(9, "disconnect", Json::object([])),
+        ] {
+            write_message(&mut input, &message).unwrap();
+        }
+        let output = OutputBuffer::new();
+        run(io::Cursor::new(input), output.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let output = output.contents();
+        let mut reader = output.as_bytes();
+        let mut messages = Vec::new();
+        while let Some(body) = read_message(&mut reader).unwrap() {
+            messages.push(Json::parse(&body).unwrap());
+        }
+        let response = |seq: usize| {
+            messages
+                .iter()
+                .find(|m| m.get("request_seq").as_usize() == Some(seq))
+                .unwrap()
+        };
+        let events: Vec<&str> = messages
+            .iter()
+            .filter_map(|m| m.get("event").as_str())
+            .collect();
+
+        assert_eq!(
+            events,
+            ["initialized", "stopped", "output", "exited", "terminated"]
+        );
+        let frames = response(5).at(&["body", "stackFrames"]).as_array().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].get("name").as_str(), Some("square()"));
+        assert_eq!(frames[0].get("line").as_usize(), Some(3));
+        let locals = response(6).at(&["body", "variables"]).to_string();
+        assert!(
+            locals.contains("{\"name\":\"result\",\"value\":\"9\""),
+            "{}",
+            locals
+        );
+        assert_eq!(response(7).at(&["body", "result"]).as_str(), Some("10"));
+        let output = messages
+            .iter()
+            .find(|m| m.get("event").as_str() == Some("output"))
+            .unwrap();
+        assert_eq!(output.at(&["body", "output"]).as_str(), Some("9\n"));
+        assert_eq!(response(9).get("success").as_bool(), Some(true));
+    }
+}
@@ -0,0 +1,576 @@
+use crate::ast::*;
+use crate::gc::Heap;
+use crate::object::{Obj, ObjRef};
+use crate::parser::{self, Dialect};
+use crate::value::Value;
+use crate::vm::{self, Vm};
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+/// How to go on after a pause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    /// Pause at the next line, entering calls, or once this function returns.
+    StepIn,
+    /// Pause at the next line of this function, or once it returns.
+    StepOver,
+    /// Pause once this function returns.
+    StepOut,
+    /// End the program with a runtime error.
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PauseReason {
+    Entry,
+    Breakpoint,
+    Step,
+}
+
+impl PauseReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            PauseReason::Entry => "entry",
+            PauseReason::Breakpoint => "breakpoint",
+            PauseReason::Step => "step",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// `f()` for functions and `script` for top-level code, like runtime error traces.
+    pub function: String,
+    pub line: u32,
+    pub locals: Vec<Variable>,
+    pub upvalues: Vec<Variable>,
+}
+
+/// Where pauses are reported and resume commands come from, e.g. a console or an editor.
+pub trait Frontend {
+    fn paused(&mut self, session: &mut Session, reason: PauseReason) -> Resume;
+}
+
+/// Breakpoints and stepping state, attached with [`Vm::set_debugger`].
+pub struct Debugger {
+    frontend: Box<dyn Frontend>,
+    breakpoints: BTreeSet<u32>,
+    resume: Resume,
+    /// Frame count when the last step started.
+    step_depth: usize,
+    /// Line last run in every active frame, outermost first.
+    lines: Vec<u32>,
+    stop_on_entry: bool,
+}
+
+impl Debugger {
+    pub fn new(frontend: impl Frontend + 'static, stop_on_entry: bool) -> Self {
+        Debugger {
+            frontend: Box::new(frontend),
+            breakpoints: BTreeSet::new(),
+            resume: Resume::Continue,
+            step_depth: 0,
+            lines: Vec::new(),
+            stop_on_entry,
+        }
+    }
+
+    pub fn set_breakpoints(&mut self, lines: impl IntoIterator<Item = u32>) {
+        self.breakpoints = lines.into_iter().collect();
+    }
+
+    /// Decides whether the instruction about to run on `line`, `depth` frames deep, pauses.
+    /// Pauses happen when a frame moves to another line, so a call returning to its caller's
+    /// line doesn't pause again.
+    fn should_pause(&mut self, depth: usize, line: u32) -> Option<PauseReason> {
+        self.lines.truncate(depth);
+        let new_line = self.lines.len() < depth || self.lines[depth - 1] != line;
+        self.lines.resize(depth, 0);
+        self.lines[depth - 1] = line;
+
+        if self.stop_on_entry {
+            self.stop_on_entry = false;
+            return Some(PauseReason::Entry);
+        }
+        let returned = depth < self.step_depth;
+        match self.resume {
+            Resume::StepIn | Resume::StepOver | Resume::StepOut if returned => {
+                return Some(PauseReason::Step)
+            }
+            _ if !new_line => return None,
+            _ => {}
+        }
+        if self.breakpoints.contains(&line) {
+            return Some(PauseReason::Breakpoint);
+        }
+        match self.resume {
+            Resume::StepIn => Some(PauseReason::Step),
+            Resume::StepOver if depth <= self.step_depth => Some(PauseReason::Step),
+            _ => None,
+        }
+    }
+}
+
+/// Runs before every instruction while a debugger is attached.
+pub(crate) fn on_instruction(vm: &mut Vm) -> Result<(), String> {
+    let Some(mut debugger) = vm.take_debugger() else {
+        return Ok(());
+    };
+    let depth = vm.frame_count();
+    let (closure, offset, _) = vm.frame_state(depth - 1);
+    let line = line_at(vm.heap(), closure, offset);
+
+    let mut result = Ok(());
+    if let Some(reason) = debugger.should_pause(depth, line) {
+        let mut session = Session {
+            vm: &mut *vm,
+            breakpoints: &mut debugger.breakpoints,
+        };
+        let resume = debugger.frontend.paused(&mut session, reason);
+        if resume == Resume::Stop {
+            result = Err("Stopped by the debugger.".to_string());
+        }
+        debugger.resume = resume;
+        debugger.step_depth = depth;
+    }
+    vm.restore_debugger(debugger);
+    result
+}
+
+fn line_at(heap: &Heap, closure: ObjRef, offset: usize) -> u32 {
+    let function = heap.function(heap.closure(closure).function);
+    function.chunk.lines[offset]
+}
+
+/// A paused program. Frames are numbered from the innermost one, which is zero.
+pub struct Session<'a> {
+    vm: &'a mut Vm,
+    breakpoints: &'a mut BTreeSet<u32>,
+}
+
+impl Session<'_> {
+    pub fn breakpoints(&self) -> &BTreeSet<u32> {
+        self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut BTreeSet<u32> {
+        self.breakpoints
+    }
+
+    pub fn heap(&self) -> &Heap {
+        self.vm.heap()
+    }
+
+    pub fn stack_trace(&self) -> Vec<Frame> {
+        (0..self.vm.frame_count())
+            .map(|frame| self.frame(frame))
+            .collect()
+    }
+
+    pub fn frame(&self, frame: usize) -> Frame {
+        let heap = self.vm.heap();
+        let (closure, offset, _) = self.vm.frame_state(self.vm.frame_count() - 1 - frame);
+        let function = heap.function(heap.closure(closure).function);
+        let upvalues = function
+            .chunk
+            .upvalue_names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| Variable {
+                name: name.to_string(),
+                value: display(heap, self.vm.read_upvalue(closure, index)),
+            })
+            .collect();
+        Frame {
+            function: match function.name {
+                Some(name) => format!("{}()", heap.str(name)),
+                None => "script".to_string(),
+            },
+            line: line_at(heap, closure, offset),
+            locals: self
+                .locals(frame)
+                .into_iter()
+                .map(|(name, value)| Variable {
+                    name,
+                    value: display(heap, value),
+                })
+                .collect(),
+            upvalues,
+        }
+    }
+
+    /// Globals in name order.
+    pub fn globals(&self) -> Vec<Variable> {
+        let heap = self.vm.heap();
+        let mut globals: Vec<Variable> = self
+            .vm
+            .globals()
+            .iter()
+            .map(|(name, value)| Variable {
+                name: heap.str(name).to_string(),
+                value: display(heap, value),
+            })
+            .collect();
+        globals.sort_by(|a, b| a.name.cmp(&b.name));
+        globals
+    }
+
+    /// Locals in scope in `frame` by slot, leaving out shadowed ones.
+    fn locals(&self, frame: usize) -> Vec<(String, Value)> {
+        let heap = self.vm.heap();
+        let (closure, offset, slots) = self.vm.frame_state(self.vm.frame_count() - 1 - frame);
+        let function = heap.function(heap.closure(closure).function);
+        let mut visible: Vec<_> = function
+            .chunk
+            .locals
+            .iter()
+            .filter(|local| local.start <= offset && offset < local.end)
+            .collect();
+        visible.sort_by_key(|local| local.slot);
+        let mut locals: Vec<(String, Value)> = Vec::new();
+        for local in visible {
+            locals.retain(|(name, _)| **name != *local.name);
+            let value = self.vm.stack_value(slots + local.slot as usize);
+            locals.push((local.name.to_string(), value));
+        }
+        locals
+    }
+
+    /// Evaluates a Lox+ expression in `frame`. Only expressions without side effects are
+    /// allowed, so calls and assignments are rejected.
+    pub fn evaluate(&mut self, frame: usize, source: &str) -> Result<String, String> {
+        let source = format!("{};", source.trim().trim_end_matches(';'));
+        let program =
+            parser::parse_dialect(source.as_bytes(), Dialect::LoxPlus).map_err(|errors| {
+                let messages: Vec<String> = errors.iter().map(|e| e.message.clone()).collect();
+                messages.join("\n")
+            })?;
+        let [Stmt {
+            kind: StmtKind::Expression(expr),
+            ..
+        }] = &program[..]
+        else {
+            return Err("Expect a single expression.".to_string());
+        };
+        let value = self.eval(frame, expr)?;
+        Ok(display(self.vm.heap(), value))
+    }
+
+    fn variable(&self, frame: usize, name: &str) -> Result<Value, String> {
+        if let Some((_, value)) = self.locals(frame).into_iter().find(|(n, _)| n == name) {
+            return Ok(value);
+        }
+        let heap = self.vm.heap();
+        let (closure, _, _) = self.vm.frame_state(self.vm.frame_count() - 1 - frame);
+        let function = heap.function(heap.closure(closure).function);
+        if let Some(index) = function
+            .chunk
+            .upvalue_names
+            .iter()
+            .position(|n| **n == *name)
+        {
+            return Ok(self.vm.read_upvalue(closure, index));
+        }
+        self.vm
+            .globals()
+            .iter()
+            .find(|&(key, _)| heap.str(key) == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| format!("Undefined variable '{}'.", name))
+    }
+
+    fn eval(&mut self, frame: usize, expr: &Expr) -> Result<Value, String> {
+        // Strings made here aren't rooted, which is fine as nothing collects while paused
+        Ok(match &expr.kind {
+            ExprKind::Literal(Literal::Nil) => Value::Nil,
+            ExprKind::Literal(Literal::Bool(b)) => Value::Bool(*b),
+            ExprKind::Literal(Literal::Number(n)) => Value::Number(*n),
+            ExprKind::Literal(Literal::String(s)) => Value::Obj(self.vm.heap_mut().intern(s)),
+            ExprKind::Variable(name) => self.variable(frame, &name.text)?,
+            ExprKind::This => self
+                .variable(frame, "this")
+                .map_err(|_| "Can't use 'this' outside of a class.".to_string())?,
+            ExprKind::Grouping(inner) => self.eval(frame, inner)?,
+            ExprKind::Unary(op, right) => match (op, self.eval(frame, right)?) {
+                (UnaryOp::Negate, Value::Number(n)) => Value::Number(-n),
+                (UnaryOp::Negate, _) => return Err("Operand must be a number.".to_string()),
+                (UnaryOp::Not, value) => Value::Bool(value.is_falsey()),
+            },
+            ExprKind::Logical(left, op, right) => {
+                let left = self.eval(frame, left)?;
+                match (op, left.is_falsey()) {
+                    (LogicalOp::And, true) | (LogicalOp::Or, false) => left,
+                    _ => self.eval(frame, right)?,
+                }
+            }
+            ExprKind::Binary(left, op, right) => {
+                let left = self.eval(frame, left)?;
+                let right = self.eval(frame, right)?;
+                self.binary(left, *op, right)?
+            }
+            ExprKind::Get(object, name) => {
+                let object = self.eval(frame, object)?;
+                let heap = self.vm.heap_mut();
+                let key = heap.intern(&name.text);
+                let Some(Obj::Instance(instance)) = object.as_obj().map(|r| heap.get(r)) else {
+                    return Err("Only instances have properties.".to_string());
+                };
+                instance
+                    .fields
+                    .get(key)
+                    .or_else(|| heap.class(instance.class).methods.get(key))
+                    .ok_or_else(|| format!("Undefined property '{}'.", name.text))?
+            }
+            ExprKind::Index(object, index) => {
+                let object = self.eval(frame, object)?;
+                let index = self.eval(frame, index)?;
+                vm::get_index(self.vm.heap_mut(), object, index)?
+            }
+            _ => return Err("Only expressions without side effects can be evaluated.".to_string()),
+        })
+    }
+
+    fn binary(&mut self, left: Value, op: BinaryOp, right: Value) -> Result<Value, String> {
+        let heap = self.vm.heap_mut();
+        let (a, b) = match (op, left, right) {
+            (BinaryOp::Equal, a, b) => return Ok(Value::Bool(a == b)),
+            (BinaryOp::NotEqual, a, b) => return Ok(Value::Bool(a != b)),
+            (_, Value::Number(a), Value::Number(b)) => (a, b),
+            (BinaryOp::Add, a, b) => {
+                return match (heap.as_string(a), heap.as_string(b)) {
+                    (Some(a), Some(b)) => {
+                        let joined = format!("{}{}", a, b);
+                        Ok(Value::Obj(heap.take_string(joined)))
+                    }
+                    _ => Err("Operands must be two numbers or two strings.".to_string()),
+                };
+            }
+            _ => return Err("Operands must be numbers.".to_string()),
+        };
+        Ok(match op {
+            BinaryOp::Add => Value::Number(a + b),
+            BinaryOp::Subtract => Value::Number(a - b),
+            BinaryOp::Multiply => Value::Number(a * b),
+            BinaryOp::Divide => Value::Number(a / b),
+            BinaryOp::Greater => Value::Bool(a > b),
+            BinaryOp::GreaterEqual => Value::Bool(a >= b),
+            BinaryOp::Less => Value::Bool(a < b),
+            BinaryOp::LessEqual => Value::Bool(a <= b),
+            BinaryOp::Equal | BinaryOp::NotEqual => unreachable!("equality handled above"),
+        })
+    }
+}
+
+/// Formats a value for display, quoting strings so they stand out from other values.
+fn display(heap: &Heap, value: Value) -> String {
+    match heap.as_string(value) {
+        Some(s) => format!("\"{}\"", s),
+        None => heap.format_value(value),
+    }
+}
+
+const CONSOLE_HELP: &str = "\
+c, continue        run to the next breakpoint
+s, step            step to the next line, entering calls
+n, next            step to the next line of this function
+o, out             run until this function returns
+bt, backtrace      list the active frames
+f, frame N         select frame N for locals and print
+l, locals          show locals and upvalues of the selected frame
+g, globals         show globals
+p, print EXPR      evaluate an expression in the selected frame
+b, break [LINE]    set a breakpoint, or list them
+d, delete LINE     remove a breakpoint
+q, quit            stop the program";
+
+/// Line-based command frontend, e.g. over standard input and standard error.
+pub struct Console<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Console<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Console { input, output }
+    }
+
+    /// Runs one command, returning how to resume if it ends the pause.
+    fn command(&mut self, session: &mut Session, frame: &mut usize, line: &str) -> Option<Resume> {
+        let (command, argument) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        let argument = argument.trim();
+        let out = &mut self.output;
+        match command {
+            "c" | "continue" => return Some(Resume::Continue),
+            "s" | "step" => return Some(Resume::StepIn),
+            "n" | "next" => return Some(Resume::StepOver),
+            "o" | "out" => return Some(Resume::StepOut),
+            "q" | "quit" => return Some(Resume::Stop),
+            "bt" | "backtrace" => {
+                for (i, f) in session.stack_trace().iter().enumerate() {
+                    let _ = writeln!(out, "#{} {} at line {}", i, f.function, f.line);
+                }
+            }
+            "f" | "frame" => match argument.parse::<usize>() {
+                Ok(n) if n < session.stack_trace().len() => *frame = n,
+                _ => {
+                    let _ = writeln!(out, "No frame '{}'.", argument);
+                }
+            },
+            "l" | "locals" => {
+                let f = session.frame(*frame);
+                for variable in f.locals.iter().chain(&f.upvalues) {
+                    let _ = writeln!(out, "{} = {}", variable.name, variable.value);
+                }
+            }
+            "g" | "globals" => {
+                for variable in session.globals() {
+                    let _ = writeln!(out, "{} = {}", variable.name, variable.value);
+                }
+            }
+            "p" | "print" => match session.evaluate(*frame, argument) {
+                Ok(value) => {
+                    let _ = writeln!(out, "{}", value);
+                }
+                Err(message) => {
+                    let _ = writeln!(out, "Error: {}", message);
+                }
+            },
+            "b" | "break" if argument.is_empty() => {
+                for line in session.breakpoints() {
+                    let _ = writeln!(out, "line {}", line);
+                }
+            }
+            "b" | "break" | "d" | "delete" => match argument.parse::<u32>() {
+                Ok(line) if command.starts_with('b') => {
+                    session.breakpoints_mut().insert(line);
+                }
+                Ok(line) => {
+                    session.breakpoints_mut().remove(&line);
+                }
+                Err(_) => {
+                    let _ = writeln!(out, "Expected a line number.");
+                }
+            },
+            "h" | "help" => {
+                let _ = writeln!(out, "{}", CONSOLE_HELP);
+            }
+            "" => {}
+            _ => {
+                let _ = writeln!(out, "Unknown command '{}', 'help' lists them.", command);
+            }
+        }
+        None
+    }
+}
+
+impl<R: BufRead, W: Write> Frontend for Console<R, W> {
+    fn paused(&mut self, session: &mut Session, reason: PauseReason) -> Resume {
+        let top = session.frame(0);
+        let _ = writeln!(
+            self.output,
+            "Paused ({}) at line {} in {}",
+            reason.name(),
+            top.line,
+            top.function
+        );
+        let mut frame = 0;
+        loop {
+            let _ = write!(self.output, "(lox) ");
+            let _ = self.output.flush();
+            let mut line = String::new();
+            // Let the program finish once input runs out
+            if !matches!(self.input.read_line(&mut line), Ok(n) if n > 0) {
+                return Resume::Continue;
+            }
+            if let Some(resume) = self.command(session, &mut frame, &line) {
+                return resume;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::OutputBuffer;
+
+    #[test]
+    fn test_console_session() {
+        let source = "\
+var total = 0;
+fun add(n) {
+  var doubled = n * 2;
+  total = total + doubled;
+  return total;
+}
+{
+  var x = 10;
+  add(x);
+  print add(1);
+}
+print total;
+";
+        // Function declarations run on their closing brace's line
+        let commands = "\
+n
+b 4
+c
+bt
+l
+f 1
+l
+p doubled
+p x + total
+p add(1)
+d 4
+o
+n
+l
+s
+s
+s
+c
+";
+        let console = OutputBuffer::new();
+        let program = OutputBuffer::new();
+        let mut vm = Vm::new();
+        vm.set_output(Box::new(program.clone()));
+        vm.set_debugger(Some(Debugger::new(
+            Console::new(commands.as_bytes(), console.clone()),
+            true,
+        )));
+        vm.interpret(source.as_bytes()).unwrap();
+
+        let text = console.contents().replace("(lox) ", "");
+        assert_eq!(
+            text,
+            "\
+Paused (entry) at line 1 in script
+Paused (step) at line 6 in script
+Paused (breakpoint) at line 4 in add()
+#0 add() at line 4
+#1 script at line 9
+n = 10
+doubled = 20
+x = 10
+Error: Undefined variable 'doubled'.
+10
+Error: Only expressions without side effects can be evaluated.
+Paused (step) at line 9 in script
+Paused (step) at line 10 in script
+x = 10
+Paused (step) at line 3 in add()
+Paused (step) at line 4 in add()
+Paused (step) at line 5 in add()
+"
+        );
+        assert_eq!(program.contents(), "22\n22\n");
+    }
+}
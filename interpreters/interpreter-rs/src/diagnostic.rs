@@ -0,0 +1,18 @@
+use lexer::with_opt_iterator::Loc;
+use std::fmt;
+
+/// A static error found while parsing or compiling, reported before anything runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: u32,
+    pub loc: Loc,
+    /// Preformatted location suffix, e.g. ` at 'x'`, ` at end` or empty.
+    pub at: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Error{}: {}", self.line, self.at, self.message)
+    }
+}
@@ -0,0 +1,157 @@
+use crate::chunk::{Chunk, OpCode};
+use crate::gc::Heap;
+use crate::object::{Obj, ObjRef};
+use crate::value::Value;
+use std::fmt::Write;
+
+/// Disassembles a compiled function followed by every function nested in its constants.
+pub fn disassemble(heap: &Heap, function: ObjRef) -> String {
+    let mut out = String::new();
+    let mut pending = vec![function];
+    while let Some(function) = pending.pop() {
+        let f = heap.function(function);
+        let name = heap.format_value(Value::Obj(function));
+        disassemble_chunk(heap, &f.chunk, &name, &mut out);
+
+        // Queue nested functions in reverse so they print in declaration order
+        for &constant in f.chunk.constants.iter().rev() {
+            if let Value::Obj(r) = constant {
+                if matches!(heap.get(r), Obj::Function(_)) {
+                    pending.push(r);
+                }
+            }
+        }
+    }
+    out
+}
+
+pub fn disassemble_chunk(heap: &Heap, chunk: &Chunk, name: &str, out: &mut String) {
+    let _ = writeln!(out, "== {} ==", name);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = disassemble_instruction(heap, chunk, offset, out);
+    }
+}
+
+/// Writes one instruction and returns the offset of the next.
+pub fn disassemble_instruction(
+    heap: &Heap,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut String,
+) -> usize {
+    let _ = write!(out, "{:04} ", offset);
+    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+        let _ = write!(out, "   | ");
+    } else {
+        let _ = write!(out, "{:4} ", chunk.lines[offset]);
+    }
+
+    let Some(op) = OpCode::from_byte(chunk.code[offset]) else {
+        let _ = writeln!(out, "Unknown opcode {}", chunk.code[offset]);
+        return offset + 1;
+    };
+    let name = format!("{:?}", op);
+    let next = offset + 1 + op.operand_len();
+
+    match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Method
+        | OpCode::Import => {
+            let constant = chunk.read_u16(offset + 1);
+            let value = heap.format_value(chunk.constants[constant as usize]);
+            let _ = writeln!(out, "{:<16} {:4} '{}'", name, constant, value);
+            next
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call => {
+            let _ = writeln!(out, "{:<16} {:4}", name, chunk.code[offset + 1]);
+            next
+        }
+        OpCode::BuildList | OpCode::BuildMap => {
+            let _ = writeln!(out, "{:<16} {:4}", name, chunk.read_u16(offset + 1));
+            next
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::PushHandler => {
+            let jump = chunk.read_u16(offset + 1) as usize;
+            let _ = writeln!(out, "{:<16} {:4} -> {}", name, offset, next + jump);
+            next
+        }
+        OpCode::Loop => {
+            let jump = chunk.read_u16(offset + 1) as usize;
+            let _ = writeln!(out, "{:<16} {:4} -> {}", name, offset, next - jump);
+            next
+        }
+        OpCode::Invoke | OpCode::SuperInvoke => {
+            let constant = chunk.read_u16(offset + 1);
+            let argc = chunk.code[offset + 3];
+            let method = heap.format_value(chunk.constants[constant as usize]);
+            let _ = writeln!(
+                out,
+                "{:<16} ({} args) {:4} '{}'",
+                name, argc, constant, method
+            );
+            next
+        }
+        OpCode::Closure => {
+            let constant = chunk.read_u16(offset + 1);
+            let function = chunk.constants[constant as usize];
+            let _ = writeln!(
+                out,
+                "{:<16} {:4} {}",
+                name,
+                constant,
+                heap.format_value(function)
+            );
+
+            let upvalue_count = heap.function(function.as_obj().unwrap()).upvalue_count;
+            let mut offset = next;
+            for _ in 0..upvalue_count {
+                let is_local = chunk.code[offset] == 1;
+                let index = chunk.code[offset + 1];
+                let _ = writeln!(
+                    out,
+                    "{:04}    |                     {} {}",
+                    offset,
+                    if is_local { "local" } else { "upvalue" },
+                    index
+                );
+                offset += 2;
+            }
+            offset
+        }
+        _ => {
+            let _ = writeln!(out, "{}", name);
+            next
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler, parser};
+
+    #[test]
+    fn test_disassembles_nested_functions() {
+        let mut heap = Heap::new();
+        let program = parser::parse(b"fun f(a) { return a + 1; }\nprint f(2);").unwrap();
+        let script = compiler::compile(&program, &mut heap, &()).unwrap();
+        let text = disassemble(&heap, script);
+
+        assert!(text.starts_with("== <script> ==\n"));
+        assert!(text.contains("Closure"));
+        assert!(text.contains("== <fn f> ==\n0000    1 GetLocal            1\n"));
+        assert!(text.contains("   2 GetGlobal           "));
+    }
+}
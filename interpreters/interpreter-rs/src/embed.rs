@@ -0,0 +1,294 @@
+use crate::gc::Heap;
+use crate::natives::{FromValue, IntoValue};
+use crate::object::{NativeFn, Obj, ObjRef};
+use crate::value::Value;
+use crate::vm::Vm;
+use std::any::Any;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// Arguments for [`Vm::call_global`]: a tuple of [`IntoValue`]s, or values already converted.
+pub trait IntoArgs {
+    fn into_args(self, heap: &mut Heap) -> Vec<Value>;
+}
+
+impl IntoArgs for Vec<Value> {
+    fn into_args(self, _heap: &mut Heap) -> Vec<Value> {
+        self
+    }
+}
+
+impl IntoArgs for () {
+    fn into_args(self, _heap: &mut Heap) -> Vec<Value> {
+        Vec::new()
+    }
+}
+
+macro_rules! tuple_args {
+    ($($name:ident),+) => {
+        impl<$($name: IntoValue),+> IntoArgs for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn into_args(self, heap: &mut Heap) -> Vec<Value> {
+                let ($($name,)+) = self;
+                vec![$($name.into_value(heap)),+]
+            }
+        }
+    };
+}
+
+tuple_args!(A);
+tuple_args!(A, B);
+tuple_args!(A, B, C);
+tuple_args!(A, B, C, D);
+tuple_args!(A, B, C, D, E);
+
+/// The value held by an instance of a host class, shared with the script.
+impl<T: Any> FromValue for Rc<RefCell<T>> {
+    const EXPECTED: &'static str = "a host object";
+
+    fn from_value(heap: &Heap, value: Value) -> Option<Self> {
+        match heap.get(value.as_obj()?) {
+            Obj::Instance(instance) => instance.host.clone()?.downcast().ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Adds the initializer and methods of a class defined with [`Vm::define_class`]:
+///
+/// ```
+/// use interpreter_rs::natives::{arg, IntoValue};
+/// use interpreter_rs::vm::Vm;
+///
+/// struct Counter(f64);
+///
+/// let mut vm = Vm::new();
+/// vm.define_class::<Counter>("Counter")
+///     .init(Some(1), |heap, args| Ok(Counter(arg(heap, args, 0)?)))
+///     .method("add", Some(1), |counter, heap, args| {
+///         counter.0 += arg::<f64>(heap, args, 0)?;
+///         Ok(counter.0.into_value(heap))
+///     });
+/// vm.interpret(b"var c = Counter(1); c.add(2); print c.add(3);").unwrap(); // 6
+/// ```
+pub struct HostClass<'a, T> {
+    vm: &'a mut Vm,
+    class: ObjRef,
+    name: Rc<str>,
+    marker: PhantomData<fn(T)>,
+}
+
+impl<'a, T: Any> HostClass<'a, T> {
+    pub(crate) fn new(vm: &'a mut Vm, class: ObjRef) -> Self {
+        let name = vm.heap().str(vm.heap().class(class).name).into();
+        HostClass {
+            vm,
+            class,
+            name,
+            marker: PhantomData,
+        }
+    }
+
+    /// Lets scripts create instances by calling the class, building their `T` from the
+    /// arguments. Without an initializer only [`Vm::host_object`] makes usable instances.
+    pub fn init(
+        self,
+        arity: Option<u8>,
+        init: impl Fn(&mut Heap, &[Value]) -> Result<T, String> + 'static,
+    ) -> Self {
+        let function: NativeFn = Rc::new(move |heap, args| {
+            let data = init(heap, &args[1..])?;
+            let Value::Obj(receiver) = args[0] else {
+                unreachable!("initializer called without an instance");
+            };
+            heap.instance_mut(receiver).host = Some(Rc::new(RefCell::new(data)));
+            Ok(args[0])
+        });
+        self.vm.define_method(self.class, "init", arity, function);
+        self
+    }
+
+    /// Adds a method getting the receiver's `T` and the arguments after the receiver.
+    pub fn method(
+        self,
+        name: &'static str,
+        arity: Option<u8>,
+        method: impl Fn(&mut T, &mut Heap, &[Value]) -> Result<Value, String> + 'static,
+    ) -> Self {
+        let class_name = self.name.clone();
+        let function: NativeFn = Rc::new(move |heap, args| {
+            let Some(data) = Rc::<RefCell<T>>::from_value(heap, args[0]) else {
+                return Err(format!(
+                    "Receiver of '{}' isn't an initialized {}.",
+                    name, class_name
+                ));
+            };
+            let mut data = data.borrow_mut();
+            method(&mut data, heap, &args[1..])
+        });
+        self.vm.define_method(self.class, name, arity, function);
+        self
+    }
+}
+
+/// A `print` destination the host reads back, shared between clones:
+///
+/// ```
+/// use interpreter_rs::embed::OutputBuffer;
+/// use interpreter_rs::vm::Vm;
+///
+/// let output = OutputBuffer::new();
+/// let mut vm = Vm::new();
+/// vm.set_output(Box::new(output.clone()));
+/// vm.interpret(b"print 1 + 2;").unwrap();
+/// assert_eq!(output.take(), "3\n");
+/// ```
+#[derive(Clone, Default)]
+pub struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything printed so far.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    /// Everything printed so far, emptying the buffer.
+    pub fn take(&self) -> String {
+        let bytes = std::mem::take(&mut *self.0.borrow_mut());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::natives::arg;
+    use crate::parser::Dialect;
+    use crate::vm::InterpretError;
+
+    struct Account {
+        owner: String,
+        balance: i64,
+    }
+
+    fn vm(stress: bool) -> (Vm, OutputBuffer) {
+        let output = OutputBuffer::new();
+        let mut vm = Vm::new();
+        vm.set_output(Box::new(output.clone()));
+        vm.set_dialect(Dialect::LoxPlus);
+        vm.heap_mut().set_stress(stress);
+        vm.define_class::<Account>("Account")
+            .init(Some(1), |heap, args| {
+                Ok(Account {
+                    owner: arg(heap, args, 0)?,
+                    balance: 0,
+                })
+            })
+            .method("deposit", Some(1), |account, heap, args| {
+                account.balance += arg::<i64>(heap, args, 0)?;
+                Ok(account.balance.into_value(heap))
+            })
+            .method("owner", Some(0), |account, heap, _| {
+                Ok(account.owner.as_str().into_value(heap))
+            });
+        (vm, output)
+    }
+
+    fn message(error: InterpretError) -> String {
+        match error {
+            InterpretError::Runtime(error) => error.message,
+            InterpretError::Compile(errors) => errors[0].to_string(),
+        }
+    }
+
+    #[test]
+    fn test_embedding() {
+        for stress in [false, true] {
+            let (mut vm, output) = vm(stress);
+            vm.interpret(
+                br#"
+                fun total(xs) { var sum = 0; for (var i = 0; i < len(xs); i = i + 1) sum = sum + xs[i]; return sum; }
+                fun open(name, amount) { var a = Account(name); a.deposit(amount); print a.owner(); return a; }
+                fun names(accounts) { var out = []; for (var i = 0; i < len(accounts); i = i + 1) out.push(accounts[i].owner()); return out; }
+                class Savings < Account { init(name) { super.init(name); this.rate = 2; } }
+                "#,
+            )
+            .unwrap();
+
+            assert_eq!(
+                vm.call_global::<i64>("total", (vec![1i64, 2, 3],)).unwrap(),
+                6
+            );
+            let account: Rc<RefCell<Account>> = vm.call_global("open", ("ada", 5i64)).unwrap();
+            assert_eq!(account.borrow().balance, 5);
+            assert_eq!(output.take(), "ada\n");
+
+            // Host objects made in Rust are shared with the script
+            let bob = vm
+                .host_object(Account {
+                    owner: "bob".into(),
+                    balance: 1,
+                })
+                .unwrap();
+            vm.set_global("bob", bob);
+            vm.interpret(b"print bob.deposit(2); print Savings(\"cy\").owner();")
+                .unwrap();
+            assert_eq!(output.take(), "3\ncy\n");
+            let names: Vec<String> = vm.call_global("names", (vec![bob, bob],)).unwrap();
+            assert_eq!(names, ["bob", "bob"]);
+            assert_eq!(
+                vm.get_global::<Rc<RefCell<Account>>>("bob")
+                    .unwrap()
+                    .borrow()
+                    .balance,
+                3
+            );
+
+            assert_eq!(
+                message(vm.call_global::<f64>("missing", ()).unwrap_err()),
+                "Undefined variable 'missing'."
+            );
+            assert_eq!(
+                message(vm.call_global::<f64>("total", (vec!["a"],)).unwrap_err()),
+                "Operands must be two numbers or two strings."
+            );
+            assert_eq!(
+                message(
+                    vm.call_global::<String>("total", (Vec::<f64>::new(),))
+                        .unwrap_err()
+                ),
+                "Expected a string as result but got number."
+            );
+            assert_eq!(
+                message(
+                    vm.interpret(b"class Raw < Account { init() {} } Raw().owner();")
+                        .unwrap_err()
+                ),
+                "Receiver of 'owner' isn't an initialized Account."
+            );
+            let error = vm.interpret(b"var f = bob.deposit; f(\"x\");").unwrap_err();
+            assert_eq!(
+                message(error),
+                "Expected an integer as argument 1 but got string."
+            );
+            assert_eq!(vm.call_global::<f64>("len", ("four",)).unwrap(), 4.0);
+        }
+    }
+}
@@ -0,0 +1,559 @@
+use crate::cst::{self, Element, Node, SyntaxKind};
+use crate::diagnostic::Diagnostic;
+use crate::parser::{self, Dialect};
+use lexer::trivia::{TriviaKind, TriviaToken};
+use lexer::with_opt_iterator::Tag;
+
+/// Comma separated lists that don't fit within this many columns get one item per line.
+pub const MAX_WIDTH: usize = 80;
+const INDENT: &str = "  ";
+
+/// Formats a Lox or Lox+ program. Layout comes from the syntax alone: original whitespace is
+/// dropped except for single blank lines between statements, and comments stay where they
+/// were relative to the tokens around them. Programs with syntax errors are left alone.
+pub fn format(source: &[u8]) -> Result<String, Vec<Diagnostic>> {
+    let dialect = match parser::parse_dialect(source, Dialect::LoxPlus) {
+        Ok(_) => Dialect::LoxPlus,
+        // Lox programs may use Lox+ keywords as names
+        Err(errors) => {
+            parser::parse(source).map_err(|_| errors)?;
+            Dialect::Lox
+        }
+    };
+    let tree = cst::parse(source, dialect);
+
+    let mut formatter = Formatter::new(source);
+    formatter.program(&tree);
+    let mut out = formatter.out;
+    out.truncate(out.trim_end().len());
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+struct Formatter<'a> {
+    source: &'a [u8],
+    out: String,
+    indent: usize,
+    /// A line comment was written, the next token has to go on a new line
+    break_pending: bool,
+    /// The current statement was broken by a comment, its remaining lines are indented deeper
+    continuation: bool,
+    /// The next token starts a declaration or statement and may be preceded by a blank line
+    statement_start: bool,
+    /// Set when measuring, to reject any layout that would have to break a line
+    flat: bool,
+    broken: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(source: &'a [u8]) -> Self {
+        Formatter {
+            source,
+            out: String::new(),
+            indent: 0,
+            break_pending: false,
+            continuation: false,
+            statement_start: false,
+            flat: false,
+            broken: false,
+        }
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.out.is_empty() || self.out.ends_with('\n')
+    }
+
+    fn column(&self) -> usize {
+        let line_start = self.out.rfind('\n').map_or(0, |i| i + 1);
+        self.out[line_start..].chars().count()
+    }
+
+    fn newline(&mut self) {
+        if self.flat {
+            self.broken = true;
+        }
+        self.out.truncate(self.out.trim_end_matches(' ').len());
+        self.out.push('\n');
+        self.break_pending = false;
+    }
+
+    /// Ends the current line, if anything is on it, and the statement with it.
+    fn end_line(&mut self) {
+        if !self.at_line_start() {
+            self.newline();
+        }
+        self.continuation = false;
+    }
+
+    /// Keeps one blank line from the source, except at the start of the file or a block.
+    fn blank_line(&mut self) {
+        let Some(before) = self.out.strip_suffix('\n') else {
+            return;
+        };
+        if !before.is_empty() && !before.ends_with(['\n', '{', '(', '[']) {
+            self.out.push('\n');
+        }
+    }
+
+    fn write(&mut self, text: &str) {
+        if self.break_pending {
+            self.newline();
+            self.continuation = true;
+        }
+        if self.at_line_start() {
+            for _ in 0..self.indent + self.continuation as usize {
+                self.out.push_str(INDENT);
+            }
+        }
+        self.out.push_str(text);
+    }
+
+    fn space(&mut self) {
+        if !self.at_line_start() && !self.break_pending {
+            self.out.push(' ');
+        }
+    }
+
+    fn text(&self, loc: lexer::with_opt_iterator::Loc) -> String {
+        String::from_utf8_lossy(&self.source[loc.start..loc.end]).into_owned()
+    }
+
+    fn leading_comments(&mut self, token: &TriviaToken) {
+        let mut newlines = 0;
+        for trivia in &token.leading {
+            match trivia.kind {
+                TriviaKind::Newline => newlines += 1,
+                TriviaKind::Whitespace => {}
+                TriviaKind::Comment => {
+                    if !self.at_line_start() {
+                        self.newline();
+                        self.continuation = !self.statement_start;
+                    } else if newlines >= 2 {
+                        self.blank_line();
+                    }
+                    let comment = self.text(trivia.loc);
+                    self.write(comment.trim_end());
+                    self.newline();
+                    newlines = 0;
+                }
+            }
+        }
+        if self.statement_start && newlines >= 2 && self.at_line_start() {
+            self.blank_line();
+        }
+        self.statement_start = false;
+    }
+
+    fn trailing_comments(&mut self, token: &TriviaToken) {
+        for trivia in &token.trailing {
+            if trivia.kind == TriviaKind::Comment {
+                let comment = self.text(trivia.loc);
+                self.space();
+                self.write(comment.trim_end());
+                self.break_pending = true;
+                if self.flat {
+                    self.broken = true;
+                }
+            }
+        }
+    }
+
+    fn token(&mut self, token: &TriviaToken) {
+        self.leading_comments(token);
+        let text = self.text(token.token.loc);
+        self.write(&text);
+        self.trailing_comments(token);
+    }
+
+    /// Drops a token from the output but keeps its comments.
+    fn skip(&mut self, token: &TriviaToken) {
+        self.leading_comments(token);
+        self.trailing_comments(token);
+    }
+
+    fn element(&mut self, element: &Element) {
+        match element {
+            Element::Node(node) => self.node(node),
+            Element::Token(token) => self.token(token),
+        }
+    }
+
+    fn program(&mut self, program: &Node) {
+        for element in &program.children {
+            match element {
+                Element::Node(node) => self.declaration(node),
+                Element::Token(eof) => {
+                    self.end_line();
+                    self.skip(eof);
+                }
+            }
+        }
+    }
+
+    fn declaration(&mut self, node: &Node) {
+        self.end_line();
+        self.statement_start = true;
+        self.node(node);
+        self.end_line();
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node.kind {
+            SyntaxKind::Program => self.program(node),
+            SyntaxKind::Block => self.block(node),
+            SyntaxKind::ClassDecl => self.class(node),
+            SyntaxKind::IfStmt | SyntaxKind::WhileStmt => self.branching(node),
+            SyntaxKind::ForStmt => self.for_statement(node),
+            SyntaxKind::TryStmt => self.try_statement(node),
+            SyntaxKind::ParamList
+            | SyntaxKind::ArgList
+            | SyntaxKind::ListLit
+            | SyntaxKind::MapLit => self.comma_list(node),
+            SyntaxKind::MapEntry => {
+                for element in &node.children {
+                    if matches!(element, Element::Node(_)) && !node_is_first(node, element) {
+                        self.space();
+                    }
+                    self.element(element);
+                }
+            }
+            // Keywords, operators and the value after them are separated by spaces
+            SyntaxKind::FunDecl
+            | SyntaxKind::VarDecl
+            | SyntaxKind::ImportDecl
+            | SyntaxKind::ExportDecl
+            | SyntaxKind::ExprStmt
+            | SyntaxKind::PrintStmt
+            | SyntaxKind::ReturnStmt
+            | SyntaxKind::ThrowStmt
+            | SyntaxKind::Binary
+            | SyntaxKind::Assign => {
+                for (i, element) in node.children.iter().enumerate() {
+                    if i > 0 && !is_token(element, Tag::Semicolon) {
+                        self.space();
+                    }
+                    self.element(element);
+                }
+            }
+            SyntaxKind::Function
+            | SyntaxKind::Literal
+            | SyntaxKind::Name
+            | SyntaxKind::Super
+            | SyntaxKind::Grouping
+            | SyntaxKind::Unary
+            | SyntaxKind::Call
+            | SyntaxKind::Get
+            | SyntaxKind::Index
+            | SyntaxKind::Error => {
+                for element in &node.children {
+                    if let Element::Node(Node {
+                        kind: SyntaxKind::Block,
+                        ..
+                    }) = element
+                    {
+                        self.space();
+                    }
+                    self.element(element);
+                }
+            }
+        }
+    }
+
+    fn block(&mut self, node: &Node) {
+        let (open, inner, close) = split_delimited(node);
+        self.token(open.expect("blocks start with '{'"));
+        self.indent += 1;
+        for element in inner {
+            if let Element::Node(node) = element {
+                self.declaration(node);
+            }
+        }
+        self.closing(close);
+    }
+
+    /// Writes a closing brace on its own line, or right after the opening one when nothing is
+    /// in between.
+    fn closing(&mut self, close: Option<&TriviaToken>) {
+        let Some(close) = close else {
+            self.indent -= 1;
+            return;
+        };
+        let empty = self.out.ends_with('{') && !has_comments(close);
+        if !empty {
+            self.end_line();
+        }
+        // Comments before the brace belong to the block's contents
+        self.statement_start = true;
+        self.leading_comments(close);
+        self.indent -= 1;
+        self.write("}");
+        self.trailing_comments(close);
+    }
+
+    fn class(&mut self, node: &Node) {
+        let mut in_body = false;
+        for (i, element) in node.children.iter().enumerate() {
+            match element {
+                Element::Token(token) if token.tag() == Tag::RightBrace => {
+                    self.closing(Some(token));
+                    in_body = false;
+                }
+                Element::Node(method) if in_body => self.declaration(method),
+                _ => {
+                    if i > 0 {
+                        self.space();
+                    }
+                    self.element(element);
+                    if is_token(element, Tag::LeftBrace) {
+                        self.indent += 1;
+                        in_body = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// `if` and `while`: a parenthesized condition followed by bodies.
+    fn branching(&mut self, node: &Node) {
+        let mut after_body = false;
+        for element in &node.children {
+            match element {
+                Element::Token(token) if token.tag() == Tag::KeywordElse => {
+                    if after_body {
+                        self.space();
+                    } else {
+                        self.end_line();
+                    }
+                    self.token(token);
+                }
+                Element::Token(token) if token.tag() == Tag::LeftParen => {
+                    self.space();
+                    self.token(token);
+                }
+                Element::Node(body) if node_after_paren(node, element) => {
+                    self.space();
+                    self.node(body);
+                    after_body = body.kind == SyntaxKind::Block;
+                }
+                _ => self.element(element),
+            }
+        }
+    }
+
+    /// `try` and its clauses, which follow the closing brace: `} catch (e) {`.
+    fn try_statement(&mut self, node: &Node) {
+        for (i, element) in node.children.iter().enumerate() {
+            let tight = is_token(element, Tag::Identifier) || is_token(element, Tag::RightParen);
+            if i > 0 && !tight {
+                self.space();
+            }
+            self.element(element);
+        }
+    }
+
+    fn for_statement(&mut self, node: &Node) {
+        let mut space_next = false;
+        for element in &node.children {
+            if space_next
+                && !is_token(element, Tag::Semicolon)
+                && !is_token(element, Tag::RightParen)
+            {
+                self.space();
+            }
+            space_next = false;
+            match element {
+                Element::Token(token) if token.tag() == Tag::KeywordFor => {
+                    self.token(token);
+                    space_next = true;
+                }
+                Element::Token(token) if token.tag() == Tag::Semicolon => {
+                    self.token(token);
+                    space_next = true;
+                }
+                Element::Token(token) if token.tag() == Tag::RightParen => {
+                    self.token(token);
+                    space_next = true;
+                }
+                // The initializer declaration ends with its own ';'
+                Element::Node(clause)
+                    if matches!(clause.kind, SyntaxKind::VarDecl | SyntaxKind::ExprStmt) =>
+                {
+                    self.node(clause);
+                    space_next = true;
+                }
+                _ => self.element(element),
+            }
+        }
+    }
+
+    /// Items between brackets separated by `, `. Lists that would run past [`MAX_WIDTH`] or
+    /// contain comments get one item per line instead.
+    fn comma_list(&mut self, node: &Node) {
+        let (open, inner, close) = split_delimited(node);
+        if let Some(open) = open {
+            self.token(open);
+        }
+        let items: Vec<&Element> = inner
+            .iter()
+            .filter(|element| !is_token(element, Tag::Comma))
+            .collect();
+        let commas: Vec<&TriviaToken> = inner
+            .iter()
+            .filter_map(|element| match element {
+                Element::Token(token) if token.tag() == Tag::Comma => Some(token),
+                _ => None,
+            })
+            .collect();
+
+        let one_per_line =
+            !items.is_empty() && (open.is_some_and(has_comments) || !self.fits(inner, close));
+        if one_per_line {
+            self.indent += 1;
+            self.newline();
+        }
+        for (i, item) in items.iter().enumerate() {
+            self.element(item);
+            let Some(comma) = commas.get(i) else {
+                continue;
+            };
+            if i + 1 < items.len() {
+                self.token(comma);
+                if one_per_line {
+                    self.end_line_in_list();
+                } else {
+                    self.space();
+                }
+            } else {
+                self.skip(comma);
+            }
+        }
+        if one_per_line {
+            self.end_line_in_list();
+            self.indent -= 1;
+        }
+        if let Some(close) = close {
+            self.token(close);
+        }
+    }
+
+    fn end_line_in_list(&mut self) {
+        if !self.at_line_start() {
+            self.newline();
+        }
+    }
+
+    /// Whether `inner` and `close` fit on the current line without comments.
+    fn fits(&self, inner: &[Element], close: Option<&TriviaToken>) -> bool {
+        if self.flat {
+            return true;
+        }
+        let mut measure = Formatter::new(self.source);
+        measure.flat = true;
+        for element in inner {
+            measure.element(element);
+            if is_token(element, Tag::Comma) {
+                measure.space();
+            }
+        }
+        if let Some(close) = close {
+            measure.token(close);
+        }
+        // Room for a following ';' or ')'
+        !measure.broken && self.column() + measure.out.chars().count() < MAX_WIDTH
+    }
+}
+
+fn is_token(element: &Element, tag: Tag) -> bool {
+    matches!(element, Element::Token(token) if token.tag() == tag)
+}
+
+fn has_comments(token: &TriviaToken) -> bool {
+    token
+        .leading
+        .iter()
+        .chain(&token.trailing)
+        .any(|trivia| trivia.kind == TriviaKind::Comment)
+}
+
+fn node_is_first(node: &Node, element: &Element) -> bool {
+    node.children
+        .first()
+        .is_some_and(|first| std::ptr::eq(first, element))
+}
+
+/// Whether `element` is a body of an `if` or `while`, i.e. a node after the condition's `)`.
+fn node_after_paren(node: &Node, element: &Element) -> bool {
+    let paren = node
+        .children
+        .iter()
+        .position(|child| is_token(child, Tag::RightParen));
+    let index = node
+        .children
+        .iter()
+        .position(|child| std::ptr::eq(child, element));
+    matches!((paren, index), (Some(paren), Some(index)) if index > paren)
+}
+
+/// Splits a bracketed node into its opening token, contents and closing token.
+fn split_delimited(node: &Node) -> (Option<&TriviaToken>, &[Element], Option<&TriviaToken>) {
+    let mut inner = &node.children[..];
+    let open = match inner.first() {
+        Some(Element::Token(token))
+            if matches!(
+                token.tag(),
+                Tag::LeftBrace | Tag::LeftParen | Tag::LeftBracket
+            ) =>
+        {
+            inner = &inner[1..];
+            Some(token)
+        }
+        _ => None,
+    };
+    let close = match inner.last() {
+        Some(Element::Token(token))
+            if matches!(
+                token.tag(),
+                Tag::RightBrace | Tag::RightParen | Tag::RightBracket
+            ) =>
+        {
+            inner = &inner[..inner.len() - 1];
+            Some(token)
+        }
+        _ => None,
+    };
+    (open, inner, close)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(source: &str, expected: &str) {
+        let formatted = format(source.as_bytes()).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(
+            format(formatted.as_bytes()).unwrap(),
+            formatted,
+            "not idempotent"
+        );
+    }
+
+    #[test]
+    fn test_layout_and_comments() {
+        check(
+            "// header\nclass A<B{init(x){this.x=x;}\n\n\n  get() { return this.x ; }}\nvar  a=-1+2*3;if(a>1)print a;else{print\"no\";}\nfor(var i=0;i<3;i=i+1){}\nwhile (true) { // forever\n}",
+            "// header\nclass A < B {\n  init(x) {\n    this.x = x;\n  }\n\n  get() {\n    return this.x;\n  }\n}\nvar a = -1 + 2 * 3;\nif (a > 1) print a;\nelse {\n  print \"no\";\n}\nfor (var i = 0; i < 3; i = i + 1) {}\nwhile (true) { // forever\n}\n",
+        );
+        check(
+            "var m = {\"a\" : [1,2,], \"b\":nil};\nif (x) {\n  f(1);\n} else g(a.b[0], // first\n  2);\n",
+            "var m = {\"a\": [1, 2], \"b\": nil};\nif (x) {\n  f(1);\n} else g(\n  a.b[0], // first\n  2\n);\n",
+        );
+        check(
+            "print some_function_name(first_argument_value, second_argument_value, third_argument);",
+            "print some_function_name(\n  first_argument_value,\n  second_argument_value,\n  third_argument\n);\n",
+        );
+        assert!(format(b"print (;").is_err());
+    }
+}
@@ -0,0 +1,586 @@
+use crate::object::*;
+use crate::table::StringTable;
+use crate::value::Value;
+use lexer::interner::hash;
+use std::rc::Rc;
+
+const INITIAL_NEXT_GC: usize = 1024 * 1024;
+const HEAP_GROW_FACTOR: usize = 2;
+/// Collections nested deeper than this print as `[...]` or `{...}`.
+pub const MAX_FORMAT_DEPTH: usize = 64;
+
+/// Anything that can hold references into the heap. Roots implement this so the collector can
+/// find every object reachable from outside the heap.
+pub trait Trace {
+    fn trace(&self, heap: &mut Heap);
+}
+
+impl Trace for Value {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_value(*self);
+    }
+}
+
+impl Trace for ObjRef {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(*self);
+    }
+}
+
+impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, heap: &mut Heap) {
+        if let Some(inner) = self {
+            inner.trace(heap);
+        }
+    }
+}
+
+impl<T: Trace> Trace for [T] {
+    fn trace(&self, heap: &mut Heap) {
+        for item in self {
+            item.trace(heap);
+        }
+    }
+}
+
+impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, heap: &mut Heap) {
+        self.as_slice().trace(heap);
+    }
+}
+
+/// No extra roots, e.g. when compiling without a VM around.
+impl Trace for () {
+    fn trace(&self, _heap: &mut Heap) {}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub objects_freed: usize,
+    pub bytes_freed: usize,
+}
+
+/// Mark-and-sweep heap for every Lox object.
+///
+/// Objects live in slots addressed by [`ObjRef`]; freed slots are recycled through a free list.
+/// The heap never collects on its own: whoever owns the roots checks [`Heap::should_collect`] at
+/// a safe point, marks its roots and then calls [`Heap::collect`]. Marking is tri-color: unmarked
+/// objects are white, marked objects waiting in the worklist are gray and marked objects whose
+/// references have been traced are black.
+pub struct Heap {
+    objects: Vec<Option<Obj>>,
+    marks: Vec<bool>,
+    free: Vec<u32>,
+    gray: Vec<ObjRef>,
+    /// Interned strings. Entries are weak: they don't keep strings alive and are dropped when
+    /// the string is swept.
+    strings: StringTable,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// Live bytes the owner allows, see [`Heap::over_limit`].
+    max_bytes: Option<usize>,
+    stress: bool,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            marks: Vec::new(),
+            free: Vec::new(),
+            gray: Vec::new(),
+            strings: StringTable::default(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_NEXT_GC,
+            max_bytes: None,
+            stress: false,
+            stats: GcStats::default(),
+        }
+    }
+
+    /// In stress mode every safe point collects, which flushes out missing roots quickly.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    #[inline(always)]
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    /// Caps the heap at `max_bytes`. Collections start before the cap is reached, and the
+    /// owner fails the program when one can't get back under it.
+    pub fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+        self.next_gc = self.next_gc.min(max_bytes.unwrap_or(usize::MAX));
+    }
+
+    pub fn over_limit(&self) -> bool {
+        self.max_bytes
+            .is_some_and(|max_bytes| self.bytes_allocated > max_bytes)
+    }
+
+    /// Accounts for `r` growing in place from `old_size` bytes, like a list push.
+    pub fn resized(&mut self, r: ObjRef, old_size: usize) {
+        self.bytes_allocated = (self.bytes_allocated + self.get(r).size()).saturating_sub(old_size);
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn next_gc(&self) -> usize {
+        self.next_gc
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.bytes_allocated += obj.size();
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index as usize] = Some(obj);
+                ObjRef(index)
+            }
+            None => {
+                self.objects.push(Some(obj));
+                self.marks.push(false);
+                ObjRef(self.objects.len() as u32 - 1)
+            }
+        }
+    }
+
+    /// Returns the interned string with these contents, allocating it if needed.
+    pub fn intern(&mut self, chars: &str) -> ObjRef {
+        let hash = hash(chars.as_bytes());
+        if let Some(r) = self.find_string(chars, hash) {
+            return r;
+        }
+        self.intern_new(chars.into(), hash)
+    }
+
+    /// Like [`Heap::intern`] but takes ownership of an already built string.
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        let hash = hash(chars.as_bytes());
+        if let Some(r) = self.find_string(&chars, hash) {
+            return r;
+        }
+        self.intern_new(chars.into(), hash)
+    }
+
+    fn find_string(&self, chars: &str, hash: u32) -> Option<ObjRef> {
+        self.strings.find(chars, hash, |r| self.str(r))
+    }
+
+    fn intern_new(&mut self, chars: Rc<str>, hash: u32) -> ObjRef {
+        let r = self.alloc(Obj::String(ObjString { chars, hash }));
+        self.strings.insert(r, hash);
+        r
+    }
+
+    #[inline(always)]
+    pub fn get(&self, r: ObjRef) -> &Obj {
+        self.objects[r.index()]
+            .as_ref()
+            .expect("dangling object reference")
+    }
+
+    #[inline(always)]
+    pub fn get_mut(&mut self, r: ObjRef) -> &mut Obj {
+        self.objects[r.index()]
+            .as_mut()
+            .expect("dangling object reference")
+    }
+
+    pub fn is_live(&self, r: ObjRef) -> bool {
+        matches!(self.objects.get(r.index()), Some(Some(_)))
+    }
+
+    pub fn string(&self, r: ObjRef) -> &ObjString {
+        match self.get(r) {
+            Obj::String(s) => s,
+            _ => unreachable!("object is not a string"),
+        }
+    }
+
+    pub fn str(&self, r: ObjRef) -> &str {
+        &self.string(r).chars
+    }
+
+    pub fn function(&self, r: ObjRef) -> &ObjFunction {
+        match self.get(r) {
+            Obj::Function(f) => f,
+            _ => unreachable!("object is not a function"),
+        }
+    }
+
+    pub fn closure(&self, r: ObjRef) -> &ObjClosure {
+        match self.get(r) {
+            Obj::Closure(c) => c,
+            _ => unreachable!("object is not a closure"),
+        }
+    }
+
+    pub fn closure_mut(&mut self, r: ObjRef) -> &mut ObjClosure {
+        match self.get_mut(r) {
+            Obj::Closure(c) => c,
+            _ => unreachable!("object is not a closure"),
+        }
+    }
+
+    pub fn upvalue(&self, r: ObjRef) -> &ObjUpvalue {
+        match self.get(r) {
+            Obj::Upvalue(u) => u,
+            _ => unreachable!("object is not an upvalue"),
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, r: ObjRef) -> &mut ObjUpvalue {
+        match self.get_mut(r) {
+            Obj::Upvalue(u) => u,
+            _ => unreachable!("object is not an upvalue"),
+        }
+    }
+
+    pub fn class(&self, r: ObjRef) -> &ObjClass {
+        match self.get(r) {
+            Obj::Class(c) => c,
+            _ => unreachable!("object is not a class"),
+        }
+    }
+
+    pub fn class_mut(&mut self, r: ObjRef) -> &mut ObjClass {
+        match self.get_mut(r) {
+            Obj::Class(c) => c,
+            _ => unreachable!("object is not a class"),
+        }
+    }
+
+    pub fn instance(&self, r: ObjRef) -> &ObjInstance {
+        match self.get(r) {
+            Obj::Instance(i) => i,
+            _ => unreachable!("object is not an instance"),
+        }
+    }
+
+    pub fn instance_mut(&mut self, r: ObjRef) -> &mut ObjInstance {
+        match self.get_mut(r) {
+            Obj::Instance(i) => i,
+            _ => unreachable!("object is not an instance"),
+        }
+    }
+
+    pub fn list(&self, r: ObjRef) -> &Vec<Value> {
+        match self.get(r) {
+            Obj::List(items) => items,
+            _ => unreachable!("object is not a list"),
+        }
+    }
+
+    pub fn list_mut(&mut self, r: ObjRef) -> &mut Vec<Value> {
+        match self.get_mut(r) {
+            Obj::List(items) => items,
+            _ => unreachable!("object is not a list"),
+        }
+    }
+
+    pub fn map(&self, r: ObjRef) -> &ObjMap {
+        match self.get(r) {
+            Obj::Map(map) => map,
+            _ => unreachable!("object is not a map"),
+        }
+    }
+
+    pub fn map_mut(&mut self, r: ObjRef) -> &mut ObjMap {
+        match self.get_mut(r) {
+            Obj::Map(map) => map,
+            _ => unreachable!("object is not a map"),
+        }
+    }
+
+    pub fn as_string(&self, value: Value) -> Option<&str> {
+        match value {
+            Value::Obj(r) => match self.get(r) {
+                Obj::String(s) => Some(&s.chars),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn is_instance(&self, value: Value) -> bool {
+        matches!(value, Value::Obj(r) if matches!(self.get(r), Obj::Instance(_)))
+    }
+
+    pub fn is_class(&self, value: Value) -> bool {
+        matches!(value, Value::Obj(r) if matches!(self.get(r), Obj::Class(_)))
+    }
+
+    pub fn is_native(&self, value: Value) -> bool {
+        matches!(value, Value::Obj(r) if matches!(self.get(r), Obj::Native(_)))
+    }
+
+    pub fn format_value(&self, value: Value) -> String {
+        match value {
+            Value::Nil => "nil".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => n.to_string(),
+            Value::Obj(r) => self.format_object(r, &mut Vec::new()),
+        }
+    }
+
+    /// `visiting` holds the collections being printed, so a collection containing itself prints
+    /// as `[...]` instead of recursing forever. Collections nested deeper than
+    /// [`MAX_FORMAT_DEPTH`] print the same way, so formatting can't overflow the native stack.
+    fn format_object(&self, r: ObjRef, visiting: &mut Vec<ObjRef>) -> String {
+        match self.get(r) {
+            Obj::String(s) => s.chars.to_string(),
+            Obj::List(_) | Obj::Map(_)
+                if visiting.len() >= MAX_FORMAT_DEPTH || visiting.contains(&r) =>
+            {
+                match self.get(r) {
+                    Obj::List(_) => "[...]".to_string(),
+                    _ => "{...}".to_string(),
+                }
+            }
+            Obj::List(items) => {
+                visiting.push(r);
+                let items: Vec<String> = items
+                    .iter()
+                    .map(|&item| self.format_element(item, visiting))
+                    .collect();
+                visiting.pop();
+                format!("[{}]", items.join(", "))
+            }
+            Obj::Map(map) => {
+                visiting.push(r);
+                let entries: Vec<String> = map
+                    .iter()
+                    .map(|(key, value)| {
+                        format!(
+                            "{}: {}",
+                            self.format_element(key, visiting),
+                            self.format_element(value, visiting)
+                        )
+                    })
+                    .collect();
+                visiting.pop();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Obj::Function(f) => self.format_function(f),
+            Obj::Native(_) => "<native fn>".to_string(),
+            Obj::Closure(c) => self.format_function(self.function(c.function)),
+            Obj::Upvalue(_) => "upvalue".to_string(),
+            Obj::Class(c) => self.str(c.name).to_string(),
+            Obj::Instance(i) => format!("{} instance", self.str(self.class(i.class).name)),
+            Obj::BoundMethod(b) => match self.get(b.method) {
+                Obj::Native(_) => "<native fn>".to_string(),
+                _ => self.format_function(self.function(self.closure(b.method).function)),
+            },
+        }
+    }
+
+    /// Formats a collection element, quoting strings so `["1"]` and `[1]` print differently.
+    fn format_element(&self, value: Value, visiting: &mut Vec<ObjRef>) -> String {
+        match value {
+            Value::Obj(r) => match self.get(r) {
+                Obj::String(s) => format!("\"{}\"", s.chars),
+                _ => self.format_object(r, visiting),
+            },
+            _ => self.format_value(value),
+        }
+    }
+
+    fn format_function(&self, function: &ObjFunction) -> String {
+        match function.name {
+            Some(name) => format!("<fn {}>", self.str(name)),
+            None => "<script>".to_string(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(r) = value {
+            self.mark_object(r);
+        }
+    }
+
+    /// Shades a white object gray.
+    #[inline(always)]
+    pub fn mark_object(&mut self, r: ObjRef) {
+        let mark = &mut self.marks[r.index()];
+        if !*mark {
+            *mark = true;
+            self.gray.push(r);
+        }
+    }
+
+    /// Traces everything reachable from the marked roots and frees the rest. Returns the number
+    /// of objects freed.
+    pub fn collect(&mut self) -> usize {
+        self.trace_references();
+        self.remove_white_strings();
+        let freed = self.sweep();
+        self.next_gc = (self.bytes_allocated * HEAP_GROW_FACTOR)
+            .max(INITIAL_NEXT_GC)
+            .min(self.max_bytes.unwrap_or(usize::MAX));
+        self.stats.collections += 1;
+        freed
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(r) = self.gray.pop() {
+            self.blacken(r);
+        }
+    }
+
+    /// Grays every object directly referenced by `r`, turning `r` black.
+    fn blacken(&mut self, r: ObjRef) {
+        let Heap {
+            objects,
+            marks,
+            gray,
+            ..
+        } = self;
+        let mut mark = |value: Value| {
+            if let Value::Obj(child) = value {
+                if !marks[child.index()] {
+                    marks[child.index()] = true;
+                    gray.push(child);
+                }
+            }
+        };
+
+        match objects[r.index()]
+            .as_ref()
+            .expect("dangling object reference")
+        {
+            Obj::String(_) | Obj::Native(_) => {}
+            Obj::Function(f) => {
+                if let Some(name) = f.name {
+                    mark(Value::Obj(name));
+                }
+                for &constant in &f.chunk.constants {
+                    mark(constant);
+                }
+            }
+            Obj::Closure(c) => {
+                mark(Value::Obj(c.function));
+                for &upvalue in &c.upvalues {
+                    mark(Value::Obj(upvalue));
+                }
+            }
+            Obj::Upvalue(ObjUpvalue::Closed(value)) => mark(*value),
+            Obj::Upvalue(ObjUpvalue::Open(_)) => {}
+            Obj::Class(c) => {
+                mark(Value::Obj(c.name));
+                for (key, method) in c.methods.iter() {
+                    mark(Value::Obj(key));
+                    mark(method);
+                }
+            }
+            Obj::Instance(i) => {
+                mark(Value::Obj(i.class));
+                for (key, value) in i.fields.iter() {
+                    mark(Value::Obj(key));
+                    mark(value);
+                }
+            }
+            Obj::BoundMethod(b) => {
+                mark(b.receiver);
+                mark(Value::Obj(b.method));
+            }
+            Obj::List(items) => items.iter().for_each(|&item| mark(item)),
+            Obj::Map(map) => {
+                for (key, value) in map.iter() {
+                    mark(key);
+                    mark(value);
+                }
+            }
+        }
+    }
+
+    fn remove_white_strings(&mut self) {
+        let marks = &self.marks;
+        self.strings.retain(|r| marks[r.index()]);
+    }
+
+    fn sweep(&mut self) -> usize {
+        let mut freed = 0;
+        let mut live_bytes = 0;
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            let Some(obj) = slot else {
+                continue;
+            };
+            if self.marks[index] {
+                // Whiten survivors for the next cycle
+                self.marks[index] = false;
+                live_bytes += obj.size();
+            } else {
+                self.stats.bytes_freed += obj.size();
+                *slot = None;
+                self.free.push(index as u32);
+                freed += 1;
+            }
+        }
+        self.stats.objects_freed += freed;
+        self.bytes_allocated = live_bytes;
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::Table;
+
+    #[test]
+    fn test_collects_cycles_and_weak_strings() {
+        let mut heap = Heap::new();
+        let name = heap.intern("Node");
+        let class = heap.alloc(Obj::Class(ObjClass {
+            name,
+            methods: Table::new(),
+        }));
+        let a = heap.alloc(Obj::Instance(ObjInstance {
+            class,
+            fields: Table::new(),
+            host: None,
+        }));
+        let b = heap.alloc(Obj::Instance(ObjInstance {
+            class,
+            fields: Table::new(),
+            host: None,
+        }));
+        let next = heap.intern("next");
+        heap.instance_mut(a).fields.insert(next, Value::Obj(b));
+        heap.instance_mut(b).fields.insert(next, Value::Obj(a));
+        let garbage = heap.intern("garbage");
+
+        // Only `a` is a root, so `b` survives through the cycle and the unreferenced string dies
+        heap.mark_object(a);
+        assert_eq!(heap.collect(), 1);
+        assert!(heap.is_live(b) && heap.is_live(name) && heap.is_live(next));
+        assert!(!heap.is_live(garbage));
+
+        // Without roots the whole cycle is reclaimed, including its interned strings
+        heap.collect();
+        assert_eq!(heap.object_count(), 0);
+        assert!(heap.strings.is_empty());
+        assert_eq!(heap.next_gc(), INITIAL_NEXT_GC);
+    }
+}
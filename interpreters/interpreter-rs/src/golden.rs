@@ -0,0 +1,241 @@
+use crate::embed::OutputBuffer;
+use crate::parser::Dialect;
+use crate::register_vm::RegisterVm;
+use crate::vm::{InterpretError, Vm};
+use std::fmt::Write as _;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Backends every golden file runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Vm,
+    UnoptimizedVm,
+    RegisterVm,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 3] = [Backend::Vm, Backend::UnoptimizedVm, Backend::RegisterVm];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Vm => "vm",
+            Backend::UnoptimizedVm => "vm --no-optimize",
+            Backend::RegisterVm => "register vm",
+        }
+    }
+}
+
+/// What a program should do, read from its comments:
+///
+/// ```lox
+/// // dialect: lox+
+/// print 1 + 2; // expect: 3
+/// print x;     // expect runtime error: Undefined variable 'x'.
+/// print;       // expect compile error: Expect expression.
+/// ```
+///
+/// Errors are expected on the line of their comment. Each outcome is rendered as one line per
+/// printed line or error, which is what gets compared and diffed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expectations {
+    pub dialect: Dialect,
+    pub lines: Vec<String>,
+}
+
+impl Expectations {
+    pub fn parse(source: &str) -> Self {
+        let mut expectations = Expectations {
+            dialect: Dialect::Lox,
+            lines: Vec::new(),
+        };
+        let mut compile_errors = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            // Found by the marker itself, as string literals may contain `//`
+            let Some(start) = line.find("// expect").or_else(|| line.find("// dialect:")) else {
+                continue;
+            };
+            let comment = line[start + 2..].trim();
+            let line = i + 1;
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expectations.lines.push(output.to_string());
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expectations.lines.push(runtime_error(line, message));
+            } else if let Some(message) = comment.strip_prefix("expect compile error: ") {
+                compile_errors.push(compile_error(line, message));
+            } else if comment == "dialect: lox+" {
+                expectations.dialect = Dialect::LoxPlus;
+            }
+        }
+        // Nothing runs when compiling fails
+        if !compile_errors.is_empty() {
+            expectations.lines = compile_errors;
+        }
+        expectations
+    }
+}
+
+fn runtime_error(line: usize, message: &str) -> String {
+    format!("[line {}] runtime error: {}", line, message)
+}
+
+fn compile_error(line: usize, message: &str) -> String {
+    format!("[line {}] compile error: {}", line, message)
+}
+
+/// Runs `source` on `backend`, rendering its output like [`Expectations::lines`].
+pub fn run(backend: Backend, source: &str, dialect: Dialect) -> Vec<String> {
+    let output = OutputBuffer::new();
+    let out = Box::new(output.clone());
+    let result = match backend {
+        Backend::Vm | Backend::UnoptimizedVm => {
+            let mut vm = Vm::new();
+            vm.set_output(out);
+            vm.set_dialect(dialect);
+            vm.set_optimize(backend == Backend::Vm);
+            vm.interpret(source.as_bytes())
+        }
+        Backend::RegisterVm => {
+            let mut vm = RegisterVm::new();
+            vm.set_output(out);
+            vm.set_dialect(dialect);
+            vm.interpret(source.as_bytes())
+        }
+    };
+
+    let output = output.contents();
+    let mut lines: Vec<String> = output.lines().map(str::to_string).collect();
+    match result {
+        Ok(()) => {}
+        Err(InterpretError::Compile(diagnostics)) => {
+            lines = diagnostics
+                .iter()
+                .map(|d| compile_error(d.line as usize, &d.message))
+                .collect();
+        }
+        Err(InterpretError::Runtime(error)) => {
+            // The innermost frame reads `[line N] in f()`
+            let line = error
+                .trace
+                .first()
+                .and_then(|frame| frame.strip_prefix("[line "))
+                .and_then(|rest| rest.split_once(']'))
+                .and_then(|(line, _)| line.parse().ok())
+                .unwrap_or(0);
+            lines.push(runtime_error(line, &error.message));
+        }
+    }
+    lines
+}
+
+/// A backend whose outcome differs from the expectations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub path: PathBuf,
+    pub backend: Backend,
+    pub diff: String,
+}
+
+/// Runs one golden file on every backend.
+pub fn check_file(path: &Path) -> io::Result<Vec<Mismatch>> {
+    let source = std::fs::read_to_string(path)?;
+    let expectations = Expectations::parse(&source);
+    let mismatches = Backend::ALL
+        .into_iter()
+        .filter_map(|backend| {
+            let actual = run(backend, &source, expectations.dialect);
+            (actual != expectations.lines).then(|| Mismatch {
+                path: path.to_path_buf(),
+                backend,
+                diff: diff(&expectations.lines, &actual),
+            })
+        })
+        .collect();
+    Ok(mismatches)
+}
+
+/// Every `.lox` file under `path`, or `path` itself if it is a file, in name order.
+pub fn discover(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            files.extend(discover(&entry)?);
+        } else if entry.extension().is_some_and(|ext| ext == "lox") {
+            files.push(entry);
+        }
+    }
+    Ok(files)
+}
+
+/// Line diff from `expected` to `actual`, with `-` for missing lines and `+` for extra ones.
+pub fn diff(expected: &[String], actual: &[String]) -> String {
+    // Longest common subsequence of every pair of suffixes
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            let _ = writeln!(out, "  {}", expected[i]);
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            let _ = writeln!(out, "- {}", expected[i]);
+            i += 1;
+        } else {
+            let _ = writeln!(out, "+ {}", actual[j]);
+            j += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_golden_files() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+        let files = discover(&root).unwrap();
+        assert!(!files.is_empty());
+        let mut failures = String::new();
+        for file in files {
+            for mismatch in check_file(&file).unwrap() {
+                let _ = write!(
+                    failures,
+                    "{} ({}):\n{}",
+                    mismatch.path.display(),
+                    mismatch.backend.name(),
+                    mismatch.diff
+                );
+            }
+        }
+        assert!(failures.is_empty(), "{}", failures);
+
+        let expectations = Expectations::parse("print \"http://x\"; // expect: http://x");
+        assert_eq!(expectations.lines, ["http://x"]);
+
+        let lines = |text: &str| text.lines().map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            diff(&lines("1\n2\n3"), &lines("1\n3\n4")),
+            "  1\n- 2\n  3\n+ 4\n"
+        );
+    }
+}
@@ -0,0 +1,405 @@
+use std::fmt;
+
+/// Just enough JSON for the editor protocols. Objects keep their keys in insertion order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<const N: usize>(fields: [(&str, Json); N]) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Field `key` of an object, or `Null` for anything else.
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map_or(&Json::Null, |(_, value)| value),
+            _ => &Json::Null,
+        }
+    }
+
+    /// Follows a path of object keys, e.g. `["textDocument", "uri"]`.
+    pub fn at(&self, path: &[&str]) -> &Json {
+        path.iter().fold(self, |json, key| json.get(key))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        self.as_f64()
+            .filter(|n| *n >= 0.0 && n.fract() == 0.0)
+            .map(|n| n as usize)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Json::Null)
+    }
+
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = JsonParser {
+            bytes: text.as_bytes(),
+            index: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.index < parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(n: f64) -> Json {
+        Json::Number(n)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Json {
+        Json::Array(items)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => f.write_str("null"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+/// Nesting of arrays and objects [`Json::parse`] accepts.
+const MAX_DEPTH: usize = 512;
+
+/// Start of the error [`Json::parse`] returns past [`MAX_DEPTH`], as opposed to input that
+/// isn't JSON.
+pub const TOO_DEEP: &str = "too deeply nested";
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    index: usize,
+    /// Arrays and objects open around the current value.
+    depth: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at offset {}", message, self.index)
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.index) {
+            self.index += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), String> {
+        if self.bytes[self.index..].starts_with(literal.as_bytes()) {
+            self.index += literal.len();
+            Ok(())
+        } else {
+            Err(self.error("unexpected token"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.bytes.get(self.index) {
+            Some(b'n') => self.expect("null").map(|_| Json::Null),
+            Some(b't') => self.expect("true").map(|_| Json::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => self.nested(Self::array),
+            Some(b'{') => self.nested(Self::object),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    /// Parses an array or object one level deeper, failing past [`MAX_DEPTH`] so hostile input
+    /// can't overflow the stack.
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Json, String>) -> Result<Json, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(TOO_DEEP));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.index += 1;
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.bytes.get(self.index) == Some(&b']') {
+            self.index += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.bytes.get(self.index) {
+                Some(b',') => self.index += 1,
+                Some(b']') => {
+                    self.index += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.index += 1;
+        let mut fields = Vec::new();
+        self.whitespace();
+        if self.bytes.get(self.index) == Some(&b'}') {
+            self.index += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.whitespace();
+            if self.bytes.get(self.index) != Some(&b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(":")?;
+            fields.push((key, self.value()?));
+            self.whitespace();
+            match self.bytes.get(self.index) {
+                Some(b',') => self.index += 1,
+                Some(b'}') => {
+                    self.index += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.index;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.bytes.get(self.index)
+        {
+            self.index += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.index])
+            .ok()
+            .and_then(|text| text.parse().ok())
+            .map(Json::Number)
+            .ok_or_else(|| self.error("invalid number"))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.index += 1; // Skip the opening quote
+        let mut out = String::new();
+        loop {
+            let start = self.index;
+            while !matches!(self.bytes.get(self.index), None | Some(b'"' | b'\\')) {
+                self.index += 1;
+            }
+            // The input is a &str and we only split at ASCII bytes
+            out.push_str(std::str::from_utf8(&self.bytes[start..self.index]).unwrap());
+
+            match self.bytes.get(self.index) {
+                Some(b'"') => {
+                    self.index += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    self.index += 1;
+                    let escape = *self
+                        .bytes
+                        .get(self.index)
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    self.index += 1;
+                    match escape {
+                        b'"' => out.push('"'),
+                        b'\\' => out.push('\\'),
+                        b'/' => out.push('/'),
+                        b'b' => out.push('\u{8}'),
+                        b'f' => out.push('\u{c}'),
+                        b'n' => out.push('\n'),
+                        b'r' => out.push('\r'),
+                        b't' => out.push('\t'),
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // Surrogate pair
+                            if (0xD800..0xDC00).contains(&code)
+                                && self.bytes[self.index..].starts_with(b"\\u")
+                            {
+                                self.index += 2;
+                                let low = self.hex4()?;
+                                code =
+                                    0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00));
+                            }
+                            out.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                _ => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self
+            .bytes
+            .get(self.index..self.index + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.index += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_print() {
+        let text =
+            r#"{"id": 1, "params": {"text": "a\n\"b\" é 😀", "list": [true, null, -2.5e1]}}"#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.get("id").as_usize(), Some(1));
+        assert_eq!(json.at(&["params", "text"]).as_str(), Some("a\n\"b\" é 😀"));
+        assert_eq!(
+            json.at(&["params", "list"]),
+            &Json::Array(vec![Json::Bool(true), Json::Null, Json::Number(-25.0)])
+        );
+        assert!(json.at(&["missing", "key"]).is_null());
+
+        assert_eq!(
+            json.to_string(),
+            "{\"id\":1,\"params\":{\"text\":\"a\\n\\\"b\\\" é 😀\",\"list\":[true,null,-25]}}"
+        );
+        assert_eq!(Json::parse(&json.to_string()).unwrap(), json);
+        assert!(Json::parse("{\"a\": }").is_err());
+        assert!(Json::parse("[1] x").is_err());
+
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            Json::parse(&nested(200_000)),
+            Err("too deeply nested at offset 512".to_string())
+        );
+        let object = format!(
+            "{}1{}",
+            "{\"a\":".repeat(MAX_DEPTH + 1),
+            "}".repeat(MAX_DEPTH + 1)
+        );
+        assert!(Json::parse(&object).is_err());
+    }
+}
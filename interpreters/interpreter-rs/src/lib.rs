@@ -0,0 +1,29 @@
+pub mod ast;
+pub mod chunk;
+pub mod compiler;
+pub mod cst;
+pub mod dap;
+pub mod debugger;
+pub mod diagnostic;
+pub mod disassembler;
+pub mod embed;
+pub mod formatter;
+pub mod gc;
+pub mod golden;
+pub mod json;
+pub mod lint;
+pub mod loxc;
+pub mod lsp;
+pub mod natives;
+pub mod object;
+pub mod optimizer;
+pub mod parser;
+pub mod profiler;
+pub mod register;
+pub mod register_compiler;
+pub mod register_vm;
+pub mod repl;
+pub mod resolver;
+pub mod table;
+pub mod value;
+pub mod vm;
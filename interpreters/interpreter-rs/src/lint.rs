@@ -0,0 +1,438 @@
+use crate::ast::*;
+use crate::diagnostic::Diagnostic;
+use crate::parser::{self, Dialect};
+use crate::resolver::{self, Resolution, SymbolKind};
+use lexer::trivia::{tokenize_with_trivia, TriviaKind};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// Every lint the checker knows, as `(id, severity)`.
+pub const LINTS: [(&str, Severity); 7] = [
+    ("unused-variable", Severity::Warning),
+    ("unused-parameter", Severity::Warning),
+    ("unreachable-code", Severity::Warning),
+    ("shadowed-variable", Severity::Warning),
+    ("assignment-in-condition", Severity::Warning),
+    ("duplicate-nil-check", Severity::Warning),
+    ("wrong-arity", Severity::Error),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub id: &'static str,
+    pub severity: Severity,
+    pub line: u32,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[line {}] {}[{}]: {}",
+            self.line, self.severity, self.id, self.message
+        )
+    }
+}
+
+/// Parses and checks `source`, returning lints in line order. A `// lint:allow(id, ...)`
+/// comment silences those lints on its own line and the next one.
+pub fn lint(source: &[u8], dialect: Dialect) -> Result<Vec<Lint>, Vec<Diagnostic>> {
+    let program = parser::parse_dialect(source, dialect)?;
+    let resolution = resolver::resolve(&program);
+    let mut linter = Linter {
+        resolution: &resolution,
+        references: resolution
+            .references
+            .iter()
+            .map(|reference| (reference.loc.start, reference.definition))
+            .collect(),
+        lints: Vec::new(),
+    };
+    linter.unused_and_shadowed();
+    linter.statements(&program);
+
+    let allowed = allowed_lints(source);
+    let mut lints = linter.lints;
+    lints.retain(|lint| {
+        allowed
+            .get(&lint.line)
+            .is_none_or(|ids| !ids.contains(lint.id))
+    });
+    lints.sort_by_key(|lint| lint.line);
+    Ok(lints)
+}
+
+/// Lint ids allowed on each line by `lint:allow` comments.
+fn allowed_lints(source: &[u8]) -> HashMap<u32, HashSet<String>> {
+    let mut allowed: HashMap<u32, HashSet<String>> = HashMap::new();
+    let comments = tokenize_with_trivia(source)
+        .into_iter()
+        .flat_map(|token| token.leading.into_iter().chain(token.trailing))
+        .filter(|trivia| trivia.kind == TriviaKind::Comment);
+    for comment in comments {
+        let text = String::from_utf8_lossy(&source[comment.loc.start..comment.loc.end]);
+        let Some(ids) = text
+            .trim_start_matches('/')
+            .trim()
+            .strip_prefix("lint:allow(")
+            .and_then(|rest| rest.split_once(')'))
+            .map(|(ids, _)| ids)
+        else {
+            continue;
+        };
+        let line = 1 + source[..comment.loc.start]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count() as u32;
+        for line in [line, line + 1] {
+            allowed
+                .entry(line)
+                .or_default()
+                .extend(ids.split(',').map(|id| id.trim().to_string()));
+        }
+    }
+    allowed
+}
+
+struct Linter<'a> {
+    resolution: &'a Resolution,
+    /// Definition used at each reference's start offset
+    references: HashMap<usize, usize>,
+    lints: Vec<Lint>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, id: &'static str, line: u32, message: String) {
+        let severity = LINTS
+            .iter()
+            .find(|(known, _)| *known == id)
+            .map_or(Severity::Warning, |(_, severity)| *severity);
+        self.lints.push(Lint {
+            id,
+            severity,
+            line,
+            message,
+        });
+    }
+
+    fn unused_and_shadowed(&mut self) {
+        let mut read = vec![false; self.resolution.definitions.len()];
+        for reference in self.resolution.references.iter().filter(|r| !r.write) {
+            read[reference.definition] = true;
+        }
+
+        for (index, definition) in self.resolution.definitions.iter().enumerate() {
+            // Globals may be used by code loaded later; `_name` marks intentionally unused
+            if !definition.global && !read[index] && !definition.name.starts_with('_') {
+                let (id, what) = match definition.kind {
+                    SymbolKind::Parameter => ("unused-parameter", "Parameter"),
+                    SymbolKind::Function => ("unused-variable", "Function"),
+                    SymbolKind::Class => ("unused-variable", "Class"),
+                    SymbolKind::Variable => ("unused-variable", "Variable"),
+                };
+                self.report(
+                    id,
+                    definition.line,
+                    format!("{} '{}' is never used.", what, definition.name),
+                );
+            }
+            if let Some(shadowed) = definition.shadows {
+                let line = self.resolution.definitions[shadowed].line;
+                self.report(
+                    "shadowed-variable",
+                    definition.line,
+                    format!(
+                        "'{}' shadows the declaration on line {}.",
+                        definition.name, line
+                    ),
+                );
+            }
+        }
+    }
+
+    fn statements(&mut self, stmts: &[Stmt]) {
+        for (i, stmt) in stmts.iter().enumerate() {
+            self.statement(stmt);
+            if matches!(stmt.kind, StmtKind::Return(_)) {
+                if let Some(next) = stmts.get(i + 1) {
+                    self.report(
+                        "unreachable-code",
+                        next.line,
+                        "Unreachable code after 'return'.".to_string(),
+                    );
+                }
+            }
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Expression(expr) | StmtKind::Print(expr) => self.expression(expr),
+            StmtKind::Var(_, initializer) => {
+                if let Some(expr) = initializer {
+                    self.expression(expr);
+                }
+            }
+            StmtKind::Block(stmts) => self.statements(stmts),
+            StmtKind::If(condition, then_branch, else_branch) => {
+                self.condition(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            StmtKind::While(condition, body) => {
+                self.condition(condition);
+                self.statement(body);
+            }
+            StmtKind::Function(function) => self.statements(&function.body),
+            StmtKind::Return(value) => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            StmtKind::Class(class) => {
+                for method in &class.methods {
+                    self.statements(&method.body);
+                }
+            }
+            StmtKind::Import(_) => {}
+            StmtKind::Export(declaration) => self.statement(declaration),
+            StmtKind::Throw(value) => self.expression(value),
+            StmtKind::Try(handler) => {
+                self.statements(&handler.body);
+                if let Some((_, body)) = &handler.catch {
+                    self.statements(body);
+                }
+                if let Some(body) = &handler.finally {
+                    self.statements(body);
+                }
+            }
+        }
+    }
+
+    fn condition(&mut self, condition: &Expr) {
+        let mut inner = condition;
+        while let ExprKind::Grouping(expr) = &inner.kind {
+            inner = expr;
+        }
+        if matches!(
+            inner.kind,
+            ExprKind::Assign(..) | ExprKind::Set(..) | ExprKind::SetIndex(..)
+        ) {
+            self.report(
+                "assignment-in-condition",
+                inner.line,
+                "Assignment used as a condition; did you mean '=='?".to_string(),
+            );
+        }
+        self.expression(condition);
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Literal(_) | ExprKind::Variable(_) | ExprKind::This | ExprKind::Super(_) => {}
+            ExprKind::Assign(_, value) => self.expression(value),
+            ExprKind::Unary(_, right) | ExprKind::Grouping(right) => self.expression(right),
+            ExprKind::Binary(left, _, right) => {
+                self.expression(left);
+                self.expression(right);
+            }
+            ExprKind::Logical(_, op, _) => {
+                let mut operands = Vec::new();
+                flatten(expr, *op, &mut operands);
+                self.nil_checks(&operands);
+                for operand in operands {
+                    self.expression(operand);
+                }
+            }
+            ExprKind::Call(callee, arguments) => {
+                self.arity(callee, arguments.len(), expr.line);
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+            }
+            ExprKind::Get(object, _) => self.expression(object),
+            ExprKind::Set(object, _, value) => {
+                self.expression(object);
+                self.expression(value);
+            }
+            ExprKind::List(items) => {
+                for item in items {
+                    self.expression(item);
+                }
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            ExprKind::Index(object, index) => {
+                self.expression(object);
+                self.expression(index);
+            }
+            ExprKind::SetIndex(object, index, value) => {
+                self.expression(object);
+                self.expression(index);
+                self.expression(value);
+            }
+        }
+    }
+
+    /// Flags `x == nil` appearing twice in one chain of `and`s or `or`s.
+    fn nil_checks(&mut self, operands: &[&Expr]) {
+        let mut seen = HashSet::new();
+        for operand in operands {
+            let ExprKind::Binary(left, BinaryOp::Equal, right) = &operand.kind else {
+                continue;
+            };
+            let name = match (&left.kind, &right.kind) {
+                (ExprKind::Variable(name), ExprKind::Literal(Literal::Nil))
+                | (ExprKind::Literal(Literal::Nil), ExprKind::Variable(name)) => name,
+                _ => continue,
+            };
+            // Unresolved names are compared by text
+            let key = self
+                .references
+                .get(&name.loc.start)
+                .map_or_else(|| Err(name.text.clone()), |&d| Ok(d));
+            if !seen.insert(key) {
+                self.report(
+                    "duplicate-nil-check",
+                    operand.line,
+                    format!("'{}' is compared to nil more than once.", name.text),
+                );
+            }
+        }
+    }
+
+    fn arity(&mut self, callee: &Expr, count: usize, line: u32) {
+        let ExprKind::Variable(name) = &callee.kind else {
+            return;
+        };
+        let Some(&index) = self.references.get(&name.loc.start) else {
+            return;
+        };
+        let definition = &self.resolution.definitions[index];
+        // Parameters and reassigned variables may hold anything
+        if !matches!(definition.kind, SymbolKind::Function | SymbolKind::Class) {
+            return;
+        }
+        let written = self
+            .resolution
+            .references
+            .iter()
+            .any(|r| r.write && r.definition == index);
+        // Declaring a global again rebinds the name its uses resolve to
+        let redeclared = definition.global
+            && self.resolution.definitions.iter().any(|other| {
+                other.global && other.name == definition.name && other.loc != definition.loc
+            });
+        if written || redeclared {
+            return;
+        }
+        if let Some(arity) = definition.arity.filter(|&arity| arity != count) {
+            self.report(
+                "wrong-arity",
+                line,
+                format!(
+                    "'{}' expects {} argument{} but is called with {}.",
+                    name.text,
+                    arity,
+                    if arity == 1 { "" } else { "s" },
+                    count
+                ),
+            );
+        }
+    }
+}
+
+/// Operands of a chain of the same logical operator, left to right.
+fn flatten<'e>(expr: &'e Expr, op: LogicalOp, operands: &mut Vec<&'e Expr>) {
+    match &expr.kind {
+        ExprKind::Logical(left, inner, right) if *inner == op => {
+            flatten(left, op, operands);
+            flatten(right, op, operands);
+        }
+        _ => operands.push(expr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lints_and_suppression() {
+        let source = "\
+fun add(a, b, _c) {
+  var unused = 1;
+  return a + b;
+  print \"never\";
+}
+add(1, 2, 3);
+add(1);
+var x = nil;
+if (x = 1) print x;
+if (x == nil or nil == x) print x;
+{
+  var x = 2;
+  print x;
+}
+// lint:allow(wrong-arity)
+add(1, 2, 3, 4);
+fun f(y) {} // lint:allow(unused-parameter)
+f(1);
+class P { init(n) { this.n = n; } }
+class Q < P {}
+Q();
+fun g() {}
+fun h(_a) {}
+h = g;
+h();
+fun k(_a) {}
+fun k() {}
+k();
+";
+        let lints = lint(source.as_bytes(), Dialect::Lox).unwrap();
+        let found: Vec<(u32, &str)> = lints.iter().map(|lint| (lint.line, lint.id)).collect();
+        assert_eq!(
+            found,
+            [
+                (2, "unused-variable"),
+                (4, "unreachable-code"),
+                (7, "wrong-arity"),
+                (9, "assignment-in-condition"),
+                (10, "duplicate-nil-check"),
+                (12, "shadowed-variable"),
+                (21, "wrong-arity"),
+            ]
+        );
+        assert_eq!(
+            lints[2].to_string(),
+            "[line 7] error[wrong-arity]: 'add' expects 3 arguments but is called with 1."
+        );
+        assert_eq!(
+            lints[6].to_string(),
+            "[line 21] error[wrong-arity]: 'Q' expects 1 argument but is called with 0."
+        );
+    }
+}
@@ -0,0 +1,732 @@
+use crate::chunk::{Chunk, LocalInfo, OpCode};
+use crate::gc::{Heap, Trace};
+use crate::object::{Obj, ObjFunction, ObjRef};
+use crate::value::Value;
+use lexer::interner::hash;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+const MAGIC: &[u8; 4] = b"LOXC";
+
+/// Bumped whenever the file layout or the meaning of any opcode changes.
+pub const VERSION: u16 = 1;
+
+const HEADER_LEN: usize = 4 + 2 + 8 + 4;
+
+/// Why a `.loxc` file can't be used. All of them mean "compile the source again".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    BadMagic,
+    Version(u16),
+    Checksum,
+    /// Compiled from a different source, dialect or optimization setting.
+    Stale,
+    Corrupt(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "Not a compiled Lox file."),
+            LoadError::Version(version) => write!(
+                f,
+                "Compiled Lox file version {} is not {}.",
+                version, VERSION
+            ),
+            LoadError::Checksum => write!(f, "Compiled Lox file checksum mismatch."),
+            LoadError::Stale => write!(f, "Compiled Lox file is out of date."),
+            LoadError::Corrupt(message) => write!(f, "Corrupt compiled Lox file: {}", message),
+        }
+    }
+}
+
+/// Identifies what a script was compiled from: its source and the settings that change the
+/// bytecode the compiler emits.
+pub fn source_hash(source: &[u8], plus: bool, optimize: bool) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in [plus as u8, optimize as u8].iter().chain(source) {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Tags of serialized constants
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const NUMBER: u8 = 3;
+const STRING: u8 = 4;
+const FUNCTION: u8 = 5;
+
+const NO_NAME: u32 = u32::MAX;
+
+/// Serializes the script function `script` and every function nested in it.
+///
+/// The file is a header (magic, version, source hash, checksum of the rest) followed by a table
+/// of the strings used as names and constants, then the functions, nested ones before those
+/// that create them, the script last.
+pub fn write(heap: &Heap, script: ObjRef, source_hash: u64) -> Vec<u8> {
+    let mut writer = Writer {
+        heap,
+        strings: Vec::new(),
+        string_ids: HashMap::new(),
+        functions: Vec::new(),
+        function_ids: HashMap::new(),
+    };
+    writer.function(script);
+
+    let mut payload = Vec::new();
+    put_u32(&mut payload, writer.strings.len() as u32);
+    for s in &writer.strings {
+        put_u32(&mut payload, s.len() as u32);
+        payload.extend_from_slice(s.as_bytes());
+    }
+    put_u32(&mut payload, writer.functions.len() as u32);
+    for function in &writer.functions {
+        payload.extend_from_slice(function);
+    }
+
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&source_hash.to_le_bytes());
+    out.extend_from_slice(&hash(&payload).to_le_bytes());
+    out.extend_from_slice(&payload);
+    out
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+struct Writer<'a> {
+    heap: &'a Heap,
+    strings: Vec<&'a str>,
+    string_ids: HashMap<&'a str, u32>,
+    /// Serialized functions in the order they are read back.
+    functions: Vec<Vec<u8>>,
+    function_ids: HashMap<ObjRef, u32>,
+}
+
+impl<'a> Writer<'a> {
+    fn string(&mut self, s: &'a str) -> u32 {
+        *self.string_ids.entry(s).or_insert_with(|| {
+            self.strings.push(s);
+            self.strings.len() as u32 - 1
+        })
+    }
+
+    fn function(&mut self, r: ObjRef) -> u32 {
+        if let Some(&id) = self.function_ids.get(&r) {
+            return id;
+        }
+        let heap = self.heap;
+        let function = heap.function(r);
+        let chunk = &function.chunk;
+
+        // Nested functions first, so loading never meets a function it hasn't read yet
+        let mut constants = Vec::new();
+        for &constant in &chunk.constants {
+            match constant {
+                Value::Nil => constants.push(NIL),
+                Value::Bool(false) => constants.push(FALSE),
+                Value::Bool(true) => constants.push(TRUE),
+                Value::Number(n) => {
+                    constants.push(NUMBER);
+                    constants.extend_from_slice(&n.to_bits().to_le_bytes());
+                }
+                Value::Obj(obj) => match heap.get(obj) {
+                    Obj::String(s) => {
+                        constants.push(STRING);
+                        put_u32(&mut constants, self.string(&s.chars));
+                    }
+                    Obj::Function(_) => {
+                        constants.push(FUNCTION);
+                        put_u32(&mut constants, self.function(obj));
+                    }
+                    _ => unreachable!("the compiler only emits string and function constants"),
+                },
+            }
+        }
+
+        let mut out = Vec::new();
+        let name = match function.name {
+            Some(name) => self.string(heap.str(name)),
+            None => NO_NAME,
+        };
+        put_u32(&mut out, name);
+        out.push(function.arity);
+        put_u32(&mut out, function.upvalue_count as u32);
+        put_u32(&mut out, chunk.code.len() as u32);
+        out.extend_from_slice(&chunk.code);
+
+        // Lines come in long runs, so they are stored as (line, run length) pairs
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for &line in &chunk.lines {
+            match runs.last_mut() {
+                Some((last, len)) if *last == line => *len += 1,
+                _ => runs.push((line, 1)),
+            }
+        }
+        put_u32(&mut out, runs.len() as u32);
+        for (line, len) in runs {
+            put_u32(&mut out, line);
+            put_u32(&mut out, len);
+        }
+
+        put_u32(&mut out, chunk.constants.len() as u32);
+        out.extend_from_slice(&constants);
+
+        put_u32(&mut out, chunk.locals.len() as u32);
+        for local in &chunk.locals {
+            put_u32(&mut out, self.string(&local.name));
+            out.push(local.slot);
+            put_u32(&mut out, local.start as u32);
+            put_u32(&mut out, local.end as u32);
+        }
+        put_u32(&mut out, chunk.upvalue_names.len() as u32);
+        for name in &chunk.upvalue_names {
+            put_u32(&mut out, self.string(name));
+        }
+
+        self.functions.push(out);
+        let id = self.functions.len() as u32 - 1;
+        self.function_ids.insert(r, id);
+        id
+    }
+}
+
+/// Loads a file made by [`write`], returning the script function.
+///
+/// Nothing is trusted: the header must match `source_hash` and every chunk is checked so that
+/// running it can't index past its code, constants, upvalues or stack frame. `roots` are the
+/// caller's GC roots, marked together with what was loaded so far if the heap collects.
+pub fn read(
+    bytes: &[u8],
+    source_hash: u64,
+    heap: &mut Heap,
+    roots: &dyn Trace,
+) -> Result<ObjRef, LoadError> {
+    if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+        return Err(LoadError::BadMagic);
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(LoadError::Version(version));
+    }
+    let payload = &bytes[HEADER_LEN..];
+    if u32::from_le_bytes(bytes[14..18].try_into().unwrap()) != hash(payload) {
+        return Err(LoadError::Checksum);
+    }
+    if u64::from_le_bytes(bytes[6..14].try_into().unwrap()) != source_hash {
+        return Err(LoadError::Stale);
+    }
+
+    let mut reader = Reader {
+        bytes: payload,
+        pos: 0,
+        heap,
+        roots,
+        loaded: Vec::new(),
+    };
+    let result = reader.program();
+    if result.is_ok() && reader.pos != payload.len() {
+        return Err(corrupt("trailing bytes"));
+    }
+    result
+}
+
+fn corrupt(message: &str) -> LoadError {
+    LoadError::Corrupt(message.to_string())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    heap: &'a mut Heap,
+    roots: &'a dyn Trace,
+    /// Every object allocated so far, kept alive until the script function owns them.
+    loaded: Vec<ObjRef>,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], LoadError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| corrupt("unexpected end of file"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// A count of items that take at least `item_len` bytes each, checked against what is left
+    /// so a corrupt count can't make us allocate wildly.
+    fn count(&mut self, item_len: usize) -> Result<usize, LoadError> {
+        let count = self.u32()? as usize;
+        if count.saturating_mul(item_len) > self.bytes.len() - self.pos {
+            return Err(corrupt("count past the end of file"));
+        }
+        Ok(count)
+    }
+
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.roots.trace(self.heap);
+            self.loaded.trace(self.heap);
+            self.heap.collect();
+        }
+        let r = self.heap.alloc(obj);
+        self.loaded.push(r);
+        r
+    }
+
+    fn intern(&mut self, chars: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.roots.trace(self.heap);
+            self.loaded.trace(self.heap);
+            self.heap.collect();
+        }
+        let r = self.heap.intern(chars);
+        self.loaded.push(r);
+        r
+    }
+
+    fn program(&mut self) -> Result<ObjRef, LoadError> {
+        let count = self.count(4)?;
+        let mut strings: Vec<Rc<str>> = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len = self.u32()? as usize;
+            let chars =
+                std::str::from_utf8(self.take(len)?).map_err(|_| corrupt("string is not UTF-8"))?;
+            strings.push(chars.into());
+        }
+        // Heap strings are only made for the strings the functions use
+        let mut heap_strings: Vec<Option<ObjRef>> = vec![None; count];
+        let mut string = |reader: &mut Self, index: u32| -> Result<ObjRef, LoadError> {
+            let index = index as usize;
+            let chars = strings
+                .get(index)
+                .ok_or_else(|| corrupt("bad string index"))?;
+            Ok(*heap_strings[index].get_or_insert_with(|| reader.intern(chars)))
+        };
+
+        let count = self.count(1)?;
+        if count == 0 {
+            return Err(corrupt("no script function"));
+        }
+        let mut functions = Vec::with_capacity(count);
+        for _ in 0..count {
+            let name = match self.u32()? {
+                NO_NAME => None,
+                index => Some(string(self, index)?),
+            };
+            let arity = self.u8()?;
+            let upvalue_count = self.u32()? as usize;
+            if upvalue_count > 256 {
+                return Err(corrupt("too many upvalues"));
+            }
+
+            let mut chunk = Chunk::new();
+            let len = self.u32()? as usize;
+            chunk.code = self.take(len)?.to_vec();
+            for _ in 0..self.count(8)? {
+                let line = self.u32()?;
+                let run = self.u32()? as usize;
+                if chunk.lines.len() + run > chunk.code.len() {
+                    return Err(corrupt("more lines than code"));
+                }
+                chunk.lines.resize(chunk.lines.len() + run, line);
+            }
+            if chunk.lines.len() != chunk.code.len() {
+                return Err(corrupt("fewer lines than code"));
+            }
+
+            for _ in 0..self.count(1)? {
+                let constant = match self.u8()? {
+                    NIL => Value::Nil,
+                    FALSE => Value::Bool(false),
+                    TRUE => Value::Bool(true),
+                    NUMBER => Value::Number(f64::from_bits(self.u64()?)),
+                    STRING => {
+                        let index = self.u32()?;
+                        Value::Obj(string(self, index)?)
+                    }
+                    FUNCTION => {
+                        let index = self.u32()? as usize;
+                        let &function = functions
+                            .get(index)
+                            .ok_or_else(|| corrupt("function used before it is defined"))?;
+                        Value::Obj(function)
+                    }
+                    _ => return Err(corrupt("bad constant tag")),
+                };
+                chunk.constants.push(constant);
+            }
+
+            for _ in 0..self.count(13)? {
+                let name = self.u32()? as usize;
+                let name = strings
+                    .get(name)
+                    .ok_or_else(|| corrupt("bad string index"))?;
+                chunk.locals.push(LocalInfo {
+                    name: name.clone(),
+                    slot: self.u8()?,
+                    start: self.u32()? as usize,
+                    end: self.u32()? as usize,
+                });
+            }
+            for _ in 0..self.count(4)? {
+                let name = self.u32()? as usize;
+                let name = strings
+                    .get(name)
+                    .ok_or_else(|| corrupt("bad string index"))?;
+                chunk.upvalue_names.push(name.clone());
+            }
+
+            verify(self.heap, &chunk, arity, upvalue_count).map_err(LoadError::Corrupt)?;
+            let function = self.alloc(Obj::Function(ObjFunction {
+                name,
+                arity,
+                upvalue_count,
+                chunk: Rc::new(chunk),
+                module: 0,
+                registers: None,
+            }));
+            functions.push(function);
+        }
+
+        let script = *functions.last().unwrap();
+        if self.heap.function(script).upvalue_count != 0 {
+            return Err(corrupt("script function has upvalues"));
+        }
+        Ok(script)
+    }
+}
+
+/// Checks that running the chunk can't read outside its code, constants, upvalues or stack
+/// frame: every instruction is known, its operands are inside the code and refer to constants
+/// of the right kind, upvalue indices are below `upvalue_count`, and jumps land on
+/// instructions. Following every path from the start, the stack depth where paths meet must
+/// agree, no instruction pops more than the frame holds, local slots are below the depth and
+/// no path runs past the end of the code.
+fn verify(heap: &Heap, chunk: &Chunk, arity: u8, upvalue_count: usize) -> Result<(), String> {
+    let code = &chunk.code;
+    let constant = |offset: usize| -> Result<Value, String> {
+        let index = chunk.read_u16(offset) as usize;
+        chunk
+            .constants
+            .get(index)
+            .copied()
+            .ok_or_else(|| format!("constant {} out of range at {}", index, offset))
+    };
+    let upvalue = |index: u8, offset: usize| -> Result<(), String> {
+        if index as usize >= upvalue_count {
+            return Err(format!("upvalue {} out of range at {}", index, offset));
+        }
+        Ok(())
+    };
+
+    // The offset of the instruction after each one, zero where no instruction starts
+    let mut next_of = vec![0; code.len()];
+    let mut offset = 0;
+    while offset < code.len() {
+        let op = OpCode::from_byte(code[offset])
+            .ok_or_else(|| format!("unknown opcode {} at {}", code[offset], offset))?;
+        let len = 1 + op.operand_len();
+        if offset + len > code.len() {
+            return Err(format!("truncated instruction at {}", offset));
+        }
+        let next = match op {
+            OpCode::Constant => {
+                constant(offset + 1)?;
+                offset + len
+            }
+            OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::Invoke
+            | OpCode::SuperInvoke
+            | OpCode::Import => {
+                if heap.as_string(constant(offset + 1)?).is_none() {
+                    return Err(format!("name at {} is not a string", offset));
+                }
+                offset + len
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                upvalue(code[offset + 1], offset)?;
+                offset + len
+            }
+            OpCode::Closure => {
+                let function = constant(offset + 1)?
+                    .as_obj()
+                    .filter(|&r| matches!(heap.get(r), Obj::Function(_)))
+                    .ok_or_else(|| format!("closure at {} is not over a function", offset))?;
+                let end = offset + len + 2 * heap.function(function).upvalue_count;
+                if end > code.len() {
+                    return Err(format!("truncated closure at {}", offset));
+                }
+                // Captured locals are checked against the stack depth below
+                for pair in code[offset + len..end].chunks_exact(2) {
+                    match pair[0] {
+                        0 => upvalue(pair[1], offset)?,
+                        1 => {}
+                        _ => return Err(format!("bad capture at {}", offset)),
+                    }
+                }
+                end
+            }
+            _ => offset + len,
+        };
+        next_of[offset] = next;
+        offset = next;
+    }
+
+    // The stack depth before each instruction, counting the callee and arguments at the
+    // bottom of the frame
+    let mut depths = vec![None; code.len()];
+    let mut pending = vec![(0, arity as usize + 1)];
+    while let Some((offset, depth)) = pending.pop() {
+        if offset >= code.len() {
+            return Err("code runs past its end".into());
+        }
+        if next_of[offset] == 0 {
+            return Err(format!("jump into an instruction at {}", offset));
+        }
+        match depths[offset] {
+            Some(seen) if seen == depth => continue,
+            Some(seen) => {
+                return Err(format!(
+                    "stack depths {} and {} meet at {}",
+                    seen, depth, offset
+                ))
+            }
+            None => depths[offset] = Some(depth),
+        }
+
+        let op = OpCode::from_byte(code[offset]).unwrap();
+        let byte = |i: usize| code[offset + i] as usize;
+        let (pops, pushes) = match op {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetLocal
+            | OpCode::GetGlobal
+            | OpCode::GetUpvalue
+            | OpCode::Class
+            | OpCode::Closure => (0, 1),
+            OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return
+            | OpCode::Throw => (1, 0),
+            OpCode::SetLocal
+            | OpCode::SetGlobal
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::JumpIfFalse => (1, 1),
+            OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Inherit
+            | OpCode::Method
+            | OpCode::GetIndex => (2, 1),
+            OpCode::SetIndex => (3, 1),
+            OpCode::Call => (byte(1) + 1, 1),
+            OpCode::Invoke => (byte(3) + 1, 1),
+            OpCode::SuperInvoke => (byte(3) + 2, 1),
+            OpCode::BuildList => (chunk.read_u16(offset + 1) as usize, 1),
+            OpCode::BuildMap => (2 * chunk.read_u16(offset + 1) as usize, 1),
+            OpCode::Import => (0, 2),
+            OpCode::FinishImport => (2, 0),
+            OpCode::Jump | OpCode::Loop | OpCode::PushHandler | OpCode::PopHandler => (0, 0),
+        };
+        if pops > depth {
+            return Err(format!("stack underflow at {}", offset));
+        }
+        match op {
+            OpCode::GetLocal | OpCode::SetLocal if byte(1) >= depth => {
+                return Err(format!("local slot {} out of range at {}", byte(1), offset));
+            }
+            OpCode::Closure => {
+                let captures = code[offset + 3..next_of[offset]].chunks_exact(2);
+                if captures
+                    .into_iter()
+                    .any(|pair| pair[0] == 1 && pair[1] as usize >= depth)
+                {
+                    return Err(format!("captured slot out of range at {}", offset));
+                }
+            }
+            _ => {}
+        }
+
+        let after = depth - pops + pushes;
+        let next = next_of[offset];
+        let jump = || next + chunk.read_u16(offset + 1) as usize;
+        match op {
+            OpCode::Return | OpCode::Throw => {}
+            OpCode::Jump => pending.push((jump(), after)),
+            OpCode::JumpIfFalse => pending.extend([(next, after), (jump(), after)]),
+            OpCode::Loop => {
+                let target = next
+                    .checked_sub(chunk.read_u16(offset + 1) as usize)
+                    .ok_or_else(|| format!("loop out of range at {}", offset))?;
+                pending.push((target, after));
+            }
+            // A thrown value lands on top of the stack as it was when the handler was pushed
+            OpCode::PushHandler => pending.extend([(next, after), (jump(), after + 1)]),
+            _ => pending.push((next, after)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::disassemble;
+    use crate::embed::OutputBuffer;
+    use crate::golden;
+    use crate::vm::Vm;
+
+    const PROGRAM: &str = r#"
+        class Counter {
+            init(start) { this.count = start; }
+            next() { this.count = this.count + 1; return this.count; }
+        }
+        fun make(label) {
+            var counter = Counter(0);
+            fun step() { print label; return counter.next(); }
+            return step;
+        }
+        var step = make("tick");
+        for (var i = 0; i < 2; i = i + 1) { step(); }
+        print step();
+        print 1.5 * 2;
+        print nil == false;
+    "#;
+
+    fn run(vm: &mut Vm, function: ObjRef) -> String {
+        let output = OutputBuffer::new();
+        vm.set_output(Box::new(output.clone()));
+        vm.run_function(function).unwrap();
+        output.take()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut vm = Vm::new();
+        let function = vm.compile_source(PROGRAM.as_bytes()).unwrap();
+        let bytes = vm.save_bytecode(function, PROGRAM.as_bytes());
+        let source = source_hash(PROGRAM.as_bytes(), false, true);
+        let expected = run(&mut vm, function);
+        assert_eq!(expected, "tick\ntick\ntick\n3\n3\nfalse\n");
+
+        let mut loaded_vm = Vm::new();
+        loaded_vm.heap_mut().set_stress(true);
+        let loaded = loaded_vm.load_bytecode(&bytes, PROGRAM.as_bytes()).unwrap();
+        assert_eq!(
+            disassemble(loaded_vm.heap(), loaded),
+            disassemble(vm.heap(), function)
+        );
+        assert_eq!(
+            loaded_vm.heap().function(loaded).chunk.locals,
+            vm.heap().function(function).chunk.locals
+        );
+        assert_eq!(run(&mut loaded_vm, loaded), expected);
+
+        // Everything the compiler emits passes verification
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+        for path in golden::discover(&root).unwrap() {
+            let source = std::fs::read_to_string(&path).unwrap();
+            let mut vm = Vm::new();
+            vm.set_dialect(golden::Expectations::parse(&source).dialect);
+            if let Ok(function) = vm.compile_source(source.as_bytes()) {
+                let bytes = vm.save_bytecode(function, source.as_bytes());
+                let loaded = vm.load_bytecode(&bytes, source.as_bytes());
+                assert!(loaded.is_ok(), "{}: {:?}", path.display(), loaded);
+            }
+        }
+
+        let mut heap = Heap::new();
+        assert_eq!(
+            read(&bytes, source + 1, &mut heap, &()),
+            Err(LoadError::Stale)
+        );
+        assert_eq!(
+            read(b"LOX", source, &mut heap, &()),
+            Err(LoadError::BadMagic)
+        );
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            read(&flipped, source, &mut heap, &()),
+            Err(LoadError::Checksum)
+        );
+        let mut old = bytes.clone();
+        old[4] = 0;
+        assert_eq!(
+            read(&old, source, &mut heap, &()),
+            Err(LoadError::Version(0))
+        );
+
+        // A corrupt body with a matching checksum is still caught
+        let mut truncated = bytes[..bytes.len() - 1].to_vec();
+        let checksum = hash(&truncated[HEADER_LEN..]);
+        truncated[14..18].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            read(&truncated, source, &mut heap, &()),
+            Err(LoadError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_slot() {
+        let program = "fun f(a) { return a; } print f(1);";
+        let mut vm = Vm::new();
+        let function = vm.compile_source(program.as_bytes()).unwrap();
+        let mut bytes = vm.save_bytecode(function, program.as_bytes());
+        let source = source_hash(program.as_bytes(), false, true);
+
+        // `f` has a frame of two slots, so slot 2 reads past it
+        let get_a = [OpCode::GetLocal as u8, 1, OpCode::Return as u8];
+        let at = bytes.windows(3).position(|w| w == get_a).unwrap();
+        bytes[at + 1] = 2;
+        let checksum = hash(&bytes[HEADER_LEN..]);
+        bytes[14..18].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            read(&bytes, source, &mut Heap::new(), &()),
+            Err(LoadError::Corrupt("local slot 2 out of range at 0".into()))
+        );
+    }
+}
@@ -0,0 +1,753 @@
+use crate::compiler;
+use crate::cst::{self, Node, SyntaxKind};
+use crate::diagnostic::Diagnostic;
+use crate::gc::Heap;
+use crate::json::Json;
+use crate::parser::{self, Dialect};
+use crate::resolver::{self, Resolution, SymbolKind, ValueKind};
+use lexer::trivia::TriviaKind;
+use lexer::with_opt_iterator::{Loc, Tag};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+
+/// Largest message body accepted, so a bad `Content-Length` can't make the server allocate
+/// without bound.
+const MAX_MESSAGE_LEN: usize = 64 << 20;
+
+/// Semantic token types, indexed by the numbers sent to the client.
+const TOKEN_TYPES: [&str; 10] = [
+    "keyword",
+    "variable",
+    "parameter",
+    "function",
+    "class",
+    "property",
+    "string",
+    "number",
+    "operator",
+    "comment",
+];
+
+// LSP SymbolKind values
+const SYMBOL_CLASS: usize = 5;
+const SYMBOL_METHOD: usize = 6;
+const SYMBOL_FUNCTION: usize = 12;
+
+/// Serves the Language Server Protocol over `input` and `output` until the client sends
+/// `exit`. Returns whether the client asked for a shutdown first, as the protocol wants the
+/// exit code to reflect that.
+pub fn run(mut input: impl BufRead, mut output: impl Write) -> io::Result<bool> {
+    let mut server = Server::new();
+    while let Some(body) = read_message(&mut input)? {
+        let replies = match Json::parse(&body) {
+            Ok(message) if message.get("method").as_str() == Some("exit") => break,
+            Ok(message) => server.handle(&message),
+            Err(e) => vec![error_response(Json::Null, PARSE_ERROR, &e)],
+        };
+        for reply in replies {
+            write_message(&mut output, &reply)?;
+        }
+    }
+    Ok(server.shutdown)
+}
+
+/// Reads one `Content-Length` framed message, or `None` at the end of the input.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    if length > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Content-Length {} is over {}", length, MAX_MESSAGE_LEN),
+        ));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+fn response(id: Json, result: Json) -> Json {
+    Json::object([("jsonrpc", "2.0".into()), ("id", id), ("result", result)])
+}
+
+fn error_response(id: Json, code: i32, message: &str) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("id", id),
+        (
+            "error",
+            Json::object([
+                ("code", Json::Number(code as f64)),
+                ("message", message.into()),
+            ]),
+        ),
+    ])
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("method", method.into()),
+        ("params", params),
+    ])
+}
+
+/// Byte offsets of line starts, for converting to and from LSP positions, which count
+/// UTF-16 code units.
+struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(text: &str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex { starts }
+    }
+
+    fn position(&self, text: &str, offset: usize) -> Json {
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.starts[line];
+        let character = utf16_len(&text.as_bytes()[start..offset]);
+        Json::object([("line", line.into()), ("character", character.into())])
+    }
+
+    fn range(&self, text: &str, loc: Loc) -> Json {
+        Json::object([
+            ("start", self.position(text, loc.start)),
+            ("end", self.position(text, loc.end)),
+        ])
+    }
+
+    fn offset(&self, text: &str, position: &Json) -> Option<usize> {
+        let line = position.get("line").as_usize()?;
+        let character = position.get("character").as_usize()?;
+        let start = *self.starts.get(line)?;
+        let mut units = 0;
+        for (i, c) in text[start..].char_indices() {
+            if units >= character || c == '\n' {
+                return Some(start + i);
+            }
+            units += c.len_utf16();
+        }
+        Some(text.len())
+    }
+
+    /// Span of 1-based `line` without its line break.
+    fn line_loc(&self, text: &str, line: u32) -> Loc {
+        let index = (line as usize).clamp(1, self.starts.len()) - 1;
+        let start = self.starts[index];
+        let end = self
+            .starts
+            .get(index + 1)
+            .map_or(text.len(), |&next| next - 1);
+        Loc { start, end }
+    }
+}
+
+/// Length in UTF-16 code units of UTF-8 `bytes`, which may start or end inside a character as
+/// the lexer works on bytes.
+fn utf16_len(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .map(|&c| match c {
+            0x80..=0xBF => 0,
+            0xF0.. => 2,
+            _ => 1,
+        })
+        .sum()
+}
+
+struct Document {
+    text: String,
+    dialect: Dialect,
+    lines: LineIndex,
+    tree: Node,
+    diagnostics: Vec<Diagnostic>,
+    /// Empty while the text doesn't parse
+    resolution: Resolution,
+}
+
+impl Document {
+    fn new(text: String, dialect: Dialect) -> Self {
+        let (diagnostics, resolution) = match parser::parse_dialect(text.as_bytes(), dialect) {
+            Ok(program) => {
+                let mut heap = Heap::new();
+                let diagnostics = compiler::compile(&program, &mut heap, &()).err();
+                (diagnostics.unwrap_or_default(), resolver::resolve(&program))
+            }
+            Err(diagnostics) => (diagnostics, Resolution::default()),
+        };
+        Document {
+            lines: LineIndex::new(&text),
+            tree: cst::parse(text.as_bytes(), dialect),
+            text,
+            dialect,
+            diagnostics,
+            resolution,
+        }
+    }
+
+    fn range(&self, loc: Loc) -> Json {
+        self.lines.range(&self.text, loc)
+    }
+
+    fn location(&self, uri: &str, loc: Loc) -> Json {
+        Json::object([("uri", uri.into()), ("range", self.range(loc))])
+    }
+
+    fn diagnostics(&self) -> Json {
+        let diagnostics = self
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                // Some compile errors only know their line
+                let loc = match diagnostic.loc {
+                    Loc { start: 0, end: 0 } => self.lines.line_loc(&self.text, diagnostic.line),
+                    loc => loc,
+                };
+                Json::object([
+                    ("range", self.range(loc)),
+                    ("severity", 1.0.into()),
+                    ("source", "lox".into()),
+                    ("message", diagnostic.message.as_str().into()),
+                ])
+            })
+            .collect::<Vec<_>>();
+        diagnostics.into()
+    }
+
+    /// Definition under the cursor at `position`.
+    fn definition_at(&self, position: &Json) -> Option<usize> {
+        let offset = self.lines.offset(&self.text, position)?;
+        self.resolution.definition_at(offset).or_else(|| {
+            // The cursor may sit right after the name
+            offset
+                .checked_sub(1)
+                .and_then(|offset| self.resolution.definition_at(offset))
+        })
+    }
+
+    fn hover(&self, position: &Json) -> Option<Json> {
+        let id = self.definition_at(position)?;
+        let definition = &self.resolution.definitions[id];
+        let signature = match definition.kind {
+            SymbolKind::Function => format!("(function) {}", definition.name),
+            SymbolKind::Class => format!("(class) {}", definition.name),
+            kind => {
+                let value = match (definition.value, definition.class) {
+                    (ValueKind::Instance, Some(class)) => {
+                        format!("instance of {}", self.resolution.definitions[class].name)
+                    }
+                    (value, _) => value.to_string(),
+                };
+                let kind = match kind {
+                    SymbolKind::Parameter => "parameter",
+                    _ => "variable",
+                };
+                format!("({}) {}: {}", kind, definition.name, value)
+            }
+        };
+        Some(Json::object([(
+            "contents",
+            Json::object([
+                ("kind", "markdown".into()),
+                ("value", format!("```lox\n{}\n```", signature).into()),
+            ]),
+        )]))
+    }
+
+    fn semantic_tokens(&self) -> Json {
+        let mut data = Vec::new();
+        let mut previous = (0, 0);
+        let mut push = |loc: Loc, kind: usize| {
+            // Tokens may not span lines, split multi-line strings and the like
+            let mut start = loc.start;
+            while start < loc.end {
+                let bytes = self.text.as_bytes();
+                let end = bytes[start..loc.end]
+                    .iter()
+                    .position(|&c| c == b'\n')
+                    .map_or(loc.end, |i| start + i);
+                if end > start {
+                    let line = self.lines.starts.partition_point(|&s| s <= start) - 1;
+                    let character = utf16_len(&bytes[self.lines.starts[line]..start]);
+                    let length = utf16_len(&bytes[start..end]);
+                    let delta_start = if line == previous.0 {
+                        character - previous.1
+                    } else {
+                        character
+                    };
+                    data.extend([line - previous.0, delta_start, length, kind, 0].map(Json::from));
+                    previous = (line, character);
+                }
+                start = end + 1;
+            }
+        };
+
+        let tokens = cst::tokenize(self.text.as_bytes(), self.dialect);
+        let mut after_dot = false;
+        for token in &tokens {
+            let trivia = token.leading.iter().chain(&token.trailing);
+            let comments: Vec<Loc> = trivia
+                .filter(|trivia| trivia.kind == TriviaKind::Comment)
+                .map(|trivia| trivia.loc)
+                .collect();
+            for &comment in comments.iter().filter(|c| c.end <= token.token.loc.start) {
+                push(comment, 9);
+            }
+
+            let tag = token.tag();
+            let kind = match tag {
+                _ if (Tag::KeywordAnd as u8..=Tag::KeywordExtra as u8).contains(&(tag as u8)) => {
+                    Some(0)
+                }
+                Tag::Identifier if after_dot => Some(5),
+                Tag::Identifier => Some(
+                    match self
+                        .resolution
+                        .definition_at(token.token.loc.start)
+                        .map(|id| self.resolution.definitions[id].kind)
+                    {
+                        Some(SymbolKind::Parameter) => 2,
+                        Some(SymbolKind::Function) => 3,
+                        Some(SymbolKind::Class) => 4,
+                        _ => 1,
+                    },
+                ),
+                Tag::String => Some(6),
+                Tag::Number => Some(7),
+                Tag::Minus
+                | Tag::Plus
+                | Tag::Slash
+                | Tag::Star
+                | Tag::Bang
+                | Tag::BangEqual
+                | Tag::Equal
+                | Tag::EqualEqual
+                | Tag::Greater
+                | Tag::GreaterEqual
+                | Tag::Less
+                | Tag::LessEqual
+                | Tag::PlusEqual
+                | Tag::MinusEqual
+                | Tag::StarEqual
+                | Tag::SlashEqual
+                | Tag::Percent
+                | Tag::Arrow
+                | Tag::AmpAmp
+                | Tag::PipePipe => Some(8),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                push(token.token.loc, kind);
+            }
+            after_dot = tag == Tag::Dot;
+
+            for &comment in comments.iter().filter(|c| c.start >= token.token.loc.end) {
+                push(comment, 9);
+            }
+        }
+        Json::object([("data", data.into())])
+    }
+
+    fn symbols(&self, node: &Node) -> Vec<Json> {
+        let mut symbols = Vec::new();
+        for child in node.nodes() {
+            let symbol = match child.kind {
+                SyntaxKind::ClassDecl => {
+                    let methods = child
+                        .nodes()
+                        .filter_map(|method| {
+                            self.symbol(method, method, SYMBOL_METHOD, self.symbols(method))
+                        })
+                        .collect();
+                    self.symbol(child, child, SYMBOL_CLASS, methods)
+                }
+                SyntaxKind::FunDecl => child.nodes().next().and_then(|function| {
+                    self.symbol(child, function, SYMBOL_FUNCTION, self.symbols(function))
+                }),
+                _ => {
+                    symbols.extend(self.symbols(child));
+                    None
+                }
+            };
+            symbols.extend(symbol);
+        }
+        symbols
+    }
+
+    /// Symbol for a declaration spanning `node` and named by the first identifier in `named`.
+    fn symbol(&self, node: &Node, named: &Node, kind: usize, children: Vec<Json>) -> Option<Json> {
+        let name = named
+            .tokens()
+            .find(|token| token.tag() == Tag::Identifier)?;
+        let range = Loc {
+            start: node.first_token()?.token.loc.start,
+            end: node.last_token()?.token.loc.end,
+        };
+        Some(Json::object([
+            (
+                "name",
+                self.text[name.token.loc.start..name.token.loc.end].into(),
+            ),
+            ("kind", kind.into()),
+            ("range", self.range(range)),
+            ("selectionRange", self.range(name.token.loc)),
+            ("children", children.into()),
+        ]))
+    }
+}
+
+pub struct Server {
+    documents: HashMap<String, Document>,
+    dialect: Dialect,
+    shutdown: bool,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server {
+            documents: HashMap::new(),
+            dialect: Dialect::Lox,
+            shutdown: false,
+        }
+    }
+
+    /// Handles one request or notification and returns the messages to send back.
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").as_str().unwrap_or_default();
+        let params = message.get("params");
+        let id = message.get("id");
+        if id.is_null() {
+            return self.notify(method, params);
+        }
+        if self.shutdown {
+            return vec![error_response(
+                id.clone(),
+                INVALID_REQUEST,
+                "The server is shutting down.",
+            )];
+        }
+
+        let uri = params
+            .at(&["textDocument", "uri"])
+            .as_str()
+            .unwrap_or_default();
+        let document = self.documents.get(uri);
+        let position = params.get("position");
+        let result = match method {
+            "initialize" => {
+                if params.at(&["initializationOptions", "dialect"]).as_str() == Some("lox+") {
+                    self.dialect = Dialect::LoxPlus;
+                }
+                self.capabilities()
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "textDocument/semanticTokens/full" => {
+                document.map_or(Json::Null, Document::semantic_tokens)
+            }
+            "textDocument/definition" => document
+                .and_then(|document| {
+                    let id = document.definition_at(position)?;
+                    let loc = document.resolution.definitions[id].loc;
+                    Some(document.location(uri, loc))
+                })
+                .unwrap_or(Json::Null),
+            "textDocument/references" => document
+                .and_then(|document| {
+                    let id = document.definition_at(position)?;
+                    let mut locs = Vec::new();
+                    if params.at(&["context", "includeDeclaration"]).as_bool() == Some(true) {
+                        locs.push(document.resolution.definitions[id].loc);
+                    }
+                    locs.extend(document.resolution.references_to(id));
+                    let locations = locs.into_iter().map(|loc| document.location(uri, loc));
+                    Some(locations.collect::<Vec<_>>().into())
+                })
+                .unwrap_or(Json::Null),
+            "textDocument/hover" => document
+                .and_then(|document| document.hover(position))
+                .unwrap_or(Json::Null),
+            "textDocument/documentSymbol" => document
+                .map(|document| document.symbols(&document.tree).into())
+                .unwrap_or(Json::Null),
+            _ => {
+                let message = format!("Unknown method '{}'.", method);
+                return vec![error_response(id.clone(), METHOD_NOT_FOUND, &message)];
+            }
+        };
+        vec![response(id.clone(), result)]
+    }
+
+    fn notify(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let Some(uri) = params.at(&["textDocument", "uri"]).as_str() else {
+            return Vec::new();
+        };
+        let text = match method {
+            "textDocument/didOpen" => params.at(&["textDocument", "text"]).as_str(),
+            // Full sync, the last change holds the whole text
+            "textDocument/didChange" => params
+                .get("contentChanges")
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change.get("text").as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                let params = Json::object([
+                    ("uri", uri.into()),
+                    ("diagnostics", Json::Array(Vec::new())),
+                ]);
+                return vec![notification("textDocument/publishDiagnostics", params)];
+            }
+            _ => None,
+        };
+        let Some(text) = text else {
+            return Vec::new();
+        };
+
+        let document = Document::new(text.to_string(), self.dialect);
+        let params = Json::object([("uri", uri.into()), ("diagnostics", document.diagnostics())]);
+        self.documents.insert(uri.to_string(), document);
+        vec![notification("textDocument/publishDiagnostics", params)]
+    }
+
+    fn capabilities(&self) -> Json {
+        let token_types = TOKEN_TYPES.map(Json::from).to_vec();
+        Json::object([
+            (
+                "capabilities",
+                Json::object([
+                    ("textDocumentSync", 1.0.into()),
+                    (
+                        "semanticTokensProvider",
+                        Json::object([
+                            (
+                                "legend",
+                                Json::object([
+                                    ("tokenTypes", token_types.into()),
+                                    ("tokenModifiers", Json::Array(Vec::new())),
+                                ]),
+                            ),
+                            ("full", true.into()),
+                        ]),
+                    ),
+                    ("definitionProvider", true.into()),
+                    ("referencesProvider", true.into()),
+                    ("hoverProvider", true.into()),
+                    ("documentSymbolProvider", true.into()),
+                ]),
+            ),
+            (
+                "serverInfo",
+                Json::object([("name", "lox-lsp".into()), ("version", "0.0.1".into())]),
+            ),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays a client: frames `messages`, runs the server over them and returns the decoded
+    /// replies.
+    fn session(messages: &[Json]) -> (bool, Vec<Json>) {
+        let mut input = Vec::new();
+        for message in messages {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut output = Vec::new();
+        let clean = run(&input[..], &mut output).unwrap();
+
+        let mut replies = Vec::new();
+        let mut reader = &output[..];
+        while let Some(body) = read_message(&mut reader).unwrap() {
+            replies.push(Json::parse(&body).unwrap());
+        }
+        (clean, replies)
+    }
+
+    fn request(id: usize, method: &str, params: Json) -> Json {
+        Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id.into()),
+            ("method", method.into()),
+            ("params", params),
+        ])
+    }
+
+    fn at(line: usize, character: usize) -> Json {
+        Json::object([
+            (
+                "textDocument",
+                Json::object([("uri", "file:///a.lox".into())]),
+            ),
+            (
+                "position",
+                Json::object([("line", line.into()), ("character", character.into())]),
+            ),
+            (
+                "context",
+                Json::object([("includeDeclaration", true.into())]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let uri = "file:///a.lox";
+        let text = "class Point {\n  init(x) { this.x = x; }\n}\nfun twice(n) { return n * 2; }\nvar p = Point(1); // origin\nprint twice(3) + twice(4);\n";
+        let document = |text: &str| {
+            Json::object([(
+                "textDocument",
+                Json::object([("uri", uri.into()), ("text", text.into())]),
+            )])
+        };
+        let change = Json::object([
+            ("textDocument", Json::object([("uri", uri.into())])),
+            (
+                "contentChanges",
+                vec![Json::object([("text", "print 1 +;".into())])].into(),
+            ),
+        ]);
+
+        let (clean, replies) = session(&[
+            request(1, "initialize", Json::object([])),
+            notification("initialized", Json::object([])),
+            notification("textDocument/didOpen", document(text)),
+            request(2, "textDocument/definition", at(5, 18)),
+            request(3, "textDocument/references", at(3, 5)),
+            request(4, "textDocument/hover", at(4, 4)),
+            request(5, "textDocument/documentSymbol", at(0, 0)),
+            request(6, "textDocument/semanticTokens/full", at(0, 0)),
+            notification("textDocument/didChange", change),
+            request(7, "textDocument/hover", at(0, 0)),
+            request(8, "nonsense", Json::Null),
+            request(9, "shutdown", Json::Null),
+            notification("exit", Json::Null),
+        ]);
+        assert!(clean);
+
+        let reply = |id: usize| {
+            replies
+                .iter()
+                .find(|reply| reply.get("id").as_usize() == Some(id))
+                .unwrap()
+        };
+        let diagnostics: Vec<&Json> = replies
+            .iter()
+            .filter(|reply| reply.get("method").as_str() == Some("textDocument/publishDiagnostics"))
+            .map(|reply| reply.at(&["params", "diagnostics"]))
+            .collect();
+
+        assert_eq!(
+            reply(1).at(&["result", "capabilities", "hoverProvider"]),
+            &Json::Bool(true)
+        );
+        assert_eq!(diagnostics[0], &Json::Array(Vec::new()));
+
+        // `twice` in `twice(4)` goes to its declaration on line 3
+        assert_eq!(
+            reply(2).at(&["result", "range", "start"]).to_string(),
+            "{\"line\":3,\"character\":4}"
+        );
+        assert_eq!(reply(3).get("result").as_array().unwrap().len(), 3);
+        assert_eq!(
+            reply(4).at(&["result", "contents", "value"]).as_str(),
+            Some("```lox\n(variable) p: instance of Point\n```")
+        );
+
+        let symbols = reply(5).get("result").as_array().unwrap();
+        let names: Vec<&str> = symbols
+            .iter()
+            .map(|symbol| symbol.get("name").as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["Point", "twice"]);
+        assert_eq!(
+            symbols[0].get("children").as_array().unwrap()[0]
+                .get("name")
+                .as_str(),
+            Some("init")
+        );
+
+        // `class` keyword, then `Point` as a class
+        let data = reply(6).at(&["result", "data"]).as_array().unwrap();
+        let first: Vec<usize> = data[..10].iter().map(|n| n.as_usize().unwrap()).collect();
+        assert_eq!(first, [0, 0, 5, 0, 0, 0, 6, 5, 4, 0]);
+
+        // The broken edit is reported, navigation keeps using the last good version
+        assert_eq!(
+            diagnostics[1].as_array().unwrap()[0]
+                .get("message")
+                .as_str(),
+            Some("Expect expression.")
+        );
+        assert_eq!(reply(8).at(&["error", "code"]).as_f64(), Some(-32601.0));
+    }
+
+    #[test]
+    fn test_oversized_message() {
+        let framed = format!("Content-Length: {}\r\n\r\n{{}}", MAX_MESSAGE_LEN + 1);
+        let error = read_message(&mut framed.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let framed = "Content-Length: 2\r\n\r\n{}";
+        assert_eq!(
+            read_message(&mut framed.as_bytes()).unwrap().as_deref(),
+            Some("{}")
+        );
+        // Nesting too deep to parse is a parse error, not a crash
+        let body = "[".repeat(200_000);
+        let framed = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let mut output = Vec::new();
+        run(framed.as_bytes(), &mut output).unwrap();
+        let reply = read_message(&mut &output[..]).unwrap().unwrap();
+        let reply = Json::parse(&reply).unwrap();
+        assert_eq!(
+            reply.at(&["error", "code"]).as_f64(),
+            Some(PARSE_ERROR as f64)
+        );
+    }
+}
@@ -0,0 +1,545 @@
+use crate::gc::Heap;
+use crate::object::{Obj, ObjRef};
+use crate::value::Value;
+use crate::vm::BuiltinType;
+use std::cell::RefCell;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Conversion from a Lox value into a native argument.
+pub trait FromValue: Sized {
+    /// Described in type errors, e.g. "a number".
+    const EXPECTED: &'static str;
+
+    fn from_value(heap: &Heap, value: Value) -> Option<Self>;
+}
+
+impl FromValue for Value {
+    const EXPECTED: &'static str = "a value";
+
+    fn from_value(_heap: &Heap, value: Value) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl FromValue for f64 {
+    const EXPECTED: &'static str = "a number";
+
+    fn from_value(_heap: &Heap, value: Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for bool {
+    const EXPECTED: &'static str = "a boolean";
+
+    fn from_value(_heap: &Heap, value: Value) -> Option<Self> {
+        match value {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+/// Strings share their characters with the heap, so taking one doesn't copy and doesn't keep
+/// the heap borrowed.
+impl FromValue for Rc<str> {
+    const EXPECTED: &'static str = "a string";
+
+    fn from_value(heap: &Heap, value: Value) -> Option<Self> {
+        match heap.get(value.as_obj()?) {
+            Obj::String(s) => Some(s.chars.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for String {
+    const EXPECTED: &'static str = "a string";
+
+    fn from_value(heap: &Heap, value: Value) -> Option<Self> {
+        heap.as_string(value).map(str::to_string)
+    }
+}
+
+/// A whole number, e.g. an index or a count.
+impl FromValue for usize {
+    const EXPECTED: &'static str = "a non-negative integer";
+
+    fn from_value(_heap: &Heap, value: Value) -> Option<Self> {
+        match value {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for i64 {
+    const EXPECTED: &'static str = "an integer";
+
+    fn from_value(_heap: &Heap, value: Value) -> Option<Self> {
+        match value {
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 2f64.powi(63) => Some(n as i64),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    const EXPECTED: &'static str = "a list";
+
+    fn from_value(heap: &Heap, value: Value) -> Option<Self> {
+        match heap.get(value.as_obj()?) {
+            Obj::List(items) => items
+                .iter()
+                .map(|&item| T::from_value(heap, item))
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    const EXPECTED: &'static str = T::EXPECTED;
+
+    fn from_value(heap: &Heap, value: Value) -> Option<Self> {
+        match value {
+            Value::Nil => Some(None),
+            _ => T::from_value(heap, value).map(Some),
+        }
+    }
+}
+
+/// Conversion from a native's result into a Lox value.
+pub trait IntoValue {
+    fn into_value(self, heap: &mut Heap) -> Value;
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _heap: &mut Heap) -> Value {
+        self
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _heap: &mut Heap) -> Value {
+        Value::Nil
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _heap: &mut Heap) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl IntoValue for usize {
+    fn into_value(self, _heap: &mut Heap) -> Value {
+        Value::Number(self as f64)
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self, _heap: &mut Heap) -> Value {
+        Value::Number(self as f64)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _heap: &mut Heap) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl IntoValue for ObjRef {
+    fn into_value(self, _heap: &mut Heap) -> Value {
+        Value::Obj(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, heap: &mut Heap) -> Value {
+        Value::Obj(heap.intern(self))
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, heap: &mut Heap) -> Value {
+        Value::Obj(heap.take_string(self))
+    }
+}
+
+/// Allocates without collecting, so the elements don't need to be reachable meanwhile.
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, heap: &mut Heap) -> Value {
+        let items = self.into_iter().map(|item| item.into_value(heap)).collect();
+        Value::Obj(heap.alloc(Obj::List(items)))
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, heap: &mut Heap) -> Value {
+        match self {
+            Some(value) => value.into_value(heap),
+            None => Value::Nil,
+        }
+    }
+}
+
+/// Reads argument `index`, failing with a type error if it isn't a `T`. Missing arguments of
+/// variadic natives read as nil.
+pub fn arg<T: FromValue>(heap: &Heap, args: &[Value], index: usize) -> Result<T, String> {
+    let value = args.get(index).copied().unwrap_or(Value::Nil);
+    T::from_value(heap, value).ok_or_else(|| {
+        format!(
+            "Expected {} as argument {} but got {}.",
+            T::EXPECTED,
+            index + 1,
+            type_name(heap, value)
+        )
+    })
+}
+
+/// Fails unless a variadic native got between `min` and `max` arguments.
+pub fn check_arity(args: &[Value], min: usize, max: usize) -> Result<(), String> {
+    if args.len() < min || args.len() > max {
+        return Err(format!(
+            "Expected {} to {} arguments but got {}.",
+            min,
+            max,
+            args.len()
+        ));
+    }
+    Ok(())
+}
+
+pub fn type_name(heap: &Heap, value: Value) -> &'static str {
+    match value {
+        Value::Nil => "nil",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::Obj(r) => match heap.get(r) {
+            Obj::String(_) => "string",
+            Obj::Instance(_) => "instance",
+            Obj::Class(_) => "class",
+            Obj::List(_) => "list",
+            Obj::Map(_) => "map",
+            _ => "function",
+        },
+    }
+}
+
+/// Where a VM's `print` writes, shared with the natives that write too so they follow
+/// `set_output`.
+pub type Output = Rc<RefCell<Box<dyn Write>>>;
+
+/// A VM that natives can be installed into, so every backend gets the same ones.
+pub trait Host {
+    fn output(&self) -> Output;
+
+    fn define_native(
+        &mut self,
+        name: &'static str,
+        arity: Option<u8>,
+        function: impl Fn(&mut Heap, &[Value]) -> Result<Value, String> + 'static,
+    );
+
+    fn define_builtin_method(
+        &mut self,
+        ty: BuiltinType,
+        name: &'static str,
+        arity: Option<u8>,
+        function: impl Fn(&mut Heap, &[Value]) -> Result<Value, String> + 'static,
+    );
+}
+
+/// Natives without side effects outside the VM.
+pub fn define_core(vm: &mut impl Host) {
+    vm.define_native("len", Some(1), len);
+    vm.define_native("str", Some(1), str);
+    vm.define_native("num", Some(1), num);
+    vm.define_native("upper", Some(1), upper);
+    vm.define_native("lower", Some(1), lower);
+    vm.define_native("trim", Some(1), trim);
+    vm.define_native("substr", None, substr);
+    vm.define_native("index_of", Some(2), index_of);
+
+    vm.define_builtin_method(BuiltinType::List, "len", Some(0), len);
+    vm.define_builtin_method(BuiltinType::List, "push", Some(1), list_push);
+    vm.define_builtin_method(BuiltinType::List, "pop", Some(0), list_pop);
+    vm.define_builtin_method(BuiltinType::List, "insert", Some(2), list_insert);
+    vm.define_builtin_method(BuiltinType::List, "remove", Some(1), list_remove);
+    vm.define_builtin_method(BuiltinType::List, "contains", Some(1), list_contains);
+
+    vm.define_builtin_method(BuiltinType::Map, "len", Some(0), len);
+    vm.define_builtin_method(BuiltinType::Map, "has", Some(1), map_has);
+    vm.define_builtin_method(BuiltinType::Map, "remove", Some(1), map_remove);
+    vm.define_builtin_method(BuiltinType::Map, "keys", Some(0), map_keys);
+    vm.define_builtin_method(BuiltinType::Map, "values", Some(0), map_values);
+}
+
+/// Natives that reach the host's clock, file system and standard input.
+pub fn define_io(vm: &mut impl Host) {
+    vm.define_native("clock", Some(0), clock);
+    vm.define_native("read_file", Some(1), read_file);
+    let out = vm.output();
+    vm.define_native("input", None, move |heap, args| {
+        input(&out, &mut io::stdin().lock(), heap, args)
+    });
+}
+
+fn clock(_heap: &mut Heap, _args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+/// Length of a string in characters, or the number of list elements or map entries.
+fn len(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let len = match args[0].as_obj().map(|r| heap.get(r)) {
+        Some(Obj::String(s)) => s.chars.chars().count(),
+        Some(Obj::List(items)) => items.len(),
+        Some(Obj::Map(map)) => map.len(),
+        _ => {
+            return Err(format!(
+                "Expected a string, list or map as argument 1 but got {}.",
+                type_name(heap, args[0])
+            ))
+        }
+    };
+    Ok(len.into_value(heap))
+}
+
+fn str(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    if heap.as_string(args[0]).is_some() {
+        return Ok(args[0]);
+    }
+    Ok(heap.format_value(args[0]).into_value(heap))
+}
+
+fn num(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    if let Value::Number(_) = args[0] {
+        return Ok(args[0]);
+    }
+    let s: Rc<str> = arg(heap, args, 0)?;
+    s.trim()
+        .parse::<f64>()
+        .map(Value::Number)
+        .map_err(|_| format!("Cannot convert '{}' to a number.", s))
+}
+
+fn upper(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let s: Rc<str> = arg(heap, args, 0)?;
+    Ok(s.to_uppercase().into_value(heap))
+}
+
+fn lower(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let s: Rc<str> = arg(heap, args, 0)?;
+    Ok(s.to_lowercase().into_value(heap))
+}
+
+fn trim(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let s: Rc<str> = arg(heap, args, 0)?;
+    Ok(s.trim().into_value(heap))
+}
+
+/// `substr(s, start)` or `substr(s, start, end)`, indexed by character.
+fn substr(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    check_arity(args, 2, 3)?;
+    let s: Rc<str> = arg(heap, args, 0)?;
+    let start: usize = arg(heap, args, 1)?;
+    let end: Option<usize> = arg(heap, args, 2)?;
+
+    let count = s.chars().count();
+    let end = end.unwrap_or(count);
+    if start > end || end > count {
+        return Err(format!(
+            "Substring range {}..{} out of bounds for length {}.",
+            start, end, count
+        ));
+    }
+    let result: String = s.chars().skip(start).take(end - start).collect();
+    Ok(result.into_value(heap))
+}
+
+/// Character index of the first occurrence of `needle`, or nil.
+fn index_of(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let s: Rc<str> = arg(heap, args, 0)?;
+    let needle: Rc<str> = arg(heap, args, 1)?;
+    let index = s.find(&*needle).map(|byte| s[..byte].chars().count());
+    Ok(index.into_value(heap))
+}
+
+// List and map methods get their receiver as the first argument, the VM guarantees its type
+
+fn list_push(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let list = receiver(args);
+    let old_size = heap.get(list).size();
+    heap.list_mut(list).push(args[1]);
+    heap.resized(list, old_size);
+    Ok(Value::Nil)
+}
+
+fn list_pop(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    heap.list_mut(receiver(args))
+        .pop()
+        .ok_or_else(|| "Can't pop from an empty list.".to_string())
+}
+
+fn list_insert(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let index: usize = arg(heap, args, 1)?;
+    let list = receiver(args);
+    let old_size = heap.get(list).size();
+    let items = heap.list_mut(list);
+    if index > items.len() {
+        return Err(format!(
+            "Insert index {} out of range for length {}.",
+            index,
+            items.len()
+        ));
+    }
+    items.insert(index, args[2]);
+    heap.resized(list, old_size);
+    Ok(Value::Nil)
+}
+
+fn list_remove(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let index: usize = arg(heap, args, 1)?;
+    let items = heap.list_mut(receiver(args));
+    if index >= items.len() {
+        return Err(format!(
+            "List index {} out of range for length {}.",
+            index,
+            items.len()
+        ));
+    }
+    Ok(items.remove(index))
+}
+
+fn list_contains(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    Ok(heap.list(receiver(args)).contains(&args[1]).into())
+}
+
+fn map_has(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    Ok(heap.map(receiver(args)).get(args[1]).is_some().into())
+}
+
+fn map_remove(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    Ok(heap
+        .map_mut(receiver(args))
+        .remove(args[1])
+        .unwrap_or(Value::Nil))
+}
+
+fn map_keys(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let keys = heap
+        .map(receiver(args))
+        .iter()
+        .map(|(key, _)| key)
+        .collect();
+    Ok(Value::Obj(heap.alloc(Obj::List(keys))))
+}
+
+fn map_values(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let values = heap
+        .map(receiver(args))
+        .iter()
+        .map(|(_, value)| value)
+        .collect();
+    Ok(Value::Obj(heap.alloc(Obj::List(values))))
+}
+
+fn receiver(args: &[Value]) -> ObjRef {
+    args[0].as_obj().expect("method receiver is an object")
+}
+
+fn read_file(heap: &mut Heap, args: &[Value]) -> Result<Value, String> {
+    let path: Rc<str> = arg(heap, args, 0)?;
+    let contents = std::fs::read_to_string(&*path)
+        .map_err(|e| format!("Could not read file '{}': {}.", path, e))?;
+    Ok(contents.into_value(heap))
+}
+
+/// `input()` or `input(prompt)`: reads a line from stdin without its newline, nil at the end of
+/// input. The prompt goes to the VM's output, like `print`.
+fn input(
+    out: &Output,
+    reader: &mut dyn BufRead,
+    heap: &mut Heap,
+    args: &[Value],
+) -> Result<Value, String> {
+    check_arity(args, 0, 1)?;
+    let prompt: Option<Rc<str>> = arg(heap, args, 0)?;
+    if let Some(prompt) = prompt {
+        let mut out = out.borrow_mut();
+        write!(out, "{}", prompt)
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Failed to print: {}.", e))?;
+    }
+
+    let mut line = String::new();
+    let read = reader
+        .read_line(&mut line)
+        .map_err(|e| format!("Could not read input: {}.", e))?;
+    if read == 0 {
+        return Ok(Value::Nil);
+    }
+    let trimmed = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(trimmed);
+    Ok(line.into_value(heap))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::OutputBuffer;
+
+    #[test]
+    fn test_string_natives_and_type_errors() {
+        let mut heap = Heap::new();
+        let s = "héllo wörld".into_value(&mut heap);
+
+        assert_eq!(len(&mut heap, &[s]), Ok(Value::Number(11.0)));
+        let sub = substr(&mut heap, &[s, Value::Number(1.0), Value::Number(4.0)]).unwrap();
+        assert_eq!(heap.as_string(sub), Some("éll"));
+        let needle = "wö".into_value(&mut heap);
+        assert_eq!(index_of(&mut heap, &[s, needle]), Ok(Value::Number(6.0)));
+
+        let n = " 2.5 ".into_value(&mut heap);
+        assert_eq!(num(&mut heap, &[n]), Ok(Value::Number(2.5)));
+        let printed = str(&mut heap, &[Value::Number(3.0)]).unwrap();
+        assert_eq!(heap.as_string(printed), Some("3"));
+
+        assert_eq!(
+            len(&mut heap, &[Value::Bool(true)]),
+            Err("Expected a string, list or map as argument 1 but got boolean.".to_string())
+        );
+        assert_eq!(
+            substr(&mut heap, &[s, Value::Number(-1.0)]),
+            Err("Expected a non-negative integer as argument 2 but got number.".to_string())
+        );
+        assert_eq!(
+            substr(&mut heap, &[s]),
+            Err("Expected 2 to 3 arguments but got 1.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_input_prompts_on_vm_output() {
+        let mut heap = Heap::new();
+        let printed = OutputBuffer::new();
+        let out: Output = Rc::new(RefCell::new(Box::new(printed.clone())));
+        let prompt = "name? ".into_value(&mut heap);
+
+        let line = input(&out, &mut &b"Ada\r\nBob\n"[..], &mut heap, &[prompt]).unwrap();
+        assert_eq!(heap.as_string(line), Some("Ada"));
+        assert_eq!(printed.take(), "name? ");
+        assert_eq!(input(&out, &mut &b""[..], &mut heap, &[]), Ok(Value::Nil));
+        assert_eq!(printed.take(), "");
+    }
+}
@@ -0,0 +1,184 @@
+use crate::chunk::Chunk;
+use crate::gc::Heap;
+use crate::register::RegisterChunk;
+use crate::table::Table;
+use crate::value::Value;
+use std::any::Any;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+/// Handle to an object living in the [`Heap`]. Two handles are equal only if they point at the
+/// same object, which for interned strings means equal contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjRef(pub(crate) u32);
+
+impl ObjRef {
+    #[inline(always)]
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Host function callable from Lox. Errors become runtime errors at the call site.
+pub type NativeFn = Rc<dyn Fn(&mut Heap, &[Value]) -> Result<Value, String>>;
+
+pub enum Obj {
+    String(ObjString),
+    Function(ObjFunction),
+    Native(ObjNative),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    List(Vec<Value>),
+    Map(ObjMap),
+}
+
+pub struct ObjString {
+    pub chars: Rc<str>,
+    pub hash: u32,
+}
+
+pub struct ObjFunction {
+    pub name: Option<ObjRef>,
+    pub arity: u8,
+    pub upvalue_count: usize,
+    pub chunk: Rc<Chunk>,
+    /// Module whose globals the function uses, 0 for the main script.
+    pub module: usize,
+    /// Code for the register VM. Its constants live in `chunk`, whose code is then empty.
+    pub registers: Option<Rc<RegisterChunk>>,
+}
+
+pub struct ObjNative {
+    pub name: &'static str,
+    /// Expected argument count, `None` for variadic natives.
+    pub arity: Option<u8>,
+    pub function: NativeFn,
+}
+
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+pub enum ObjUpvalue {
+    /// Still points into the VM stack at the given slot.
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct ObjClass {
+    pub name: ObjRef,
+    pub methods: Table,
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: Table,
+    /// Rust value of a host object, a `RefCell` of the type its class was defined for.
+    pub host: Option<Rc<dyn Any>>,
+}
+
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+/// Value used as a map key. Objects compare by identity, which for interned strings means by
+/// contents, and numbers by value. NaN is rejected before it gets here so equality is total.
+#[derive(Debug, Clone, Copy)]
+pub struct MapKey(pub Value);
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.0 {
+            Value::Nil => 0u8.hash(state),
+            Value::Bool(b) => b.hash(state),
+            // 0.0 and -0.0 are equal, so they must hash alike
+            Value::Number(n) => (if n == 0.0 { 0.0f64 } else { n }).to_bits().hash(state),
+            Value::Obj(r) => r.hash(state),
+        }
+    }
+}
+
+/// Map that iterates in insertion order. Removal swaps the last entry into the hole.
+#[derive(Debug, Default)]
+pub struct ObjMap {
+    entries: Vec<(Value, Value)>,
+    index: HashMap<MapKey, usize>,
+}
+
+impl ObjMap {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: Value) -> Option<Value> {
+        self.index
+            .get(&MapKey(key))
+            .map(|&index| self.entries[index].1)
+    }
+
+    pub fn insert(&mut self, key: Value, value: Value) {
+        match self.index.get(&MapKey(key)) {
+            Some(&index) => self.entries[index].1 = value,
+            None => {
+                self.index.insert(MapKey(key), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: Value) -> Option<Value> {
+        let index = self.index.remove(&MapKey(key))?;
+        let (_, value) = self.entries.swap_remove(index);
+        if let Some(&(moved, _)) = self.entries.get(index) {
+            self.index.insert(MapKey(moved), index);
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Value, Value)> + '_ {
+        self.entries.iter().copied()
+    }
+}
+
+impl Obj {
+    /// Approximate number of bytes owned by this object, used to pace the collector.
+    pub fn size(&self) -> usize {
+        let payload = match self {
+            Obj::String(s) => s.chars.len(),
+            Obj::Function(f) => {
+                f.chunk.code.len()
+                    + f.chunk.lines.len() * std::mem::size_of::<u32>()
+                    + f.registers.as_ref().map_or(0, |r| r.size())
+                    + f.chunk.constants.len() * std::mem::size_of::<Value>()
+            }
+            Obj::Native(_) | Obj::Upvalue(_) | Obj::BoundMethod(_) => 0,
+            Obj::Closure(c) => c.upvalues.len() * std::mem::size_of::<ObjRef>(),
+            Obj::Class(c) => c.methods.capacity() * std::mem::size_of::<(u32, Value)>(),
+            Obj::Instance(i) => i.fields.capacity() * std::mem::size_of::<(u32, Value)>(),
+            Obj::List(items) => items.capacity() * std::mem::size_of::<Value>(),
+            Obj::Map(map) => {
+                map.entries.capacity() * std::mem::size_of::<(Value, Value)>()
+                    + map.index.capacity() * std::mem::size_of::<(MapKey, usize)>()
+            }
+        };
+        std::mem::size_of::<Obj>() + payload
+    }
+}
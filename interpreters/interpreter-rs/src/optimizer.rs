@@ -0,0 +1,202 @@
+use crate::ast::*;
+use crate::chunk::{Chunk, OpCode};
+use crate::gc::Heap;
+use crate::value::Value;
+use std::cmp::Ordering;
+
+/// The literal `expr` evaluates to when it only combines literals. Operations that would fail
+/// at runtime, like negating a string, are left for the VM to report.
+pub fn fold(expr: &Expr) -> Option<Literal> {
+    match &expr.kind {
+        ExprKind::Literal(literal) => Some(literal.clone()),
+        ExprKind::Grouping(inner) => fold(inner),
+        ExprKind::Unary(op, right) => match (op, fold(right)?) {
+            (UnaryOp::Negate, Literal::Number(n)) => Some(Literal::Number(-n)),
+            (UnaryOp::Negate, _) => None,
+            (UnaryOp::Not, right) => Some(Literal::Bool(!is_truthy(&right))),
+        },
+        ExprKind::Binary(left, op, right) => binary(fold(left)?, *op, fold(right)?),
+        // Both sides must fold, so an operand skipped at runtime is still compiled and checked
+        ExprKind::Logical(left, op, right) => {
+            let (left, right) = (fold(left)?, fold(right)?);
+            match (op, is_truthy(&left)) {
+                (LogicalOp::And, false) | (LogicalOp::Or, true) => Some(left),
+                _ => Some(right),
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn is_truthy(literal: &Literal) -> bool {
+    !matches!(literal, Literal::Nil | Literal::Bool(false))
+}
+
+fn binary(left: Literal, op: BinaryOp, right: Literal) -> Option<Literal> {
+    use Literal::{Bool, Number};
+    Some(match (left, op, right) {
+        (Number(a), BinaryOp::Add, Number(b)) => Number(a + b),
+        (Literal::String(a), BinaryOp::Add, Literal::String(b)) => {
+            Literal::String(format!("{}{}", a, b).into())
+        }
+        (Number(a), BinaryOp::Subtract, Number(b)) => Number(a - b),
+        (Number(a), BinaryOp::Multiply, Number(b)) => Number(a * b),
+        (Number(a), BinaryOp::Divide, Number(b)) => Number(a / b),
+        (Number(a), BinaryOp::Greater, Number(b)) => Bool(a > b),
+        // Compiled as `!(a < b)`, which differs from `a >= b` for NaN
+        (Number(a), BinaryOp::GreaterEqual, Number(b)) => {
+            Bool(a.partial_cmp(&b) != Some(Ordering::Less))
+        }
+        (Number(a), BinaryOp::Less, Number(b)) => Bool(a < b),
+        (Number(a), BinaryOp::LessEqual, Number(b)) => {
+            Bool(a.partial_cmp(&b) != Some(Ordering::Greater))
+        }
+        // Strings are interned, so the VM compares them by content too
+        (a, BinaryOp::Equal, b) => Bool(a == b),
+        (a, BinaryOp::NotEqual, b) => Bool(a != b),
+        _ => return None,
+    })
+}
+
+/// Points every jump that lands on an unconditional jump straight at its final target. A
+/// `JumpIfFalse` landing on another `JumpIfFalse` follows it too, since the tested value is
+/// still on the stack, and a `Jump` landing on a `Loop` becomes that `Loop`.
+pub fn thread_jumps(chunk: &mut Chunk, heap: &Heap) {
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::from_byte(chunk.code[offset]).unwrap();
+        if matches!(op, OpCode::Jump | OpCode::JumpIfFalse) {
+            thread_jump(chunk, offset, op);
+        }
+        offset += instruction_len(chunk, heap, offset);
+    }
+}
+
+fn thread_jump(chunk: &mut Chunk, offset: usize, op: OpCode) {
+    let after = offset + 3;
+    let mut target = jump_target(chunk, offset);
+    let mut changed = false;
+    // Forward jumps only move further ahead, so this ends
+    loop {
+        match OpCode::from_byte(chunk.code[target]) {
+            Some(OpCode::Jump) => {}
+            Some(OpCode::JumpIfFalse) if op == OpCode::JumpIfFalse => {}
+            Some(OpCode::Loop) if op == OpCode::Jump => {
+                let distance = after - jump_target(chunk, target);
+                if distance <= u16::MAX as usize {
+                    chunk.code[offset] = OpCode::Loop as u8;
+                    write_u16(chunk, offset + 1, distance as u16);
+                    return;
+                }
+                break;
+            }
+            _ => break,
+        }
+        let next = jump_target(chunk, target);
+        if next - after > u16::MAX as usize {
+            break;
+        }
+        target = next;
+        changed = true;
+    }
+    if changed {
+        write_u16(chunk, offset + 1, (target - after) as u16);
+    }
+}
+
+fn jump_target(chunk: &Chunk, offset: usize) -> usize {
+    let distance = chunk.read_u16(offset + 1) as usize;
+    match OpCode::from_byte(chunk.code[offset]) {
+        Some(OpCode::Loop) => offset + 3 - distance,
+        _ => offset + 3 + distance,
+    }
+}
+
+fn write_u16(chunk: &mut Chunk, offset: usize, value: u16) {
+    chunk.code[offset] = (value >> 8) as u8;
+    chunk.code[offset + 1] = value as u8;
+}
+
+/// Length in bytes of the instruction at `offset`, operands included.
+fn instruction_len(chunk: &Chunk, heap: &Heap, offset: usize) -> usize {
+    let op = OpCode::from_byte(chunk.code[offset]).unwrap();
+    let len = 1 + op.operand_len();
+    if op != OpCode::Closure {
+        return len;
+    }
+    let constant = chunk.constants[chunk.read_u16(offset + 1) as usize];
+    let Value::Obj(function) = constant else {
+        unreachable!("closure operand is not a function");
+    };
+    len + 2 * heap.function(function).upvalue_count
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler;
+    use crate::disassembler;
+    use crate::embed::OutputBuffer;
+    use crate::gc::Heap;
+    use crate::parser::{self, Dialect};
+    use crate::vm::Vm;
+
+    fn run(source: &str, optimize: bool) -> String {
+        let output = OutputBuffer::new();
+        let mut vm = Vm::new();
+        vm.set_output(Box::new(output.clone()));
+        vm.set_optimize(optimize);
+        let result = vm.interpret(source.as_bytes());
+        let mut text = output.contents();
+        if let Err(e) = result {
+            text.push_str(&e.to_string());
+        }
+        text
+    }
+
+    fn disassemble(source: &str, optimize: bool) -> String {
+        let program = parser::parse_dialect(source.as_bytes(), Dialect::Lox).unwrap();
+        let mut heap = Heap::new();
+        let script = compiler::compile_with(&program, &mut heap, &(), optimize).unwrap();
+        disassembler::disassemble(&heap, script)
+    }
+
+    const PROGRAMS: [&str; 8] = [
+        "print 1 + 2 * 3 - 4 / 8; print -(2 + 3); print !nil; print \"a\" + \"b\" + \"c\";",
+        "print 1 < 2 == true; print 3 >= 3; print \"x\" == \"x\"; print 1 == \"1\"; print 0/0 == 0/0;",
+        "if (false) print \"no\"; else print \"yes\"; if (1 > 2) print \"no\"; if (true) print \"t\";",
+        "fun f() { var i = 0; while (false) print i; while (true) { i = i + 1; if (i > 3) return i; } } print f();",
+        "fun f(x) { if (x) { return \"a\"; print \"dead\"; } else { return \"b\"; } } print f(1); print f(nil);",
+        "var i = 0; while (i < 5) { if (i == 2) print \"two\"; else if (i == 3) print \"three\"; else print i; i = i + 1; }",
+        "var a = nil; var b = 2; print a and b and 3; print a or b or 3; print true and b; print false or \"x\";",
+        "print 1 + 2; print -\"str\";",
+    ];
+
+    #[test]
+    fn test_optimized_output_matches_unoptimized() {
+        for source in PROGRAMS {
+            assert_eq!(run(source, true), run(source, false), "{}", source);
+        }
+        // Dead code is still checked
+        let dead = "fun f() { return; this.x; }";
+        assert_eq!(run(dead, true), run(dead, false));
+        assert!(run(dead, true).contains("Can't use 'this' outside of a class."));
+        for skipped in ["{ var b = false and b; }", "{ var b = true or b; }"] {
+            assert_eq!(run(skipped, true), run(skipped, false), "{}", skipped);
+            assert!(
+                run(skipped, true).contains("Can't read local variable in its own initializer.")
+            );
+        }
+
+        let source =
+            "if (false) { print 1; print 2; } print 2 * 3 + 4; fun g() { return 1; print 2; }";
+        let count = |text: String| text.lines().count();
+        assert!(count(disassemble(source, true)) < count(disassemble(source, false)));
+
+        // The jump over the else branch goes straight back to the loop condition
+        let source = "var i = 0; while (i < 3) { i = i + 1; if (i == 2) print i; else print -i; }";
+        let loops = |text: String| text.matches("Loop").count();
+        assert_eq!(loops(disassemble(source, false)), 1);
+        assert_eq!(loops(disassemble(source, true)), 2);
+        assert_eq!(run(source, true), run(source, false));
+    }
+}
@@ -0,0 +1,1086 @@
+use crate::ast::*;
+use crate::diagnostic::Diagnostic;
+use lexer::config::LexerConfig;
+use lexer::interner::{Interner, Symbol};
+use lexer::with_opt_iterator::{keyword_interner, Loc, Tag, Token};
+use std::rc::Rc;
+
+const MAX_ARGS: usize = 255;
+
+/// Default limit on how deeply statements and expressions nest, shallow enough that neither
+/// parsing nor the passes walking the tree overflow the stack.
+pub const MAX_DEPTH: usize = 128;
+
+/// Language accepted by the parser.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// Lox as defined by Crafting Interpreters.
+    #[default]
+    Lox,
+    /// Lox with extensions: list and map literals, indexing and exceptions.
+    LoxPlus,
+}
+
+impl Dialect {
+    /// The lexer for this dialect, which makes the Lox+ keywords names in plain Lox.
+    pub fn lexer_config(self) -> LexerConfig {
+        match self {
+            Dialect::Lox => LexerConfig::lox(),
+            Dialect::LoxPlus => LexerConfig::lox_plus(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lexeme {
+    pub token: Token,
+    /// Line the token starts on.
+    pub line: u32,
+    /// Interned identifier or string literal contents.
+    pub symbol: Option<Symbol>,
+}
+
+/// Tokenizes the whole buffer up front.
+pub fn tokenize(source: &[u8], config: &LexerConfig, interner: &mut Interner) -> Vec<Lexeme> {
+    let mut tokenizer = config.tokenizer(source);
+    let mut tokens = Vec::new();
+
+    while let Some((token, symbol)) = tokenizer.next_token_interned(interner) {
+        // Only strings can span lines, everything else ends on the line it starts on
+        let mut line = tokenizer.line();
+        if token.tag == Tag::String {
+            line -= source[token.loc.start..token.loc.end]
+                .iter()
+                .filter(|&&c| c == b'\n')
+                .count();
+        }
+
+        let eof = token.tag == Tag::Eof;
+        tokens.push(Lexeme {
+            token,
+            line: line as u32,
+            symbol,
+        });
+        if eof {
+            break;
+        }
+    }
+
+    tokens
+}
+
+pub fn parse(source: &[u8]) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+    Parser::new(source).parse()
+}
+
+pub fn parse_dialect(source: &[u8], dialect: Dialect) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+    Parser::new(source).with_dialect(dialect).parse()
+}
+
+pub struct Parser<'a> {
+    source: &'a [u8],
+    /// Tokenized when parsing starts, once the dialect is known.
+    tokens: Vec<Lexeme>,
+    current: usize,
+    errors: Vec<Diagnostic>,
+    dialect: Dialect,
+    interner: Interner,
+    /// Shared text for every identifier symbol, so repeated names don't allocate.
+    names: Vec<Option<Rc<str>>>,
+    /// Nesting of the tree being built around the current token.
+    depth: usize,
+    max_depth: usize,
+}
+
+type ParseResult<T> = Result<T, Diagnostic>;
+
+impl<'a> Parser<'a> {
+    pub fn new(source: &'a [u8]) -> Self {
+        Parser {
+            source,
+            tokens: Vec::new(),
+            current: 0,
+            errors: Vec::new(),
+            dialect: Dialect::Lox,
+            interner: keyword_interner(),
+            names: Vec::new(),
+            depth: 0,
+            max_depth: MAX_DEPTH,
+        }
+    }
+
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Fails with "Too much nesting." past `max_depth` levels instead of [`MAX_DEPTH`].
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn parse(mut self) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+        let config = self.dialect.lexer_config();
+        self.tokens = tokenize(self.source, &config, &mut self.interner);
+        let mut statements = Vec::new();
+        while !self.is_at_end() {
+            match self.top_level_declaration() {
+                Ok(stmt) => statements.push(stmt),
+                Err(error) => {
+                    self.errors.push(error);
+                    self.synchronize();
+                }
+            }
+        }
+
+        if self.errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    /// A declaration, or an import or export, which are only allowed outside any block.
+    fn top_level_declaration(&mut self) -> ParseResult<Stmt> {
+        if self.match_tag(Tag::KeywordImport) {
+            let line = self.previous_line();
+            let path = self.consume(Tag::String, "Expect module path string after 'import'.")?;
+            let text = self.lexeme(&path.token);
+            let path: Rc<str> = text[1..text.len() - 1].into();
+            self.consume(Tag::Semicolon, "Expect ';' after import.")?;
+            Ok(Stmt {
+                kind: StmtKind::Import(path),
+                line,
+            })
+        } else if self.match_tag(Tag::KeywordExport) {
+            let line = self.previous_line();
+            if !matches!(
+                self.peek().tag,
+                Tag::KeywordVar | Tag::KeywordFun | Tag::KeywordClass
+            ) {
+                return Err(self.error_at_current("Expect declaration after 'export'."));
+            }
+            let declaration = self.declaration()?;
+            Ok(Stmt {
+                kind: StmtKind::Export(Box::new(declaration)),
+                line,
+            })
+        } else {
+            self.declaration()
+        }
+    }
+
+    fn declaration(&mut self) -> ParseResult<Stmt> {
+        if self.check(Tag::KeywordImport) || self.check(Tag::KeywordExport) {
+            // Reported, but parsed as usual so the rest of the block still makes sense
+            let error = match self.peek().tag {
+                Tag::KeywordImport => self.error_at_current("Can only import at the top level."),
+                _ => self.error_at_current("Can only export at the top level."),
+            };
+            self.errors.push(error);
+            self.top_level_declaration()
+        } else if self.match_tag(Tag::KeywordClass) {
+            self.class_declaration()
+        } else if self.match_tag(Tag::KeywordFun) {
+            let line = self.previous_line();
+            let function = self.function("function")?;
+            Ok(Stmt {
+                kind: StmtKind::Function(Rc::new(function)),
+                line,
+            })
+        } else if self.match_tag(Tag::KeywordVar) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        }
+    }
+
+    fn class_declaration(&mut self) -> ParseResult<Stmt> {
+        let line = self.previous_line();
+        let name = self.consume_name("Expect class name.")?;
+
+        let superclass = if self.match_tag(Tag::Less) {
+            Some(self.consume_name("Expect superclass name.")?)
+        } else {
+            None
+        };
+
+        self.consume(Tag::LeftBrace, "Expect '{' before class body.")?;
+        let mut methods = Vec::new();
+        while !self.check(Tag::RightBrace) && !self.is_at_end() {
+            methods.push(Rc::new(self.function("method")?));
+        }
+        self.consume(Tag::RightBrace, "Expect '}' after class body.")?;
+
+        Ok(Stmt {
+            kind: StmtKind::Class(Class {
+                name,
+                superclass,
+                methods,
+            }),
+            line,
+        })
+    }
+
+    fn function(&mut self, kind: &str) -> ParseResult<Function> {
+        let name = self.consume_name(&format!("Expect {} name.", kind))?;
+        self.consume(Tag::LeftParen, &format!("Expect '(' after {} name.", kind))?;
+
+        let mut params = Vec::new();
+        if !self.check(Tag::RightParen) {
+            loop {
+                if params.len() >= MAX_ARGS {
+                    let error = self.error_at_current("Can't have more than 255 parameters.");
+                    self.errors.push(error);
+                }
+                params.push(self.consume_name("Expect parameter name.")?);
+                if !self.match_tag(Tag::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(Tag::RightParen, "Expect ')' after parameters.")?;
+
+        self.consume(
+            Tag::LeftBrace,
+            &format!("Expect '{{' before {} body.", kind),
+        )?;
+        let body = self.block()?;
+
+        Ok(Function {
+            name,
+            params,
+            body,
+            end_line: self.previous_line(),
+        })
+    }
+
+    fn var_declaration(&mut self) -> ParseResult<Stmt> {
+        let line = self.previous_line();
+        let name = self.consume_name("Expect variable name.")?;
+
+        let initializer = if self.match_tag(Tag::Equal) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+
+        self.consume(Tag::Semicolon, "Expect ';' after variable declaration.")?;
+        Ok(Stmt {
+            kind: StmtKind::Var(name, initializer),
+            line,
+        })
+    }
+
+    fn statement(&mut self) -> ParseResult<Stmt> {
+        let line = self.peek_line();
+        let kind = if self.match_tag(Tag::KeywordPrint) {
+            let value = self.expression()?;
+            self.consume(Tag::Semicolon, "Expect ';' after value.")?;
+            StmtKind::Print(value)
+        } else if self.match_tag(Tag::KeywordFor) {
+            return self.for_statement();
+        } else if self.match_tag(Tag::KeywordIf) {
+            self.consume(Tag::LeftParen, "Expect '(' after 'if'.")?;
+            let condition = self.expression()?;
+            self.consume(Tag::RightParen, "Expect ')' after if condition.")?;
+
+            let then_branch = Box::new(self.nested(Self::statement)?);
+            let else_branch = if self.match_tag(Tag::KeywordElse) {
+                Some(Box::new(self.nested(Self::statement)?))
+            } else {
+                None
+            };
+            StmtKind::If(condition, then_branch, else_branch)
+        } else if self.match_tag(Tag::KeywordReturn) {
+            let value = if self.check(Tag::Semicolon) {
+                None
+            } else {
+                Some(self.expression()?)
+            };
+            self.consume(Tag::Semicolon, "Expect ';' after return value.")?;
+            StmtKind::Return(value)
+        } else if self.match_tag(Tag::KeywordWhile) {
+            self.consume(Tag::LeftParen, "Expect '(' after 'while'.")?;
+            let condition = self.expression()?;
+            self.consume(Tag::RightParen, "Expect ')' after condition.")?;
+            StmtKind::While(condition, Box::new(self.nested(Self::statement)?))
+        } else if self.match_tag(Tag::LeftBrace) {
+            StmtKind::Block(self.block()?)
+        } else if self.match_tag(Tag::KeywordThrow) {
+            let value = self.expression()?;
+            self.consume(Tag::Semicolon, "Expect ';' after thrown value.")?;
+            StmtKind::Throw(value)
+        } else if self.match_tag(Tag::KeywordTry) {
+            StmtKind::Try(self.try_statement()?)
+        } else {
+            let expr = self.expression()?;
+            self.consume(Tag::Semicolon, "Expect ';' after expression.")?;
+            StmtKind::Expression(expr)
+        };
+
+        Ok(Stmt { kind, line })
+    }
+
+    fn try_statement(&mut self) -> ParseResult<Try> {
+        self.consume(Tag::LeftBrace, "Expect '{' after 'try'.")?;
+        let body = self.block()?;
+        let catch = if self.match_tag(Tag::KeywordCatch) {
+            self.consume(Tag::LeftParen, "Expect '(' after 'catch'.")?;
+            let name = self.consume_name("Expect name of the caught value.")?;
+            self.consume(Tag::RightParen, "Expect ')' after caught value name.")?;
+            self.consume(Tag::LeftBrace, "Expect '{' before catch body.")?;
+            Some((name, self.block()?))
+        } else {
+            None
+        };
+        let finally = if self.match_tag(Tag::KeywordFinally) {
+            self.consume(Tag::LeftBrace, "Expect '{' after 'finally'.")?;
+            Some(self.block()?)
+        } else {
+            None
+        };
+        if catch.is_none() && finally.is_none() {
+            return Err(self.error_at_current("Expect 'catch' or 'finally' after try block."));
+        }
+        Ok(Try {
+            body,
+            catch,
+            finally,
+        })
+    }
+
+    /// Desugars `for` into a `while` loop wrapped in blocks.
+    fn for_statement(&mut self) -> ParseResult<Stmt> {
+        let line = self.previous_line();
+        self.consume(Tag::LeftParen, "Expect '(' after 'for'.")?;
+
+        let initializer = if self.match_tag(Tag::Semicolon) {
+            None
+        } else if self.match_tag(Tag::KeywordVar) {
+            Some(self.var_declaration()?)
+        } else {
+            let line = self.peek_line();
+            let expr = self.expression()?;
+            self.consume(Tag::Semicolon, "Expect ';' after loop initializer.")?;
+            Some(Stmt {
+                kind: StmtKind::Expression(expr),
+                line,
+            })
+        };
+
+        let condition = if self.check(Tag::Semicolon) {
+            let token = self.peek();
+            Expr {
+                kind: ExprKind::Literal(Literal::Bool(true)),
+                loc: token.loc,
+                line: self.peek_line(),
+            }
+        } else {
+            self.expression()?
+        };
+        self.consume(Tag::Semicolon, "Expect ';' after loop condition.")?;
+
+        let increment = if self.check(Tag::RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(Tag::RightParen, "Expect ')' after for clauses.")?;
+
+        let mut body = self.nested(Self::statement)?;
+        if let Some(increment) = increment {
+            let increment_line = increment.line;
+            body = Stmt {
+                line: body.line,
+                kind: StmtKind::Block(vec![
+                    body,
+                    Stmt {
+                        kind: StmtKind::Expression(increment),
+                        line: increment_line,
+                    },
+                ]),
+            };
+        }
+
+        body = Stmt {
+            kind: StmtKind::While(condition, Box::new(body)),
+            line,
+        };
+
+        if let Some(initializer) = initializer {
+            body = Stmt {
+                kind: StmtKind::Block(vec![initializer, body]),
+                line,
+            };
+        }
+
+        Ok(body)
+    }
+
+    fn block(&mut self) -> ParseResult<Vec<Stmt>> {
+        self.nested(|parser| {
+            let mut statements = Vec::new();
+            while !parser.check(Tag::RightBrace) && !parser.is_at_end() {
+                statements.push(parser.declaration()?);
+            }
+            parser.consume(Tag::RightBrace, "Expect '}' after block.")?;
+            Ok(statements)
+        })
+    }
+
+    fn expression(&mut self) -> ParseResult<Expr> {
+        self.nested(Self::assignment)
+    }
+
+    fn assignment(&mut self) -> ParseResult<Expr> {
+        let expr = self.or()?;
+
+        if self.match_tag(Tag::Equal) {
+            let equals = self.previous_index();
+            let value = Box::new(self.nested(Self::assignment)?);
+            let loc = Loc {
+                start: expr.loc.start,
+                end: value.loc.end,
+            };
+            let line = expr.line;
+
+            return match expr.kind {
+                ExprKind::Variable(name) => Ok(Expr {
+                    kind: ExprKind::Assign(name, value),
+                    loc,
+                    line,
+                }),
+                ExprKind::Get(object, name) => Ok(Expr {
+                    kind: ExprKind::Set(object, name, value),
+                    loc,
+                    line,
+                }),
+                ExprKind::Index(object, index) => Ok(Expr {
+                    kind: ExprKind::SetIndex(object, index, value),
+                    loc,
+                    line,
+                }),
+                _ => {
+                    // Report without unwinding, the parser is not confused
+                    let error = self.error_at(equals, "Invalid assignment target.");
+                    self.errors.push(error);
+                    Ok(*value)
+                }
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.and()?;
+        while self.match_tag(Tag::KeywordOr) {
+            self.deeper()?;
+            let right = self.and()?;
+            expr = Self::logical(expr, LogicalOp::Or, right);
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.equality()?;
+        while self.match_tag(Tag::KeywordAnd) {
+            self.deeper()?;
+            let right = self.equality()?;
+            expr = Self::logical(expr, LogicalOp::And, right);
+        }
+        Ok(expr)
+    }
+
+    fn equality(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.comparison()?;
+        loop {
+            let op = match self.peek().tag {
+                Tag::BangEqual => BinaryOp::NotEqual,
+                Tag::EqualEqual => BinaryOp::Equal,
+                _ => return Ok(expr),
+            };
+            self.advance();
+            self.deeper()?;
+            let right = self.comparison()?;
+            expr = Self::binary(expr, op, right);
+        }
+    }
+
+    fn comparison(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.term()?;
+        loop {
+            let op = match self.peek().tag {
+                Tag::Greater => BinaryOp::Greater,
+                Tag::GreaterEqual => BinaryOp::GreaterEqual,
+                Tag::Less => BinaryOp::Less,
+                Tag::LessEqual => BinaryOp::LessEqual,
+                _ => return Ok(expr),
+            };
+            self.advance();
+            self.deeper()?;
+            let right = self.term()?;
+            expr = Self::binary(expr, op, right);
+        }
+    }
+
+    fn term(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.factor()?;
+        loop {
+            let op = match self.peek().tag {
+                Tag::Minus => BinaryOp::Subtract,
+                Tag::Plus => BinaryOp::Add,
+                _ => return Ok(expr),
+            };
+            self.advance();
+            self.deeper()?;
+            let right = self.factor()?;
+            expr = Self::binary(expr, op, right);
+        }
+    }
+
+    fn factor(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = match self.peek().tag {
+                Tag::Slash => BinaryOp::Divide,
+                Tag::Star => BinaryOp::Multiply,
+                _ => return Ok(expr),
+            };
+            self.advance();
+            self.deeper()?;
+            let right = self.unary()?;
+            expr = Self::binary(expr, op, right);
+        }
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr> {
+        let op = match self.peek().tag {
+            Tag::Bang => UnaryOp::Not,
+            Tag::Minus => UnaryOp::Negate,
+            _ => return self.call(),
+        };
+        let Lexeme { token, line, .. } = self.advance();
+        let right = self.nested(Self::unary)?;
+
+        Ok(Expr {
+            loc: Loc {
+                start: token.loc.start,
+                end: right.loc.end,
+            },
+            kind: ExprKind::Unary(op, Box::new(right)),
+            line,
+        })
+    }
+
+    fn call(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.primary()?;
+
+        loop {
+            if self.check(Tag::LeftParen) || self.check(Tag::Dot) || self.check(Tag::LeftBracket) {
+                self.deeper()?;
+            }
+            if self.match_tag(Tag::LeftParen) {
+                expr = self.finish_call(expr)?;
+            } else if self.match_tag(Tag::Dot) {
+                let name = self.consume_name("Expect property name after '.'.")?;
+                expr = Expr {
+                    loc: Loc {
+                        start: expr.loc.start,
+                        end: name.loc.end,
+                    },
+                    line: name.line,
+                    kind: ExprKind::Get(Box::new(expr), name),
+                };
+            } else if self.match_tag(Tag::LeftBracket) {
+                self.require_plus("Indexing requires the Lox+ dialect.")?;
+                let index = self.expression()?;
+                let Lexeme {
+                    token: bracket,
+                    line,
+                    ..
+                } = self.consume(Tag::RightBracket, "Expect ']' after index.")?;
+                expr = Expr {
+                    loc: Loc {
+                        start: expr.loc.start,
+                        end: bracket.loc.end,
+                    },
+                    line,
+                    kind: ExprKind::Index(Box::new(expr), Box::new(index)),
+                };
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn finish_call(&mut self, callee: Expr) -> ParseResult<Expr> {
+        let mut arguments = Vec::new();
+        if !self.check(Tag::RightParen) {
+            loop {
+                if arguments.len() >= MAX_ARGS {
+                    let error = self.error_at_current("Can't have more than 255 arguments.");
+                    self.errors.push(error);
+                }
+                arguments.push(self.expression()?);
+                if !self.match_tag(Tag::Comma) {
+                    break;
+                }
+            }
+        }
+        let Lexeme {
+            token: paren, line, ..
+        } = self.consume(Tag::RightParen, "Expect ')' after arguments.")?;
+
+        Ok(Expr {
+            loc: Loc {
+                start: callee.loc.start,
+                end: paren.loc.end,
+            },
+            kind: ExprKind::Call(Box::new(callee), arguments),
+            line,
+        })
+    }
+
+    fn primary(&mut self) -> ParseResult<Expr> {
+        let lexeme = self.peek_lexeme();
+        let Lexeme { token, line, .. } = lexeme.clone();
+        let kind = match token.tag {
+            Tag::KeywordFalse => ExprKind::Literal(Literal::Bool(false)),
+            Tag::KeywordTrue => ExprKind::Literal(Literal::Bool(true)),
+            Tag::KeywordNil => ExprKind::Literal(Literal::Nil),
+            Tag::KeywordThis => ExprKind::This,
+            Tag::Number => {
+                let value = self.lexeme(&token).parse().unwrap_or(0.0);
+                ExprKind::Literal(Literal::Number(value))
+            }
+            Tag::String => {
+                let text = self.lexeme(&token);
+                ExprKind::Literal(Literal::String(text[1..text.len() - 1].into()))
+            }
+            Tag::Identifier => ExprKind::Variable(self.name(&lexeme)),
+            Tag::KeywordSuper => {
+                self.advance();
+                self.consume(Tag::Dot, "Expect '.' after 'super'.")?;
+                let method = self.consume_name("Expect superclass method name.")?;
+                return Ok(Expr {
+                    loc: Loc {
+                        start: token.loc.start,
+                        end: method.loc.end,
+                    },
+                    kind: ExprKind::Super(method),
+                    line,
+                });
+            }
+            Tag::LeftParen => {
+                self.advance();
+                let expr = self.expression()?;
+                let paren = self
+                    .consume(Tag::RightParen, "Expect ')' after expression.")?
+                    .token;
+                return Ok(Expr {
+                    loc: Loc {
+                        start: token.loc.start,
+                        end: paren.loc.end,
+                    },
+                    kind: ExprKind::Grouping(Box::new(expr)),
+                    line,
+                });
+            }
+            Tag::LeftBracket => {
+                self.advance();
+                self.require_plus("List literals require the Lox+ dialect.")?;
+                let mut items = Vec::new();
+                while !self.check(Tag::RightBracket) && !self.is_at_end() {
+                    items.push(self.expression()?);
+                    if !self.match_tag(Tag::Comma) {
+                        break;
+                    }
+                }
+                let bracket = self
+                    .consume(Tag::RightBracket, "Expect ']' after list elements.")?
+                    .token;
+                return Ok(Expr {
+                    loc: Loc {
+                        start: token.loc.start,
+                        end: bracket.loc.end,
+                    },
+                    kind: ExprKind::List(items),
+                    line,
+                });
+            }
+            // Statements starting with '{' are blocks, so this only sees map literals
+            Tag::LeftBrace => {
+                self.advance();
+                self.require_plus("Map literals require the Lox+ dialect.")?;
+                let mut entries = Vec::new();
+                while !self.check(Tag::RightBrace) && !self.is_at_end() {
+                    let key = self.expression()?;
+                    self.consume(Tag::Colon, "Expect ':' after map key.")?;
+                    entries.push((key, self.expression()?));
+                    if !self.match_tag(Tag::Comma) {
+                        break;
+                    }
+                }
+                let brace = self
+                    .consume(Tag::RightBrace, "Expect '}' after map entries.")?
+                    .token;
+                return Ok(Expr {
+                    loc: Loc {
+                        start: token.loc.start,
+                        end: brace.loc.end,
+                    },
+                    kind: ExprKind::Map(entries),
+                    line,
+                });
+            }
+            _ => return Err(self.error_at_current("Expect expression.")),
+        };
+
+        self.advance();
+        Ok(Expr {
+            kind,
+            loc: token.loc,
+            line,
+        })
+    }
+
+    /// Runs `parse` one level deeper, restoring the depth however far it nested.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> ParseResult<T>) -> ParseResult<T> {
+        let depth = self.depth;
+        self.deeper()?;
+        let result = parse(self);
+        self.depth = depth;
+        result
+    }
+
+    fn deeper(&mut self) -> ParseResult<()> {
+        if self.depth >= self.max_depth {
+            return Err(self.error_at_current("Too much nesting."));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Rejects a Lox+ construct, whose first token was just consumed, in strict Lox.
+    fn require_plus(&self, message: &str) -> ParseResult<()> {
+        if self.dialect == Dialect::LoxPlus {
+            return Ok(());
+        }
+        Err(self.error_at(self.previous_index(), message))
+    }
+
+    fn binary(left: Expr, op: BinaryOp, right: Expr) -> Expr {
+        Expr {
+            loc: Loc {
+                start: left.loc.start,
+                end: right.loc.end,
+            },
+            line: left.line,
+            kind: ExprKind::Binary(Box::new(left), op, Box::new(right)),
+        }
+    }
+
+    fn logical(left: Expr, op: LogicalOp, right: Expr) -> Expr {
+        Expr {
+            loc: Loc {
+                start: left.loc.start,
+                end: right.loc.end,
+            },
+            line: left.line,
+            kind: ExprKind::Logical(Box::new(left), op, Box::new(right)),
+        }
+    }
+
+    fn lexeme(&self, token: &Token) -> &'a str {
+        std::str::from_utf8(&self.source[token.loc.start..token.loc.end]).unwrap_or("")
+    }
+
+    fn name(&mut self, lexeme: &Lexeme) -> Name {
+        let symbol = lexeme.symbol.expect("identifiers are always interned");
+        if self.names.len() <= symbol.index() {
+            self.names.resize(symbol.index() + 1, None);
+        }
+        let text = self.names[symbol.index()]
+            .get_or_insert_with(|| self.interner.resolve(symbol).into())
+            .clone();
+
+        Name {
+            text,
+            symbol,
+            loc: lexeme.token.loc,
+            line: lexeme.line,
+        }
+    }
+
+    fn consume_name(&mut self, message: &str) -> ParseResult<Name> {
+        let lexeme = self.consume(Tag::Identifier, message)?;
+        Ok(self.name(&lexeme))
+    }
+
+    fn consume(&mut self, tag: Tag, message: &str) -> ParseResult<Lexeme> {
+        if self.check(tag) {
+            Ok(self.advance())
+        } else {
+            Err(self.error_at_current(message))
+        }
+    }
+
+    fn match_tag(&mut self, tag: Tag) -> bool {
+        if self.check(tag) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check(&self, tag: Tag) -> bool {
+        self.peek().tag == tag
+    }
+
+    fn advance(&mut self) -> Lexeme {
+        let token = self.peek_lexeme();
+        if !self.is_at_end() {
+            self.current += 1;
+        }
+        token
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.peek().tag == Tag::Eof
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current].token
+    }
+
+    fn peek_lexeme(&self) -> Lexeme {
+        self.tokens[self.current].clone()
+    }
+
+    fn peek_line(&self) -> u32 {
+        self.tokens[self.current].line
+    }
+
+    fn previous_index(&self) -> usize {
+        self.current.saturating_sub(1)
+    }
+
+    fn previous_line(&self) -> u32 {
+        self.tokens[self.previous_index()].line
+    }
+
+    fn error_at_current(&self, message: &str) -> Diagnostic {
+        self.error_at(self.current, message)
+    }
+
+    fn error_at(&self, index: usize, message: &str) -> Diagnostic {
+        let Lexeme { token, line, .. } = &self.tokens[index];
+        let lexeme = self.lexeme(token);
+        let (at, message) = match token.tag {
+            Tag::Eof => (" at end".to_string(), message.to_string()),
+            Tag::Invalid if lexeme.starts_with('"') => {
+                (String::new(), "Unterminated string.".to_string())
+            }
+            Tag::Invalid => (String::new(), "Unexpected character.".to_string()),
+            _ => (format!(" at '{}'", lexeme), message.to_string()),
+        };
+
+        Diagnostic {
+            line: *line,
+            loc: token.loc,
+            at,
+            message,
+        }
+    }
+
+    fn synchronize(&mut self) {
+        self.advance();
+        while !self.is_at_end() {
+            if self.tokens[self.previous_index()].token.tag == Tag::Semicolon {
+                return;
+            }
+            match self.peek().tag {
+                Tag::KeywordClass
+                | Tag::KeywordFun
+                | Tag::KeywordVar
+                | Tag::KeywordFor
+                | Tag::KeywordIf
+                | Tag::KeywordWhile
+                | Tag::KeywordPrint
+                | Tag::KeywordReturn
+                | Tag::KeywordImport
+                | Tag::KeywordExport
+                | Tag::KeywordTry
+                | Tag::KeywordThrow => return,
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence_and_errors() {
+        let program = parse(b"var x = 1 + 2 * -3;\nfor (;;) print x;").unwrap();
+        let StmtKind::Var(name, Some(init)) = &program[0].kind else {
+            panic!("expected var declaration");
+        };
+        assert_eq!(&*name.text, "x");
+        let ExprKind::Binary(_, BinaryOp::Add, right) = &init.kind else {
+            panic!("expected addition at the root");
+        };
+        assert!(matches!(
+            right.kind,
+            ExprKind::Binary(_, BinaryOp::Multiply, _)
+        ));
+        assert!(matches!(program[1].kind, StmtKind::While(..)));
+        assert_eq!(program[1].line, 2);
+
+        let errors = parse(b"var = 1;\nprint 1 +;\na + b = c;\n\"oops").unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "[line 1] Error at '=': Expect variable name.",
+                "[line 2] Error at ';': Expect expression.",
+                "[line 3] Error at '=': Invalid assignment target.",
+                "[line 4] Error: Unterminated string.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lox_plus_extensions() {
+        let source = b"var m = {\"k\": [1, 2,]};\nm[\"k\"][0] = m;";
+        let program = parse_dialect(source, Dialect::LoxPlus).unwrap();
+        let lines: Vec<String> = program.iter().map(|stmt| stmt.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "(var m (map (\"k\" (list 1 2))))",
+                "(; (= ([] ([] m \"k\") 0) m))"
+            ]
+        );
+
+        let errors = parse(source).unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "[line 1] Error at '{': Map literals require the Lox+ dialect.",
+                "[line 2] Error at '[': Indexing requires the Lox+ dialect.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let source = b"try { throw 1; } catch (e) { print e; } finally { f(); }";
+        let program = parse_dialect(source, Dialect::LoxPlus).unwrap();
+        assert_eq!(
+            program[0].to_string(),
+            "(try\n  (throw 1)\n  (catch e\n    (print e))\n  (finally\n    (; (call f))))"
+        );
+
+        let error =
+            |source: &[u8]| parse_dialect(source, Dialect::LoxPlus).unwrap_err()[0].to_string();
+        assert_eq!(
+            error(b"try { }\nprint 1;"),
+            "[line 2] Error at 'print': Expect 'catch' or 'finally' after try block."
+        );
+        assert_eq!(
+            error(b"try { } catch e { }"),
+            "[line 1] Error at 'e': Expect '(' after 'catch'."
+        );
+
+        // Only Lox+ reserves the new keywords
+        let source = b"var try = 1; fun throw(catch, finally) {} throw(try, 2);\n\
+                       var import = 3; class export {}";
+        let program = parse(source).unwrap();
+        let lines: Vec<String> = program.iter().map(|stmt| stmt.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "(var try 1)",
+                "(fun throw (catch finally))",
+                "(; (call throw try 2))",
+                "(var import 3)",
+                "(class export)"
+            ]
+        );
+        let errors = parse_dialect(b"var try = 1;", Dialect::LoxPlus).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "[line 1] Error at 'try': Expect variable name."
+        );
+    }
+
+    #[test]
+    fn test_imports_and_exports() {
+        let program = parse_dialect(
+            b"import \"lib/a.lox\";\nexport var x = 1;",
+            Dialect::LoxPlus,
+        )
+        .unwrap();
+        let lines: Vec<String> = program.iter().map(|stmt| stmt.to_string()).collect();
+        assert_eq!(lines, ["(import \"lib/a.lox\")", "(export\n  (var x 1))"]);
+        assert_eq!(program[1].declared_name().unwrap().text.as_ref(), "x");
+
+        let errors = parse_dialect(
+            b"{ import \"a.lox\"; }\nfun f() { export var y; }\nexport print 1;",
+            Dialect::LoxPlus,
+        )
+        .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "[line 1] Error at 'import': Can only import at the top level.",
+                "[line 2] Error at 'export': Can only export at the top level.",
+                "[line 3] Error at 'print': Expect declaration after 'export'.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        // Debug builds take more stack per level than a test thread has
+        let deep = std::thread::Builder::new().stack_size(16 << 20);
+        let messages = deep
+            .spawn(|| {
+                let sources = [
+                    format!("print {}1{};", "(".repeat(500), ")".repeat(500)),
+                    format!("print {}1;", "-".repeat(100_000)),
+                    format!("{}{}", "{".repeat(3000), "}".repeat(3000)),
+                    format!("{}print 1;", "if (true) ".repeat(3000)),
+                    format!("print 1{};", " + 1".repeat(100_000)),
+                    format!("f{};", "()".repeat(100_000)),
+                    format!("a{}1;", " = a".repeat(100_000)),
+                ];
+                let limit = MAX_DEPTH - 1;
+                let just_fits = format!("print {}1{};", "(".repeat(limit), ")".repeat(limit));
+                assert!(parse(just_fits.as_bytes()).is_ok());
+                sources.map(|source| parse(source.as_bytes()).unwrap_err()[0].message.clone())
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(messages, ["Too much nesting."; 7]);
+
+        let parse_nested = |source: &[u8]| Parser::new(source).with_max_depth(2).parse();
+        assert!(parse_nested(b"print (1);").is_ok());
+        assert_eq!(
+            parse_nested(b"print ((1));").unwrap_err()[0].to_string(),
+            "[line 1] Error at '1': Too much nesting."
+        );
+    }
+}
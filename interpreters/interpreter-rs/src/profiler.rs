@@ -0,0 +1,302 @@
+use crate::object::ObjRef;
+use crate::vm::Vm;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Instructions between clock readings. Time read at a sample is charged to the function and
+/// line running then.
+const SAMPLE_EVERY: u32 = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+/// Counts every instruction by function, source line and call stack, and samples wall time.
+/// Attach it with [`Vm::set_profiler`].
+pub struct Profiler {
+    /// Each function labeled with where its code starts, e.g. `fib (script:2)`.
+    functions: Vec<(String, Stats)>,
+    /// Functions by name, module and first line of code, which tells apart same-named methods.
+    function_ids: HashMap<(String, usize, u32), usize>,
+    /// Folded call stacks, e.g. `script;fib;fib`, with their instructions.
+    stacks: Vec<(String, u64)>,
+    /// Stack reached by calling a function from a stack, `None` being the empty stack.
+    stack_ids: HashMap<(Option<usize>, usize), usize>,
+    /// Source lines by module and line number.
+    lines: HashMap<(usize, u32), Stats>,
+    /// Path of every module seen, by index.
+    modules: Vec<String>,
+    /// Closure, stack and function of each frame as last seen.
+    path: Vec<(ObjRef, usize, usize)>,
+    until_sample: u32,
+    last_sample: Instant,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            stacks: Vec::new(),
+            stack_ids: HashMap::new(),
+            lines: HashMap::new(),
+            modules: Vec::new(),
+            path: Vec::new(),
+            until_sample: SAMPLE_EVERY,
+            last_sample: Instant::now(),
+        }
+    }
+
+    /// Functions, heaviest first.
+    pub fn functions(&self) -> Vec<(&str, Stats)> {
+        let mut functions: Vec<_> = self
+            .functions
+            .iter()
+            .map(|(name, stats)| (name.as_str(), *stats))
+            .collect();
+        functions.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(b.0)));
+        functions
+    }
+
+    /// Source lines with the path of their module, heaviest first.
+    pub fn lines(&self) -> Vec<(&str, u32, Stats)> {
+        let mut lines: Vec<_> = self.lines.iter().collect();
+        lines.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(b.0)));
+        lines
+            .into_iter()
+            .map(|(&(module, line), &stats)| (self.modules[module].as_str(), line, stats))
+            .collect()
+    }
+
+    /// Stacks in the folded format flamegraph tools read, weighted by instructions:
+    /// `script;outer;inner 1234` per line.
+    pub fn folded(&self) -> String {
+        let mut stacks: Vec<_> = self.stacks.iter().filter(|(_, count)| *count > 0).collect();
+        stacks.sort();
+        let mut out = String::new();
+        for (stack, count) in stacks {
+            let _ = writeln!(out, "{} {}", stack, count);
+        }
+        out
+    }
+
+    /// The `top` heaviest functions and lines with their share of all instructions.
+    pub fn report(&self, top: usize) -> String {
+        let total: u64 = self.functions.iter().map(|(_, s)| s.instructions).sum();
+        let percent = |n: u64| 100.0 * n as f64 / total.max(1) as f64;
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:>12} {:>7} {:>10}  function",
+            "instructions", "%", "ms"
+        );
+        for (name, stats) in self.functions().into_iter().take(top) {
+            let _ = writeln!(
+                out,
+                "{:>12} {:>6.2}% {:>10.2}  {}",
+                stats.instructions,
+                percent(stats.instructions),
+                stats.time.as_secs_f64() * 1000.0,
+                name
+            );
+        }
+        let _ = writeln!(
+            out,
+            "\n{:>12} {:>7} {:>10}  line",
+            "instructions", "%", "ms"
+        );
+        for (module, line, stats) in self.lines().into_iter().take(top) {
+            let _ = writeln!(
+                out,
+                "{:>12} {:>6.2}% {:>10.2}  {}:{}",
+                stats.instructions,
+                percent(stats.instructions),
+                stats.time.as_secs_f64() * 1000.0,
+                module,
+                line
+            );
+        }
+        out
+    }
+
+    fn record(&mut self, vm: &Vm, module: usize, line: u32) {
+        // Calls and returns change at most the innermost frame between two instructions
+        let depth = vm.frame_count();
+        self.path.truncate(depth);
+        if let Some(&(closure, _, _)) = self.path.last() {
+            if closure != vm.frame_state(self.path.len() - 1).0 {
+                self.path.pop();
+            }
+        }
+        while self.path.len() < depth {
+            let closure = vm.frame_state(self.path.len()).0;
+            let function = self.function_id(vm, closure);
+            let parent = self.path.last().map(|&(_, stack, _)| stack);
+            let (stacks, name) = (&mut self.stacks, &self.functions[function].0);
+            let stack = *self.stack_ids.entry((parent, function)).or_insert_with(|| {
+                let folded = match parent {
+                    Some(parent) => format!("{};{}", stacks[parent].0, name),
+                    None => name.to_string(),
+                };
+                stacks.push((folded, 0));
+                stacks.len() - 1
+            });
+            self.path.push((closure, stack, function));
+        }
+
+        let (_, stack, function) = self.path[depth - 1];
+        self.stacks[stack].1 += 1;
+        self.functions[function].1.instructions += 1;
+        let source_line = self.lines.entry((module, line)).or_default();
+        source_line.instructions += 1;
+
+        self.until_sample -= 1;
+        if self.until_sample == 0 {
+            self.until_sample = SAMPLE_EVERY;
+            let now = Instant::now();
+            let elapsed = now - self.last_sample;
+            self.last_sample = now;
+            source_line.time += elapsed;
+            self.functions[function].1.time += elapsed;
+        }
+    }
+
+    fn function_id(&mut self, vm: &Vm, closure: ObjRef) -> usize {
+        let heap = vm.heap();
+        let function = heap.function(heap.closure(closure).function);
+        let name = function.name.map_or("", |name| heap.str(name));
+        let key = (name.to_string(), function.module, function.chunk.lines[0]);
+        if let Some(&id) = self.function_ids.get(&key) {
+            return id;
+        }
+
+        // Modules keep their index once their code runs
+        while self.modules.len() <= function.module {
+            let path = vm.module_path(self.modules.len()).display().to_string();
+            self.modules.push(path);
+        }
+        let module = &self.modules[function.module];
+        let label = match function.name {
+            Some(_) => format!("{} ({}:{})", name, module, key.2),
+            None => module.clone(),
+        };
+        self.functions.push((label, Stats::default()));
+        self.function_ids.insert(key, self.functions.len() - 1);
+        self.functions.len() - 1
+    }
+}
+
+/// Runs before every instruction while a profiler is attached.
+pub(crate) fn on_instruction(vm: &mut Vm) {
+    let Some(mut profiler) = vm.take_profiler() else {
+        return;
+    };
+    let (closure, offset, _) = vm.frame_state(vm.frame_count() - 1);
+    let heap = vm.heap();
+    let function = heap.function(heap.closure(closure).function);
+    let (module, line) = (function.module, function.chunk.lines[offset]);
+    profiler.record(vm, module, line);
+    vm.restore_profiler(profiler);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_counts_every_instruction() {
+        let source = "\
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+fun run() { return fib(10); }
+print run();
+";
+        let mut vm = Vm::new();
+        vm.set_output(Box::new(std::io::sink()));
+        vm.set_profiler(Some(Profiler::new()));
+        vm.interpret(source.as_bytes()).unwrap();
+        let executed = vm.instructions_executed();
+        let profiler = vm.take_profiler().unwrap();
+
+        let functions = profiler.functions();
+        assert_eq!(functions[0].0, "fib (script:2)");
+        let total: u64 = functions.iter().map(|(_, s)| s.instructions).sum();
+        assert_eq!(total, executed);
+        let lines: u64 = profiler
+            .lines()
+            .iter()
+            .map(|(_, _, s)| s.instructions)
+            .sum();
+        assert_eq!(lines, executed);
+        let mut hottest: Vec<u32> = profiler.lines()[..2]
+            .iter()
+            .map(|(_, line, _)| *line)
+            .collect();
+        hottest.sort();
+        assert_eq!(hottest, [2, 3]);
+
+        let folded = profiler.folded();
+        let stacks: Vec<&str> = folded
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect();
+        assert!(stacks.contains(&"script;run (script:5);fib (script:2);fib (script:2)"));
+        let weight: u64 = folded
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(weight, executed);
+
+        let report = profiler.report(2);
+        assert_eq!(report.lines().count(), 7);
+        assert!(report.lines().nth(1).unwrap().ends_with("  fib (script:2)"));
+        assert!(report.contains("  script:3\n"));
+    }
+
+    #[test]
+    fn test_same_names_stay_apart() {
+        let source = "\
+class A {
+  init() { this.n = 1; }
+  get() { return this.n; }
+}
+class B {
+  init() { this.n = 2; }
+  get() { return this.n + this.n; }
+}
+print A().get() + B().get();
+";
+        let mut vm = Vm::new();
+        vm.set_output(Box::new(std::io::sink()));
+        vm.set_profiler(Some(Profiler::new()));
+        vm.interpret(source.as_bytes()).unwrap();
+        let profiler = vm.take_profiler().unwrap();
+
+        let mut names: Vec<&str> = profiler.functions().iter().map(|(name, _)| *name).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "get (script:3)",
+                "get (script:7)",
+                "init (script:2)",
+                "init (script:6)",
+                "script"
+            ]
+        );
+        let folded = profiler.folded();
+        assert!(folded.contains("script;get (script:3) 3\n"));
+        assert!(folded.contains("script;get (script:7) 6\n"));
+    }
+}
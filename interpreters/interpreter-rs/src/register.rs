@@ -0,0 +1,246 @@
+use std::fmt::Write;
+
+/// Register index relative to the frame base. Register zero holds the callee, or the receiver
+/// for methods, and parameters follow it like in the stack VM.
+pub type Reg = u8;
+
+/// Three-address instructions for the register VM. Jump targets are absolute instruction
+/// indexes, `name` and `constant` operands index the function's constant table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instr {
+    LoadConstant {
+        dst: Reg,
+        constant: u16,
+    },
+    LoadNil {
+        dst: Reg,
+    },
+    LoadBool {
+        dst: Reg,
+        value: bool,
+    },
+    Move {
+        dst: Reg,
+        src: Reg,
+    },
+    GetGlobal {
+        dst: Reg,
+        name: u16,
+    },
+    DefineGlobal {
+        src: Reg,
+        name: u16,
+    },
+    SetGlobal {
+        src: Reg,
+        name: u16,
+    },
+    GetUpvalue {
+        dst: Reg,
+        index: u8,
+    },
+    SetUpvalue {
+        src: Reg,
+        index: u8,
+    },
+    GetProperty {
+        dst: Reg,
+        object: Reg,
+        name: u16,
+    },
+    SetProperty {
+        object: Reg,
+        name: u16,
+        src: Reg,
+    },
+    GetSuper {
+        dst: Reg,
+        object: Reg,
+        superclass: Reg,
+        name: u16,
+    },
+    Equal {
+        dst: Reg,
+        a: Reg,
+        b: Reg,
+    },
+    NotEqual {
+        dst: Reg,
+        a: Reg,
+        b: Reg,
+    },
+    Greater {
+        dst: Reg,
+        a: Reg,
+        b: Reg,
+    },
+    GreaterEqual {
+        dst: Reg,
+        a: Reg,
+        b: Reg,
+    },
+    Less {
+        dst: Reg,
+        a: Reg,
+        b: Reg,
+    },
+    LessEqual {
+        dst: Reg,
+        a: Reg,
+        b: Reg,
+    },
+    Add {
+        dst: Reg,
+        a: Reg,
+        b: Reg,
+    },
+    Subtract {
+        dst: Reg,
+        a: Reg,
+        b: Reg,
+    },
+    Multiply {
+        dst: Reg,
+        a: Reg,
+        b: Reg,
+    },
+    Divide {
+        dst: Reg,
+        a: Reg,
+        b: Reg,
+    },
+    Not {
+        dst: Reg,
+        src: Reg,
+    },
+    Negate {
+        dst: Reg,
+        src: Reg,
+    },
+    Print {
+        src: Reg,
+    },
+    Jump {
+        target: u32,
+    },
+    JumpIfFalse {
+        cond: Reg,
+        target: u32,
+    },
+    JumpIfTrue {
+        cond: Reg,
+        target: u32,
+    },
+    /// Calls the callee in `base` with the `argc` registers after it, leaving the result in
+    /// `base`.
+    Call {
+        base: Reg,
+        argc: u8,
+    },
+    /// Like `Call` with the receiver in `base`.
+    Invoke {
+        base: Reg,
+        name: u16,
+        argc: u8,
+    },
+    SuperInvoke {
+        base: Reg,
+        name: u16,
+        argc: u8,
+        superclass: Reg,
+    },
+    /// Captures the upvalues listed in the function's [`RegisterChunk::upvalues`].
+    Closure {
+        dst: Reg,
+        function: u16,
+    },
+    /// Closes upvalues pointing at `from` or any register above it.
+    CloseUpvalues {
+        from: Reg,
+    },
+    Return {
+        src: Reg,
+    },
+    Class {
+        dst: Reg,
+        name: u16,
+    },
+    Inherit {
+        class: Reg,
+        superclass: Reg,
+    },
+    Method {
+        class: Reg,
+        name: u16,
+        src: Reg,
+    },
+    BuildList {
+        dst: Reg,
+        start: Reg,
+        count: u8,
+    },
+    BuildMap {
+        dst: Reg,
+        start: Reg,
+        count: u8,
+    },
+    GetIndex {
+        dst: Reg,
+        object: Reg,
+        index: Reg,
+    },
+    SetIndex {
+        object: Reg,
+        index: Reg,
+        src: Reg,
+    },
+    /// Installs a handler that resumes at `target` with the thrown value in `dst`, which is
+    /// above every register still in scope there.
+    PushHandler {
+        dst: Reg,
+        target: u32,
+    },
+    PopHandler,
+    Throw {
+        src: Reg,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueSource {
+    pub is_local: bool,
+    /// A register of the enclosing function, or one of its upvalues.
+    pub index: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RegisterChunk {
+    pub code: Vec<Instr>,
+    /// Source line for every instruction.
+    pub lines: Vec<u32>,
+    /// Registers a frame of this function needs, register zero included.
+    pub registers: usize,
+    pub upvalues: Vec<UpvalueSource>,
+}
+
+impl RegisterChunk {
+    pub fn write(&mut self, instr: Instr, line: u32) {
+        self.code.push(instr);
+        self.lines.push(line);
+    }
+
+    pub fn size(&self) -> usize {
+        self.code.len() * std::mem::size_of::<Instr>()
+            + self.lines.len() * std::mem::size_of::<u32>()
+            + self.upvalues.len() * std::mem::size_of::<UpvalueSource>()
+    }
+
+    /// One instruction per line, e.g. `0003    2 Add { dst: 1, a: 1, b: 2 }`.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        for (i, instr) in self.code.iter().enumerate() {
+            let _ = writeln!(out, "{:04} {:4} {:?}", i, self.lines[i], instr);
+        }
+        out
+    }
+}
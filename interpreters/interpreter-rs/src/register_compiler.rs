@@ -0,0 +1,1002 @@
+use crate::ast::*;
+use crate::chunk::Chunk;
+use crate::compiler;
+use crate::diagnostic::Diagnostic;
+use crate::gc::{Heap, Trace};
+use crate::object::{Obj, ObjFunction, ObjRef};
+use crate::optimizer;
+use crate::register::{Instr, Reg, RegisterChunk, UpvalueSource};
+use crate::value::Value;
+use lexer::interner::Symbol;
+use lexer::with_opt_iterator::{Loc, Tag};
+use std::rc::Rc;
+
+const MAX_REGISTERS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+/// Local `i` of a function lives in register `i`.
+struct Local {
+    name: Option<Symbol>,
+    /// `None` while the variable's initializer is being compiled.
+    depth: Option<u32>,
+    is_captured: bool,
+}
+
+/// A `try` statement whose body or `catch` block is being compiled.
+struct TryState {
+    /// Whether its handler is installed, which in a `catch` block it only is for `finally`.
+    handler: bool,
+    finally: Option<Vec<Stmt>>,
+}
+
+struct FunctionState {
+    kind: FunctionKind,
+    name: Option<ObjRef>,
+    arity: u8,
+    /// Only the constant table is used.
+    constants: Chunk,
+    code: RegisterChunk,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueSource>,
+    scope_depth: u32,
+    /// First free register. Locals sit at the bottom, temporaries above them.
+    next: usize,
+    /// Enclosing `try` statements, innermost last, which a `return` leaves.
+    tries: Vec<TryState>,
+}
+
+impl FunctionState {
+    fn new(kind: FunctionKind, name: Option<ObjRef>) -> Self {
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => Tag::KeywordThis.keyword_symbol(),
+            _ => None,
+        };
+        FunctionState {
+            kind,
+            name,
+            arity: 0,
+            constants: Chunk::new(),
+            code: RegisterChunk {
+                registers: 1,
+                ..RegisterChunk::default()
+            },
+            locals: vec![Local {
+                name: slot_zero,
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            next: 1,
+            tries: Vec::new(),
+        }
+    }
+}
+
+enum Variable {
+    Local(Reg),
+    Upvalue(u8),
+    Global(u16),
+}
+
+/// Compiles a parsed program for the register VM. The stack compiler checks the program first,
+/// so both backends reject the same programs with the same diagnostics.
+pub fn compile(
+    program: &[Stmt],
+    heap: &mut Heap,
+    roots: &dyn Trace,
+) -> Result<ObjRef, Vec<Diagnostic>> {
+    compiler::compile_with(program, heap, roots, false)?;
+
+    let mut compiler = Compiler {
+        heap,
+        roots,
+        states: vec![FunctionState::new(FunctionKind::Script, None)],
+        classes: Vec::new(),
+        errors: Vec::new(),
+        line: 1,
+        strings: Vec::new(),
+    };
+    compiler.statements(program);
+    compiler.emit_return();
+
+    let state = compiler.states.pop().unwrap();
+    if !compiler.errors.is_empty() {
+        return Err(compiler.errors);
+    }
+    Ok(compiler.finish(state))
+}
+
+struct Compiler<'a> {
+    heap: &'a mut Heap,
+    roots: &'a dyn Trace,
+    states: Vec<FunctionState>,
+    /// Whether each enclosing class has a superclass.
+    classes: Vec<bool>,
+    errors: Vec<Diagnostic>,
+    line: u32,
+    strings: Vec<Option<ObjRef>>,
+}
+
+impl Compiler<'_> {
+    fn state(&mut self) -> &mut FunctionState {
+        self.states.last_mut().unwrap()
+    }
+
+    fn collect_if_needed(&mut self, finished: Option<&FunctionState>) {
+        if !self.heap.should_collect() {
+            return;
+        }
+        self.roots.trace(self.heap);
+        self.strings.trace(self.heap);
+        for state in self.states.iter().chain(finished) {
+            state.name.trace(self.heap);
+            state.constants.constants.trace(self.heap);
+        }
+        self.heap.collect();
+    }
+
+    fn intern(&mut self, chars: &str) -> ObjRef {
+        self.collect_if_needed(None);
+        self.heap.intern(chars)
+    }
+
+    fn finish(&mut self, mut state: FunctionState) -> ObjRef {
+        self.collect_if_needed(Some(&state));
+        state.code.upvalues = state.upvalues;
+        self.heap.alloc(Obj::Function(ObjFunction {
+            name: state.name,
+            arity: state.arity,
+            upvalue_count: state.code.upvalues.len(),
+            chunk: Rc::new(state.constants),
+            module: 0,
+            registers: Some(Rc::new(state.code)),
+        }))
+    }
+
+    fn error_at_line(&mut self, message: &str) {
+        self.errors.push(Diagnostic {
+            line: self.line,
+            loc: Loc { start: 0, end: 0 },
+            at: String::new(),
+            message: message.to_string(),
+        });
+    }
+
+    fn emit(&mut self, instr: Instr) {
+        let line = self.line;
+        self.state().code.write(instr, line);
+    }
+
+    fn emit_jump(&mut self, instr: Instr) -> usize {
+        self.emit(instr);
+        self.state().code.code.len() - 1
+    }
+
+    fn patch_jump(&mut self, at: usize) {
+        let here = self.state().code.code.len() as u32;
+        match &mut self.state().code.code[at] {
+            Instr::Jump { target }
+            | Instr::JumpIfFalse { target, .. }
+            | Instr::JumpIfTrue { target, .. }
+            | Instr::PushHandler { target, .. } => *target = here,
+            _ => unreachable!("patching a non-jump"),
+        }
+    }
+
+    fn emit_return(&mut self) {
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit(Instr::Return { src: 0 });
+            return;
+        }
+        let mark = self.state().next;
+        let dst = self.alloc();
+        self.emit(Instr::LoadNil { dst });
+        self.emit(Instr::Return { src: dst });
+        self.free_to(mark);
+    }
+
+    fn alloc(&mut self) -> Reg {
+        let state = self.state();
+        let reg = state.next;
+        state.next += 1;
+        state.code.registers = state.code.registers.max(state.next);
+        if reg >= MAX_REGISTERS {
+            // Report once, later registers are past the limit too
+            if reg == MAX_REGISTERS {
+                self.error_at_line("Too many registers needed in function.");
+            }
+            return 0;
+        }
+        reg as Reg
+    }
+
+    fn free_to(&mut self, mark: usize) {
+        self.state().next = mark;
+    }
+
+    /// Whether `reg` holds no variable a later read could see, so it may be written before an
+    /// expression is done reading its operands.
+    fn is_scratch(&mut self, reg: Reg) -> bool {
+        let locals = &self.state().locals;
+        let initialized = locals.len() - locals.last().is_some_and(|l| l.depth.is_none()) as usize;
+        reg as usize >= initialized
+    }
+
+    fn make_constant(&mut self, value: Value) -> u16 {
+        let index = self.state().constants.add_constant(value);
+        if index > u16::MAX as usize {
+            self.error_at_line("Too many constants in one chunk.");
+            return 0;
+        }
+        index as u16
+    }
+
+    fn name_string(&mut self, name: &Name) -> ObjRef {
+        let index = name.symbol.index();
+        if let Some(Some(string)) = self.strings.get(index) {
+            return *string;
+        }
+
+        let string = self.intern(&name.text);
+        if self.strings.len() <= index {
+            self.strings.resize(index + 1, None);
+        }
+        self.strings[index] = Some(string);
+        string
+    }
+
+    fn identifier_constant(&mut self, name: &Name) -> u16 {
+        let string = self.name_string(name);
+        self.make_constant(Value::Obj(string))
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.state();
+        state.scope_depth -= 1;
+        let depth = state.scope_depth;
+
+        let mut close_from = None;
+        while let Some(local) = state.locals.last() {
+            if local.depth.is_some_and(|d| d <= depth) {
+                break;
+            }
+            if local.is_captured {
+                close_from = Some(state.locals.len() - 1);
+            }
+            state.locals.pop();
+        }
+        state.next = state.locals.len();
+        if let Some(from) = close_from {
+            self.emit(Instr::CloseUpvalues { from: from as Reg });
+        }
+    }
+
+    fn declare_local(&mut self, name: &Name) -> Reg {
+        self.state().locals.push(Local {
+            name: Some(name.symbol),
+            depth: None,
+            is_captured: false,
+        });
+        self.alloc()
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let depth = state.scope_depth;
+        state.locals.last_mut().unwrap().depth = Some(depth);
+    }
+
+    fn resolve_local(&self, state_index: usize, name: &Name) -> Option<u8> {
+        self.states[state_index]
+            .locals
+            .iter()
+            .rposition(|local| local.name == Some(name.symbol))
+            .map(|slot| slot as u8)
+    }
+
+    fn resolve_upvalue(&mut self, state_index: usize, name: &Name) -> Option<u8> {
+        if state_index == 0 {
+            return None;
+        }
+
+        if let Some(local) = self.resolve_local(state_index - 1, name) {
+            self.states[state_index - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state_index, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(state_index - 1, name)?;
+        Some(self.add_upvalue(state_index, upvalue, false))
+    }
+
+    fn add_upvalue(&mut self, state_index: usize, index: u8, is_local: bool) -> u8 {
+        let source = UpvalueSource { is_local, index };
+        let upvalues = &mut self.states[state_index].upvalues;
+        if let Some(existing) = upvalues.iter().position(|&u| u == source) {
+            return existing as u8;
+        }
+        upvalues.push(source);
+        (upvalues.len() - 1) as u8
+    }
+
+    fn resolve(&mut self, name: &Name) -> Variable {
+        let current = self.states.len() - 1;
+        if let Some(reg) = self.resolve_local(current, name) {
+            Variable::Local(reg)
+        } else if let Some(index) = self.resolve_upvalue(current, name) {
+            Variable::Upvalue(index)
+        } else {
+            Variable::Global(self.identifier_constant(name))
+        }
+    }
+
+    fn statements(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        self.line = stmt.line;
+        let mark = self.state().next;
+        match &stmt.kind {
+            StmtKind::Expression(expr) => self.effect(expr),
+            StmtKind::Print(expr) => {
+                let src = self.expr_any(expr);
+                self.emit(Instr::Print { src });
+            }
+            StmtKind::Var(name, initializer) => {
+                if self.state().scope_depth == 0 {
+                    let name = self.identifier_constant(name);
+                    let src = self.alloc();
+                    self.initializer(initializer.as_ref(), src);
+                    self.line = stmt.line;
+                    self.emit(Instr::DefineGlobal { src, name });
+                } else {
+                    let dst = self.declare_local(name);
+                    self.initializer(initializer.as_ref(), dst);
+                    self.mark_initialized();
+                    // The new local stays allocated
+                    return;
+                }
+            }
+            StmtKind::Block(stmts) => self.block(stmts),
+            StmtKind::If(condition, then_branch, else_branch) => {
+                let cond = self.expr_any(condition);
+                self.free_to(mark);
+                let then_jump = self.emit_jump(Instr::JumpIfFalse { cond, target: 0 });
+                self.statement(then_branch);
+                match else_branch {
+                    Some(else_branch) => {
+                        let else_jump = self.emit_jump(Instr::Jump { target: 0 });
+                        self.patch_jump(then_jump);
+                        self.statement(else_branch);
+                        self.patch_jump(else_jump);
+                    }
+                    None => self.patch_jump(then_jump),
+                }
+            }
+            StmtKind::While(condition, body) => {
+                let loop_start = self.state().code.code.len() as u32;
+                let cond = self.expr_any(condition);
+                self.free_to(mark);
+                let exit_jump = self.emit_jump(Instr::JumpIfFalse { cond, target: 0 });
+                self.statement(body);
+                self.line = stmt.line;
+                self.emit(Instr::Jump { target: loop_start });
+                self.patch_jump(exit_jump);
+            }
+            StmtKind::Function(function) => {
+                if self.state().scope_depth == 0 {
+                    let name = self.identifier_constant(&function.name);
+                    let dst = self.alloc();
+                    self.function(function, FunctionKind::Function, dst);
+                    self.emit(Instr::DefineGlobal { src: dst, name });
+                } else {
+                    let dst = self.declare_local(&function.name);
+                    // Allow the function to refer to itself
+                    self.mark_initialized();
+                    self.function(function, FunctionKind::Function, dst);
+                    return;
+                }
+            }
+            StmtKind::Return(value) if !self.state().tries.is_empty() => {
+                // The result waits in a hidden local while `finally` blocks run
+                let src = self.alloc();
+                match value {
+                    Some(value) => self.expr_to(value, src),
+                    None if self.state().kind == FunctionKind::Initializer => {
+                        self.emit(Instr::Move { dst: src, src: 0 })
+                    }
+                    None => self.emit(Instr::LoadNil { dst: src }),
+                }
+                self.add_hidden_local();
+                self.leave_tries();
+                self.emit(Instr::Return { src });
+                self.state().locals.pop();
+            }
+            StmtKind::Return(None) => self.emit_return(),
+            StmtKind::Return(Some(value)) => {
+                let src = self.expr_any(value);
+                self.emit(Instr::Return { src });
+            }
+            StmtKind::Class(class) => {
+                self.class(class);
+                if self.state().scope_depth > 0 {
+                    return;
+                }
+            }
+            StmtKind::Import(_) => {
+                self.error_at_line("Imports are only supported by the stack VM.")
+            }
+            StmtKind::Export(declaration) => self.statement(declaration),
+            StmtKind::Throw(value) => {
+                let src = self.expr_any(value);
+                self.line = stmt.line;
+                self.emit(Instr::Throw { src });
+            }
+            StmtKind::Try(handler) => self.try_statement(handler),
+        }
+        self.free_to(mark);
+    }
+
+    /// Compiles `try` like the stack compiler does, with the thrown value landing in the first
+    /// free register, which the `catch` variable then takes.
+    fn try_statement(&mut self, handler: &Try) {
+        let finally = handler.finally.as_deref();
+        let dst = self.handler_register();
+        let catch_jump = self.emit_jump(Instr::PushHandler { dst, target: 0 });
+        self.guarded(true, finally, |compiler| compiler.block(&handler.body));
+        self.emit(Instr::PopHandler);
+        self.finally_block(finally);
+        let body_exit = self.emit_jump(Instr::Jump { target: 0 });
+        self.patch_jump(catch_jump);
+
+        let Some((name, body)) = &handler.catch else {
+            self.rethrow(finally.unwrap_or_default(), 1);
+            self.patch_jump(body_exit);
+            return;
+        };
+        self.begin_scope();
+        self.declare_local(name);
+        self.mark_initialized();
+        let rethrow_jump = finally.map(|_| {
+            let dst = self.handler_register();
+            self.emit_jump(Instr::PushHandler { dst, target: 0 })
+        });
+        self.guarded(finally.is_some(), finally, |compiler| {
+            compiler.statements(body)
+        });
+        if finally.is_some() {
+            self.emit(Instr::PopHandler);
+        }
+        self.end_scope();
+        self.finally_block(finally);
+        if let (Some(rethrow_jump), Some(finally)) = (rethrow_jump, finally) {
+            let catch_exit = self.emit_jump(Instr::Jump { target: 0 });
+            self.patch_jump(rethrow_jump);
+            // The catch variable's register is still below the thrown value
+            self.rethrow(finally, 2);
+            self.patch_jump(catch_exit);
+        }
+        self.patch_jump(body_exit);
+    }
+
+    /// The first free register, where a handler receives the thrown value. It stays free until
+    /// then but still counts towards the frame's registers.
+    fn handler_register(&mut self) -> Reg {
+        let mark = self.state().next;
+        let reg = self.alloc();
+        self.free_to(mark);
+        reg
+    }
+
+    fn guarded(&mut self, handler: bool, finally: Option<&[Stmt]>, f: impl FnOnce(&mut Self)) {
+        self.state().tries.push(TryState {
+            handler,
+            finally: finally.map(<[Stmt]>::to_vec),
+        });
+        f(self);
+        self.state().tries.pop();
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        self.begin_scope();
+        self.statements(stmts);
+        self.end_scope();
+    }
+
+    fn finally_block(&mut self, finally: Option<&[Stmt]>) {
+        if let Some(finally) = finally {
+            self.block(finally);
+        }
+    }
+
+    /// Handler code that runs `finally` and throws the value in the last of `hidden` registers
+    /// past the locals on.
+    fn rethrow(&mut self, finally: &[Stmt], hidden: usize) {
+        let mark = self.state().next;
+        for _ in 0..hidden {
+            self.alloc();
+            self.add_hidden_local();
+        }
+        self.block(finally);
+        let src = (self.state().locals.len() - 1) as Reg;
+        self.emit(Instr::Throw { src });
+        let state = self.state();
+        state.locals.truncate(state.locals.len() - hidden);
+        self.free_to(mark);
+    }
+
+    /// Pops the handlers and runs the `finally` blocks of every enclosing `try`, innermost
+    /// first.
+    fn leave_tries(&mut self) {
+        let Some(innermost) = self.state().tries.pop() else {
+            return;
+        };
+        if innermost.handler {
+            self.emit(Instr::PopHandler);
+        }
+        self.finally_block(innermost.finally.as_deref());
+        self.leave_tries();
+        self.state().tries.push(innermost);
+    }
+
+    /// Claims the last allocated register as a local no name refers to.
+    fn add_hidden_local(&mut self) {
+        let depth = self.state().scope_depth;
+        self.state().locals.push(Local {
+            name: None,
+            depth: Some(depth),
+            is_captured: false,
+        });
+    }
+
+    fn initializer(&mut self, initializer: Option<&Expr>, dst: Reg) {
+        match initializer {
+            Some(expr) => self.expr_to(expr, dst),
+            None => self.emit(Instr::LoadNil { dst }),
+        }
+    }
+
+    fn class(&mut self, class: &Class) {
+        let name = self.identifier_constant(&class.name);
+        let local = self.state().scope_depth > 0;
+        if local {
+            let dst = self.declare_local(&class.name);
+            self.emit(Instr::Class { dst, name });
+            self.mark_initialized();
+        } else {
+            let mark = self.state().next;
+            let dst = self.alloc();
+            self.emit(Instr::Class { dst, name });
+            self.emit(Instr::DefineGlobal { src: dst, name });
+            self.free_to(mark);
+        }
+
+        self.classes.push(false);
+        if let Some(superclass) = &class.superclass {
+            self.begin_scope();
+            let super_reg = self.declare_local(&synthetic(superclass, Tag::KeywordSuper));
+            self.load_variable(superclass, super_reg);
+            self.mark_initialized();
+
+            let mark = self.state().next;
+            let class_reg = self.expr_any(&variable(&class.name));
+            self.emit(Instr::Inherit {
+                class: class_reg,
+                superclass: super_reg,
+            });
+            self.free_to(mark);
+            *self.classes.last_mut().unwrap() = true;
+        }
+
+        let mark = self.state().next;
+        let class_reg = self.expr_any(&variable(&class.name));
+        for method in &class.methods {
+            let name = self.identifier_constant(&method.name);
+            let kind = if &*method.name.text == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            let method_mark = self.state().next;
+            let src = self.alloc();
+            self.function(method, kind, src);
+            self.line = method.end_line;
+            self.emit(Instr::Method {
+                class: class_reg,
+                name,
+                src,
+            });
+            self.free_to(method_mark);
+        }
+        self.free_to(mark);
+
+        if self.classes.pop().unwrap() {
+            self.end_scope();
+        }
+    }
+
+    fn function(&mut self, function: &Function, kind: FunctionKind, dst: Reg) {
+        let name = self.name_string(&function.name);
+        self.states.push(FunctionState::new(kind, Some(name)));
+        self.begin_scope();
+
+        self.state().arity = function.params.len().min(u8::MAX as usize) as u8;
+        for param in &function.params {
+            self.declare_local(param);
+            self.mark_initialized();
+        }
+        self.statements(&function.body);
+        self.line = function.end_line;
+        self.emit_return();
+
+        let state = self.states.pop().unwrap();
+        let function = self.finish(state);
+        let constant = self.make_constant(Value::Obj(function));
+        self.emit(Instr::Closure {
+            dst,
+            function: constant,
+        });
+    }
+
+    fn load_variable(&mut self, name: &Name, dst: Reg) {
+        match self.resolve(name) {
+            Variable::Local(src) if src == dst => {}
+            Variable::Local(src) => self.emit(Instr::Move { dst, src }),
+            Variable::Upvalue(index) => self.emit(Instr::GetUpvalue { dst, index }),
+            Variable::Global(name) => self.emit(Instr::GetGlobal { dst, name }),
+        }
+    }
+
+    fn literal(&mut self, literal: &Literal, dst: Reg) {
+        match literal {
+            Literal::Nil => self.emit(Instr::LoadNil { dst }),
+            Literal::Bool(value) => self.emit(Instr::LoadBool { dst, value: *value }),
+            Literal::Number(n) => {
+                let constant = self.make_constant(Value::Number(*n));
+                self.emit(Instr::LoadConstant { dst, constant });
+            }
+            Literal::String(s) => {
+                let string = self.intern(s);
+                let constant = self.make_constant(Value::Obj(string));
+                self.emit(Instr::LoadConstant { dst, constant });
+            }
+        }
+    }
+
+    /// Evaluates `expr` for its side effects only.
+    fn effect(&mut self, expr: &Expr) {
+        self.line = expr.line;
+        match &expr.kind {
+            ExprKind::Grouping(inner) => self.effect(inner),
+            ExprKind::Assign(name, value) => {
+                if let Variable::Local(dst) = self.resolve(name) {
+                    self.expr_to(value, dst);
+                } else {
+                    let dst = self.alloc();
+                    self.expr_to(expr, dst);
+                }
+            }
+            ExprKind::Set(object, name, value) => self.set_property(object, name, value, None),
+            ExprKind::SetIndex(object, index, value) => self.set_index(object, index, value, None),
+            _ => {
+                let dst = self.alloc();
+                self.expr_to(expr, dst);
+            }
+        }
+    }
+
+    /// Evaluates `expr` into a register, which is a local's own register for plain variable
+    /// reads. Temporaries it allocates stay allocated.
+    fn expr_any(&mut self, expr: &Expr) -> Reg {
+        match &expr.kind {
+            ExprKind::Grouping(inner) => return self.expr_any(inner),
+            ExprKind::Variable(name) => {
+                if let Variable::Local(reg) = self.resolve(name) {
+                    return reg;
+                }
+            }
+            ExprKind::This => {
+                if let Variable::Local(reg) = self.resolve(&this(expr)) {
+                    return reg;
+                }
+            }
+            _ => {}
+        }
+        let dst = self.alloc();
+        self.expr_to(expr, dst);
+        dst
+    }
+
+    /// Like [`Compiler::expr_any`] for an operand evaluated before `rest`. A local read directly
+    /// from its register could change while `rest` runs, so it is copied unless `rest` is pure.
+    fn operand(&mut self, expr: &Expr, rest: &[&Expr]) -> Reg {
+        if rest.iter().all(|expr| is_pure(expr)) {
+            return self.expr_any(expr);
+        }
+        let dst = self.alloc();
+        self.expr_to(expr, dst);
+        dst
+    }
+
+    fn expr_to(&mut self, expr: &Expr, dst: Reg) {
+        self.line = expr.line;
+        if let ExprKind::Unary(..) | ExprKind::Binary(..) | ExprKind::Logical(..) = expr.kind {
+            if let Some(folded) = optimizer::fold(expr) {
+                self.literal(&folded, dst);
+                return;
+            }
+        }
+
+        let mark = self.state().next;
+        match &expr.kind {
+            ExprKind::Literal(literal) => self.literal(literal, dst),
+            ExprKind::Variable(name) => self.load_variable(name, dst),
+            ExprKind::Assign(name, value) => match self.resolve(name) {
+                Variable::Local(local) => {
+                    self.expr_to(value, local);
+                    if local != dst {
+                        self.emit(Instr::Move { dst, src: local });
+                    }
+                }
+                Variable::Upvalue(index) => {
+                    self.expr_to(value, dst);
+                    self.line = expr.line;
+                    self.emit(Instr::SetUpvalue { src: dst, index });
+                }
+                Variable::Global(name) => {
+                    self.expr_to(value, dst);
+                    self.line = expr.line;
+                    self.emit(Instr::SetGlobal { src: dst, name });
+                }
+            },
+            ExprKind::Unary(op, right) => {
+                let src = self.expr_any(right);
+                self.line = expr.line;
+                match op {
+                    UnaryOp::Negate => self.emit(Instr::Negate { dst, src }),
+                    UnaryOp::Not => self.emit(Instr::Not { dst, src }),
+                }
+            }
+            ExprKind::Binary(left, op, right) => {
+                let a = self.operand(left, &[right]);
+                let b = self.expr_any(right);
+                self.line = expr.line;
+                self.emit(match op {
+                    BinaryOp::Add => Instr::Add { dst, a, b },
+                    BinaryOp::Subtract => Instr::Subtract { dst, a, b },
+                    BinaryOp::Multiply => Instr::Multiply { dst, a, b },
+                    BinaryOp::Divide => Instr::Divide { dst, a, b },
+                    BinaryOp::Equal => Instr::Equal { dst, a, b },
+                    BinaryOp::NotEqual => Instr::NotEqual { dst, a, b },
+                    BinaryOp::Greater => Instr::Greater { dst, a, b },
+                    BinaryOp::GreaterEqual => Instr::GreaterEqual { dst, a, b },
+                    BinaryOp::Less => Instr::Less { dst, a, b },
+                    BinaryOp::LessEqual => Instr::LessEqual { dst, a, b },
+                });
+            }
+            ExprKind::Logical(left, op, right) => {
+                // The left value lands in `dst` before the right side runs
+                if !self.is_scratch(dst) {
+                    let temp = self.alloc();
+                    self.expr_to(expr, temp);
+                    self.emit(Instr::Move { dst, src: temp });
+                } else {
+                    self.expr_to(left, dst);
+                    self.line = expr.line;
+                    let end_jump = self.emit_jump(match op {
+                        LogicalOp::And => Instr::JumpIfFalse {
+                            cond: dst,
+                            target: 0,
+                        },
+                        LogicalOp::Or => Instr::JumpIfTrue {
+                            cond: dst,
+                            target: 0,
+                        },
+                    });
+                    self.expr_to(right, dst);
+                    self.patch_jump(end_jump);
+                }
+            }
+            ExprKind::Grouping(inner) => self.expr_to(inner, dst),
+            ExprKind::Call(callee, arguments) => self.call(expr, callee, arguments, dst),
+            ExprKind::Get(object, name) => {
+                let object = self.expr_any(object);
+                self.line = expr.line;
+                let name = self.identifier_constant(name);
+                self.emit(Instr::GetProperty { dst, object, name });
+            }
+            ExprKind::Set(object, name, value) => self.set_property(object, name, value, Some(dst)),
+            ExprKind::This => self.load_variable(&this(expr), dst),
+            ExprKind::Super(method) => {
+                let object = self.expr_any(&Expr {
+                    kind: ExprKind::This,
+                    ..expr.clone()
+                });
+                let superclass = self.expr_any(&variable(&synthetic(method, Tag::KeywordSuper)));
+                let name = self.identifier_constant(method);
+                self.emit(Instr::GetSuper {
+                    dst,
+                    object,
+                    superclass,
+                    name,
+                });
+            }
+            ExprKind::List(items) => {
+                let start = self.consecutive(items.iter());
+                self.line = expr.line;
+                let count = self.count(items.len());
+                self.emit(Instr::BuildList { dst, start, count });
+            }
+            ExprKind::Map(entries) => {
+                let start = self.consecutive(entries.iter().flat_map(|(key, value)| [key, value]));
+                self.line = expr.line;
+                let count = self.count(entries.len());
+                self.emit(Instr::BuildMap { dst, start, count });
+            }
+            ExprKind::Index(object, index) => {
+                let object = self.operand(object, &[index]);
+                let index = self.expr_any(index);
+                self.line = expr.line;
+                self.emit(Instr::GetIndex { dst, object, index });
+            }
+            ExprKind::SetIndex(object, index, value) => {
+                self.set_index(object, index, value, Some(dst))
+            }
+        }
+        self.free_to(mark);
+    }
+
+    fn count(&mut self, len: usize) -> u8 {
+        if len > u8::MAX as usize {
+            self.error_at_line("Too many registers needed in function.");
+            return 0;
+        }
+        len as u8
+    }
+
+    /// Evaluates `exprs` into consecutive new registers and returns the first.
+    fn consecutive<'e>(&mut self, exprs: impl Iterator<Item = &'e Expr>) -> Reg {
+        let start = self.state().next as Reg;
+        for expr in exprs {
+            let reg = self.alloc();
+            self.expr_to(expr, reg);
+            self.free_to(reg as usize + 1);
+        }
+        start
+    }
+
+    fn set_property(&mut self, object: &Expr, name: &Name, value: &Expr, dst: Option<Reg>) {
+        let mark = self.state().next;
+        let object = self.operand(object, &[value]);
+        let src = self.expr_any(value);
+        let name = self.identifier_constant(name);
+        self.emit(Instr::SetProperty { object, name, src });
+        if let Some(dst) = dst.filter(|&dst| dst != src) {
+            self.emit(Instr::Move { dst, src });
+        }
+        self.free_to(mark);
+    }
+
+    fn set_index(&mut self, object: &Expr, index: &Expr, value: &Expr, dst: Option<Reg>) {
+        let mark = self.state().next;
+        let object = self.operand(object, &[index, value]);
+        let index = self.operand(index, &[value]);
+        let src = self.expr_any(value);
+        self.emit(Instr::SetIndex { object, index, src });
+        if let Some(dst) = dst.filter(|&dst| dst != src) {
+            self.emit(Instr::Move { dst, src });
+        }
+        self.free_to(mark);
+    }
+
+    fn call(&mut self, expr: &Expr, callee: &Expr, arguments: &[Expr], dst: Reg) {
+        let mark = self.state().next;
+        // Call straight into `dst` when nothing above it is in use
+        let base = if self.is_scratch(dst) && dst as usize + 1 == mark {
+            dst
+        } else {
+            self.alloc()
+        };
+        let argc = arguments.len().min(u8::MAX as usize) as u8;
+
+        match &callee.kind {
+            ExprKind::Get(object, name) => {
+                self.expr_to(object, base);
+                self.consecutive(arguments.iter());
+                self.line = expr.line;
+                let name = self.identifier_constant(name);
+                self.emit(Instr::Invoke { base, name, argc });
+            }
+            ExprKind::Super(method) => {
+                self.load_variable(&this(callee), base);
+                self.consecutive(arguments.iter());
+                let superclass = self.expr_any(&variable(&synthetic(method, Tag::KeywordSuper)));
+                self.line = expr.line;
+                let name = self.identifier_constant(method);
+                self.emit(Instr::SuperInvoke {
+                    base,
+                    name,
+                    argc,
+                    superclass,
+                });
+            }
+            _ => {
+                self.expr_to(callee, base);
+                self.consecutive(arguments.iter());
+                self.line = expr.line;
+                self.emit(Instr::Call { base, argc });
+            }
+        }
+        if base != dst {
+            self.emit(Instr::Move { dst, src: base });
+        }
+        self.free_to(mark);
+    }
+}
+
+/// Whether evaluating `expr` can't assign to any variable.
+fn is_pure(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Literal(_) | ExprKind::Variable(_) | ExprKind::This | ExprKind::Super(_) => true,
+        ExprKind::Unary(_, inner) | ExprKind::Grouping(inner) | ExprKind::Get(inner, _) => {
+            is_pure(inner)
+        }
+        ExprKind::Binary(left, _, right)
+        | ExprKind::Logical(left, _, right)
+        | ExprKind::Index(left, right) => is_pure(left) && is_pure(right),
+        ExprKind::List(items) => items.iter().all(is_pure),
+        ExprKind::Map(entries) => entries
+            .iter()
+            .all(|(key, value)| is_pure(key) && is_pure(value)),
+        ExprKind::Assign(..) | ExprKind::Call(..) | ExprKind::Set(..) | ExprKind::SetIndex(..) => {
+            false
+        }
+    }
+}
+
+fn variable(name: &Name) -> Expr {
+    Expr {
+        kind: ExprKind::Variable(name.clone()),
+        loc: name.loc,
+        line: name.line,
+    }
+}
+
+fn this(expr: &Expr) -> Name {
+    Name {
+        text: "this".into(),
+        symbol: Tag::KeywordThis.keyword_symbol().unwrap(),
+        loc: expr.loc,
+        line: expr.line,
+    }
+}
+
+/// A name for the hidden `super` local, located at `like`.
+fn synthetic(like: &Name, keyword: Tag) -> Name {
+    let text = match keyword {
+        Tag::KeywordThis => "this",
+        _ => "super",
+    };
+    Name {
+        text: text.into(),
+        symbol: keyword.keyword_symbol().unwrap(),
+        ..like.clone()
+    }
+}
@@ -0,0 +1,919 @@
+use crate::ast::Stmt;
+use crate::chunk::Chunk;
+use crate::gc::{Heap, Trace};
+use crate::natives::{self, Output};
+use crate::object::*;
+use crate::parser::{self, Dialect};
+use crate::register::{Instr, RegisterChunk};
+use crate::register_compiler;
+use crate::table::Table;
+use crate::value::Value;
+use crate::vm::{self, BuiltinType, InterpretError, RuntimeError, FRAMES_MAX};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+struct CallFrame {
+    closure: ObjRef,
+    code: Rc<RegisterChunk>,
+    constants: Rc<Chunk>,
+    ip: usize,
+    /// Index of register zero in the register file.
+    base: usize,
+    /// End of the highest register window used by this frame or any frame below it. A callee's
+    /// window can end before its caller's, so returning truncates to this rather than to the
+    /// caller's own window.
+    top: usize,
+}
+
+/// Where a `throw` inside a `try` lands.
+struct Handler {
+    /// Number of frames, the last one being the frame with the `try`.
+    frame: usize,
+    /// Absolute index of the register receiving the thrown value.
+    register: usize,
+    ip: usize,
+}
+
+struct Roots<'a> {
+    registers: &'a [Value],
+    frames: &'a [CallFrame],
+    globals: &'a Table,
+    open_upvalues: &'a [ObjRef],
+    init_string: ObjRef,
+    error_class: ObjRef,
+    builtin_methods: &'a [Table; 2],
+}
+
+impl Trace for Roots<'_> {
+    fn trace(&self, heap: &mut Heap) {
+        self.registers.trace(heap);
+        for frame in self.frames {
+            heap.mark_object(frame.closure);
+        }
+        self.globals.trace(heap);
+        self.open_upvalues.trace(heap);
+        heap.mark_object(self.init_string);
+        heap.mark_object(self.error_class);
+        self.builtin_methods.trace(heap);
+    }
+}
+
+/// Register-based counterpart of [`vm::Vm`] with the same heap, natives and error messages.
+/// Each frame owns a window of the register file, and calls pass arguments in the registers
+/// right after the callee, which become the callee's parameters.
+pub struct RegisterVm {
+    heap: Heap,
+    registers: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: Table,
+    open_upvalues: Vec<ObjRef>,
+    handlers: Vec<Handler>,
+    init_string: ObjRef,
+    error_class: ObjRef,
+    builtin_methods: [Table; 2],
+    dialect: Dialect,
+    instructions: u64,
+    out: Output,
+}
+
+impl Default for RegisterVm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegisterVm {
+    pub fn new() -> Self {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let error_name = heap.intern("Error");
+        let error_class = heap.alloc(Obj::Class(ObjClass {
+            name: error_name,
+            methods: Table::new(),
+        }));
+        let mut vm = RegisterVm {
+            heap,
+            registers: Vec::with_capacity(FRAMES_MAX * 256),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            globals: Table::new(),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            init_string,
+            error_class,
+            builtin_methods: [Table::new(), Table::new()],
+            dialect: Dialect::Lox,
+            instructions: 0,
+            out: Rc::new(RefCell::new(Box::new(io::stdout()))),
+        };
+        natives::define_core(&mut vm);
+        natives::define_io(&mut vm);
+        vm
+    }
+
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        *self.out.borrow_mut() = out;
+    }
+
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// Instructions executed since the VM was created.
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions
+    }
+
+    fn alloc_native(
+        &mut self,
+        name: &'static str,
+        arity: Option<u8>,
+        function: NativeFn,
+    ) -> (ObjRef, ObjRef) {
+        let key = self.heap.intern(name);
+        // Keep the name reachable while the native is allocated
+        self.registers.push(Value::Obj(key));
+        let native = self.alloc(Obj::Native(ObjNative {
+            name,
+            arity,
+            function,
+        }));
+        self.registers.pop();
+        (key, native)
+    }
+
+    pub fn interpret(&mut self, source: &[u8]) -> Result<(), InterpretError> {
+        let program =
+            parser::parse_dialect(source, self.dialect).map_err(InterpretError::Compile)?;
+        let function = self.compile(&program)?;
+        self.run_function(function)
+    }
+
+    pub fn compile(&mut self, program: &[Stmt]) -> Result<ObjRef, InterpretError> {
+        let roots = Roots {
+            registers: &self.registers,
+            frames: &self.frames,
+            globals: &self.globals,
+            open_upvalues: &self.open_upvalues,
+            init_string: self.init_string,
+            error_class: self.error_class,
+            builtin_methods: &self.builtin_methods,
+        };
+        register_compiler::compile(program, &mut self.heap, &roots).map_err(InterpretError::Compile)
+    }
+
+    /// Runs a compiled top-level script function to completion.
+    pub fn run_function(&mut self, function: ObjRef) -> Result<(), InterpretError> {
+        self.registers.clear();
+        self.registers.push(Value::Obj(function));
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.registers[0] = Value::Obj(closure);
+
+        let result = self.call(closure, 0, 0).and_then(|_| self.run());
+        if result.is_err() {
+            self.reset();
+        }
+        Ok(result?)
+    }
+
+    pub fn collect_garbage(&mut self) -> usize {
+        let roots = Roots {
+            registers: &self.registers,
+            frames: &self.frames,
+            globals: &self.globals,
+            open_upvalues: &self.open_upvalues,
+            init_string: self.init_string,
+            error_class: self.error_class,
+            builtin_methods: &self.builtin_methods,
+        };
+        roots.trace(&mut self.heap);
+        self.heap.collect()
+    }
+
+    /// Allocates at a safe point: every value still needed must be in a register.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    fn take_string(&mut self, chars: String) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.take_string(chars)
+    }
+
+    fn reset(&mut self) {
+        self.registers.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.handlers.clear();
+    }
+
+    fn runtime_error(&self, message: String) -> RuntimeError {
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self
+                    .heap
+                    .function(self.heap.closure(frame.closure).function);
+                let line = frame.code.lines[frame.ip.saturating_sub(1)];
+                match function.name {
+                    Some(name) => format!("[line {}] in {}()", line, self.heap.str(name)),
+                    None => format!("[line {}] in script", line),
+                }
+            })
+            .collect();
+        RuntimeError { message, trace }
+    }
+
+    /// Calls the value in register `base`, an absolute index, with the `argc` registers after
+    /// it as arguments.
+    fn call_value(&mut self, base: usize, argc: usize) -> Result<(), RuntimeError> {
+        let Value::Obj(r) = self.registers[base] else {
+            return Err(self.runtime_error("Can only call functions and classes.".into()));
+        };
+
+        match self.heap.get(r) {
+            Obj::BoundMethod(bound) => {
+                let (receiver, method) = (bound.receiver, bound.method);
+                self.registers[base] = receiver;
+                match self.heap.get(method) {
+                    Obj::Native(_) => self.call_native(method, base, argc, true),
+                    _ => self.call(method, base, argc),
+                }
+            }
+            Obj::Class(class) => {
+                let initializer = class.methods.get(self.init_string);
+                let instance = self.alloc(Obj::Instance(ObjInstance {
+                    class: r,
+                    fields: Table::new(),
+                    host: None,
+                }));
+                self.registers[base] = Value::Obj(instance);
+
+                match initializer {
+                    Some(Value::Obj(init)) => self.call(init, base, argc),
+                    _ if argc != 0 => {
+                        Err(self.runtime_error(format!("Expected 0 arguments but got {}.", argc)))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Obj::Closure(_) => self.call(r, base, argc),
+            Obj::Native(_) => self.call_native(r, base, argc, false),
+            _ => Err(self.runtime_error("Can only call functions and classes.".into())),
+        }
+    }
+
+    /// Calls a native with the registers after `base`, storing the result in `base`. Methods
+    /// also get the receiver in `base` as their first argument.
+    fn call_native(
+        &mut self,
+        native: ObjRef,
+        base: usize,
+        argc: usize,
+        with_receiver: bool,
+    ) -> Result<(), RuntimeError> {
+        let Obj::Native(native) = self.heap.get(native) else {
+            unreachable!("object is not a native");
+        };
+        if let Some(arity) = native.arity {
+            if arity as usize != argc {
+                return Err(
+                    self.runtime_error(format!("Expected {} arguments but got {}.", arity, argc))
+                );
+            }
+        }
+
+        let function = native.function.clone();
+        let args_start = if with_receiver { base } else { base + 1 };
+        let result = function(&mut self.heap, &self.registers[args_start..base + 1 + argc])
+            .map_err(|m| self.runtime_error(m))?;
+        self.registers[base] = result;
+        Ok(())
+    }
+
+    fn builtin_type(&self, value: Value) -> Option<BuiltinType> {
+        match self.heap.get(value.as_obj()?) {
+            Obj::List(_) => Some(BuiltinType::List),
+            Obj::Map(_) => Some(BuiltinType::Map),
+            _ => None,
+        }
+    }
+
+    fn call(&mut self, closure: ObjRef, base: usize, argc: usize) -> Result<(), RuntimeError> {
+        let function = self.heap.function(self.heap.closure(closure).function);
+        if argc != function.arity as usize {
+            return Err(self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                function.arity, argc
+            )));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow.".into()));
+        }
+
+        let code = function
+            .registers
+            .clone()
+            .expect("function was compiled for the stack VM");
+        let constants = function.chunk.clone();
+        let end = base + code.registers;
+        if self.registers.len() < end {
+            self.registers.resize(end, Value::Nil);
+        }
+        let top = self.frames.last().map_or(end, |caller| caller.top.max(end));
+        self.frames.push(CallFrame {
+            closure,
+            code,
+            constants,
+            ip: 0,
+            base,
+            top,
+        });
+        Ok(())
+    }
+
+    fn invoke(&mut self, name: ObjRef, base: usize, argc: usize) -> Result<(), RuntimeError> {
+        let receiver = self.registers[base];
+        if let Some(ty) = self.builtin_type(receiver) {
+            return match self.builtin_methods[ty as usize].get(name) {
+                Some(Value::Obj(method)) => self.call_native(method, base, argc, true),
+                _ => {
+                    Err(self
+                        .runtime_error(format!("Undefined property '{}'.", self.heap.str(name))))
+                }
+            };
+        }
+        if !self.heap.is_instance(receiver) {
+            return Err(self.runtime_error("Only instances have methods.".into()));
+        }
+
+        let instance = self.heap.instance(receiver.as_obj().unwrap());
+        if let Some(field) = instance.fields.get(name) {
+            self.registers[base] = field;
+            return self.call_value(base, argc);
+        }
+
+        let class = instance.class;
+        self.invoke_from_class(class, name, base, argc)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        base: usize,
+        argc: usize,
+    ) -> Result<(), RuntimeError> {
+        match self.heap.class(class).methods.get(name) {
+            Some(Value::Obj(method)) => self.call(method, base, argc),
+            _ => Err(self.runtime_error(format!("Undefined property '{}'.", self.heap.str(name)))),
+        }
+    }
+
+    /// The method `name` of `class` bound to `receiver`, which must be in a register.
+    fn bind_method(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        receiver: Value,
+    ) -> Result<Value, RuntimeError> {
+        let Some(Value::Obj(method)) = self.heap.class(class).methods.get(name) else {
+            return Err(
+                self.runtime_error(format!("Undefined property '{}'.", self.heap.str(name)))
+            );
+        };
+        let bound = self.alloc(Obj::BoundMethod(ObjBoundMethod { receiver, method }));
+        Ok(Value::Obj(bound))
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, &upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            match *self.heap.upvalue(upvalue) {
+                ObjUpvalue::Open(s) if s == slot => return upvalue,
+                ObjUpvalue::Open(s) if s < slot => break,
+                _ => insert_at = i,
+            }
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    /// Closes every open upvalue pointing at register `last` or above.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let ObjUpvalue::Open(slot) = *self.heap.upvalue(upvalue) else {
+                unreachable!("closed upvalue in open list");
+            };
+            if slot < last {
+                break;
+            }
+            *self.heap.upvalue_mut(upvalue) = ObjUpvalue::Closed(self.registers[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn read_upvalue(&self, closure: ObjRef, index: usize) -> Value {
+        let upvalue = self.heap.closure(closure).upvalues[index];
+        match *self.heap.upvalue(upvalue) {
+            ObjUpvalue::Open(slot) => self.registers[slot],
+            ObjUpvalue::Closed(value) => value,
+        }
+    }
+
+    fn write_upvalue(&mut self, closure: ObjRef, index: usize, value: Value) {
+        let upvalue = self.heap.closure(closure).upvalues[index];
+        match self.heap.upvalue_mut(upvalue) {
+            ObjUpvalue::Open(slot) => {
+                let slot = *slot;
+                self.registers[slot] = value;
+            }
+            ObjUpvalue::Closed(closed) => *closed = value,
+        }
+    }
+
+    /// Throws `value` to the innermost handler, or fails the run with it.
+    fn throw(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if self.handlers.is_empty() {
+            let error = self.runtime_error(self.heap.format_value(value));
+            return Err(vm::uncaught_error(
+                &mut self.heap,
+                self.error_class,
+                value,
+                error,
+            ));
+        }
+        self.unwind(value);
+        Ok(())
+    }
+
+    /// Resumes at the innermost handler with `value` in its register.
+    fn unwind(&mut self, value: Value) {
+        let handler = self.handlers.pop().expect("no handler to unwind to");
+        self.close_upvalues(handler.register);
+        self.frames.truncate(handler.frame);
+        let frame = self.frames.last_mut().unwrap();
+        frame.ip = handler.ip;
+        let top = frame.top;
+        self.registers.truncate(top);
+        self.registers[handler.register] = value;
+    }
+
+    /// Runs until the script returns, catching errors with the installed handlers.
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            let error = match self.execute() {
+                Ok(()) => return Ok(()),
+                Err(error) if self.handlers.is_empty() => return Err(error),
+                Err(error) => error,
+            };
+            let value = vm::error_object(&mut self.heap, self.error_class, &error);
+            self.unwind(value);
+        }
+    }
+
+    fn execute(&mut self) -> Result<(), RuntimeError> {
+        let frame = self.frames.last().unwrap();
+        let mut code = frame.code.clone();
+        let mut constants = frame.constants.clone();
+        let mut ip = frame.ip;
+        let mut base = frame.base;
+        let mut closure = frame.closure;
+
+        macro_rules! reg {
+            ($r:expr) => {
+                self.registers[base + $r as usize]
+            };
+        }
+        macro_rules! string {
+            ($constant:expr) => {
+                constants.constants[$constant as usize].as_obj().unwrap()
+            };
+        }
+        macro_rules! save_frame {
+            () => {
+                self.frames.last_mut().unwrap().ip = ip;
+            };
+        }
+        macro_rules! load_frame {
+            () => {{
+                let frame = self.frames.last().unwrap();
+                code = frame.code.clone();
+                constants = frame.constants.clone();
+                ip = frame.ip;
+                base = frame.base;
+                closure = frame.closure;
+            }};
+        }
+        macro_rules! throw {
+            ($($arg:tt)*) => {{
+                save_frame!();
+                return Err(self.runtime_error(format!($($arg)*)));
+            }};
+        }
+        macro_rules! numeric {
+            ($dst:expr, $a:expr, $b:expr, |$x:ident, $y:ident| $result:expr) => {{
+                match (reg!($a), reg!($b)) {
+                    (Value::Number($x), Value::Number($y)) => reg!($dst) = $result,
+                    _ => throw!("Operands must be numbers."),
+                }
+            }};
+        }
+
+        loop {
+            self.instructions += 1;
+            let instr = code.code[ip];
+            ip += 1;
+
+            match instr {
+                Instr::LoadConstant { dst, constant } => {
+                    reg!(dst) = constants.constants[constant as usize];
+                }
+                Instr::LoadNil { dst } => reg!(dst) = Value::Nil,
+                Instr::LoadBool { dst, value } => reg!(dst) = Value::Bool(value),
+                Instr::Move { dst, src } => reg!(dst) = reg!(src),
+                Instr::GetGlobal { dst, name } => {
+                    let name = string!(name);
+                    match self.globals.get(name) {
+                        Some(value) => reg!(dst) = value,
+                        None => throw!("Undefined variable '{}'.", self.heap.str(name)),
+                    }
+                }
+                Instr::DefineGlobal { src, name } => {
+                    let name = string!(name);
+                    self.globals.insert(name, reg!(src));
+                }
+                Instr::SetGlobal { src, name } => {
+                    let name = string!(name);
+                    let value = reg!(src);
+                    match self.globals.get_mut(name) {
+                        Some(slot) => *slot = value,
+                        None => throw!("Undefined variable '{}'.", self.heap.str(name)),
+                    }
+                }
+                Instr::GetUpvalue { dst, index } => {
+                    reg!(dst) = self.read_upvalue(closure, index as usize);
+                }
+                Instr::SetUpvalue { src, index } => {
+                    self.write_upvalue(closure, index as usize, reg!(src));
+                }
+                Instr::GetProperty { dst, object, name } => {
+                    let name = string!(name);
+                    let receiver = reg!(object);
+                    if let Some(ty) = self.builtin_type(receiver) {
+                        let Some(Value::Obj(method)) = self.builtin_methods[ty as usize].get(name)
+                        else {
+                            throw!("Undefined property '{}'.", self.heap.str(name));
+                        };
+                        save_frame!();
+                        let bound =
+                            self.alloc(Obj::BoundMethod(ObjBoundMethod { receiver, method }));
+                        reg!(dst) = Value::Obj(bound);
+                        continue;
+                    }
+                    if !self.heap.is_instance(receiver) {
+                        throw!("Only instances have properties.");
+                    }
+
+                    let instance = self.heap.instance(receiver.as_obj().unwrap());
+                    if let Some(value) = instance.fields.get(name) {
+                        reg!(dst) = value;
+                    } else {
+                        let class = instance.class;
+                        save_frame!();
+                        reg!(dst) = self.bind_method(class, name, receiver)?;
+                    }
+                }
+                Instr::SetProperty { object, name, src } => {
+                    let name = string!(name);
+                    let receiver = reg!(object);
+                    if !self.heap.is_instance(receiver) {
+                        throw!("Only instances have fields.");
+                    }
+                    let value = reg!(src);
+                    let instance = receiver.as_obj().unwrap();
+                    let old_size = self.heap.get(instance).size();
+                    self.heap.instance_mut(instance).fields.insert(name, value);
+                    self.heap.resized(instance, old_size);
+                }
+                Instr::GetSuper {
+                    dst,
+                    object,
+                    superclass,
+                    name,
+                } => {
+                    let name = string!(name);
+                    let superclass = reg!(superclass).as_obj().unwrap();
+                    save_frame!();
+                    reg!(dst) = self.bind_method(superclass, name, reg!(object))?;
+                }
+                Instr::Equal { dst, a, b } => reg!(dst) = Value::Bool(reg!(a) == reg!(b)),
+                Instr::NotEqual { dst, a, b } => reg!(dst) = Value::Bool(reg!(a) != reg!(b)),
+                Instr::Greater { dst, a, b } => numeric!(dst, a, b, |x, y| Value::Bool(x > y)),
+                Instr::GreaterEqual { dst, a, b } => {
+                    // `!(x < y)` like the stack VM, which differs from `x >= y` for NaN
+                    numeric!(dst, a, b, |x, y| Value::Bool(
+                        x.partial_cmp(&y) != Some(std::cmp::Ordering::Less)
+                    ))
+                }
+                Instr::Less { dst, a, b } => numeric!(dst, a, b, |x, y| Value::Bool(x < y)),
+                Instr::LessEqual { dst, a, b } => {
+                    numeric!(dst, a, b, |x, y| Value::Bool(
+                        x.partial_cmp(&y) != Some(std::cmp::Ordering::Greater)
+                    ))
+                }
+                Instr::Add { dst, a, b } => match (reg!(a), reg!(b)) {
+                    (Value::Number(x), Value::Number(y)) => reg!(dst) = Value::Number(x + y),
+                    (x, y) => match (self.heap.as_string(x), self.heap.as_string(y)) {
+                        (Some(x), Some(y)) => {
+                            let mut result = String::with_capacity(x.len() + y.len());
+                            result.push_str(x);
+                            result.push_str(y);
+                            // Operands stay in their registers until the result is allocated
+                            let string = self.take_string(result);
+                            reg!(dst) = Value::Obj(string);
+                        }
+                        _ => throw!("Operands must be two numbers or two strings."),
+                    },
+                },
+                Instr::Subtract { dst, a, b } => numeric!(dst, a, b, |x, y| Value::Number(x - y)),
+                Instr::Multiply { dst, a, b } => numeric!(dst, a, b, |x, y| Value::Number(x * y)),
+                Instr::Divide { dst, a, b } => numeric!(dst, a, b, |x, y| Value::Number(x / y)),
+                Instr::Not { dst, src } => reg!(dst) = Value::Bool(reg!(src).is_falsey()),
+                Instr::Negate { dst, src } => match reg!(src) {
+                    Value::Number(n) => reg!(dst) = Value::Number(-n),
+                    _ => throw!("Operand must be a number."),
+                },
+                Instr::Print { src } => {
+                    let text = self.heap.format_value(reg!(src));
+                    if let Err(e) = writeln!(self.out.borrow_mut(), "{}", text) {
+                        throw!("Failed to print: {}.", e);
+                    }
+                }
+                Instr::Jump { target } => ip = target as usize,
+                Instr::JumpIfFalse { cond, target } => {
+                    if reg!(cond).is_falsey() {
+                        ip = target as usize;
+                    }
+                }
+                Instr::JumpIfTrue { cond, target } => {
+                    if !reg!(cond).is_falsey() {
+                        ip = target as usize;
+                    }
+                }
+                Instr::Call { base: callee, argc } => {
+                    save_frame!();
+                    self.call_value(base + callee as usize, argc as usize)?;
+                    load_frame!();
+                }
+                Instr::Invoke {
+                    base: receiver,
+                    name,
+                    argc,
+                } => {
+                    let name = string!(name);
+                    save_frame!();
+                    self.invoke(name, base + receiver as usize, argc as usize)?;
+                    load_frame!();
+                }
+                Instr::SuperInvoke {
+                    base: receiver,
+                    name,
+                    argc,
+                    superclass,
+                } => {
+                    let name = string!(name);
+                    let superclass = reg!(superclass).as_obj().unwrap();
+                    save_frame!();
+                    self.invoke_from_class(
+                        superclass,
+                        name,
+                        base + receiver as usize,
+                        argc as usize,
+                    )?;
+                    load_frame!();
+                }
+                Instr::Closure { dst, function } => {
+                    let function = constants.constants[function as usize].as_obj().unwrap();
+                    let sources = self.heap.function(function).registers.clone().unwrap();
+                    let new_closure = self.alloc(Obj::Closure(ObjClosure {
+                        function,
+                        upvalues: Vec::with_capacity(sources.upvalues.len()),
+                    }));
+                    // Root the closure before capturing, capturing may allocate
+                    reg!(dst) = Value::Obj(new_closure);
+
+                    for source in &sources.upvalues {
+                        let upvalue = if source.is_local {
+                            self.capture_upvalue(base + source.index as usize)
+                        } else {
+                            self.heap.closure(closure).upvalues[source.index as usize]
+                        };
+                        self.heap.closure_mut(new_closure).upvalues.push(upvalue);
+                    }
+                }
+                Instr::CloseUpvalues { from } => self.close_upvalues(base + from as usize),
+                Instr::Return { src } => {
+                    let result = reg!(src);
+                    self.close_upvalues(base);
+                    self.frames.pop();
+                    let Some(caller) = self.frames.last() else {
+                        self.registers.clear();
+                        return Ok(());
+                    };
+
+                    let top = caller.top;
+                    self.registers[base] = result;
+                    self.registers.truncate(top);
+                    load_frame!();
+                }
+                Instr::Class { dst, name } => {
+                    let name = string!(name);
+                    let class = self.alloc(Obj::Class(ObjClass {
+                        name,
+                        methods: Table::new(),
+                    }));
+                    reg!(dst) = Value::Obj(class);
+                }
+                Instr::Inherit { class, superclass } => {
+                    let superclass = reg!(superclass);
+                    if !self.heap.is_class(superclass) {
+                        throw!("Superclass must be a class.");
+                    }
+
+                    // Copy-down inheritance, methods defined later override these
+                    let methods = self
+                        .heap
+                        .class(superclass.as_obj().unwrap())
+                        .methods
+                        .clone();
+                    let subclass = reg!(class).as_obj().unwrap();
+                    self.heap.class_mut(subclass).methods.extend_from(&methods);
+                }
+                Instr::Method { class, name, src } => {
+                    let name = string!(name);
+                    let class = reg!(class).as_obj().unwrap();
+                    let method = reg!(src);
+                    self.heap.class_mut(class).methods.insert(name, method);
+                }
+                Instr::BuildList { dst, start, count } => {
+                    let start = base + start as usize;
+                    let items = self.registers[start..start + count as usize].to_vec();
+                    save_frame!();
+                    let list = self.alloc(Obj::List(items));
+                    reg!(dst) = Value::Obj(list);
+                }
+                Instr::BuildMap { dst, start, count } => {
+                    let start = base + start as usize;
+                    let mut map = ObjMap::default();
+                    for pair in self.registers[start..start + 2 * count as usize].chunks_exact(2) {
+                        if matches!(pair[0], Value::Number(n) if n.is_nan()) {
+                            throw!("Map key can't be NaN.");
+                        }
+                        map.insert(pair[0], pair[1]);
+                    }
+                    save_frame!();
+                    let map = self.alloc(Obj::Map(map));
+                    reg!(dst) = Value::Obj(map);
+                }
+                Instr::GetIndex { dst, object, index } => {
+                    if self.heap.should_collect() {
+                        self.collect_garbage();
+                    }
+                    match vm::get_index(&mut self.heap, reg!(object), reg!(index)) {
+                        Ok(value) => reg!(dst) = value,
+                        Err(message) => throw!("{}", message),
+                    }
+                }
+                Instr::SetIndex { object, index, src } => {
+                    if let Err(message) =
+                        vm::set_index(&mut self.heap, reg!(object), reg!(index), reg!(src))
+                    {
+                        throw!("{}", message);
+                    }
+                }
+                Instr::PushHandler { dst, target } => self.handlers.push(Handler {
+                    frame: self.frames.len(),
+                    register: base + dst as usize,
+                    ip: target as usize,
+                }),
+                Instr::PopHandler => {
+                    self.handlers.pop();
+                }
+                Instr::Throw { src } => {
+                    save_frame!();
+                    self.throw(reg!(src))?;
+                    load_frame!();
+                }
+            }
+        }
+    }
+}
+
+impl natives::Host for RegisterVm {
+    fn output(&self) -> Output {
+        self.out.clone()
+    }
+
+    fn define_native(
+        &mut self,
+        name: &'static str,
+        arity: Option<u8>,
+        function: impl Fn(&mut Heap, &[Value]) -> Result<Value, String> + 'static,
+    ) {
+        let (key, native) = self.alloc_native(name, arity, Rc::new(function));
+        self.globals.insert(key, Value::Obj(native));
+    }
+
+    fn define_builtin_method(
+        &mut self,
+        ty: BuiltinType,
+        name: &'static str,
+        arity: Option<u8>,
+        function: impl Fn(&mut Heap, &[Value]) -> Result<Value, String> + 'static,
+    ) {
+        let (key, native) = self.alloc_native(name, arity, Rc::new(function));
+        self.builtin_methods[ty as usize].insert(key, Value::Obj(native));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::OutputBuffer;
+    use crate::vm::Vm;
+
+    /// Output followed by the error, if any, from the stack VM and then the register VM.
+    fn run_both(source: &str, stress: bool) -> (String, String) {
+        let stack_output = OutputBuffer::new();
+        let mut stack = Vm::new();
+        stack.set_output(Box::new(stack_output.clone()));
+        stack.set_dialect(Dialect::LoxPlus);
+        stack.heap_mut().set_stress(stress);
+        let stack_result = stack.interpret(source.as_bytes());
+
+        let register_output = OutputBuffer::new();
+        let mut register = RegisterVm::new();
+        register.set_output(Box::new(register_output.clone()));
+        register.set_dialect(Dialect::LoxPlus);
+        register.heap_mut().set_stress(stress);
+        let register_result = register.interpret(source.as_bytes());
+
+        let text = |output: &OutputBuffer, result: Result<(), InterpretError>| {
+            let mut text = output.contents();
+            if let Err(e) = result {
+                text.push_str(&e.to_string());
+            }
+            text
+        };
+        (
+            text(&stack_output, stack_result),
+            text(&register_output, register_result),
+        )
+    }
+
+    const CORPUS: [&str; 12] = [
+        "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(15);",
+        "var a = 1; var b = 2; { var a = 3; var c = a + b; print c; a = a + (a = 10); print a; } print a;",
+        "fun counter() { var n = 0; fun inc() { n = n + 1; return n; } return inc; } var c = counter(); c(); print c(); var fs = []; for (var i = 0; i < 3; i = i + 1) { fun f() { return i; } fs.push(f); } print fs[0]() + fs[2]();",
+        "class A { init(x) { this.x = x; } get() { return this.x; } } class B < A { init(x) { super.init(x * 2); } get() { return super.get() + 1; } } var b = B(4); print b.get(); var m = b.get; print m(); print B;",
+        "var x = nil; print x or \"default\"; print x and 1; var y = 2; y = y or 3; print y; y = nil; y = y or y; print y; print !y == true;",
+        "var l = [1, \"two\", [3]]; l[0] = l[0] + 1; print l; var m = {\"k\": 1}; m[\"j\"] = m[\"k\"] + 1; print m; print m.keys(); print len(l) + l.len();",
+        "print \"a\" + \"b\"; print 1 / 0 > 0; print (0/0) >= 0; print 2 <= 2; print 1 != 2; print str(3) + \"!\";",
+        "fun f(a, b) { return a - b; } print f(1);",
+        "class P {} var p = P(); print p.missing;",
+        "var s = \"\"; for (var i = 0; i < 50; i = i + 1) { s = s + str(i); var t = [s, {i: s}]; } print len(s);",
+        "fun g() { var a = 1; var b = 2; var c = 3; var d = 4; return a + b + c + d; } fun f() { return g(); } { var x = 1; var y = 2; var z = 3; var w = 4; var r = f(); print x + y + z + w + r; }",
+        "fun f(n) { try { if (n > 0) return f(n - 1); throw [n]; } finally { print n; } } try { f(2); } catch (e) { print e; } try { nil(); } catch (e) { print e.message + str(len(e.stack)); }",
+    ];
+
+    #[test]
+    fn test_register_vm_matches_stack_vm() {
+        for source in CORPUS {
+            for stress in [false, true] {
+                let (stack, register) = run_both(source, stress);
+                assert_eq!(register, stack, "{}", source);
+            }
+        }
+    }
+}
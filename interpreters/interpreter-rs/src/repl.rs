@@ -0,0 +1,196 @@
+use crate::ast::{Stmt, StmtKind};
+use crate::disassembler::disassemble;
+use crate::parser::{self, Dialect};
+use crate::vm::{InterpretError, Vm};
+use lexer::with_opt_iterator::{keyword_interner, Tag, Tokenizer};
+use std::fmt::Write;
+
+pub const HELP: &str = "\
+Enter declarations and statements to run them. A bare expression without a trailing ';' prints
+its value. Unclosed braces, parentheses or strings continue on the next line, an empty line
+submits the entry as is.
+
+  :tokens <code>    show the tokens of <code>
+  :ast <code>       show the syntax tree of <code>
+  :bytecode <code>  show the compiled bytecode of <code> without running it
+  :help             show this help
+  :quit             exit (or Ctrl+D)";
+
+/// Line-oriented driver around a [`Vm`] whose globals persist across entries.
+pub struct Repl {
+    vm: Vm,
+    pending: String,
+}
+
+impl Repl {
+    pub fn new(vm: Vm) -> Self {
+        Repl {
+            vm,
+            pending: String::new(),
+        }
+    }
+
+    pub fn vm_mut(&mut self) -> &mut Vm {
+        &mut self.vm
+    }
+
+    /// True while an incomplete entry is waiting for more lines.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Drops the incomplete entry, e.g. on Ctrl+C.
+    pub fn clear_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Adds one line of input and returns the whole entry once it is complete.
+    pub fn push_line(&mut self, line: &str) -> Option<String> {
+        if line.trim().is_empty() && !self.is_pending() {
+            return None;
+        }
+        self.pending.push_str(line);
+        self.pending.push('\n');
+
+        let code = match split_command(&self.pending) {
+            Some((_, code)) => code,
+            None => &self.pending,
+        };
+        if line.trim().is_empty() || is_complete(code) {
+            Some(std::mem::take(&mut self.pending))
+        } else {
+            None
+        }
+    }
+
+    /// Runs an entry or meta-command. Meta-commands return their output, code prints through
+    /// the VM.
+    pub fn eval(&mut self, entry: &str) -> Result<String, InterpretError> {
+        let Some((command, code)) = split_command(entry) else {
+            let program = parse_entry(entry, self.vm.dialect())?;
+            let function = self.vm.compile(&program)?;
+            self.vm.run_function(function)?;
+            return Ok(String::new());
+        };
+
+        match command {
+            "tokens" => Ok(show_tokens(code, self.vm.dialect())),
+            "ast" => {
+                let program = parse_entry(code, self.vm.dialect())?;
+                let lines: Vec<String> = program.iter().map(|stmt| stmt.to_string()).collect();
+                Ok(lines.join("\n"))
+            }
+            "bytecode" => {
+                let program = parse_entry(code, self.vm.dialect())?;
+                let function = self.vm.compile(&program)?;
+                let text = disassemble(self.vm.heap(), function);
+                Ok(text.trim_end().to_string())
+            }
+            "help" => Ok(HELP.to_string()),
+            _ => Ok(format!("Unknown command ':{}'. Try :help.", command)),
+        }
+    }
+}
+
+/// Splits `:command rest` into its parts, or returns `None` for plain code.
+fn split_command(entry: &str) -> Option<(&str, &str)> {
+    let rest = entry.trim_start().strip_prefix(':')?;
+    let end = rest.find(|c: char| c.is_whitespace()).unwrap_or(rest.len());
+    Some((&rest[..end], &rest[end..]))
+}
+
+/// Parses an entry, accepting a final expression without its `;` and printing its value.
+fn parse_entry(source: &str, dialect: Dialect) -> Result<Vec<Stmt>, InterpretError> {
+    let errors = match parser::parse_dialect(source.as_bytes(), dialect) {
+        Ok(program) => return Ok(program),
+        Err(errors) => errors,
+    };
+
+    let terminated = format!("{};", source.trim_end());
+    if let Ok(mut program) = parser::parse_dialect(terminated.as_bytes(), dialect) {
+        if let Some(last) = program.last_mut() {
+            if let StmtKind::Expression(expr) = &last.kind {
+                last.kind = StmtKind::Print(expr.clone());
+                return Ok(program);
+            }
+        }
+    }
+    Err(InterpretError::Compile(errors))
+}
+
+/// Whether `source` can be run as is: every brace and parenthesis is closed and no string is
+/// left open.
+pub fn is_complete(source: &str) -> bool {
+    let mut depth = 0i32;
+    for token in Tokenizer::new(source.as_bytes()) {
+        match token.tag {
+            Tag::LeftBrace | Tag::LeftParen | Tag::LeftBracket => depth += 1,
+            Tag::RightBrace | Tag::RightParen | Tag::RightBracket => depth -= 1,
+            Tag::Invalid if source.as_bytes()[token.loc.start] == b'"' => return false,
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+fn show_tokens(code: &str, dialect: Dialect) -> String {
+    let mut interner = keyword_interner();
+    let tokens = parser::tokenize(code.as_bytes(), &dialect.lexer_config(), &mut interner);
+    let mut out = String::new();
+    for lexeme in tokens {
+        let token = lexeme.token;
+        if token.tag == Tag::Eof {
+            break;
+        }
+        let _ = writeln!(
+            out,
+            "{:4} {:<16} '{}'",
+            lexeme.line,
+            format!("{:?}", token.tag),
+            &code[token.loc.start..token.loc.end]
+        );
+    }
+    out.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::OutputBuffer;
+
+    #[test]
+    fn test_multi_line_entries_and_auto_print() {
+        let output = OutputBuffer::new();
+        let mut vm = Vm::new();
+        vm.set_output(Box::new(output.clone()));
+        let mut repl = Repl::new(vm);
+
+        assert_eq!(repl.push_line("fun add(a, b) {"), None);
+        assert_eq!(repl.push_line("  return a + b;"), None);
+        let entry = repl.push_line("}").unwrap();
+        assert_eq!(repl.eval(&entry).unwrap(), "");
+
+        assert_eq!(repl.push_line("var s = \"multi"), None);
+        let entry = repl.push_line("line\";").unwrap();
+        repl.eval(&entry).unwrap();
+
+        // Globals persist between entries and a bare expression prints its value
+        repl.eval("add(1, 2)").unwrap();
+        repl.eval("s;").unwrap();
+        assert!(repl.eval("add(1,").is_err());
+        assert_eq!(output.contents(), "3\n");
+
+        assert_eq!(
+            repl.eval(":ast 1 + 2 * x").unwrap(),
+            "(print (+ 1 (* 2 x)))"
+        );
+        assert_eq!(
+            repl.eval(":tokens var x").unwrap(),
+            "   1 KeywordVar       'var'\n   1 Identifier       'x'"
+        );
+        assert!(repl
+            .eval(":bytecode add(1, 2)")
+            .unwrap()
+            .starts_with("== <script> ==\n0000    1 GetGlobal"));
+    }
+}
@@ -0,0 +1,455 @@
+use crate::ast::*;
+use lexer::with_opt_iterator::Loc;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Variable,
+    Parameter,
+    Function,
+    Class,
+}
+
+/// What a variable holds as far as can be told without running the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    Nil,
+    Bool,
+    Number,
+    String,
+    List,
+    Map,
+    Function,
+    Class,
+    Instance,
+    Unknown,
+}
+
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ValueKind::Nil => "nil",
+            ValueKind::Bool => "bool",
+            ValueKind::Number => "number",
+            ValueKind::String => "string",
+            ValueKind::List => "list",
+            ValueKind::Map => "map",
+            ValueKind::Function => "function",
+            ValueKind::Class => "class",
+            ValueKind::Instance => "instance",
+            ValueKind::Unknown => "unknown",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: Rc<str>,
+    pub loc: Loc,
+    pub line: u32,
+    pub kind: SymbolKind,
+    pub value: ValueKind,
+    /// For instances, the class they were created from.
+    pub class: Option<usize>,
+    /// Declared at the top level rather than in a block or function.
+    pub global: bool,
+    /// An enclosing declaration of the same name this one hides.
+    pub shadows: Option<usize>,
+    /// Arguments a call must pass, for functions and for classes whose initializer is known.
+    pub arity: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reference {
+    pub loc: Loc,
+    pub definition: usize,
+    /// The use assigns to the variable instead of reading it.
+    pub write: bool,
+}
+
+/// Which declaration every variable use in a program refers to. Uses of undefined globals,
+/// natives included, have no reference.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Resolution {
+    pub definitions: Vec<Definition>,
+    pub references: Vec<Reference>,
+}
+
+impl Resolution {
+    /// The definition declared or used at byte `offset`.
+    pub fn definition_at(&self, offset: usize) -> Option<usize> {
+        let contains = |loc: &Loc| loc.start <= offset && offset < loc.end;
+        self.definitions
+            .iter()
+            .position(|definition| contains(&definition.loc))
+            .or_else(|| {
+                self.references
+                    .iter()
+                    .find(|reference| contains(&reference.loc))
+                    .map(|reference| reference.definition)
+            })
+    }
+
+    /// Uses of `definition`, in source order.
+    pub fn references_to(&self, definition: usize) -> impl Iterator<Item = Loc> + '_ {
+        self.references
+            .iter()
+            .filter(move |reference| reference.definition == definition)
+            .map(|reference| reference.loc)
+    }
+}
+
+/// Binds variable uses to declarations with Lox's scoping rules: blocks and functions are
+/// lexically scoped, globals are late bound so a function may use a global declared after it.
+pub fn resolve(program: &[Stmt]) -> Resolution {
+    let mut resolver = Resolver {
+        resolution: Resolution::default(),
+        scopes: Vec::new(),
+        globals: HashMap::new(),
+        pending_globals: Vec::new(),
+    };
+    for stmt in program {
+        resolver.statement(stmt);
+    }
+
+    for (loc, name, write) in std::mem::take(&mut resolver.pending_globals) {
+        if let Some(&definition) = resolver.globals.get(&name) {
+            resolver.resolution.references.push(Reference {
+                loc,
+                definition,
+                write,
+            });
+        }
+    }
+    resolver
+        .resolution
+        .references
+        .sort_by_key(|reference| reference.loc.start);
+    resolver.resolution
+}
+
+struct Resolver {
+    resolution: Resolution,
+    scopes: Vec<HashMap<Rc<str>, usize>>,
+    globals: HashMap<Rc<str>, usize>,
+    /// Global uses, bound once every global is known
+    pending_globals: Vec<(Loc, Rc<str>, bool)>,
+}
+
+impl Resolver {
+    fn define(
+        &mut self,
+        name: &Name,
+        kind: SymbolKind,
+        value: ValueKind,
+        class: Option<usize>,
+    ) -> usize {
+        let index = self.resolution.definitions.len();
+        let shadows = match self.scopes.split_last() {
+            Some((_, enclosing)) => enclosing
+                .iter()
+                .rev()
+                .find_map(|scope| scope.get(&name.text).copied())
+                .or_else(|| self.globals.get(&name.text).copied()),
+            None => None,
+        };
+        self.resolution.definitions.push(Definition {
+            name: name.text.clone(),
+            loc: name.loc,
+            line: name.line,
+            kind,
+            value,
+            class,
+            global: self.scopes.is_empty(),
+            shadows,
+            arity: None,
+        });
+        match self.scopes.last_mut() {
+            Some(scope) => {
+                scope.insert(name.text.clone(), index);
+            }
+            // Redefining a global keeps the first declaration as the target
+            None => {
+                self.globals.entry(name.text.clone()).or_insert(index);
+            }
+        }
+        index
+    }
+
+    fn lookup(&self, name: &Name) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name.text).copied())
+    }
+
+    fn use_name(&mut self, name: &Name, write: bool) -> Option<usize> {
+        match self.lookup(name) {
+            Some(definition) => {
+                self.resolution.references.push(Reference {
+                    loc: name.loc,
+                    definition,
+                    write,
+                });
+                Some(definition)
+            }
+            None => {
+                self.pending_globals
+                    .push((name.loc, name.text.clone(), write));
+                self.globals.get(&name.text).copied()
+            }
+        }
+    }
+
+    fn scoped(&mut self, f: impl FnOnce(&mut Self)) {
+        self.scopes.push(HashMap::new());
+        f(self);
+        self.scopes.pop();
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Expression(expr) | StmtKind::Print(expr) => self.expression(expr),
+            StmtKind::Var(name, initializer) => {
+                let (value, class) = match initializer {
+                    Some(expr) => {
+                        self.expression(expr);
+                        self.infer(expr)
+                    }
+                    None => (ValueKind::Nil, None),
+                };
+                self.define(name, SymbolKind::Variable, value, class);
+            }
+            StmtKind::Block(stmts) => self.block(stmts),
+            StmtKind::If(condition, then_branch, else_branch) => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            StmtKind::While(condition, body) => {
+                self.expression(condition);
+                self.statement(body);
+            }
+            StmtKind::Function(function) => {
+                let definition = self.define(
+                    &function.name,
+                    SymbolKind::Function,
+                    ValueKind::Function,
+                    None,
+                );
+                self.resolution.definitions[definition].arity = Some(function.params.len());
+                self.function(function);
+            }
+            StmtKind::Return(value) => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            StmtKind::Class(class) => {
+                let inherited = match &class.superclass {
+                    Some(superclass) => self
+                        .use_name(superclass, false)
+                        .and_then(|superclass| self.resolution.definitions[superclass].arity),
+                    None => Some(0),
+                };
+                let definition =
+                    self.define(&class.name, SymbolKind::Class, ValueKind::Class, None);
+                let initializer = class
+                    .methods
+                    .iter()
+                    .find(|method| method.name.text.as_ref() == "init");
+                self.resolution.definitions[definition].arity =
+                    initializer.map_or(inherited, |init| Some(init.params.len()));
+                for method in &class.methods {
+                    self.function(method);
+                }
+            }
+            StmtKind::Import(_) => {}
+            StmtKind::Export(declaration) => self.statement(declaration),
+            StmtKind::Throw(value) => self.expression(value),
+            StmtKind::Try(handler) => {
+                self.block(&handler.body);
+                if let Some((name, body)) = &handler.catch {
+                    self.scoped(|resolver| {
+                        resolver.define(name, SymbolKind::Variable, ValueKind::Unknown, None);
+                        for stmt in body {
+                            resolver.statement(stmt);
+                        }
+                    });
+                }
+                if let Some(body) = &handler.finally {
+                    self.block(body);
+                }
+            }
+        }
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        self.scoped(|resolver| {
+            for stmt in stmts {
+                resolver.statement(stmt);
+            }
+        });
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.scoped(|resolver| {
+            for param in &function.params {
+                resolver.define(param, SymbolKind::Parameter, ValueKind::Unknown, None);
+            }
+            for stmt in &function.body {
+                resolver.statement(stmt);
+            }
+        });
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Literal(_) | ExprKind::This | ExprKind::Super(_) => {}
+            ExprKind::Variable(name) => {
+                self.use_name(name, false);
+            }
+            ExprKind::Assign(name, value) => {
+                self.expression(value);
+                let (kind, class) = self.infer(value);
+                if let Some(definition) = self.use_name(name, true) {
+                    // A variable assigned values of different kinds could hold any of them
+                    let definition = &mut self.resolution.definitions[definition];
+                    if definition.value != kind || definition.class != class {
+                        definition.value = ValueKind::Unknown;
+                        definition.class = None;
+                    }
+                }
+            }
+            ExprKind::Unary(_, right) | ExprKind::Grouping(right) => self.expression(right),
+            ExprKind::Binary(left, _, right) | ExprKind::Logical(left, _, right) => {
+                self.expression(left);
+                self.expression(right);
+            }
+            ExprKind::Call(callee, arguments) => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+            }
+            ExprKind::Get(object, _) => self.expression(object),
+            ExprKind::Set(object, _, value) => {
+                self.expression(object);
+                self.expression(value);
+            }
+            ExprKind::List(items) => {
+                for item in items {
+                    self.expression(item);
+                }
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            ExprKind::Index(object, index) => {
+                self.expression(object);
+                self.expression(index);
+            }
+            ExprKind::SetIndex(object, index, value) => {
+                self.expression(object);
+                self.expression(index);
+                self.expression(value);
+            }
+        }
+    }
+
+    /// Kind of value `expr` evaluates to, and the class for instances. Only looks at the
+    /// expression itself and at declarations it names, never at control flow.
+    fn infer(&self, expr: &Expr) -> (ValueKind, Option<usize>) {
+        let kind = match &expr.kind {
+            ExprKind::Literal(Literal::Nil) => ValueKind::Nil,
+            ExprKind::Literal(Literal::Bool(_)) => ValueKind::Bool,
+            ExprKind::Literal(Literal::Number(_)) => ValueKind::Number,
+            ExprKind::Literal(Literal::String(_)) => ValueKind::String,
+            ExprKind::List(_) => ValueKind::List,
+            ExprKind::Map(_) => ValueKind::Map,
+            ExprKind::Grouping(inner) | ExprKind::Assign(_, inner) => return self.infer(inner),
+            ExprKind::Variable(name) => {
+                let definition = self
+                    .lookup(name)
+                    .or_else(|| self.globals.get(&name.text).copied());
+                return definition.map_or((ValueKind::Unknown, None), |definition| {
+                    let definition = &self.resolution.definitions[definition];
+                    (definition.value, definition.class)
+                });
+            }
+            ExprKind::Unary(UnaryOp::Negate, _) => ValueKind::Number,
+            ExprKind::Unary(UnaryOp::Not, _) => ValueKind::Bool,
+            ExprKind::Binary(left, BinaryOp::Add, right) => {
+                match (self.infer(left).0, self.infer(right).0) {
+                    (ValueKind::Number, ValueKind::Number) => ValueKind::Number,
+                    (ValueKind::String, _) | (_, ValueKind::String) => ValueKind::String,
+                    _ => ValueKind::Unknown,
+                }
+            }
+            ExprKind::Binary(_, BinaryOp::Subtract | BinaryOp::Multiply | BinaryOp::Divide, _) => {
+                ValueKind::Number
+            }
+            ExprKind::Binary(..) => ValueKind::Bool,
+            ExprKind::Call(callee, _) => {
+                if let ExprKind::Variable(name) = &callee.kind {
+                    let definition = self
+                        .lookup(name)
+                        .or_else(|| self.globals.get(&name.text).copied());
+                    if let Some(class) = definition
+                        .filter(|&d| self.resolution.definitions[d].kind == SymbolKind::Class)
+                    {
+                        return (ValueKind::Instance, Some(class));
+                    }
+                }
+                ValueKind::Unknown
+            }
+            _ => ValueKind::Unknown,
+        };
+        (kind, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{self, Dialect};
+
+    #[test]
+    fn test_scopes_and_inferred_kinds() {
+        let source = "fun f(a) { var x = a; { var x = \"s\"; print x; } return x + later; }\nclass P {}\nvar p = P();\nvar later = 1;\nvar mixed = 1;\nmixed = \"two\";\nprint f(later) + [1][0];";
+        let program = parser::parse_dialect(source.as_bytes(), Dialect::LoxPlus).unwrap();
+        let resolution = resolve(&program);
+
+        let offset = |needle: &str, nth: usize| {
+            source
+                .match_indices(needle)
+                .nth(nth)
+                .map(|(offset, _)| offset)
+                .unwrap()
+        };
+        let definition =
+            |offset| &resolution.definitions[resolution.definition_at(offset).unwrap()];
+
+        // The inner `print x` sees the shadowing string, `return x` the outer one
+        assert_eq!(definition(offset("x", 2)).value, ValueKind::String);
+        assert_eq!(definition(offset("x", 3)).loc.start, offset("x", 0));
+        // Globals are late bound
+        assert_eq!(definition(offset("later", 0)).loc.start, offset("later", 1));
+        assert_eq!(definition(offset("= a", 0) + 2).kind, SymbolKind::Parameter);
+
+        let p = definition(offset("p = P", 0));
+        assert_eq!(p.value, ValueKind::Instance);
+        assert_eq!(resolution.definitions[p.class.unwrap()].name.as_ref(), "P");
+        assert_eq!(definition(offset("mixed", 0)).value, ValueKind::Unknown);
+
+        let later = resolution.definition_at(offset("later", 1)).unwrap();
+        assert_eq!(resolution.references_to(later).count(), 2);
+    }
+}
@@ -0,0 +1,299 @@
+use crate::gc::{Heap, Trace};
+use crate::object::ObjRef;
+use crate::value::Value;
+
+const EMPTY: u32 = u32::MAX;
+const TOMBSTONE: u32 = u32::MAX - 1;
+const MIN_CAPACITY: usize = 8;
+
+/// Strings are interned, so a key's identity is its contents. Hashing the id instead of the
+/// characters means lookups never have to touch the string object.
+#[inline(always)]
+fn hash_key(key: u32) -> usize {
+    ((key as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// Object index of the key string, or `EMPTY` / `TOMBSTONE`.
+    key: u32,
+    value: Value,
+}
+
+const VACANT: Entry = Entry {
+    key: EMPTY,
+    value: Value::Nil,
+};
+
+/// Open-addressing hash table with linear probing from interned strings to values, used for
+/// globals, instance fields and class methods.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    /// Length is zero or a power of two.
+    entries: Vec<Entry>,
+    len: usize,
+    /// Live entries plus tombstones, which both lengthen probe sequences.
+    used: usize,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the slot holding `key`, or the slot where it should be inserted (preferring the
+    /// first tombstone on the way).
+    #[inline(always)]
+    fn find(&self, key: u32) -> usize {
+        let mask = self.entries.len() - 1;
+        let mut index = hash_key(key) & mask;
+        let mut tombstone = None;
+        loop {
+            match self.entries[index].key {
+                EMPTY => return tombstone.unwrap_or(index),
+                TOMBSTONE => {
+                    tombstone.get_or_insert(index);
+                }
+                k if k == key => return index,
+                _ => {}
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    #[inline(always)]
+    pub fn get(&self, key: ObjRef) -> Option<Value> {
+        if self.len == 0 {
+            return None;
+        }
+        let entry = &self.entries[self.find(key.0)];
+        (entry.key == key.0).then_some(entry.value)
+    }
+
+    #[inline(always)]
+    pub fn get_mut(&mut self, key: ObjRef) -> Option<&mut Value> {
+        if self.len == 0 {
+            return None;
+        }
+        let index = self.find(key.0);
+        let entry = &mut self.entries[index];
+        (entry.key == key.0).then_some(&mut entry.value)
+    }
+
+    pub fn contains_key(&self, key: ObjRef) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Inserts or overwrites `key`. Returns true if the key is new.
+    pub fn insert(&mut self, key: ObjRef, value: Value) -> bool {
+        if (self.used + 1) * 4 > self.entries.len() * 3 {
+            self.grow();
+        }
+
+        let index = self.find(key.0);
+        let entry = &mut self.entries[index];
+        let is_new = entry.key != key.0;
+        if is_new {
+            self.len += 1;
+            // Reusing a tombstone doesn't change how full the table is
+            if entry.key == EMPTY {
+                self.used += 1;
+            }
+        }
+        *entry = Entry { key: key.0, value };
+        is_new
+    }
+
+    pub fn remove(&mut self, key: ObjRef) -> Option<Value> {
+        if self.len == 0 {
+            return None;
+        }
+        let index = self.find(key.0);
+        let entry = &mut self.entries[index];
+        if entry.key != key.0 {
+            return None;
+        }
+        entry.key = TOMBSTONE;
+        self.len -= 1;
+        Some(entry.value)
+    }
+
+    /// Copies every entry of `other` into this table, overwriting existing keys.
+    pub fn extend_from(&mut self, other: &Table) {
+        for (key, value) in other.iter() {
+            self.insert(key, value);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ObjRef, Value)> + '_ {
+        self.entries
+            .iter()
+            .filter(|entry| entry.key < TOMBSTONE)
+            .map(|entry| (ObjRef(entry.key), entry.value))
+    }
+
+    fn grow(&mut self) {
+        let capacity = (self.entries.len() * 2).max(MIN_CAPACITY);
+        let old = std::mem::replace(&mut self.entries, vec![VACANT; capacity]);
+        self.len = 0;
+        self.used = 0;
+        for entry in old {
+            if entry.key < TOMBSTONE {
+                let index = self.find(entry.key);
+                self.entries[index] = entry;
+                self.len += 1;
+                self.used += 1;
+            }
+        }
+    }
+}
+
+impl Trace for Table {
+    fn trace(&self, heap: &mut Heap) {
+        for (key, value) in self.iter() {
+            heap.mark_object(key);
+            heap.mark_value(value);
+        }
+    }
+}
+
+/// Set of interned strings keyed by contents, the one place strings are compared by value.
+///
+/// Entries are weak, [`StringTable::retain`] drops the ones the collector didn't mark.
+#[derive(Debug, Default)]
+pub struct StringTable {
+    /// Object indices of the strings, or `EMPTY` / `TOMBSTONE`.
+    slots: Vec<u32>,
+    /// Content hash of the string in each slot, so probing rarely compares characters.
+    hashes: Vec<u32>,
+    len: usize,
+    used: usize,
+}
+
+impl StringTable {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Finds the string with these contents. `chars_of` returns the characters of a stored
+    /// string.
+    pub fn find<'a>(
+        &self,
+        chars: &str,
+        hash: u32,
+        chars_of: impl Fn(ObjRef) -> &'a str,
+    ) -> Option<ObjRef> {
+        if self.len == 0 {
+            return None;
+        }
+        let mask = self.slots.len() - 1;
+        let mut index = hash as usize & mask;
+        loop {
+            match self.slots[index] {
+                EMPTY => return None,
+                TOMBSTONE => {}
+                r if self.hashes[index] == hash && chars_of(ObjRef(r)) == chars => {
+                    return Some(ObjRef(r))
+                }
+                _ => {}
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    /// Adds a string known not to be in the table yet.
+    pub fn insert(&mut self, string: ObjRef, hash: u32) {
+        if (self.used + 1) * 4 > self.slots.len() * 3 {
+            self.grow();
+        }
+        let index = self.vacant_slot(hash);
+        if self.slots[index] == EMPTY {
+            self.used += 1;
+        }
+        self.slots[index] = string.0;
+        self.hashes[index] = hash;
+        self.len += 1;
+    }
+
+    /// Removes every string for which `keep` returns false.
+    pub fn retain(&mut self, mut keep: impl FnMut(ObjRef) -> bool) {
+        for slot in self.slots.iter_mut() {
+            if *slot < TOMBSTONE && !keep(ObjRef(*slot)) {
+                *slot = TOMBSTONE;
+                self.len -= 1;
+            }
+        }
+    }
+
+    fn vacant_slot(&self, hash: u32) -> usize {
+        let mask = self.slots.len() - 1;
+        let mut index = hash as usize & mask;
+        while self.slots[index] < TOMBSTONE {
+            index = (index + 1) & mask;
+        }
+        index
+    }
+
+    fn grow(&mut self) {
+        // Rehashing also clears out tombstones, so only grow when live entries need the room
+        let capacity = if (self.len + 1) * 2 > self.slots.len() {
+            (self.slots.len() * 2).max(MIN_CAPACITY)
+        } else {
+            self.slots.len()
+        };
+        let slots = std::mem::replace(&mut self.slots, vec![EMPTY; capacity]);
+        let hashes = std::mem::replace(&mut self.hashes, vec![0; capacity]);
+        self.len = 0;
+        self.used = 0;
+        for (slot, hash) in slots.into_iter().zip(hashes) {
+            if slot < TOMBSTONE {
+                self.insert(ObjRef(slot), hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove_and_tombstones() {
+        let mut table = Table::new();
+        for i in 0..100 {
+            assert!(table.insert(ObjRef(i), Value::Number(i as f64)));
+        }
+        assert!(!table.insert(ObjRef(7), Value::Nil));
+        assert_eq!(table.get(ObjRef(7)), Some(Value::Nil));
+
+        for i in (0..100).step_by(2) {
+            assert_eq!(table.remove(ObjRef(i)), Some(Value::Number(i as f64)));
+        }
+        assert_eq!(table.len(), 50);
+        assert_eq!(table.get(ObjRef(4)), None);
+        assert_eq!(table.get(ObjRef(5)), Some(Value::Number(5.0)));
+
+        // Tombstones are reused without losing entries further down the probe sequence
+        for i in 0..100 {
+            table.insert(ObjRef(i), Value::Bool(true));
+        }
+        assert_eq!(table.len(), 100);
+        assert!((0..100).all(|i| table.get(ObjRef(i)) == Some(Value::Bool(true))));
+    }
+}
@@ -0,0 +1,42 @@
+use crate::object::ObjRef;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+impl Value {
+    #[inline(always)]
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    #[inline(always)]
+    pub fn as_obj(&self) -> Option<ObjRef> {
+        match self {
+            Value::Obj(r) => Some(*r),
+            _ => None,
+        }
+    }
+}
+
+impl From<ObjRef> for Value {
+    fn from(r: ObjRef) -> Self {
+        Value::Obj(r)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
@@ -0,0 +1,2007 @@
+use crate::ast::{Stmt, StmtKind};
+use crate::chunk::{Chunk, OpCode};
+use crate::compiler;
+use crate::debugger::{self, Debugger};
+use crate::diagnostic::Diagnostic;
+use crate::embed::{HostClass, IntoArgs};
+use crate::gc::{Heap, Trace};
+use crate::loxc::{self, LoadError};
+use crate::natives::{self, FromValue, IntoValue, Output};
+use crate::object::*;
+use crate::parser::{self, Dialect};
+use crate::profiler::{self, Profiler};
+use crate::table::Table;
+use crate::value::Value;
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub const FRAMES_MAX: usize = 64;
+
+struct CallFrame {
+    closure: ObjRef,
+    chunk: Rc<Chunk>,
+    ip: usize,
+    /// Stack index of slot zero for this frame.
+    slots: usize,
+    /// Module whose globals the code uses.
+    module: usize,
+}
+
+/// Where a `throw` inside a `try` lands.
+struct Handler {
+    /// Number of frames, the last one being the frame with the `try`.
+    frame: usize,
+    /// Stack height to unwind to.
+    stack: usize,
+    ip: usize,
+    /// Length of [`Vm::importing`] when the handler was installed.
+    importing: usize,
+}
+
+/// A file run by `import`.
+struct Module {
+    /// As resolved from the import, for messages and resolving its own imports.
+    path: PathBuf,
+    globals: Table,
+    /// Names bound in the importing module's globals.
+    exports: Vec<ObjRef>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// Innermost frame first, e.g. `[line 3] in fib()`.
+    pub trace: Vec<String>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for line in &self.trace {
+            write!(f, "\n{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterpretError {
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpretError::Compile(errors) => {
+                let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", messages.join("\n"))
+            }
+            InterpretError::Runtime(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<RuntimeError> for InterpretError {
+    fn from(error: RuntimeError) -> Self {
+        InterpretError::Runtime(error)
+    }
+}
+
+/// Budgets for running untrusted scripts. Fuel and the timeout start over with every
+/// [`Vm::interpret`] call, and running out of any budget is a runtime error, except nesting,
+/// which fails compilation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Instructions a run may execute, "Out of fuel." past that.
+    pub fuel: Option<u64>,
+    /// Call depth, "Stack overflow." past that.
+    pub max_frames: usize,
+    /// Live heap bytes, "Out of memory." when a collection can't get back under it.
+    pub max_heap_bytes: Option<usize>,
+    /// Wall time of a run, checked at backward jumps and calls, "Timed out." past that.
+    pub timeout: Option<Duration>,
+    /// Nesting of statements and expressions in scripts and imported modules, "Too much
+    /// nesting." past that. Deeper than the default may overflow the native stack.
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            fuel: None,
+            max_frames: FRAMES_MAX,
+            max_heap_bytes: None,
+            timeout: None,
+            max_depth: parser::MAX_DEPTH,
+        }
+    }
+}
+
+/// Everything outside the heap that can keep an object alive.
+struct Roots<'a> {
+    stack: &'a [Value],
+    frames: &'a [CallFrame],
+    globals: &'a Table,
+    builtins: &'a Table,
+    modules: &'a [Module],
+    open_upvalues: &'a [ObjRef],
+    init_string: ObjRef,
+    error_class: ObjRef,
+    builtin_methods: &'a [Table; 2],
+}
+
+impl Trace for Roots<'_> {
+    fn trace(&self, heap: &mut Heap) {
+        self.stack.trace(heap);
+        for frame in self.frames {
+            heap.mark_object(frame.closure);
+        }
+        self.globals.trace(heap);
+        self.builtins.trace(heap);
+        for module in self.modules {
+            module.globals.trace(heap);
+            module.exports.trace(heap);
+        }
+        self.open_upvalues.trace(heap);
+        heap.mark_object(self.init_string);
+        heap.mark_object(self.error_class);
+        self.builtin_methods.trace(heap);
+    }
+}
+
+/// Built-in types whose values have native methods.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinType {
+    List,
+    Map,
+}
+
+pub struct Vm {
+    heap: Heap,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    /// Globals of the main script.
+    globals: Table,
+    /// Natives, which every imported module starts with.
+    builtins: Table,
+    /// Imported modules by index, 0 standing for the main script.
+    modules: Vec<Module>,
+    /// Index of every module by canonical path.
+    module_ids: HashMap<PathBuf, usize>,
+    /// Modules whose top-level code is running, outermost first.
+    importing: Vec<usize>,
+    /// Directories searched for modules not found next to the importing file.
+    search_path: Vec<PathBuf>,
+    /// Upvalues still pointing into the stack, sorted by stack slot.
+    open_upvalues: Vec<ObjRef>,
+    /// Installed `try` handlers, innermost last.
+    handlers: Vec<Handler>,
+    /// Set along with an error no handler may catch: a budget running out, or the debugger
+    /// stopping the script.
+    fatal: bool,
+    init_string: ObjRef,
+    /// Class of the objects runtime errors are caught as.
+    error_class: ObjRef,
+    /// Classes defined with [`Vm::define_class`] by the type their instances hold.
+    host_classes: HashMap<TypeId, ObjRef>,
+    /// Native methods of each [`BuiltinType`], receiving the receiver as their first argument.
+    builtin_methods: [Table; 2],
+    dialect: Dialect,
+    optimize: bool,
+    /// Whether scripts may reach outside the VM, through natives or `import`.
+    host_access: bool,
+    /// Instructions dispatched so far, for comparing backends.
+    instructions: u64,
+    limits: Limits,
+    /// Value of `instructions` past which the fuel is gone.
+    fuel_end: u64,
+    /// Value of `instructions` past which the run loop checks fuel and memory.
+    check_at: u64,
+    deadline: Option<Instant>,
+    /// Consulted before every instruction while attached.
+    debugger: Option<Debugger>,
+    /// Sees every instruction while attached.
+    profiler: Option<Profiler>,
+    out: Output,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::with_host_access(true)
+    }
+
+    /// Without host access scripts get no natives that reach outside the VM: `clock`,
+    /// `read_file` and `input` are left undefined, and `import` is a runtime error.
+    pub fn with_host_access(host_access: bool) -> Self {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let error_name = heap.intern("Error");
+        let error_class = heap.alloc(Obj::Class(ObjClass {
+            name: error_name,
+            methods: Table::new(),
+        }));
+        let mut vm = Vm {
+            heap,
+            stack: Vec::with_capacity(FRAMES_MAX * 256),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            globals: Table::new(),
+            builtins: Table::new(),
+            modules: vec![Module {
+                path: PathBuf::from("script"),
+                globals: Table::new(),
+                exports: Vec::new(),
+            }],
+            module_ids: HashMap::new(),
+            importing: Vec::new(),
+            search_path: Vec::new(),
+            open_upvalues: Vec::new(),
+            handlers: Vec::new(),
+            fatal: false,
+            init_string,
+            error_class,
+            host_classes: HashMap::new(),
+            builtin_methods: [Table::new(), Table::new()],
+            dialect: Dialect::Lox,
+            optimize: true,
+            host_access,
+            instructions: 0,
+            limits: Limits::default(),
+            fuel_end: u64::MAX,
+            check_at: u64::MAX,
+            deadline: None,
+            debugger: None,
+            profiler: None,
+            out: Rc::new(RefCell::new(Box::new(io::stdout()))),
+        };
+        natives::define_core(&mut vm);
+        if host_access {
+            natives::define_io(&mut vm);
+        }
+        vm
+    }
+
+    /// Redirects `print` output, e.g. to capture it in tests.
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        *self.out.borrow_mut() = out;
+    }
+
+    /// Selects the language accepted by [`Vm::interpret`].
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    /// Turns the compiler's optimization pass on or off. It is on by default.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Names the file the main script comes from. Its imports are resolved relative to it, and
+    /// importing it from a module is a cycle.
+    pub fn set_script_path(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        if let Ok(canonical) = path.canonicalize() {
+            self.module_ids.insert(canonical, 0);
+        }
+        self.modules[0].path = path;
+    }
+
+    /// Directories to look for imported files in when they aren't found relative to the file
+    /// importing them.
+    pub fn set_search_path(&mut self, directories: Vec<PathBuf>) {
+        self.search_path = directories;
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.heap.set_max_bytes(limits.max_heap_bytes);
+        self.limits = limits;
+    }
+
+    /// Attaches a debugger, or detaches it with `None`. Execution is much slower while one is
+    /// attached, as every instruction goes through it.
+    pub fn set_debugger(&mut self, debugger: Option<Debugger>) {
+        self.debugger = debugger;
+        self.reset_check();
+    }
+
+    /// Attaches a profiler, or detaches it with `None`. Like a debugger it slows every
+    /// instruction down.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+        self.reset_check();
+    }
+
+    /// Detaches the profiler to read what it counted.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Bytecode instructions executed since the VM was created.
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// Defines a global native function, `arity: None` for variadic ones. Arguments are read
+    /// with [`natives::arg`] and results built with [`natives::IntoValue`]:
+    ///
+    /// ```
+    /// use interpreter_rs::natives::{arg, IntoValue};
+    /// use interpreter_rs::vm::Vm;
+    /// use std::rc::Rc;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.define_native("shout", Some(1), |heap, args| {
+    ///     let text: Rc<str> = arg(heap, args, 0)?;
+    ///     Ok(format!("{}!", text.to_uppercase()).into_value(heap))
+    /// });
+    /// ```
+    pub fn define_native(
+        &mut self,
+        name: &'static str,
+        arity: Option<u8>,
+        function: impl Fn(&mut Heap, &[Value]) -> Result<Value, String> + 'static,
+    ) {
+        let (key, native) = self.alloc_native(name, arity, Rc::new(function));
+        self.globals.insert(key, Value::Obj(native));
+        self.builtins.insert(key, Value::Obj(native));
+    }
+
+    /// Defines a native method on a built-in type. `arity` doesn't count the receiver, which
+    /// is passed as the first argument.
+    pub fn define_builtin_method(
+        &mut self,
+        ty: BuiltinType,
+        name: &'static str,
+        arity: Option<u8>,
+        function: impl Fn(&mut Heap, &[Value]) -> Result<Value, String> + 'static,
+    ) {
+        let (key, native) = self.alloc_native(name, arity, Rc::new(function));
+        self.builtin_methods[ty as usize].insert(key, Value::Obj(native));
+    }
+
+    fn alloc_native(
+        &mut self,
+        name: &'static str,
+        arity: Option<u8>,
+        function: NativeFn,
+    ) -> (ObjRef, ObjRef) {
+        let key = self.heap.intern(name);
+        // Keep the name reachable while the native is allocated
+        self.stack.push(Value::Obj(key));
+        let native = self.alloc(Obj::Native(ObjNative {
+            name,
+            arity,
+            function,
+        }));
+        self.stack.pop();
+        (key, native)
+    }
+
+    pub fn interpret(&mut self, source: &[u8]) -> Result<(), InterpretError> {
+        let function = self.compile_source(source)?;
+        self.run_function(function)
+    }
+
+    /// Like [`Vm::interpret`], but reuses the bytecode in the `.loxc` file at `cache` if it was
+    /// compiled from this source, and otherwise compiles and rewrites it. Failing to write the
+    /// cache doesn't fail the run.
+    pub fn interpret_cached(&mut self, source: &[u8], cache: &Path) -> Result<(), InterpretError> {
+        let cached = std::fs::read(cache)
+            .ok()
+            .and_then(|bytes| self.load_bytecode(&bytes, source).ok());
+        let function = match cached {
+            Some(function) => function,
+            None => {
+                let function = self.compile_source(source)?;
+                let _ = std::fs::write(cache, self.save_bytecode(function, source));
+                function
+            }
+        };
+        self.run_function(function)
+    }
+
+    /// Parses and compiles a script in the VM's dialect without running it.
+    pub fn compile_source(&mut self, source: &[u8]) -> Result<ObjRef, InterpretError> {
+        let program = self.parse(source).map_err(InterpretError::Compile)?;
+        self.compile(&program)
+    }
+
+    fn parse(&self, source: &[u8]) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+        parser::Parser::new(source)
+            .with_dialect(self.dialect)
+            .with_max_depth(self.limits.max_depth)
+            .parse()
+    }
+
+    /// Serializes a script function compiled from `source` by [`Vm::compile_source`].
+    pub fn save_bytecode(&self, function: ObjRef, source: &[u8]) -> Vec<u8> {
+        loxc::write(&self.heap, function, self.source_hash(source))
+    }
+
+    /// Loads a script function saved by [`Vm::save_bytecode`], if it was compiled from `source`
+    /// with this VM's dialect and optimization setting.
+    pub fn load_bytecode(&mut self, bytes: &[u8], source: &[u8]) -> Result<ObjRef, LoadError> {
+        let hash = self.source_hash(source);
+        let (heap, roots) = self.heap_and_roots();
+        loxc::read(bytes, hash, heap, &roots)
+    }
+
+    fn source_hash(&self, source: &[u8]) -> u64 {
+        loxc::source_hash(source, self.dialect == Dialect::LoxPlus, self.optimize)
+    }
+
+    pub fn compile(&mut self, program: &[Stmt]) -> Result<ObjRef, InterpretError> {
+        let optimize = self.optimize;
+        let (heap, roots) = self.heap_and_roots();
+        compiler::compile_with(program, heap, &roots, optimize).map_err(InterpretError::Compile)
+    }
+
+    /// The heap along with everything in the VM that keeps objects in it alive.
+    fn heap_and_roots(&mut self) -> (&mut Heap, Roots<'_>) {
+        let roots = Roots {
+            stack: &self.stack,
+            frames: &self.frames,
+            globals: &self.globals,
+            builtins: &self.builtins,
+            modules: &self.modules,
+            open_upvalues: &self.open_upvalues,
+            init_string: self.init_string,
+            error_class: self.error_class,
+            builtin_methods: &self.builtin_methods,
+        };
+        (&mut self.heap, roots)
+    }
+
+    /// Runs a compiled top-level script function to completion.
+    pub fn run_function(&mut self, function: ObjRef) -> Result<(), InterpretError> {
+        self.stack.push(Value::Obj(function));
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack.pop();
+        self.stack.push(Value::Obj(closure));
+
+        self.start_run();
+        let result = self.call(closure, 0).and_then(|_| self.run());
+        self.end_run(&result);
+        result?;
+        self.pop();
+        Ok(())
+    }
+
+    /// Calls the global `name` with `args`, runs it to completion and converts its result:
+    ///
+    /// ```
+    /// use interpreter_rs::vm::Vm;
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.interpret(b"fun greet(name, times) { return \"hi \" + name + str(times); }").unwrap();
+    /// let greeting: String = vm.call_global("greet", ("lox", 2.0)).unwrap();
+    /// assert_eq!(greeting, "hi lox2");
+    /// ```
+    pub fn call_global<R: FromValue>(
+        &mut self,
+        name: &str,
+        args: impl IntoArgs,
+    ) -> Result<R, InterpretError> {
+        let Some(callee) = self.get_global::<Value>(name) else {
+            return Err(host_error(format!("Undefined variable '{}'.", name)).into());
+        };
+        // Converting allocates without collecting, so the arguments are safe until pushed
+        let args = args.into_args(&mut self.heap);
+        let argc = args.len();
+        self.stack.push(callee);
+        self.stack.extend(args);
+
+        self.start_run();
+        let result = self
+            .call_value(callee, argc)
+            .and_then(|_| match self.frames.is_empty() {
+                // Natives return straight away
+                true => Ok(()),
+                false => self.run(),
+            });
+        self.end_run(&result);
+        result?;
+        let value = self.pop();
+        R::from_value(&self.heap, value).ok_or_else(|| {
+            let got = natives::type_name(&self.heap, value);
+            host_error(format!(
+                "Expected {} as result but got {}.",
+                R::EXPECTED,
+                got
+            ))
+            .into()
+        })
+    }
+
+    /// Reads a global of the main script, `None` if it's undefined or not a `T`.
+    pub fn get_global<T: FromValue>(&mut self, name: &str) -> Option<T> {
+        let key = self.heap.intern(name);
+        T::from_value(&self.heap, self.globals.get(key)?)
+    }
+
+    /// Defines or overwrites a global of the main script.
+    pub fn set_global(&mut self, name: &str, value: impl IntoValue) {
+        let key = self.heap.intern(name);
+        // Both are unreachable until inserted, but neither conversion collects
+        let value = value.into_value(&mut self.heap);
+        self.globals.insert(key, value);
+    }
+
+    /// Defines a global class whose instances hold a `T`, built by the class's initializer or
+    /// by [`Vm::host_object`], and whose methods are Rust functions. See [`HostClass`].
+    pub fn define_class<T: Any>(&mut self, name: &'static str) -> HostClass<'_, T> {
+        let key = self.heap.intern(name);
+        self.stack.push(Value::Obj(key));
+        let class = self.alloc(Obj::Class(ObjClass {
+            name: key,
+            methods: Table::new(),
+        }));
+        self.stack.pop();
+        self.globals.insert(key, Value::Obj(class));
+        self.builtins.insert(key, Value::Obj(class));
+        self.host_classes.insert(TypeId::of::<T>(), class);
+        HostClass::new(self, class)
+    }
+
+    /// Wraps `data` in an instance of the class defined for `T`, `None` if there is none.
+    pub fn host_object<T: Any>(&mut self, data: T) -> Option<Value> {
+        let class = *self.host_classes.get(&TypeId::of::<T>())?;
+        let instance = self.alloc(Obj::Instance(ObjInstance {
+            class,
+            fields: Table::new(),
+            host: Some(Rc::new(RefCell::new(data))),
+        }));
+        Some(Value::Obj(instance))
+    }
+
+    /// Adds a native method to a class. `arity` doesn't count the receiver, which is passed as
+    /// the first argument.
+    pub(crate) fn define_method(
+        &mut self,
+        class: ObjRef,
+        name: &'static str,
+        arity: Option<u8>,
+        function: NativeFn,
+    ) {
+        let (key, native) = self.alloc_native(name, arity, function);
+        self.heap
+            .class_mut(class)
+            .methods
+            .insert(key, Value::Obj(native));
+    }
+
+    fn start_run(&mut self) {
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        self.fuel_end = self
+            .limits
+            .fuel
+            .map_or(u64::MAX, |fuel| self.instructions + fuel);
+        self.reset_check();
+        self.importing = vec![0];
+    }
+
+    fn end_run(&mut self, result: &Result<(), RuntimeError>) {
+        if result.is_err() {
+            self.reset_stack();
+            // Modules that failed halfway through are run again by the next import
+            for module in self.importing.drain(1..) {
+                self.module_ids.retain(|_, &mut id| id != module);
+            }
+        }
+    }
+
+    pub fn collect_garbage(&mut self) -> usize {
+        let (heap, roots) = self.heap_and_roots();
+        roots.trace(heap);
+        let freed = self.heap.collect();
+        if self.heap.over_limit() {
+            // Fail at the next instruction, where the VM's state is consistent
+            self.check_at = 0;
+        }
+        freed
+    }
+
+    /// Reports the budget that ran out once `instructions` passes `check_at`.
+    fn check_limits(&mut self) -> Result<(), String> {
+        if self.heap.over_limit() {
+            return Err("Out of memory.".into());
+        }
+        if self.instructions > self.fuel_end {
+            return Err("Out of fuel.".into());
+        }
+        self.reset_check();
+        Ok(())
+    }
+
+    fn reset_check(&mut self) {
+        self.check_at = match (&self.debugger, &self.profiler) {
+            (None, None) => self.fuel_end,
+            _ => 0,
+        };
+    }
+
+    fn check_deadline(&mut self) -> Result<(), RuntimeError> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                self.fatal = true;
+                Err(self.runtime_error("Timed out.".into()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Allocates at a safe point: everything the caller still needs must be reachable from the
+    /// roots, usually by leaving it on the stack until the new object is pushed.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    fn take_string(&mut self, chars: String) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.take_string(chars)
+    }
+
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.handlers.clear();
+        self.fatal = false;
+    }
+
+    #[inline(always)]
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    #[inline(always)]
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    #[inline(always)]
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn runtime_error(&self, message: String) -> RuntimeError {
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self
+                    .heap
+                    .function(self.heap.closure(frame.closure).function);
+                let line = frame.chunk.lines[frame.ip.saturating_sub(1)];
+                let path = self.modules[frame.module].path.display();
+                match (function.name, frame.module) {
+                    (Some(name), 0) => format!("[line {}] in {}()", line, self.heap.str(name)),
+                    (None, 0) => format!("[line {}] in script", line),
+                    (Some(name), _) => {
+                        format!("[line {}] in {}() in {}", line, self.heap.str(name), path)
+                    }
+                    (None, _) => format!("[line {}] in {}", line, path),
+                }
+            })
+            .collect();
+        RuntimeError { message, trace }
+    }
+
+    fn call_value(&mut self, callee: Value, argc: usize) -> Result<(), RuntimeError> {
+        let Value::Obj(r) = callee else {
+            return Err(self.runtime_error("Can only call functions and classes.".into()));
+        };
+
+        match self.heap.get(r) {
+            Obj::BoundMethod(bound) => {
+                let (receiver, method) = (bound.receiver, bound.method);
+                let slot = self.stack.len() - argc - 1;
+                self.stack[slot] = receiver;
+                match self.heap.get(method) {
+                    Obj::Native(_) => self.call_native(method, argc, true),
+                    _ => self.call(method, argc),
+                }
+            }
+            Obj::Class(class) => {
+                let initializer = class.methods.get(self.init_string);
+                let instance = self.alloc(Obj::Instance(ObjInstance {
+                    class: r,
+                    fields: Table::new(),
+                    host: None,
+                }));
+                let slot = self.stack.len() - argc - 1;
+                self.stack[slot] = Value::Obj(instance);
+
+                match initializer {
+                    Some(Value::Obj(init)) if self.heap.is_native(Value::Obj(init)) => {
+                        self.call_native(init, argc, true)
+                    }
+                    Some(Value::Obj(init)) => self.call(init, argc),
+                    _ if argc != 0 => {
+                        Err(self.runtime_error(format!("Expected 0 arguments but got {}.", argc)))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Obj::Closure(_) => self.call(r, argc),
+            Obj::Native(_) => self.call_native(r, argc, false),
+            _ => Err(self.runtime_error("Can only call functions and classes.".into())),
+        }
+    }
+
+    /// Calls a native with the top `argc` values, replacing them and the callee or receiver
+    /// below them with the result. Methods also get the receiver as their first argument.
+    fn call_native(
+        &mut self,
+        native: ObjRef,
+        argc: usize,
+        with_receiver: bool,
+    ) -> Result<(), RuntimeError> {
+        let Obj::Native(native) = self.heap.get(native) else {
+            unreachable!("object is not a native");
+        };
+        if let Some(arity) = native.arity {
+            if arity as usize != argc {
+                return Err(
+                    self.runtime_error(format!("Expected {} arguments but got {}.", arity, argc))
+                );
+            }
+        }
+
+        let function = native.function.clone();
+        let slot = self.stack.len() - argc - 1;
+        let args_start = if with_receiver { slot } else { slot + 1 };
+        let result = function(&mut self.heap, &self.stack[args_start..])
+            .map_err(|m| self.runtime_error(m))?;
+        self.stack.truncate(slot);
+        self.push(result);
+        // Natives allocate straight from the heap, this is their safe point
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        Ok(())
+    }
+
+    fn builtin_type(&self, value: Value) -> Option<BuiltinType> {
+        match self.heap.get(value.as_obj()?) {
+            Obj::List(_) => Some(BuiltinType::List),
+            Obj::Map(_) => Some(BuiltinType::Map),
+            _ => None,
+        }
+    }
+
+    fn call(&mut self, closure: ObjRef, argc: usize) -> Result<(), RuntimeError> {
+        let function = self.heap.function(self.heap.closure(closure).function);
+        if argc != function.arity as usize {
+            return Err(self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                function.arity, argc
+            )));
+        }
+        if self.frames.len() >= self.limits.max_frames {
+            return Err(self.runtime_error("Stack overflow.".into()));
+        }
+
+        let chunk = function.chunk.clone();
+        let module = function.module;
+        self.check_deadline()?;
+        self.frames.push(CallFrame {
+            closure,
+            chunk,
+            ip: 0,
+            slots: self.stack.len() - argc - 1,
+            module,
+        });
+        Ok(())
+    }
+
+    /// Starts running the module at `path` on behalf of `importer`, leaving its index and the
+    /// result of its top-level code for [`OpCode::FinishImport`]. A module already loaded isn't
+    /// run again.
+    fn import(&mut self, importer: usize, path: ObjRef) -> Result<(), RuntimeError> {
+        let path = self.heap.str(path).to_string();
+        if !self.host_access {
+            return Err(self.runtime_error(format!("Can't import '{}' without host access.", path)));
+        }
+        let base = self.modules[importer]
+            .path
+            .parent()
+            .unwrap_or(Path::new(""));
+        let Some(resolved) = std::iter::once(base)
+            .chain(self.search_path.iter().map(PathBuf::as_path))
+            .map(|directory| directory.join(&path))
+            .find(|candidate| candidate.is_file())
+        else {
+            return Err(self.runtime_error(format!("Can't find module '{}'.", path)));
+        };
+        let canonical = resolved.canonicalize().unwrap_or_else(|_| resolved.clone());
+
+        if let Some(&id) = self.module_ids.get(&canonical) {
+            if let Some(start) = self.importing.iter().position(|&m| m == id) {
+                let cycle: Vec<String> = self.importing[start..]
+                    .iter()
+                    .chain([&id])
+                    .map(|&m| self.modules[m].path.display().to_string())
+                    .collect();
+                return Err(self.runtime_error(format!("Import cycle: {}.", cycle.join(" -> "))));
+            }
+            self.push(Value::Number(id as f64));
+            self.push(Value::Nil);
+            return Ok(());
+        }
+
+        let source = std::fs::read(&resolved).map_err(|e| {
+            self.runtime_error(format!(
+                "Can't read module '{}': {}.",
+                resolved.display(),
+                e
+            ))
+        })?;
+        let id = self.modules.len();
+        self.modules.push(Module {
+            path: resolved,
+            globals: self.builtins.clone(),
+            exports: Vec::new(),
+        });
+        let compiled = self.parse(&source).and_then(|program| {
+            let exports: Vec<Rc<str>> = program
+                .iter()
+                .filter(|stmt| matches!(stmt.kind, StmtKind::Export(_)))
+                .filter_map(|stmt| stmt.declared_name().map(|name| name.text.clone()))
+                .collect();
+            let optimize = self.optimize;
+            let (heap, roots) = self.heap_and_roots();
+            let function = compiler::compile_module(&program, heap, &roots, optimize, id)?;
+            Ok((function, exports))
+        });
+        let (function, exports) = match compiled {
+            Ok(compiled) => compiled,
+            Err(diagnostics) => {
+                let module = self.modules.pop().unwrap();
+                let path = module.path.display();
+                let mut message = format!("Can't compile module '{}'.", path);
+                for diagnostic in diagnostics {
+                    message.push_str(&format!("\n{}: {}", path, diagnostic));
+                }
+                return Err(self.runtime_error(message));
+            }
+        };
+
+        self.module_ids.insert(canonical, id);
+        self.importing.push(id);
+        self.push(Value::Number(id as f64));
+        self.push(Value::Obj(function));
+        for name in exports {
+            let name = self.take_string(name.to_string());
+            self.modules[id].exports.push(name);
+        }
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack.pop();
+        self.push(Value::Obj(closure));
+        self.call(closure, 0)
+    }
+
+    /// Throws `value` to the innermost handler, or fails the run with it.
+    fn throw(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if self.handlers.is_empty() {
+            let error = self.runtime_error(self.heap.format_value(value));
+            return Err(uncaught_error(
+                &mut self.heap,
+                self.error_class,
+                value,
+                error,
+            ));
+        }
+        self.unwind(value);
+        Ok(())
+    }
+
+    /// Resumes at the innermost handler with `value` on top of the stack.
+    fn unwind(&mut self, value: Value) {
+        let handler = self.handlers.pop().expect("no handler to unwind to");
+        self.close_upvalues(handler.stack);
+        self.frames.truncate(handler.frame);
+        self.stack.truncate(handler.stack);
+        self.push(value);
+        self.frames.last_mut().unwrap().ip = handler.ip;
+        // Modules whose top-level code was cut short are run again by the next import
+        for module in self.importing.drain(handler.importing..) {
+            self.module_ids.retain(|_, &mut id| id != module);
+        }
+    }
+
+    /// Binds the exports of the module [`Vm::import`] left on the stack in `importer`.
+    fn finish_import(&mut self, importer: usize) {
+        self.pop();
+        let Value::Number(id) = self.pop() else {
+            unreachable!("Import leaves the module index on the stack");
+        };
+        let id = id as usize;
+        if self.importing.last() == Some(&id) {
+            self.importing.pop();
+        }
+        for i in 0..self.modules[id].exports.len() {
+            let name = self.modules[id].exports[i];
+            let value = self.modules[id].globals.get(name).unwrap_or(Value::Nil);
+            match importer {
+                0 => self.globals.insert(name, value),
+                _ => self.modules[importer].globals.insert(name, value),
+            };
+        }
+    }
+
+    fn invoke(&mut self, name: ObjRef, argc: usize) -> Result<(), RuntimeError> {
+        let receiver = self.peek(argc);
+        if let Some(ty) = self.builtin_type(receiver) {
+            return match self.builtin_methods[ty as usize].get(name) {
+                Some(Value::Obj(method)) => self.call_native(method, argc, true),
+                _ => {
+                    Err(self
+                        .runtime_error(format!("Undefined property '{}'.", self.heap.str(name))))
+                }
+            };
+        }
+        if !self.heap.is_instance(receiver) {
+            return Err(self.runtime_error("Only instances have methods.".into()));
+        }
+
+        let instance = self.heap.instance(receiver.as_obj().unwrap());
+        if let Some(field) = instance.fields.get(name) {
+            let slot = self.stack.len() - argc - 1;
+            self.stack[slot] = field;
+            return self.call_value(field, argc);
+        }
+
+        let class = instance.class;
+        self.invoke_from_class(class, name, argc)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        argc: usize,
+    ) -> Result<(), RuntimeError> {
+        match self.heap.class(class).methods.get(name) {
+            Some(Value::Obj(method)) if self.heap.is_native(Value::Obj(method)) => {
+                self.call_native(method, argc, true)
+            }
+            Some(Value::Obj(method)) => self.call(method, argc),
+            _ => Err(self.runtime_error(format!("Undefined property '{}'.", self.heap.str(name)))),
+        }
+    }
+
+    /// Replaces the instance on top of the stack with the bound method `name` from `class`.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), RuntimeError> {
+        let Some(Value::Obj(method)) = self.heap.class(class).methods.get(name) else {
+            return Err(
+                self.runtime_error(format!("Undefined property '{}'.", self.heap.str(name)))
+            );
+        };
+
+        let bound = self.alloc(Obj::BoundMethod(ObjBoundMethod {
+            receiver: self.peek(0),
+            method,
+        }));
+        self.pop();
+        self.push(Value::Obj(bound));
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, &upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            match *self.heap.upvalue(upvalue) {
+                ObjUpvalue::Open(s) if s == slot => return upvalue,
+                ObjUpvalue::Open(s) if s < slot => break,
+                _ => insert_at = i,
+            }
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    /// Closes every open upvalue pointing at `last` or above.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let ObjUpvalue::Open(slot) = *self.heap.upvalue(upvalue) else {
+                unreachable!("closed upvalue in open list");
+            };
+            if slot < last {
+                break;
+            }
+            *self.heap.upvalue_mut(upvalue) = ObjUpvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    pub(crate) fn take_debugger(&mut self) -> Option<Debugger> {
+        self.debugger.take()
+    }
+
+    pub(crate) fn restore_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(debugger);
+    }
+
+    pub(crate) fn restore_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub(crate) fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Closure, offset of the instruction running and stack index of slot zero of frame
+    /// `index`, the outermost frame being zero.
+    pub(crate) fn frame_state(&self, index: usize) -> (ObjRef, usize, usize) {
+        let frame = &self.frames[index];
+        (frame.closure, frame.ip.saturating_sub(1), frame.slots)
+    }
+
+    /// Path of module `module`, `script` for the main script until it's given one.
+    pub(crate) fn module_path(&self, module: usize) -> &Path {
+        &self.modules[module].path
+    }
+
+    pub(crate) fn stack_value(&self, index: usize) -> Value {
+        self.stack[index]
+    }
+
+    pub(crate) fn globals(&self) -> &Table {
+        &self.globals
+    }
+
+    pub(crate) fn read_upvalue(&self, closure: ObjRef, index: usize) -> Value {
+        let upvalue = self.heap.closure(closure).upvalues[index];
+        match *self.heap.upvalue(upvalue) {
+            ObjUpvalue::Open(slot) => self.stack[slot],
+            ObjUpvalue::Closed(value) => value,
+        }
+    }
+
+    fn write_upvalue(&mut self, closure: ObjRef, index: usize, value: Value) {
+        let upvalue = self.heap.closure(closure).upvalues[index];
+        match self.heap.upvalue_mut(upvalue) {
+            ObjUpvalue::Open(slot) => {
+                let slot = *slot;
+                self.stack[slot] = value;
+            }
+            ObjUpvalue::Closed(closed) => *closed = value,
+        }
+    }
+
+    /// Runs until the script returns, catching errors with the installed handlers.
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            let error = match self.execute() {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            if std::mem::take(&mut self.fatal) || self.handlers.is_empty() {
+                return Err(error);
+            }
+            let value = error_object(&mut self.heap, self.error_class, &error);
+            self.unwind(value);
+        }
+    }
+
+    fn execute(&mut self) -> Result<(), RuntimeError> {
+        // Hot frame state is kept in locals and written back before anything that inspects
+        // frames: calls, returns and errors.
+        let frame = self.frames.last().unwrap();
+        let mut chunk = frame.chunk.clone();
+        let mut ip = frame.ip;
+        let mut slots = frame.slots;
+        let mut closure = frame.closure;
+        let mut module = frame.module;
+
+        macro_rules! read_byte {
+            () => {{
+                let byte = chunk.code[ip];
+                ip += 1;
+                byte
+            }};
+        }
+        macro_rules! read_u16 {
+            () => {{
+                let value = chunk.read_u16(ip);
+                ip += 2;
+                value
+            }};
+        }
+        macro_rules! read_constant {
+            () => {
+                chunk.constants[read_u16!() as usize]
+            };
+        }
+        macro_rules! read_string {
+            () => {
+                read_constant!().as_obj().unwrap()
+            };
+        }
+        macro_rules! save_frame {
+            () => {
+                self.frames.last_mut().unwrap().ip = ip;
+            };
+        }
+        macro_rules! load_frame {
+            () => {{
+                let frame = self.frames.last().unwrap();
+                chunk = frame.chunk.clone();
+                ip = frame.ip;
+                slots = frame.slots;
+                closure = frame.closure;
+                module = frame.module;
+            }};
+        }
+        macro_rules! globals {
+            () => {
+                match module {
+                    0 => &mut self.globals,
+                    _ => &mut self.modules[module].globals,
+                }
+            };
+        }
+        macro_rules! throw {
+            ($($arg:tt)*) => {{
+                save_frame!();
+                return Err(self.runtime_error(format!($($arg)*)));
+            }};
+        }
+        macro_rules! binary_op {
+            ($wrap:expr, $op:tt) => {{
+                match (self.peek(1), self.peek(0)) {
+                    (Value::Number(a), Value::Number(b)) => {
+                        self.pop();
+                        let top = self.stack.len() - 1;
+                        self.stack[top] = $wrap(a $op b);
+                    }
+                    _ => throw!("Operands must be numbers."),
+                }
+            }};
+        }
+
+        loop {
+            self.instructions += 1;
+            let Some(op) = OpCode::from_byte(read_byte!()) else {
+                throw!("Invalid opcode.");
+            };
+            if self.instructions > self.check_at {
+                save_frame!();
+                profiler::on_instruction(self);
+                let checked = self.check_limits();
+                if let Err(message) = checked.and_then(|_| debugger::on_instruction(self)) {
+                    self.fatal = true;
+                    throw!("{}", message);
+                }
+            }
+
+            match op {
+                OpCode::Constant => {
+                    let constant = read_constant!();
+                    self.push(constant);
+                }
+                OpCode::Nil => self.push(Value::Nil),
+                OpCode::True => self.push(Value::Bool(true)),
+                OpCode::False => self.push(Value::Bool(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = read_byte!() as usize;
+                    self.push(self.stack[slots + slot]);
+                }
+                OpCode::SetLocal => {
+                    let slot = read_byte!() as usize;
+                    self.stack[slots + slot] = self.peek(0);
+                }
+                OpCode::GetGlobal => {
+                    let name = read_string!();
+                    match globals!().get(name) {
+                        Some(value) => self.push(value),
+                        None => throw!("Undefined variable '{}'.", self.heap.str(name)),
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let name = read_string!();
+                    let value = self.peek(0);
+                    globals!().insert(name, value);
+                    self.pop();
+                }
+                OpCode::SetGlobal => {
+                    let name = read_string!();
+                    let value = self.peek(0);
+                    match globals!().get_mut(name) {
+                        Some(slot) => *slot = value,
+                        None => throw!("Undefined variable '{}'.", self.heap.str(name)),
+                    }
+                }
+                OpCode::GetUpvalue => {
+                    let index = read_byte!() as usize;
+                    self.push(self.read_upvalue(closure, index));
+                }
+                OpCode::SetUpvalue => {
+                    let index = read_byte!() as usize;
+                    self.write_upvalue(closure, index, self.peek(0));
+                }
+                OpCode::GetProperty => {
+                    let name = read_string!();
+                    let receiver = self.peek(0);
+                    if let Some(ty) = self.builtin_type(receiver) {
+                        let Some(Value::Obj(method)) = self.builtin_methods[ty as usize].get(name)
+                        else {
+                            throw!("Undefined property '{}'.", self.heap.str(name));
+                        };
+                        save_frame!();
+                        let bound =
+                            self.alloc(Obj::BoundMethod(ObjBoundMethod { receiver, method }));
+                        self.pop();
+                        self.push(Value::Obj(bound));
+                        continue;
+                    }
+                    if !self.heap.is_instance(receiver) {
+                        throw!("Only instances have properties.");
+                    }
+
+                    let instance = self.heap.instance(receiver.as_obj().unwrap());
+                    if let Some(value) = instance.fields.get(name) {
+                        self.pop();
+                        self.push(value);
+                    } else {
+                        let class = instance.class;
+                        save_frame!();
+                        self.bind_method(class, name)?;
+                    }
+                }
+                OpCode::SetProperty => {
+                    let name = read_string!();
+                    let receiver = self.peek(1);
+                    if !self.heap.is_instance(receiver) {
+                        throw!("Only instances have fields.");
+                    }
+
+                    let value = self.peek(0);
+                    let instance = receiver.as_obj().unwrap();
+                    let old_size = self.heap.get(instance).size();
+                    self.heap.instance_mut(instance).fields.insert(name, value);
+                    self.heap.resized(instance, old_size);
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::GetSuper => {
+                    let name = read_string!();
+                    let superclass = self.pop().as_obj().unwrap();
+                    save_frame!();
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::Bool(a == b));
+                }
+                OpCode::Greater => binary_op!(Value::Bool, >),
+                OpCode::Less => binary_op!(Value::Bool, <),
+                OpCode::Add => match (self.peek(1), self.peek(0)) {
+                    (Value::Number(a), Value::Number(b)) => {
+                        self.pop();
+                        let top = self.stack.len() - 1;
+                        self.stack[top] = Value::Number(a + b);
+                    }
+                    (a, b) => match (self.heap.as_string(a), self.heap.as_string(b)) {
+                        (Some(a), Some(b)) => {
+                            let mut result = String::with_capacity(a.len() + b.len());
+                            result.push_str(a);
+                            result.push_str(b);
+                            // Operands stay on the stack until the result is allocated
+                            let string = self.take_string(result);
+                            self.pop();
+                            self.pop();
+                            self.push(Value::Obj(string));
+                        }
+                        _ => throw!("Operands must be two numbers or two strings."),
+                    },
+                },
+                OpCode::Subtract => binary_op!(Value::Number, -),
+                OpCode::Multiply => binary_op!(Value::Number, *),
+                OpCode::Divide => binary_op!(Value::Number, /),
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.push(Value::Bool(value.is_falsey()));
+                }
+                OpCode::Negate => match self.peek(0) {
+                    Value::Number(n) => {
+                        let top = self.stack.len() - 1;
+                        self.stack[top] = Value::Number(-n);
+                    }
+                    _ => throw!("Operand must be a number."),
+                },
+                OpCode::Print => {
+                    let value = self.pop();
+                    let text = self.heap.format_value(value);
+                    if let Err(e) = writeln!(self.out.borrow_mut(), "{}", text) {
+                        throw!("Failed to print: {}.", e);
+                    }
+                }
+                OpCode::Jump => {
+                    let offset = read_u16!() as usize;
+                    ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = read_u16!() as usize;
+                    if self.peek(0).is_falsey() {
+                        ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = read_u16!() as usize;
+                    if self.deadline.is_some() {
+                        save_frame!();
+                        self.check_deadline()?;
+                    }
+                    ip -= offset;
+                }
+                OpCode::Call => {
+                    let argc = read_byte!() as usize;
+                    save_frame!();
+                    self.call_value(self.peek(argc), argc)?;
+                    load_frame!();
+                }
+                OpCode::Invoke => {
+                    let name = read_string!();
+                    let argc = read_byte!() as usize;
+                    save_frame!();
+                    self.invoke(name, argc)?;
+                    load_frame!();
+                }
+                OpCode::SuperInvoke => {
+                    let name = read_string!();
+                    let argc = read_byte!() as usize;
+                    let superclass = self.pop().as_obj().unwrap();
+                    save_frame!();
+                    self.invoke_from_class(superclass, name, argc)?;
+                    load_frame!();
+                }
+                OpCode::Closure => {
+                    let function = read_constant!().as_obj().unwrap();
+                    let upvalue_count = self.heap.function(function).upvalue_count;
+                    let new_closure = self.alloc(Obj::Closure(ObjClosure {
+                        function,
+                        upvalues: Vec::with_capacity(upvalue_count),
+                    }));
+                    // Root the closure before capturing, capturing may allocate
+                    self.push(Value::Obj(new_closure));
+
+                    for _ in 0..upvalue_count {
+                        let is_local = read_byte!() == 1;
+                        let index = read_byte!() as usize;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(slots + index)
+                        } else {
+                            self.heap.closure(closure).upvalues[index]
+                        };
+                        self.heap.closure_mut(new_closure).upvalues.push(upvalue);
+                    }
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    self.close_upvalues(slots);
+                    self.frames.pop();
+                    self.stack.truncate(slots);
+                    self.push(result);
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                    load_frame!();
+                }
+                OpCode::Class => {
+                    let name = read_string!();
+                    let class = self.alloc(Obj::Class(ObjClass {
+                        name,
+                        methods: Table::new(),
+                    }));
+                    self.push(Value::Obj(class));
+                }
+                OpCode::Inherit => {
+                    let superclass = self.peek(1);
+                    if !self.heap.is_class(superclass) {
+                        throw!("Superclass must be a class.");
+                    }
+
+                    // Copy-down inheritance, methods defined later override these
+                    let methods = self
+                        .heap
+                        .class(superclass.as_obj().unwrap())
+                        .methods
+                        .clone();
+                    let subclass = self.peek(0).as_obj().unwrap();
+                    self.heap.class_mut(subclass).methods.extend_from(&methods);
+                    self.pop();
+                }
+                OpCode::Method => {
+                    let name = read_string!();
+                    let method = self.peek(0);
+                    let class = self.peek(1).as_obj().unwrap();
+                    self.heap.class_mut(class).methods.insert(name, method);
+                    self.pop();
+                }
+                OpCode::BuildList => {
+                    let count = read_u16!() as usize;
+                    let start = self.stack.len() - count;
+                    // The elements stay on the stack, and rooted, until the list exists
+                    let items = self.stack[start..].to_vec();
+                    save_frame!();
+                    let list = self.alloc(Obj::List(items));
+                    self.stack.truncate(start);
+                    self.push(Value::Obj(list));
+                }
+                OpCode::BuildMap => {
+                    let count = read_u16!() as usize;
+                    let start = self.stack.len() - count * 2;
+                    let mut map = ObjMap::default();
+                    for pair in self.stack[start..].chunks_exact(2) {
+                        if matches!(pair[0], Value::Number(n) if n.is_nan()) {
+                            throw!("Map key can't be NaN.");
+                        }
+                        map.insert(pair[0], pair[1]);
+                    }
+                    save_frame!();
+                    let map = self.alloc(Obj::Map(map));
+                    self.stack.truncate(start);
+                    self.push(Value::Obj(map));
+                }
+                OpCode::GetIndex => {
+                    let index = self.peek(0);
+                    let object = self.peek(1);
+                    if self.heap.should_collect() {
+                        self.collect_garbage();
+                    }
+                    let value = match get_index(&mut self.heap, object, index) {
+                        Ok(value) => value,
+                        Err(message) => throw!("{}", message),
+                    };
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::SetIndex => {
+                    let value = self.peek(0);
+                    let index = self.peek(1);
+                    let object = self.peek(2);
+                    if let Err(message) = set_index(&mut self.heap, object, index, value) {
+                        throw!("{}", message);
+                    }
+                    self.stack.truncate(self.stack.len() - 3);
+                    self.push(value);
+                }
+                OpCode::Import => {
+                    let path = read_string!();
+                    save_frame!();
+                    self.import(module, path)?;
+                    load_frame!();
+                }
+                OpCode::FinishImport => self.finish_import(module),
+                OpCode::PushHandler => {
+                    let offset = read_u16!() as usize;
+                    self.handlers.push(Handler {
+                        frame: self.frames.len(),
+                        stack: self.stack.len(),
+                        ip: ip + offset,
+                        importing: self.importing.len(),
+                    });
+                }
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
+                OpCode::Throw => {
+                    let value = self.pop();
+                    save_frame!();
+                    self.throw(value)?;
+                    load_frame!();
+                }
+            }
+        }
+    }
+}
+
+impl natives::Host for Vm {
+    fn output(&self) -> Output {
+        self.out.clone()
+    }
+
+    fn define_native(
+        &mut self,
+        name: &'static str,
+        arity: Option<u8>,
+        function: impl Fn(&mut Heap, &[Value]) -> Result<Value, String> + 'static,
+    ) {
+        Vm::define_native(self, name, arity, function);
+    }
+
+    fn define_builtin_method(
+        &mut self,
+        ty: BuiltinType,
+        name: &'static str,
+        arity: Option<u8>,
+        function: impl Fn(&mut Heap, &[Value]) -> Result<Value, String> + 'static,
+    ) {
+        Vm::define_builtin_method(self, ty, name, arity, function);
+    }
+}
+
+/// An error raised on behalf of the host, outside any frame.
+fn host_error(message: String) -> RuntimeError {
+    RuntimeError {
+        message,
+        trace: Vec::new(),
+    }
+}
+
+/// The `Error` instance of `class` a caught runtime error becomes, with its `message` and its
+/// `stack` as a list of lines. Allocates without collecting.
+pub(crate) fn error_object(heap: &mut Heap, class: ObjRef, error: &RuntimeError) -> Value {
+    let message = heap.take_string(error.message.clone());
+    let lines = error
+        .trace
+        .iter()
+        .map(|line| Value::Obj(heap.take_string(line.clone())))
+        .collect();
+    let stack = heap.alloc(Obj::List(lines));
+    let mut fields = Table::new();
+    fields.insert(heap.intern("message"), Value::Obj(message));
+    fields.insert(heap.intern("stack"), Value::Obj(stack));
+    Value::Obj(heap.alloc(Obj::Instance(ObjInstance {
+        class,
+        fields,
+        host: None,
+    })))
+}
+
+/// The error ending a run when nothing catches `value`, given `error` reporting it as thrown
+/// here. A caught runtime error thrown again reports where it first happened instead.
+pub(crate) fn uncaught_error(
+    heap: &mut Heap,
+    class: ObjRef,
+    value: Value,
+    mut error: RuntimeError,
+) -> RuntimeError {
+    let message_name = heap.intern("message");
+    let stack_name = heap.intern("stack");
+    let fields = match value.as_obj().map(|r| heap.get(r)) {
+        Some(Obj::Instance(instance)) if instance.class == class => &instance.fields,
+        _ => return error,
+    };
+    if let Some(message) = fields.get(message_name).and_then(|m| heap.as_string(m)) {
+        error.message = message.to_string();
+    }
+    if let Some(Obj::List(lines)) = fields
+        .get(stack_name)
+        .and_then(|s| Some(heap.get(s.as_obj()?)))
+    {
+        error.trace = lines.iter().map(|&line| heap.format_value(line)).collect();
+    }
+    error
+}
+
+/// Reads `object[index]`. Indexing a string interns a new string without collecting first.
+pub(crate) fn get_index(heap: &mut Heap, object: Value, index: Value) -> Result<Value, String> {
+    let Value::Obj(r) = object else {
+        return Err("Only lists, maps and strings can be indexed.".into());
+    };
+    match heap.get(r) {
+        Obj::List(items) => Ok(items[sequence_index(index, items.len(), "List")?]),
+        Obj::Map(map) => {
+            map_key(index)?;
+            Ok(map.get(index).unwrap_or(Value::Nil))
+        }
+        Obj::String(s) => {
+            let chars = s.chars.clone();
+            let count = chars.chars().count();
+            let c = chars
+                .chars()
+                .nth(sequence_index(index, count, "String")?)
+                .unwrap();
+            Ok(Value::Obj(heap.intern(c.encode_utf8(&mut [0; 4]))))
+        }
+        _ => Err("Only lists, maps and strings can be indexed.".into()),
+    }
+}
+
+pub(crate) fn set_index(
+    heap: &mut Heap,
+    object: Value,
+    index: Value,
+    value: Value,
+) -> Result<(), String> {
+    let Value::Obj(r) = object else {
+        return Err("Only lists and maps support index assignment.".into());
+    };
+    let old_size = heap.get(r).size();
+    match heap.get_mut(r) {
+        Obj::List(items) => {
+            let i = sequence_index(index, items.len(), "List")?;
+            items[i] = value;
+            Ok(())
+        }
+        Obj::Map(map) => {
+            map_key(index)?;
+            map.insert(index, value);
+            heap.resized(r, old_size);
+            Ok(())
+        }
+        _ => Err("Only lists and maps support index assignment.".into()),
+    }
+}
+
+/// Checks a list or string index, `kind` names the indexed type in errors.
+fn sequence_index(index: Value, len: usize, kind: &str) -> Result<usize, String> {
+    match index {
+        Value::Number(n) if n.fract() == 0.0 && n >= 0.0 && (n as usize) < len => Ok(n as usize),
+        Value::Number(n) if n.fract() == 0.0 => Err(format!(
+            "{} index {} out of range for length {}.",
+            kind, n, len
+        )),
+        _ => Err(format!("{} index must be an integer.", kind)),
+    }
+}
+
+fn map_key(key: Value) -> Result<(), String> {
+    match key {
+        Value::Number(n) if n.is_nan() => Err("Map key can't be NaN.".into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::OutputBuffer;
+
+    fn run(source: &str, stress: bool) -> (Result<(), InterpretError>, String) {
+        run_dialect(source, stress, Dialect::Lox)
+    }
+
+    fn run_dialect(
+        source: &str,
+        stress: bool,
+        dialect: Dialect,
+    ) -> (Result<(), InterpretError>, String) {
+        let output = OutputBuffer::new();
+        let mut vm = Vm::new();
+        vm.set_output(Box::new(output.clone()));
+        vm.heap_mut().set_stress(stress);
+        vm.set_dialect(dialect);
+        let result = vm.interpret(source.as_bytes());
+        (result, output.contents())
+    }
+
+    const PROGRAM: &str = r#"
+        class Node {
+            init(value) { this.value = value; this.next = nil; }
+        }
+        class Pair < Node {
+            init(a, b) { super.init(a); this.other = b; }
+            sum() { return this.value + this.other; }
+        }
+        fun counter() {
+            var count = 0;
+            fun inc() { count = count + 1; return count; }
+            return inc;
+        }
+        var c = counter();
+        c(); c();
+        print c();
+        for (var i = 0; i < 50; i = i + 1) {
+            var a = Node("a" + "b");
+            var b = Node(a);
+            a.next = b;
+            b.next = a;
+        }
+        var p = Pair(1, 2);
+        print p.sum();
+        var s = "x";
+        for (var i = 0; i < 3; i = i + 1) s = s + s;
+        print s;
+    "#;
+
+    #[test]
+    fn test_programs_under_gc_stress() {
+        for stress in [false, true] {
+            let (result, output) = run(PROGRAM, stress);
+            assert_eq!(result, Ok(()));
+            assert_eq!(output, "3\n3\nxxxxxxxx\n");
+        }
+
+        let (result, _) = run(
+            "fun f() { return g(); }\nfun g() { return 1 + nil; }\nf();",
+            true,
+        );
+        let Err(InterpretError::Runtime(error)) = result else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(
+            error.to_string(),
+            "Operands must be two numbers or two strings.\n[line 2] in g()\n[line 1] in f()\n[line 3] in script"
+        );
+    }
+
+    #[test]
+    fn test_lists_and_maps() {
+        let source = r#"
+            var xs = [1, "two"];
+            xs.push([3]);
+            xs[0] = xs[0] + 10;
+            var m = {"a": xs, 1: nil};
+            m["b"] = m.keys();
+            print m;
+            print xs.pop()[0] + len(m) + m.len();
+        "#;
+        let (result, output) = run_dialect(source, true, Dialect::LoxPlus);
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            output,
+            "{\"a\": [11, \"two\", [3]], 1: nil, \"b\": [\"a\", 1]}\n9\n"
+        );
+
+        let (result, _) = run_dialect("print [1, 2][2];", false, Dialect::LoxPlus);
+        let Err(InterpretError::Runtime(error)) = result else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(error.message, "List index 2 out of range for length 2.");
+
+        let (result, _) = run("print [1];", false);
+        assert!(matches!(result, Err(InterpretError::Compile(_))));
+
+        // Deep nesting is cut short instead of overflowing the native stack
+        let source = "var a = []; for (var i = 0; i < 200000; i = i + 1) a = [a]; print a;";
+        let (result, output) = run_dialect(source, false, Dialect::LoxPlus);
+        assert_eq!(result, Ok(()));
+        let depth = crate::gc::MAX_FORMAT_DEPTH;
+        assert_eq!(
+            output,
+            format!("{}[...]{}\n", "[".repeat(depth), "]".repeat(depth))
+        );
+    }
+
+    #[test]
+    fn test_cycles_are_reclaimed() {
+        let mut vm = Vm::new();
+        vm.interpret(b"class A {} { var a = A(); var b = A(); a.b = b; b.a = a; }")
+            .unwrap();
+        let before = vm.heap().object_count();
+        assert!(vm.collect_garbage() >= 2);
+        assert!(vm.heap().object_count() < before);
+    }
+
+    #[test]
+    fn test_limits() {
+        let message = |vm: &mut Vm, source: &str| match vm.interpret(source.as_bytes()) {
+            Err(InterpretError::Runtime(error)) => error.message,
+            other => panic!("expected a runtime error, got {:?}", other),
+        };
+        let mut vm = Vm::with_host_access(false);
+        vm.set_output(Box::new(io::sink()));
+        vm.set_dialect(Dialect::LoxPlus);
+
+        vm.set_limits(Limits {
+            fuel: Some(1000),
+            ..Limits::default()
+        });
+        assert_eq!(message(&mut vm, "while (true) {}"), "Out of fuel.");
+        // Fuel is per run
+        vm.interpret(b"var i = 0; while (i < 10) i = i + 1;")
+            .unwrap();
+
+        vm.set_limits(Limits {
+            max_frames: 16,
+            ..Limits::default()
+        });
+        assert_eq!(
+            message(&mut vm, "fun f(n) { return f(n + 1); } f(0);"),
+            "Stack overflow."
+        );
+        vm.interpret(b"fun g(n) { if (n > 0) g(n - 1); } g(10);")
+            .unwrap();
+
+        vm.set_limits(Limits {
+            max_heap_bytes: Some(64 * 1024),
+            ..Limits::default()
+        });
+        // Garbage doesn't count
+        vm.interpret(b"for (var i = 0; i < 10000; i = i + 1) { var s = [str(i)]; }")
+            .unwrap();
+        assert_eq!(
+            message(&mut vm, "var xs = []; while (true) xs.push(xs.len());"),
+            "Out of memory."
+        );
+        assert_eq!(
+            message(&mut vm, "var s = \"x\"; while (true) s = s + s;"),
+            "Out of memory."
+        );
+
+        vm.set_limits(Limits {
+            timeout: Some(Duration::from_millis(20)),
+            ..Limits::default()
+        });
+        assert_eq!(message(&mut vm, "while (true) {}"), "Timed out.");
+
+        vm.set_limits(Limits {
+            max_depth: 32,
+            ..Limits::default()
+        });
+        let nested = format!("print {}1{};", "(".repeat(100_000), ")".repeat(100_000));
+        match vm.interpret(nested.as_bytes()) {
+            Err(InterpretError::Compile(errors)) => {
+                assert_eq!(errors[0].message, "Too much nesting.")
+            }
+            other => panic!("expected a compile error, got {:?}", other),
+        }
+        vm.interpret(b"print ((1));").unwrap();
+
+        vm.set_limits(Limits::default());
+        assert_eq!(message(&mut vm, "clock();"), "Undefined variable 'clock'.");
+        assert_eq!(
+            message(&mut vm, "read_file(\"x\");"),
+            "Undefined variable 'read_file'."
+        );
+    }
+
+    #[test]
+    fn test_imports() {
+        let dir = std::env::temp_dir().join(format!("lox-imports-{}", std::process::id()));
+        let shared = dir.join("shared");
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::create_dir_all(&shared).unwrap();
+        let files = [
+            (
+                "lib/math.lox",
+                "import \"util.lox\";\n\
+                 var calls = 0;\n\
+                 fun helper(x) { calls = calls + 1; return x * x; }\n\
+                 export fun square(x) { return double(helper(x)) / 2; }\n\
+                 export var name = \"math\";\n\
+                 export fun fail() { return nil + 1; }\n\
+                 print \"loading math\";\n",
+            ),
+            ("lib/util.lox", "export fun double(x) { return x * 2; }\n"),
+            ("a.lox", "import \"b.lox\";\n"),
+            ("b.lox", "import \"a.lox\";\n"),
+            ("bad.lox", "var = 1;\n"),
+            ("shared/greet.lox", "export var greeting = \"hi\";\n"),
+        ];
+        for (path, source) in files {
+            std::fs::write(dir.join(path), source).unwrap();
+        }
+
+        let run = |source: &str| {
+            let output = OutputBuffer::new();
+            let mut vm = Vm::new();
+            vm.set_output(Box::new(output.clone()));
+            vm.set_dialect(Dialect::LoxPlus);
+            vm.heap_mut().set_stress(true);
+            vm.set_script_path(dir.join("main.lox"));
+            vm.set_search_path(vec![shared.clone()]);
+            let result = vm.interpret(source.as_bytes());
+            (result, output.take())
+        };
+        let error = |source: &str| match run(source).0 {
+            Err(InterpretError::Runtime(error)) => error,
+            other => panic!("expected a runtime error, got {:?}", other),
+        };
+
+        // Modules run once, see only their own globals and share only their exports
+        let (result, output) = run("\
+            var calls = 100;
+            import \"lib/math.lox\";
+            import \"lib/math.lox\";
+            print square(3);
+            print name;
+            print calls;
+            import \"greet.lox\";
+            print greeting;
+        ");
+        result.unwrap();
+        assert_eq!(output, "loading math\n9\nmath\n100\nhi\n");
+        assert_eq!(
+            error("import \"lib/math.lox\"; helper(1);").message,
+            "Undefined variable 'helper'."
+        );
+        assert_eq!(
+            error("import \"lib/math.lox\"; double(1);").message,
+            "Undefined variable 'double'."
+        );
+
+        let math = dir.join("lib/math.lox");
+        let failure = error("import \"lib/math.lox\";\nfail();");
+        assert_eq!(
+            failure.trace,
+            [
+                format!("[line 6] in fail() in {}", math.display()),
+                "[line 2] in script".to_string()
+            ]
+        );
+
+        let (a, b) = (dir.join("a.lox"), dir.join("b.lox"));
+        assert_eq!(
+            error("import \"a.lox\";").message,
+            format!(
+                "Import cycle: {} -> {} -> {}.",
+                a.display(),
+                b.display(),
+                a.display()
+            )
+        );
+        let bad = dir.join("bad.lox");
+        assert_eq!(
+            error("import \"bad.lox\";").message,
+            format!(
+                "Can't compile module '{0}'.\n{0}: [line 1] Error at '=': Expect variable name.",
+                bad.display()
+            )
+        );
+        assert_eq!(
+            error("import \"missing.lox\";").message,
+            "Can't find module 'missing.lox'."
+        );
+
+        // Without host access no file is read, wherever it is
+        let util = dir.join("lib/util.lox");
+        for path in ["lib/util.lox", &util.display().to_string()] {
+            let mut vm = Vm::with_host_access(false);
+            vm.set_dialect(Dialect::LoxPlus);
+            vm.set_script_path(dir.join("main.lox"));
+            let source = format!("import \"{}\";", path);
+            match vm.interpret(source.as_bytes()) {
+                Err(InterpretError::Runtime(error)) => assert_eq!(
+                    error.message,
+                    format!("Can't import '{}' without host access.", path)
+                ),
+                other => panic!("expected a runtime error, got {:?}", other),
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
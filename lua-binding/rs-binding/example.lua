@@ -1,8 +1,32 @@
----@class kznllm
----@field hello fun(message: string): nil
----Prints hello world
-
 ---@type kznllm
 local kznllm_c = require("kznllm_c")
 
 kznllm_c.hello("world")
+
+local tree, errors = kznllm_c.parse("print 1 +;")
+if not tree then
+  for _, error in ipairs(errors) do
+    print(error.text)
+  end
+end
+
+local vm = kznllm_c.new_vm({ fuel = 1000000 })
+local output = vm:eval("fun square(n) { return n * n; } print square(3);")
+io.write(output) -- 9
+print(vm:call("square", 4)) -- 16
+
+local buffer = kznllm_c.buffer()
+local tokens = kznllm_c.token_stream("var answer = 42;")
+for token in tokens.next, tokens do
+  buffer:push(token.tag .. " " .. tokens:text(token) .. "\n")
+end
+io.write(buffer:take())
+
+local decoder = kznllm_c.stream_decoder()
+for _, chunk in ipairs({ 'data: {"choices":[{"delta":{"content":"Hel', 'lo"}}]}\n\ndata: [DONE]\n\n' }) do
+  local events, text = decoder:feed(chunk)
+  io.write(text) -- Hello
+  if events[#events] and events[#events].done then
+    print()
+  end
+end
@@ -0,0 +1,263 @@
+use crate::buffer::Buffer;
+use crate::stream::{self, StreamDecoder};
+use crate::token_stream::{self, TokenStream};
+use crate::vm::{self, LoxVm};
+use crate::{syntax, MODULE_DOC};
+use mlua::prelude::*;
+use std::fmt::Write;
+
+/// LuaLS description of a userdata type or table, rendered as a `---@class` with each
+/// `---@field` followed by its documentation.
+pub struct ClassDoc {
+    /// Qualified class name, e.g. `kznllm.Buffer`.
+    pub name: &'static str,
+    pub doc: &'static str,
+    /// Operators from metamethods as `(operator, result type)`, e.g. `("len", "integer")`.
+    pub operators: &'static [(&'static str, &'static str)],
+    pub fields: &'static [FieldDoc],
+}
+
+pub struct FieldDoc {
+    /// Field name, or a key type in brackets like `[integer]` for `__index` lookups.
+    pub name: &'static str,
+    /// LuaLS type, e.g. `fun(self: kznllm.Buffer, text: string)`.
+    pub ty: &'static str,
+    pub doc: &'static str,
+}
+
+/// A userdata type that documents itself for LuaLS. Its `__gc` is the Rust `Drop`, which mlua
+/// runs when Lua collects the value.
+pub trait LuaClass: LuaUserData {
+    const DOC: ClassDoc;
+}
+
+/// Registers the methods of a [`LuaClass`], checking in debug builds that each is annotated in
+/// its [`ClassDoc`], as `export` does for module functions.
+pub trait ClassMethods<T: LuaClass>: LuaUserDataMethods<T> {
+    fn method<M, A, R>(&mut self, name: &str, method: M)
+    where
+        M: Fn(&Lua, &T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        debug_assert!(
+            T::DOC.has_field(name),
+            "{} has no annotation in {}",
+            name,
+            T::DOC.name
+        );
+        self.add_method(name, method);
+    }
+
+    fn method_mut<M, A, R>(&mut self, name: &str, method: M)
+    where
+        M: FnMut(&Lua, &mut T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        debug_assert!(
+            T::DOC.has_field(name),
+            "{} has no annotation in {}",
+            name,
+            T::DOC.name
+        );
+        self.add_method_mut(name, method);
+    }
+
+    fn meta_method<M, A, R>(&mut self, meta: LuaMetaMethod, method: M)
+    where
+        M: Fn(&Lua, &T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        debug_assert!(
+            T::DOC.has_meta(meta),
+            "{} has no annotation in {}",
+            meta,
+            T::DOC.name
+        );
+        self.add_meta_method(meta, method);
+    }
+}
+
+impl<T: LuaClass, M: LuaUserDataMethods<T>> ClassMethods<T> for M {}
+
+impl ClassDoc {
+    fn has_field(&self, name: &str) -> bool {
+        self.fields.iter().any(|field| field.name == name)
+    }
+
+    /// Whether the operator or `[key]` field a metamethod provides is annotated. LuaLS has no
+    /// annotation for `__tostring`.
+    fn has_meta(&self, meta: LuaMetaMethod) -> bool {
+        match meta {
+            LuaMetaMethod::ToString => true,
+            LuaMetaMethod::Index => self.fields.iter().any(|field| field.name.starts_with('[')),
+            _ => {
+                let name = meta.name().trim_start_matches("__");
+                self.operators.iter().any(|(operator, _)| *operator == name)
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for line in self.doc.lines() {
+            let _ = writeln!(out, "---{}", line);
+        }
+        let _ = writeln!(out, "---@class {}", self.name);
+        for (operator, result) in self.operators {
+            let _ = writeln!(out, "---@operator {}: {}", operator, result);
+        }
+        for field in self.fields {
+            let _ = writeln!(out, "---@field {} {}", field.name, field.ty);
+            for line in field.doc.lines() {
+                let _ = writeln!(out, "---{}", line);
+            }
+        }
+        out
+    }
+}
+
+/// The LuaLS definitions file for the module, committed as `types/kznllm_c.lua`.
+pub fn stub() -> String {
+    let classes = [
+        MODULE_DOC,
+        token_stream::TOKEN_DOC,
+        syntax::OPTIONS_DOC,
+        syntax::DIAGNOSTIC_DOC,
+        syntax::LINT_DOC,
+        vm::OPTIONS_DOC,
+        stream::OPTIONS_DOC,
+        stream::EVENT_DOC,
+        Buffer::DOC,
+        TokenStream::DOC,
+        LoxVm::DOC,
+        StreamDecoder::DOC,
+    ];
+    let mut out = String::from("---@meta kznllm_c\n");
+    for class in &classes {
+        out.push('\n');
+        out.push_str(&class.render());
+    }
+    out.push_str("\n---@type kznllm\nlocal kznllm_c\nreturn kznllm_c\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the names `add_methods` registers, running the checks in [`ClassMethods`].
+    #[derive(Default)]
+    struct Registered(Vec<String>);
+
+    impl<T> LuaUserDataMethods<T> for Registered {
+        fn add_method<M, A, R>(&mut self, name: impl ToString, _: M)
+        where
+            M: Fn(&Lua, &T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        {
+            self.0.push(name.to_string());
+        }
+
+        fn add_method_mut<M, A, R>(&mut self, name: impl ToString, _: M)
+        where
+            M: FnMut(&Lua, &mut T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        {
+            self.0.push(name.to_string());
+        }
+
+        fn add_function<F, A, R>(&mut self, name: impl ToString, _: F)
+        where
+            F: Fn(&Lua, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        {
+            self.0.push(name.to_string());
+        }
+
+        fn add_function_mut<F, A, R>(&mut self, name: impl ToString, _: F)
+        where
+            F: FnMut(&Lua, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        {
+            self.0.push(name.to_string());
+        }
+
+        fn add_meta_method<M, A, R>(&mut self, _: impl ToString, _: M)
+        where
+            M: Fn(&Lua, &T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        {
+        }
+
+        fn add_meta_method_mut<M, A, R>(&mut self, _: impl ToString, _: M)
+        where
+            M: FnMut(&Lua, &mut T, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        {
+        }
+
+        fn add_meta_function<F, A, R>(&mut self, _: impl ToString, _: F)
+        where
+            F: Fn(&Lua, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        {
+        }
+
+        fn add_meta_function_mut<F, A, R>(&mut self, _: impl ToString, _: F)
+        where
+            F: FnMut(&Lua, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+        {
+        }
+    }
+
+    fn assert_documented<T: LuaClass>() {
+        let mut registered = Registered::default();
+        T::add_methods(&mut registered);
+        for field in T::DOC.fields {
+            assert!(
+                field.name.starts_with('[') || registered.0.iter().any(|name| name == field.name),
+                "{} is annotated in {} but not registered",
+                field.name,
+                T::DOC.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_methods_match_annotations() {
+        assert_documented::<Buffer>();
+        assert_documented::<TokenStream>();
+        assert_documented::<LoxVm>();
+        assert_documented::<StreamDecoder>();
+    }
+
+    #[test]
+    fn test_renders_like_example() {
+        let doc = ClassDoc {
+            name: "kznllm.Example",
+            doc: "Shown in tests",
+            operators: &[("len", "integer")],
+            fields: &[
+                FieldDoc {
+                    name: "hello",
+                    ty: "fun(message: string): nil",
+                    doc: "Prints hello world",
+                },
+                FieldDoc {
+                    name: "[integer]",
+                    ty: "string?",
+                    doc: "",
+                },
+            ],
+        };
+        assert_eq!(
+            doc.render(),
+            "---Shown in tests\n\
+             ---@class kznllm.Example\n\
+             ---@operator len: integer\n\
+             ---@field hello fun(message: string): nil\n\
+             ---Prints hello world\n\
+             ---@field [integer] string?\n"
+        );
+        assert!(
+            include_str!("../types/kznllm_c.lua") == stub(),
+            "types/kznllm_c.lua is stale, run `cargo run --bin kznllm-stubs > types/kznllm_c.lua`"
+        );
+    }
+}
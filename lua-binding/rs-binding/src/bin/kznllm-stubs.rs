@@ -0,0 +1,4 @@
+/// Prints the LuaLS definitions for `kznllm_c`, committed as `types/kznllm_c.lua`.
+fn main() {
+    print!("{}", rs_binding::annotations::stub());
+}
@@ -0,0 +1,96 @@
+use crate::annotations::{ClassDoc, ClassMethods, FieldDoc, LuaClass};
+use mlua::prelude::*;
+
+/// Bytes appended from Lua, e.g. streamed output, kept in Rust until taken out as one string.
+#[derive(Default)]
+pub struct Buffer {
+    bytes: Vec<u8>,
+}
+
+impl Buffer {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+
+    /// The byte at a 1-based `index`, as Lua's `string.byte` counts.
+    pub fn byte(&self, index: i64) -> Option<u8> {
+        let index = usize::try_from(index.checked_sub(1)?).ok()?;
+        self.bytes.get(index).copied()
+    }
+}
+
+impl LuaUserData for Buffer {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.method_mut("push", |_, this, text: LuaString| {
+            this.push(&text.as_bytes());
+            Ok(())
+        });
+        methods.method_mut("take", |lua, this, ()| lua.create_string(this.take()));
+        methods.method_mut("clear", |_, this, ()| {
+            this.bytes.clear();
+            Ok(())
+        });
+        methods.meta_method(LuaMetaMethod::ToString, |lua, this, ()| {
+            lua.create_string(&this.bytes)
+        });
+        methods.meta_method(LuaMetaMethod::Len, |_, this, ()| Ok(this.bytes.len()));
+        methods.meta_method(LuaMetaMethod::Index, |_, this, key: LuaValue| {
+            Ok(match key {
+                LuaValue::Integer(i) => this.byte(i),
+                _ => None,
+            })
+        });
+    }
+}
+
+impl LuaClass for Buffer {
+    const DOC: ClassDoc = ClassDoc {
+        name: "kznllm.Buffer",
+        doc: "Collects text in Rust, e.g. a streamed response, until it's taken out",
+        operators: &[("len", "integer")],
+        fields: &[
+            FieldDoc {
+                name: "[integer]",
+                ty: "integer?",
+                doc: "The byte at a 1-based position, like string.byte",
+            },
+            FieldDoc {
+                name: "push",
+                ty: "fun(self: kznllm.Buffer, text: string)",
+                doc: "Appends text",
+            },
+            FieldDoc {
+                name: "take",
+                ty: "fun(self: kznllm.Buffer): string",
+                doc: "Returns the contents and empties the buffer",
+            },
+            FieldDoc {
+                name: "clear",
+                ty: "fun(self: kznllm.Buffer)",
+                doc: "",
+            },
+        ],
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_and_take() {
+        let mut buffer = Buffer::default();
+        buffer.push(b"hel");
+        buffer.push(b"lo");
+        assert_eq!(buffer.byte(1), Some(b'h'));
+        assert_eq!(buffer.byte(5), Some(b'o'));
+        assert_eq!(buffer.byte(6), None);
+        assert_eq!(buffer.byte(0), None);
+        assert_eq!(buffer.take(), b"hello");
+        assert_eq!(buffer.take(), b"");
+    }
+}
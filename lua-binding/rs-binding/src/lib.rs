@@ -1,13 +1,155 @@
+pub mod annotations;
+mod buffer;
+mod stream;
+mod syntax;
+mod token_stream;
+mod vm;
+
+use annotations::{ClassDoc, FieldDoc};
+use buffer::Buffer;
+use interpreter_rs::json::Json;
 use mlua::prelude::*;
+use stream::StreamDecoder;
+use token_stream::TokenStream;
+use vm::{LoxVm, VmOptions};
+
+/// The table returned by `require("kznllm_c")`. Every export needs an entry here.
+pub const MODULE_DOC: ClassDoc = ClassDoc {
+    name: "kznllm",
+    doc: "",
+    operators: &[],
+    fields: &[
+        FieldDoc {
+            name: "hello",
+            ty: "fun(message: string): nil",
+            doc: "Prints hello world",
+        },
+        FieldDoc {
+            name: "tokenize",
+            ty: "fun(source: string): kznllm.Token[]",
+            doc: "Splits Lox source into tokens",
+        },
+        FieldDoc {
+            name: "parse",
+            ty: "fun(source: string, options?: kznllm.SyntaxOptions): table[]?, kznllm.SyntaxError[]?",
+            doc: "Parses Lox source into statement nodes, or returns nil and the syntax errors",
+        },
+        FieldDoc {
+            name: "lint",
+            ty: "fun(source: string, options?: kznllm.SyntaxOptions): kznllm.Lint[]?, kznllm.SyntaxError[]?",
+            doc: "Checks Lox source for likely mistakes",
+        },
+        FieldDoc {
+            name: "new_vm",
+            ty: "fun(options?: kznllm.VmOptions): kznllm.LoxVm",
+            doc: "Creates a VM whose globals persist between calls",
+        },
+        FieldDoc {
+            name: "token_stream",
+            ty: "fun(source: string): kznllm.TokenStream",
+            doc: "Lexes Lox source into a stream of tokens",
+        },
+        FieldDoc {
+            name: "buffer",
+            ty: "fun(): kznllm.Buffer",
+            doc: "Creates an empty buffer",
+        },
+        FieldDoc {
+            name: "stream_decoder",
+            ty: "fun(options?: kznllm.StreamOptions): kznllm.StreamDecoder",
+            doc: "Creates a decoder for server-sent events or NDJSON fed in chunks",
+        },
+    ],
+};
 
 fn hello(_: &Lua, name: String) -> LuaResult<()> {
     println!("hello, {}!", name);
     Ok(())
 }
 
+fn tokenize(lua: &Lua, source: LuaString) -> LuaResult<LuaTable> {
+    let tokens = syntax::tokenize(&source.as_bytes());
+    let table = lua.create_table_with_capacity(tokens.len(), 0)?;
+    for token in &tokens {
+        table.raw_push(token_stream::token_table(lua, token)?)?;
+    }
+    Ok(table)
+}
+
+/// Returns the syntax tree, or nil and the syntax errors.
+fn parse(
+    lua: &Lua,
+    (source, options): (LuaString, Option<LuaTable>),
+) -> LuaResult<(LuaValue, LuaValue)> {
+    let dialect = syntax::dialect(options.as_ref())?;
+    match syntax::parse(&source.as_bytes(), dialect) {
+        Ok(tree) => Ok((syntax::to_lua(lua, &tree)?, LuaValue::Nil)),
+        Err(errors) => {
+            let errors = Json::Array(errors.iter().map(syntax::diagnostic).collect());
+            Ok((LuaValue::Nil, syntax::to_lua(lua, &errors)?))
+        }
+    }
+}
+
+/// Returns the lints, or nil and the syntax errors.
+fn lint(
+    lua: &Lua,
+    (source, options): (LuaString, Option<LuaTable>),
+) -> LuaResult<(LuaValue, LuaValue)> {
+    let dialect = syntax::dialect(options.as_ref())?;
+    match syntax::lint(&source.as_bytes(), dialect) {
+        Ok(lints) => {
+            let lints = Json::Array(lints.iter().map(syntax::lint_json).collect());
+            Ok((syntax::to_lua(lua, &lints)?, LuaValue::Nil))
+        }
+        Err(errors) => {
+            let errors = Json::Array(errors.iter().map(syntax::diagnostic).collect());
+            Ok((LuaValue::Nil, syntax::to_lua(lua, &errors)?))
+        }
+    }
+}
+
+fn new_vm(_: &Lua, options: Option<LuaTable>) -> LuaResult<LoxVm> {
+    Ok(LoxVm::new(VmOptions::from_lua(options.as_ref())?))
+}
+
+fn token_stream(_: &Lua, source: LuaString) -> LuaResult<TokenStream> {
+    Ok(TokenStream::new(&source.as_bytes()))
+}
+
+fn buffer(_: &Lua, _: ()) -> LuaResult<Buffer> {
+    Ok(Buffer::default())
+}
+
+fn stream_decoder(_: &Lua, options: Option<LuaTable>) -> LuaResult<StreamDecoder> {
+    Ok(StreamDecoder::new(stream::format(options.as_ref())?))
+}
+
+/// Sets `exports[name]`, checking in debug builds that `name` is annotated in [`MODULE_DOC`].
+fn export<F, A, R>(lua: &Lua, exports: &LuaTable, name: &str, function: F) -> LuaResult<()>
+where
+    F: Fn(&Lua, A) -> LuaResult<R> + mlua::MaybeSend + 'static,
+    A: FromLuaMulti,
+    R: IntoLuaMulti,
+{
+    debug_assert!(
+        MODULE_DOC.fields.iter().any(|field| field.name == name),
+        "{} has no annotation in MODULE_DOC",
+        name
+    );
+    exports.set(name, lua.create_function(function)?)
+}
+
 #[mlua::lua_module]
 fn kznllm_c(lua: &Lua) -> LuaResult<LuaTable> {
     let exports = lua.create_table()?;
-    exports.set("hello", lua.create_function(hello)?)?;
+    export(lua, &exports, "hello", hello)?;
+    export(lua, &exports, "tokenize", tokenize)?;
+    export(lua, &exports, "parse", parse)?;
+    export(lua, &exports, "lint", lint)?;
+    export(lua, &exports, "new_vm", new_vm)?;
+    export(lua, &exports, "token_stream", token_stream)?;
+    export(lua, &exports, "buffer", buffer)?;
+    export(lua, &exports, "stream_decoder", stream_decoder)?;
     Ok(exports)
 }
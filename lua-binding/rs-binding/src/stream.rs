@@ -0,0 +1,423 @@
+use crate::annotations::{ClassDoc, ClassMethods, FieldDoc, LuaClass};
+use interpreter_rs::json::{self, Json};
+use mlua::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Server-sent events, `data: ...` lines ended by a blank line.
+    Sse,
+    /// One JSON document per line, as Ollama streams.
+    Ndjson,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The `event:` field, `"message"` when none was given. Always `None` for NDJSON.
+    pub event: Option<String>,
+    /// The `data:` lines joined by `\n`, or the whole NDJSON line.
+    pub data: String,
+    /// The last `id:` seen, which carries over to later events.
+    pub id: Option<String>,
+    /// The text `data` adds to the response, see [`delta`].
+    pub text: Option<String>,
+    /// Whether `data` marks the end of the response, e.g. `[DONE]`.
+    pub done: bool,
+}
+
+/// Splits a byte stream into events, keeping whatever follows the last line break until the next
+/// chunk. Lines are decoded only when complete, so a codepoint split between chunks survives.
+pub struct StreamDecoder {
+    format: Format,
+    line: Vec<u8>,
+    /// A chunk ended in `\r`, so a `\n` starting the next one belongs to the same line break.
+    after_cr: bool,
+    started: bool,
+    event: Option<String>,
+    data: Option<String>,
+    id: Option<String>,
+    /// Why the first event the current `feed` or `finish` couldn't decode was dropped.
+    error: Option<String>,
+}
+
+impl StreamDecoder {
+    pub fn new(format: Format) -> Self {
+        StreamDecoder {
+            format,
+            line: Vec::new(),
+            after_cr: false,
+            started: false,
+            event: None,
+            data: None,
+            id: None,
+            error: None,
+        }
+    }
+
+    /// The events completed by `chunk`. Fails if one holds JSON nested too deeply to decode,
+    /// dropping the chunk's events, though the decoder still takes the chunk into account.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Event>, String> {
+        let mut events = Vec::new();
+        let mut rest = chunk;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n' || b == b'\r') {
+            let newline = rest[end];
+            if end == 0 && newline == b'\n' && self.after_cr {
+                self.after_cr = false;
+                rest = &rest[1..];
+                continue;
+            }
+            self.line.extend_from_slice(&rest[..end]);
+            self.after_cr = newline == b'\r';
+            let line = std::mem::take(&mut self.line);
+            self.line_ended(&line, &mut events);
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            self.after_cr = false;
+            self.line.extend_from_slice(rest);
+        }
+        self.error.take().map_or(Ok(events), Err)
+    }
+
+    /// Ends the stream: a last NDJSON line without a line break still counts, while an SSE event
+    /// missing its blank line is dropped, as the SSE spec says. The decoder can then be reused.
+    pub fn finish(&mut self) -> Result<Vec<Event>, String> {
+        let mut events = Vec::new();
+        let line = std::mem::take(&mut self.line);
+        if !line.is_empty() {
+            self.line_ended(&line, &mut events);
+        }
+        let error = self.error.take();
+        *self = StreamDecoder::new(self.format);
+        error.map_or(Ok(events), Err)
+    }
+
+    fn line_ended(&mut self, line: &[u8], events: &mut Vec<Event>) {
+        let line = match line.strip_prefix("\u{feff}".as_bytes()) {
+            Some(rest) if !self.started => rest,
+            _ => line,
+        };
+        self.started = true;
+        let line = String::from_utf8_lossy(line);
+        if self.format == Format::Ndjson {
+            if !line.trim().is_empty() {
+                self.push(event(None, line.into_owned(), None), events);
+            }
+            return;
+        }
+        if line.is_empty() {
+            let name = self.event.take();
+            if let Some(data) = self.data.take() {
+                let name = name.unwrap_or_else(|| "message".to_string());
+                self.push(event(Some(name), data, self.id.clone()), events);
+            }
+            return;
+        }
+        if line.starts_with(':') {
+            return;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (&*line, ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => match &mut self.data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_string()),
+            },
+            "id" if !value.contains('\0') => {
+                self.id = (!value.is_empty()).then(|| value.to_string());
+            }
+            _ => {}
+        }
+    }
+
+    fn push(&mut self, event: Result<Event, String>, events: &mut Vec<Event>) {
+        match event {
+            Ok(event) => events.push(event),
+            Err(error) => {
+                self.error.get_or_insert(error);
+            }
+        }
+    }
+}
+
+/// An event, unless its data is JSON nested deeper than [`Json::parse`] accepts. Data that isn't
+/// JSON at all is fine, it just has no text.
+fn event(name: Option<String>, data: String, id: Option<String>) -> Result<Event, String> {
+    let json = match Json::parse(&data) {
+        Ok(json) => json,
+        Err(error) if error.starts_with(json::TOO_DEEP) => {
+            return Err(format!("Can't decode event data: {}", error));
+        }
+        Err(_) => Json::Null,
+    };
+    Ok(Event {
+        event: name,
+        text: delta(&json),
+        done: data.trim() == "[DONE]" || is_done(&json),
+        data,
+        id,
+    })
+}
+
+/// The text a chunk of a streamed completion adds, in the response shapes of the OpenAI chat,
+/// completions and responses APIs, Anthropic messages, Gemini and Ollama.
+pub fn delta(json: &Json) -> Option<String> {
+    let choice = json
+        .get("choices")
+        .as_array()
+        .and_then(|choices| choices.first())
+        .unwrap_or(&Json::Null);
+    let text = choice
+        .at(&["delta", "content"])
+        .as_str()
+        .or_else(|| choice.get("text").as_str())
+        .or_else(|| match json.get("type").as_str() {
+            Some("response.output_text.delta") => json.get("delta").as_str(),
+            Some("content_block_delta") => json.at(&["delta", "text"]).as_str(),
+            _ => None,
+        })
+        .or_else(|| json.at(&["message", "content"]).as_str())
+        .or_else(|| json.get("response").as_str());
+    if let Some(text) = text {
+        return Some(text.to_string());
+    }
+    let parts = json
+        .get("candidates")
+        .as_array()
+        .and_then(|candidates| candidates.first())?
+        .at(&["content", "parts"])
+        .as_array()?;
+    Some(
+        parts
+            .iter()
+            .filter_map(|part| part.get("text").as_str())
+            .collect(),
+    )
+}
+
+fn is_done(json: &Json) -> bool {
+    matches!(
+        json.get("type").as_str(),
+        Some("message_stop" | "response.completed")
+    ) || json.get("done").as_bool() == Some(true)
+}
+
+/// Reads the `format` option, `"sse"` (the default) or `"ndjson"`.
+pub fn format(options: Option<&LuaTable>) -> LuaResult<Format> {
+    let Some(options) = options else {
+        return Ok(Format::Sse);
+    };
+    match options.get::<Option<String>>("format")?.as_deref() {
+        None | Some("sse") => Ok(Format::Sse),
+        Some("ndjson") => Ok(Format::Ndjson),
+        Some(other) => Err(LuaError::runtime(format!("unknown format '{}'", other))),
+    }
+}
+
+/// The events as `kznllm.StreamEvent` tables, and their text joined.
+fn events_to_lua(lua: &Lua, events: Vec<Event>) -> LuaResult<(LuaTable, String)> {
+    let table = lua.create_table_with_capacity(events.len(), 0)?;
+    let mut text = String::new();
+    for event in events {
+        let entry = lua.create_table_with_capacity(0, 5)?;
+        entry.set("event", event.event)?;
+        entry.set("data", event.data)?;
+        entry.set("id", event.id)?;
+        if let Some(delta) = &event.text {
+            text.push_str(delta);
+        }
+        entry.set("text", event.text)?;
+        entry.set("done", event.done)?;
+        table.raw_push(entry)?;
+    }
+    Ok((table, text))
+}
+
+/// The options table read by [`format`].
+pub const OPTIONS_DOC: ClassDoc = ClassDoc {
+    name: "kznllm.StreamOptions",
+    doc: "",
+    operators: &[],
+    fields: &[FieldDoc {
+        name: "format?",
+        ty: "\"sse\"|\"ndjson\"",
+        doc: "",
+    }],
+};
+
+/// The tables built by [`events_to_lua`].
+pub const EVENT_DOC: ClassDoc = ClassDoc {
+    name: "kznllm.StreamEvent",
+    doc: "",
+    operators: &[],
+    fields: &[
+        FieldDoc {
+            name: "event?",
+            ty: "string",
+            doc: "The event type, \"message\" unless given. Nil for NDJSON",
+        },
+        FieldDoc {
+            name: "data",
+            ty: "string",
+            doc: "",
+        },
+        FieldDoc {
+            name: "id?",
+            ty: "string",
+            doc: "The last event id seen",
+        },
+        FieldDoc {
+            name: "text?",
+            ty: "string",
+            doc: "The text delta, for OpenAI, Anthropic, Gemini and Ollama responses",
+        },
+        FieldDoc {
+            name: "done",
+            ty: "boolean",
+            doc: "Whether this ends the response, e.g. data: [DONE]",
+        },
+    ],
+};
+
+impl LuaUserData for StreamDecoder {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.method_mut("feed", |lua, this, chunk: LuaString| {
+            let events = this.feed(&chunk.as_bytes()).map_err(LuaError::runtime)?;
+            events_to_lua(lua, events)
+        });
+        methods.method_mut("finish", |lua, this, ()| {
+            events_to_lua(lua, this.finish().map_err(LuaError::runtime)?)
+        });
+        methods.meta_method(LuaMetaMethod::ToString, |_, this, ()| {
+            Ok(format!(
+                "StreamDecoder({:?}, {} bytes pending)",
+                this.format,
+                this.line.len()
+            ))
+        });
+    }
+}
+
+impl LuaClass for StreamDecoder {
+    const DOC: ClassDoc = ClassDoc {
+        name: "kznllm.StreamDecoder",
+        doc: "Decodes a streamed response fed in chunks of any size",
+        operators: &[],
+        fields: &[
+            FieldDoc {
+                name: "feed",
+                ty: "fun(self: kznllm.StreamDecoder, chunk: string): kznllm.StreamEvent[], string",
+                doc: "Returns the events the chunk completes and their text joined. Errors if one holds\nJSON nested too deeply to decode, dropping the chunk's events",
+            },
+            FieldDoc {
+                name: "finish",
+                ty: "fun(self: kznllm.StreamDecoder): kznllm.StreamEvent[], string",
+                doc: "Ends the stream, returning a last unterminated NDJSON line, and resets the decoder",
+            },
+        ],
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(format: Format, chunks: &[&[u8]]) -> Vec<Event> {
+        let mut decoder = StreamDecoder::new(format);
+        let mut events = Vec::new();
+        for chunk in chunks {
+            events.extend(decoder.feed(chunk).unwrap());
+        }
+        events.extend(decoder.finish().unwrap());
+        events
+    }
+
+    #[test]
+    fn test_events_split_anywhere() {
+        let stream = "\u{feff}: ping\r\n\
+                      data: {\"choices\":[{\"delta\":{\"content\":\"h\u{e9}\"}}]}\r\n\r\n\
+                      event: content_block_delta\r\nid: 7\r\n\
+                      data: {\"type\":\"content_block_delta\",\"delta\":{\"text\":\"llo \u{1f44b}\"}}\r\r\
+                      data: a\ndata:b\n\n\
+                      data: [DONE]\n\n\
+                      data: unterminated\n"
+            .as_bytes();
+        let whole = decode(Format::Sse, &[stream]);
+        let texts = whole.iter().map(|e| e.text.as_deref()).collect::<Vec<_>>();
+        assert_eq!(texts, [Some("h\u{e9}"), Some("llo \u{1f44b}"), None, None]);
+        assert_eq!(whole[1].event.as_deref(), Some("content_block_delta"));
+        assert_eq!(
+            (whole[2].data.as_str(), whole[2].id.as_deref()),
+            ("a\nb", Some("7"))
+        );
+        assert_eq!(whole[0].event.as_deref(), Some("message"));
+        assert!(whole[3].done);
+        // Chunks can end anywhere, inside a codepoint or between \r and \n
+        for split in 0..stream.len() {
+            let (a, b) = stream.split_at(split);
+            assert_eq!(decode(Format::Sse, &[a, b]), whole, "split at {}", split);
+        }
+        let bytes = stream.chunks(1).collect::<Vec<_>>();
+        assert_eq!(decode(Format::Sse, &bytes), whole);
+
+        let ndjson = decode(
+            Format::Ndjson,
+            &[
+                b"{\"message\":{\"content\":\"hi\"},\"done\":false}\n\n{\"respo",
+                b"nse\":\"!\",\"done\":true}",
+            ],
+        );
+        let texts = ndjson.iter().map(|e| e.text.as_deref()).collect::<Vec<_>>();
+        assert_eq!(texts, [Some("hi"), Some("!")]);
+        assert_eq!(
+            ndjson.iter().map(|e| e.done).collect::<Vec<_>>(),
+            [false, true]
+        );
+    }
+
+    #[test]
+    fn test_deltas() {
+        let text = |json: &str| delta(&Json::parse(json).unwrap());
+        assert_eq!(text(r#"{"choices":[{"text":"a"}]}"#).as_deref(), Some("a"));
+        assert_eq!(
+            text(r#"{"type":"response.output_text.delta","delta":"b"}"#).as_deref(),
+            Some("b")
+        );
+        assert_eq!(
+            text(r#"{"candidates":[{"content":{"parts":[{"text":"c"},{"text":"d"}]}}]}"#)
+                .as_deref(),
+            Some("cd")
+        );
+        assert_eq!(
+            text(r#"{"type":"message_start","message":{"id":"x"}}"#),
+            None
+        );
+        assert_eq!(
+            text(r#"{"choices":[{"delta":{"role":"assistant"}}]}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn test_deeply_nested_event() {
+        let mut decoder = StreamDecoder::new(Format::Sse);
+        let nested = format!(
+            "data: {}\n\ndata: {{\"response\":\"ok\"}}\n\n",
+            "[".repeat(200_000)
+        );
+        let error = decoder.feed(nested.as_bytes()).unwrap_err();
+        assert!(error.contains(json::TOO_DEEP), "{}", error);
+        // The decoder carries on with the next chunk
+        let events = decoder.feed(b"data: {\"response\":\"!\"}\n\n").unwrap();
+        assert_eq!(events[0].text.as_deref(), Some("!"));
+
+        let mut decoder = StreamDecoder::new(Format::Ndjson);
+        decoder.feed("[".repeat(1000).as_bytes()).unwrap();
+        assert!(decoder.finish().is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use crate::annotations::{ClassDoc, FieldDoc};
+use interpreter_rs::ast::*;
+use interpreter_rs::diagnostic::Diagnostic;
+use interpreter_rs::json::Json;
+use interpreter_rs::lint::{self, Lint};
+use interpreter_rs::parser::{self, Dialect};
+use lexer::with_opt_iterator::{Loc, Tokenizer};
+use mlua::prelude::*;
+
+/// A token as handed to Lua. Offsets are 0-based bytes with `end` exclusive, lines 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub tag: String,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+}
+
+pub fn tokenize(source: &[u8]) -> Vec<TokenInfo> {
+    let mut line = 1;
+    let mut counted = 0;
+    Tokenizer::new(source)
+        .map(|token| {
+            line += source[counted..token.loc.start]
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count();
+            counted = token.loc.start;
+            TokenInfo {
+                tag: format!("{:?}", token.tag),
+                start: token.loc.start,
+                end: token.loc.end,
+                line,
+            }
+        })
+        .collect()
+}
+
+/// The program as a tree of `{type = ..., line = ...}` nodes, or the syntax errors.
+pub fn parse(source: &[u8], dialect: Dialect) -> Result<Json, Vec<Diagnostic>> {
+    let program = parser::parse_dialect(source, dialect)?;
+    Ok(statements(&program))
+}
+
+pub fn lint(source: &[u8], dialect: Dialect) -> Result<Vec<Lint>, Vec<Diagnostic>> {
+    lint::lint(source, dialect)
+}
+
+/// The options table read by [`dialect`].
+pub const OPTIONS_DOC: ClassDoc = ClassDoc {
+    name: "kznllm.SyntaxOptions",
+    doc: "",
+    operators: &[],
+    fields: &[FieldDoc {
+        name: "dialect?",
+        ty: "\"lox\"|\"lox+\"",
+        doc: "",
+    }],
+};
+
+/// Reads the `dialect` option, `"lox"` (the default) or `"lox+"`.
+pub fn dialect(options: Option<&LuaTable>) -> LuaResult<Dialect> {
+    let Some(options) = options else {
+        return Ok(Dialect::Lox);
+    };
+    match options.get::<Option<String>>("dialect")?.as_deref() {
+        None | Some("lox") => Ok(Dialect::Lox),
+        Some("lox+") => Ok(Dialect::LoxPlus),
+        Some(other) => Err(LuaError::runtime(format!("unknown dialect '{}'", other))),
+    }
+}
+
+/// The tables built by [`diagnostic`].
+pub const DIAGNOSTIC_DOC: ClassDoc = ClassDoc {
+    name: "kznllm.SyntaxError",
+    doc: "",
+    operators: &[],
+    fields: &[
+        FieldDoc {
+            name: "line",
+            ty: "integer",
+            doc: "",
+        },
+        FieldDoc {
+            name: "start",
+            ty: "integer",
+            doc: "",
+        },
+        FieldDoc {
+            name: "end",
+            ty: "integer",
+            doc: "",
+        },
+        FieldDoc {
+            name: "message",
+            ty: "string",
+            doc: "",
+        },
+        FieldDoc {
+            name: "text",
+            ty: "string",
+            doc: "The whole report, e.g. \"[line 1] Error at ';': Expect expression.\"",
+        },
+    ],
+};
+
+pub fn diagnostic(diagnostic: &Diagnostic) -> Json {
+    Json::object([
+        ("line", (diagnostic.line as usize).into()),
+        ("start", diagnostic.loc.start.into()),
+        ("end", diagnostic.loc.end.into()),
+        ("message", diagnostic.message.as_str().into()),
+        ("text", diagnostic.to_string().into()),
+    ])
+}
+
+/// The tables built by [`lint_json`].
+pub const LINT_DOC: ClassDoc = ClassDoc {
+    name: "kznllm.Lint",
+    doc: "",
+    operators: &[],
+    fields: &[
+        FieldDoc {
+            name: "line",
+            ty: "integer",
+            doc: "",
+        },
+        FieldDoc {
+            name: "id",
+            ty: "string",
+            doc: "",
+        },
+        FieldDoc {
+            name: "severity",
+            ty: "\"warning\"|\"error\"",
+            doc: "",
+        },
+        FieldDoc {
+            name: "message",
+            ty: "string",
+            doc: "",
+        },
+    ],
+};
+
+pub fn lint_json(lint: &Lint) -> Json {
+    Json::object([
+        ("line", (lint.line as usize).into()),
+        ("id", lint.id.into()),
+        ("severity", lint.severity.to_string().into()),
+        ("message", lint.message.as_str().into()),
+    ])
+}
+
+/// Builds the Lua value for `json`. Null fields are left out of tables.
+pub fn to_lua(lua: &Lua, json: &Json) -> LuaResult<LuaValue> {
+    Ok(match json {
+        Json::Null => LuaValue::Nil,
+        Json::Bool(b) => LuaValue::Boolean(*b),
+        Json::Number(n) => number(*n),
+        Json::String(s) => LuaValue::String(lua.create_string(s)?),
+        Json::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for item in items {
+                table.raw_push(to_lua(lua, item)?)?;
+            }
+            LuaValue::Table(table)
+        }
+        Json::Object(fields) => {
+            let table = lua.create_table_with_capacity(0, fields.len())?;
+            for (key, value) in fields {
+                table.raw_set(key.as_str(), to_lua(lua, value)?)?;
+            }
+            LuaValue::Table(table)
+        }
+    })
+}
+
+/// Whole numbers become Lua integers, so they print without a fraction.
+pub fn number(n: f64) -> LuaValue {
+    if n.fract() == 0.0 && n.abs() < 2f64.powi(53) {
+        LuaValue::Integer(n as i64)
+    } else {
+        LuaValue::Number(n)
+    }
+}
+
+fn statements(stmts: &[Stmt]) -> Json {
+    Json::Array(stmts.iter().map(stmt).collect())
+}
+
+fn optional<T>(value: Option<T>, f: impl FnOnce(T) -> Json) -> Json {
+    value.map_or(Json::Null, f)
+}
+
+fn name(name: &Name) -> Json {
+    name.text.as_ref().into()
+}
+
+fn node<const N: usize>(kind: &str, line: u32, fields: [(&str, Json); N]) -> Json {
+    let mut node = vec![
+        ("type".to_string(), kind.into()),
+        ("line".to_string(), (line as usize).into()),
+    ];
+    node.extend(
+        fields
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value)),
+    );
+    Json::Object(node)
+}
+
+fn stmt(stmt: &Stmt) -> Json {
+    let line = stmt.line;
+    match &stmt.kind {
+        StmtKind::Expression(e) => node("Expression", line, [("expression", expr(e))]),
+        StmtKind::Print(e) => node("Print", line, [("expression", expr(e))]),
+        StmtKind::Var(n, init) => node(
+            "Var",
+            line,
+            [
+                ("name", name(n)),
+                ("initializer", optional(init.as_ref(), expr)),
+            ],
+        ),
+        StmtKind::Block(body) => node("Block", line, [("statements", statements(body))]),
+        StmtKind::If(condition, then, otherwise) => node(
+            "If",
+            line,
+            [
+                ("condition", expr(condition)),
+                ("then_branch", self::stmt(then)),
+                ("else_branch", optional(otherwise.as_deref(), self::stmt)),
+            ],
+        ),
+        StmtKind::While(condition, body) => node(
+            "While",
+            line,
+            [("condition", expr(condition)), ("body", self::stmt(body))],
+        ),
+        StmtKind::Function(f) => function(f),
+        StmtKind::Return(value) => {
+            node("Return", line, [("value", optional(value.as_ref(), expr))])
+        }
+        StmtKind::Class(class) => node(
+            "Class",
+            line,
+            [
+                ("name", name(&class.name)),
+                ("superclass", optional(class.superclass.as_ref(), name)),
+                (
+                    "methods",
+                    Json::Array(class.methods.iter().map(|m| function(m)).collect()),
+                ),
+            ],
+        ),
+        StmtKind::Import(path) => node("Import", line, [("path", path.as_ref().into())]),
+        StmtKind::Export(declaration) => {
+            node("Export", line, [("declaration", self::stmt(declaration))])
+        }
+        StmtKind::Throw(value) => node("Throw", line, [("value", expr(value))]),
+        StmtKind::Try(handler) => node(
+            "Try",
+            line,
+            [
+                ("body", statements(&handler.body)),
+                (
+                    "catch",
+                    optional(handler.catch.as_ref(), |(n, body)| {
+                        Json::object([("name", name(n)), ("body", statements(body))])
+                    }),
+                ),
+                ("finally", optional(handler.finally.as_deref(), statements)),
+            ],
+        ),
+    }
+}
+
+fn function(function: &Function) -> Json {
+    node(
+        "Function",
+        function.name.line,
+        [
+            ("name", name(&function.name)),
+            (
+                "params",
+                Json::Array(function.params.iter().map(name).collect()),
+            ),
+            ("body", statements(&function.body)),
+        ],
+    )
+}
+
+fn expr(e: &Expr) -> Json {
+    let fields: Vec<(&str, Json)> = match &e.kind {
+        ExprKind::Literal(literal) => vec![(
+            "value",
+            match literal {
+                Literal::Nil => Json::Null,
+                Literal::Bool(b) => (*b).into(),
+                Literal::Number(n) => (*n).into(),
+                Literal::String(s) => s.as_ref().into(),
+            },
+        )],
+        ExprKind::Variable(n) => vec![("name", name(n))],
+        ExprKind::Assign(n, value) => vec![("name", name(n)), ("value", expr(value))],
+        ExprKind::Unary(op, operand) => {
+            vec![("op", op.to_string().into()), ("operand", expr(operand))]
+        }
+        ExprKind::Binary(left, op, right) => vec![
+            ("op", op.to_string().into()),
+            ("left", expr(left)),
+            ("right", expr(right)),
+        ],
+        ExprKind::Logical(left, op, right) => vec![
+            ("op", op.to_string().into()),
+            ("left", expr(left)),
+            ("right", expr(right)),
+        ],
+        ExprKind::Grouping(inner) => vec![("expression", expr(inner))],
+        ExprKind::Call(callee, args) => vec![
+            ("callee", expr(callee)),
+            ("arguments", Json::Array(args.iter().map(expr).collect())),
+        ],
+        ExprKind::Get(object, n) => vec![("object", expr(object)), ("name", name(n))],
+        ExprKind::Set(object, n, value) => vec![
+            ("object", expr(object)),
+            ("name", name(n)),
+            ("value", expr(value)),
+        ],
+        ExprKind::This => vec![],
+        ExprKind::Super(method) => vec![("method", name(method))],
+        ExprKind::List(items) => {
+            vec![("elements", Json::Array(items.iter().map(expr).collect()))]
+        }
+        ExprKind::Map(entries) => vec![(
+            "entries",
+            Json::Array(
+                entries
+                    .iter()
+                    .map(|(key, value)| Json::object([("key", expr(key)), ("value", expr(value))]))
+                    .collect(),
+            ),
+        )],
+        ExprKind::Index(object, index) => {
+            vec![("object", expr(object)), ("index", expr(index))]
+        }
+        ExprKind::SetIndex(object, index, value) => vec![
+            ("object", expr(object)),
+            ("index", expr(index)),
+            ("value", expr(value)),
+        ],
+    };
+    let Loc { start, end } = e.loc;
+    let mut node = vec![
+        ("type".to_string(), expr_kind(&e.kind).into()),
+        ("line".to_string(), (e.line as usize).into()),
+        ("start".to_string(), start.into()),
+        ("end".to_string(), end.into()),
+    ];
+    node.extend(
+        fields
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value)),
+    );
+    Json::Object(node)
+}
+
+fn expr_kind(kind: &ExprKind) -> &'static str {
+    match kind {
+        ExprKind::Literal(_) => "Literal",
+        ExprKind::Variable(_) => "Variable",
+        ExprKind::Assign(..) => "Assign",
+        ExprKind::Unary(..) => "Unary",
+        ExprKind::Binary(..) => "Binary",
+        ExprKind::Logical(..) => "Logical",
+        ExprKind::Grouping(_) => "Grouping",
+        ExprKind::Call(..) => "Call",
+        ExprKind::Get(..) => "Get",
+        ExprKind::Set(..) => "Set",
+        ExprKind::This => "This",
+        ExprKind::Super(_) => "Super",
+        ExprKind::List(_) => "List",
+        ExprKind::Map(_) => "Map",
+        ExprKind::Index(..) => "Index",
+        ExprKind::SetIndex(..) => "SetIndex",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_and_tree() {
+        let tokens = tokenize(b"var x = \"a\nb\";\nprint x;");
+        let summary: Vec<(&str, usize, usize, usize)> = tokens
+            .iter()
+            .map(|t| (t.tag.as_str(), t.start, t.end, t.line))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("KeywordVar", 0, 3, 1),
+                ("Identifier", 4, 5, 1),
+                ("Equal", 6, 7, 1),
+                ("String", 8, 13, 1),
+                ("Semicolon", 13, 14, 2),
+                ("KeywordPrint", 15, 20, 3),
+                ("Identifier", 21, 22, 3),
+                ("Semicolon", 22, 23, 3),
+            ]
+        );
+
+        let tree = parse(b"fun f(a) { return -a; }\nprint f(1) + 2;", Dialect::Lox).unwrap();
+        let [function, print] = tree.as_array().unwrap() else {
+            panic!("expected two statements");
+        };
+        assert_eq!(function.get("type").as_str(), Some("Function"));
+        assert_eq!(function.get("params").as_array().unwrap().len(), 1);
+        let ret = &function.get("body").as_array().unwrap()[0];
+        assert_eq!(ret.at(&["value", "op"]).as_str(), Some("-"));
+        let sum = print.get("expression");
+        assert_eq!(sum.get("op").as_str(), Some("+"));
+        assert_eq!(sum.at(&["left", "callee", "name"]).as_str(), Some("f"));
+        assert_eq!(sum.at(&["right", "value"]).as_f64(), Some(2.0));
+        assert_eq!(sum.get("line").as_usize(), Some(2));
+
+        let errors = parse(b"print [1];", Dialect::Lox).unwrap_err();
+        assert_eq!(
+            diagnostic(&errors[0]).get("text").as_str(),
+            Some("[line 1] Error at '[': List literals require the Lox+ dialect.")
+        );
+        assert!(parse(b"print [1];", Dialect::LoxPlus).is_ok());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::annotations::{ClassDoc, ClassMethods, FieldDoc, LuaClass};
+use crate::syntax::{self, TokenInfo};
+use mlua::prelude::*;
+
+/// Lexes Lox source up front and hands the tokens out one at a time.
+pub struct TokenStream {
+    source: Vec<u8>,
+    tokens: Vec<TokenInfo>,
+    position: usize,
+}
+
+impl TokenStream {
+    pub fn new(source: &[u8]) -> Self {
+        TokenStream {
+            source: source.to_vec(),
+            tokens: syntax::tokenize(source),
+            position: 0,
+        }
+    }
+
+    pub fn peek(&self) -> Option<&TokenInfo> {
+        self.tokens.get(self.position)
+    }
+
+    pub fn advance(&mut self) -> Option<&TokenInfo> {
+        let token = self.tokens.get(self.position)?;
+        self.position += 1;
+        Some(token)
+    }
+
+    /// The source text between byte offsets, or `None` when they're outside it.
+    pub fn text(&self, start: usize, end: usize) -> Option<&[u8]> {
+        self.source.get(start..end)
+    }
+}
+
+/// The tables built by [`token_table`].
+pub const TOKEN_DOC: ClassDoc = ClassDoc {
+    name: "kznllm.Token",
+    doc: "",
+    operators: &[],
+    fields: &[
+        FieldDoc {
+            name: "tag",
+            ty: "string",
+            doc: "e.g. \"KeywordVar\" or \"Identifier\"",
+        },
+        FieldDoc {
+            name: "start",
+            ty: "integer",
+            doc: "0-based byte offset",
+        },
+        FieldDoc {
+            name: "end",
+            ty: "integer",
+            doc: "Exclusive byte offset",
+        },
+        FieldDoc {
+            name: "line",
+            ty: "integer",
+            doc: "",
+        },
+    ],
+};
+
+/// A `kznllm.Token` table, as returned by `tokenize`.
+pub fn token_table(lua: &Lua, token: &TokenInfo) -> LuaResult<LuaTable> {
+    let table = lua.create_table_with_capacity(0, 4)?;
+    table.set("tag", token.tag.as_str())?;
+    table.set("start", token.start)?;
+    table.set("end", token.end)?;
+    table.set("line", token.line)?;
+    Ok(table)
+}
+
+fn optional_table(lua: &Lua, token: Option<&TokenInfo>) -> LuaResult<Option<LuaTable>> {
+    token.map(|token| token_table(lua, token)).transpose()
+}
+
+impl LuaUserData for TokenStream {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.method_mut("next", |lua, this, ()| optional_table(lua, this.advance()));
+        methods.method("peek", |lua, this, ()| optional_table(lua, this.peek()));
+        methods.method_mut("reset", |_, this, ()| {
+            this.position = 0;
+            Ok(())
+        });
+        methods.method("text", |lua, this, token: LuaTable| {
+            let (start, end): (usize, usize) = (token.get("start")?, token.get("end")?);
+            match this.text(start, end) {
+                Some(text) => lua.create_string(text),
+                None => Err(LuaError::runtime("token is out of range of the source")),
+            }
+        });
+        methods.meta_method(LuaMetaMethod::ToString, |_, this, ()| {
+            Ok(format!(
+                "TokenStream({}/{})",
+                this.position,
+                this.tokens.len()
+            ))
+        });
+        methods.meta_method(LuaMetaMethod::Len, |_, this, ()| Ok(this.tokens.len()));
+        methods.meta_method(LuaMetaMethod::Index, |lua, this, key: LuaValue| {
+            let token = match key {
+                LuaValue::Integer(i) if i >= 1 => this.tokens.get(i as usize - 1),
+                _ => None,
+            };
+            optional_table(lua, token)
+        });
+    }
+}
+
+impl LuaClass for TokenStream {
+    const DOC: ClassDoc = ClassDoc {
+        name: "kznllm.TokenStream",
+        doc: "Lexes Lox source and hands out its tokens in order",
+        operators: &[("len", "integer")],
+        fields: &[
+            FieldDoc {
+                name: "[integer]",
+                ty: "kznllm.Token?",
+                doc: "The token at a 1-based position",
+            },
+            FieldDoc {
+                name: "next",
+                ty: "fun(self: kznllm.TokenStream): kznllm.Token?",
+                doc: "Returns the next token and moves past it, or nil at the end",
+            },
+            FieldDoc {
+                name: "peek",
+                ty: "fun(self: kznllm.TokenStream): kznllm.Token?",
+                doc: "Returns the next token without moving past it",
+            },
+            FieldDoc {
+                name: "reset",
+                ty: "fun(self: kznllm.TokenStream)",
+                doc: "Goes back to the first token",
+            },
+            FieldDoc {
+                name: "text",
+                ty: "fun(self: kznllm.TokenStream, token: kznllm.Token): string",
+                doc: "Returns the source text of a token",
+            },
+        ],
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advance_and_text() {
+        let mut stream = TokenStream::new(b"var x;");
+        assert_eq!(stream.peek().map(|t| t.tag.as_str()), Some("KeywordVar"));
+        let mut texts = Vec::new();
+        while let Some(token) = stream.advance() {
+            let (start, end) = (token.start, token.end);
+            texts.push(stream.text(start, end).unwrap().to_vec());
+        }
+        assert_eq!(texts, [&b"var"[..], b"x", b";"]);
+        assert!(stream.advance().is_none());
+        assert_eq!(stream.text(4, 99), None);
+    }
+}
@@ -0,0 +1,299 @@
+use crate::annotations::{ClassDoc, ClassMethods, FieldDoc, LuaClass};
+use crate::syntax;
+use interpreter_rs::embed::OutputBuffer;
+use interpreter_rs::gc::Heap;
+use interpreter_rs::object::{Obj, ObjMap};
+use interpreter_rs::parser::Dialect;
+use interpreter_rs::value::Value;
+use interpreter_rs::vm::{InterpretError, Limits, Vm};
+use mlua::prelude::*;
+use std::time::Duration;
+
+/// A Lox VM kept alive between calls, so globals defined by one snippet are seen by the next.
+pub struct LoxVm {
+    vm: Vm,
+    output: OutputBuffer,
+}
+
+/// How a [`LoxVm`] is set up, read from the table passed to `new_vm`.
+pub struct VmOptions {
+    pub dialect: Dialect,
+    /// Off by default, as `input` would block the editor.
+    pub host_access: bool,
+    pub fuel: Option<u64>,
+    pub timeout_ms: Option<u64>,
+}
+
+/// The options table read by [`VmOptions::from_lua`].
+pub const OPTIONS_DOC: ClassDoc = ClassDoc {
+    name: "kznllm.VmOptions",
+    doc: "",
+    operators: &[],
+    fields: &[
+        FieldDoc {
+            name: "dialect?",
+            ty: "\"lox\"|\"lox+\"",
+            doc: "",
+        },
+        FieldDoc {
+            name: "host_access?",
+            ty: "boolean",
+            doc: "Enables clock, read_file and input",
+        },
+        FieldDoc {
+            name: "fuel?",
+            ty: "integer",
+            doc: "Instructions each run may execute",
+        },
+        FieldDoc {
+            name: "timeout_ms?",
+            ty: "integer",
+            doc: "",
+        },
+    ],
+};
+
+impl VmOptions {
+    /// Reads `dialect`, `host_access`, `fuel` and `timeout_ms`, all optional.
+    pub fn from_lua(options: Option<&LuaTable>) -> LuaResult<Self> {
+        let dialect = syntax::dialect(options)?;
+        let Some(options) = options else {
+            return Ok(VmOptions::new(dialect));
+        };
+        Ok(VmOptions {
+            dialect,
+            host_access: options.get::<Option<bool>>("host_access")?.unwrap_or(false),
+            fuel: options.get("fuel")?,
+            timeout_ms: options.get("timeout_ms")?,
+        })
+    }
+
+    pub fn new(dialect: Dialect) -> Self {
+        VmOptions {
+            dialect,
+            host_access: false,
+            fuel: None,
+            timeout_ms: None,
+        }
+    }
+}
+
+impl LoxVm {
+    pub fn new(options: VmOptions) -> Self {
+        let output = OutputBuffer::new();
+        let mut vm = Vm::with_host_access(options.host_access);
+        vm.set_output(Box::new(output.clone()));
+        vm.set_dialect(options.dialect);
+        vm.set_limits(Limits {
+            fuel: options.fuel,
+            timeout: options.timeout_ms.map(Duration::from_millis),
+            ..Limits::default()
+        });
+        LoxVm { vm, output }
+    }
+
+    /// Runs `source`, returning what it printed and the error that stopped it, if any.
+    fn eval(&mut self, source: &[u8]) -> (String, Option<String>) {
+        let error = self.vm.interpret(source).err().map(|e| message(&e));
+        (self.output.take(), error)
+    }
+}
+
+impl LuaUserData for LoxVm {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.method_mut("eval", |_, this, source: LuaString| {
+            Ok(this.eval(&source.as_bytes()))
+        });
+        methods.method_mut(
+            "call",
+            |lua, this, (name, args): (String, LuaMultiValue)| {
+                let args = args
+                    .iter()
+                    .map(|arg| from_lua(this.vm.heap_mut(), arg))
+                    .collect::<LuaResult<Vec<_>>>()?;
+                let result = this.vm.call_global::<Value>(&name, args);
+                let output = this.output.take();
+                match result {
+                    Ok(value) => Ok((to_lua(lua, this.vm.heap(), value)?, output, None)),
+                    Err(e) => Ok((LuaValue::Nil, output, Some(message(&e)))),
+                }
+            },
+        );
+        methods.method_mut("get", |lua, this, name: String| {
+            match this.vm.get_global::<Value>(&name) {
+                Some(value) => to_lua(lua, this.vm.heap(), value),
+                None => Ok(LuaValue::Nil),
+            }
+        });
+        methods.method_mut("set", |_, this, (name, value): (String, LuaValue)| {
+            let value = from_lua(this.vm.heap_mut(), &value)?;
+            this.vm.set_global(&name, value);
+            Ok(())
+        });
+        methods.meta_method(LuaMetaMethod::ToString, |_, this, ()| {
+            Ok(format!("LoxVm({:?})", this.vm.dialect()))
+        });
+    }
+}
+
+impl LuaClass for LoxVm {
+    const DOC: ClassDoc = ClassDoc {
+        name: "kznllm.LoxVm",
+        doc: "A Lox VM whose globals persist between calls",
+        operators: &[],
+        fields: &[
+            FieldDoc {
+                name: "eval",
+                ty: "fun(self: kznllm.LoxVm, source: string): string, string?",
+                doc: "Runs source, returning what it printed and the error, if any",
+            },
+            FieldDoc {
+                name: "call",
+                ty: "fun(self: kznllm.LoxVm, name: string, ...: any): any, string, string?",
+                doc: "Calls a global function, returning its result, what it printed and the error, if any",
+            },
+            FieldDoc {
+                name: "get",
+                ty: "fun(self: kznllm.LoxVm, name: string): any",
+                doc: "",
+            },
+            FieldDoc {
+                name: "set",
+                ty: "fun(self: kznllm.LoxVm, name: string, value: any)",
+                doc: "",
+            },
+        ],
+    };
+}
+
+fn message(error: &InterpretError) -> String {
+    error.to_string().trim_end().to_string()
+}
+
+/// Converts a Lua value for Lox. Sequences become lists and other tables maps.
+fn from_lua(heap: &mut Heap, value: &LuaValue) -> LuaResult<Value> {
+    Ok(match value {
+        LuaValue::Nil => Value::Nil,
+        LuaValue::Boolean(b) => Value::Bool(*b),
+        LuaValue::Integer(n) => Value::Number(*n as f64),
+        LuaValue::Number(n) => Value::Number(*n),
+        LuaValue::String(s) => Value::Obj(heap.take_string(s.to_string_lossy())),
+        // Allocating doesn't collect, so the values converted so far don't need rooting
+        LuaValue::Table(table) if is_sequence(table)? => {
+            let items = table
+                .sequence_values::<LuaValue>()
+                .map(|item| from_lua(heap, &item?))
+                .collect::<LuaResult<Vec<_>>>()?;
+            Value::Obj(heap.alloc(Obj::List(items)))
+        }
+        LuaValue::Table(table) => {
+            let mut map = ObjMap::default();
+            for pair in table.pairs::<LuaValue, LuaValue>() {
+                let (key, value) = pair?;
+                let key = match key {
+                    LuaValue::Integer(_)
+                    | LuaValue::Number(_)
+                    | LuaValue::String(_)
+                    | LuaValue::Boolean(_) => from_lua(heap, &key)?,
+                    _ => {
+                        return Err(LuaError::runtime(
+                            "map keys must be strings, numbers or booleans",
+                        ))
+                    }
+                };
+                map.insert(key, from_lua(heap, &value)?);
+            }
+            Value::Obj(heap.alloc(Obj::Map(map)))
+        }
+        other => {
+            return Err(LuaError::runtime(format!(
+                "can't pass a {} to Lox",
+                other.type_name()
+            )))
+        }
+    })
+}
+
+/// Whether the keys of `table` are exactly 1 to its length. Empty tables count as lists.
+fn is_sequence(table: &LuaTable) -> LuaResult<bool> {
+    let len = table.raw_len();
+    let mut count = 0;
+    for pair in table.pairs::<LuaValue, LuaValue>() {
+        match pair?.0 {
+            LuaValue::Integer(i) if i >= 1 && i as usize <= len => count += 1,
+            _ => return Ok(false),
+        }
+    }
+    Ok(count == len)
+}
+
+/// Converts a Lox value for Lua. Lists and maps become tables, other objects their printed form.
+fn to_lua(lua: &Lua, heap: &Heap, value: Value) -> LuaResult<LuaValue> {
+    to_lua_inner(lua, heap, value, &mut Vec::new())
+}
+
+fn to_lua_inner(
+    lua: &Lua,
+    heap: &Heap,
+    value: Value,
+    visiting: &mut Vec<Value>,
+) -> LuaResult<LuaValue> {
+    let r = match value {
+        Value::Nil => return Ok(LuaValue::Nil),
+        Value::Bool(b) => return Ok(LuaValue::Boolean(b)),
+        Value::Number(n) => return Ok(syntax::number(n)),
+        Value::Obj(r) => r,
+    };
+    if visiting.contains(&value) {
+        return Err(LuaError::runtime("can't pass a cyclic value to Lua"));
+    }
+    visiting.push(value);
+    let result = match heap.get(r) {
+        Obj::String(s) => LuaValue::String(lua.create_string(&*s.chars)?),
+        Obj::List(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            // Indexed explicitly so elements after a nil keep their place
+            for (i, &item) in items.iter().enumerate() {
+                table.raw_set(i + 1, to_lua_inner(lua, heap, item, visiting)?)?;
+            }
+            LuaValue::Table(table)
+        }
+        Obj::Map(map) => {
+            let table = lua.create_table_with_capacity(0, map.len())?;
+            for (key, value) in map.iter() {
+                table.raw_set(
+                    to_lua_inner(lua, heap, key, visiting)?,
+                    to_lua_inner(lua, heap, value, visiting)?,
+                )?;
+            }
+            LuaValue::Table(table)
+        }
+        _ => LuaValue::String(lua.create_string(heap.format_value(value))?),
+    };
+    visiting.pop();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval_keeps_state() {
+        let mut vm = LoxVm::new(VmOptions::new(Dialect::Lox));
+        assert_eq!(vm.eval(b"var x = 1; print x;"), ("1\n".to_string(), None));
+        assert_eq!(
+            vm.eval(b"print x + 1; print nope;"),
+            (
+                "2\n".to_string(),
+                Some("Undefined variable 'nope'.\n[line 1] in script".to_string())
+            )
+        );
+        // Natives reaching the host are left out unless asked for
+        let (_, error) = vm.eval(b"input();");
+        assert_eq!(
+            error.as_deref(),
+            Some("Undefined variable 'input'.\n[line 1] in script")
+        );
+    }
+}
@@ -0,0 +1,113 @@
+---@meta kznllm_c
+
+---@class kznllm
+---@field hello fun(message: string): nil
+---Prints hello world
+---@field tokenize fun(source: string): kznllm.Token[]
+---Splits Lox source into tokens
+---@field parse fun(source: string, options?: kznllm.SyntaxOptions): table[]?, kznllm.SyntaxError[]?
+---Parses Lox source into statement nodes, or returns nil and the syntax errors
+---@field lint fun(source: string, options?: kznllm.SyntaxOptions): kznllm.Lint[]?, kznllm.SyntaxError[]?
+---Checks Lox source for likely mistakes
+---@field new_vm fun(options?: kznllm.VmOptions): kznllm.LoxVm
+---Creates a VM whose globals persist between calls
+---@field token_stream fun(source: string): kznllm.TokenStream
+---Lexes Lox source into a stream of tokens
+---@field buffer fun(): kznllm.Buffer
+---Creates an empty buffer
+---@field stream_decoder fun(options?: kznllm.StreamOptions): kznllm.StreamDecoder
+---Creates a decoder for server-sent events or NDJSON fed in chunks
+
+---@class kznllm.Token
+---@field tag string
+---e.g. "KeywordVar" or "Identifier"
+---@field start integer
+---0-based byte offset
+---@field end integer
+---Exclusive byte offset
+---@field line integer
+
+---@class kznllm.SyntaxOptions
+---@field dialect? "lox"|"lox+"
+
+---@class kznllm.SyntaxError
+---@field line integer
+---@field start integer
+---@field end integer
+---@field message string
+---@field text string
+---The whole report, e.g. "[line 1] Error at ';': Expect expression."
+
+---@class kznllm.Lint
+---@field line integer
+---@field id string
+---@field severity "warning"|"error"
+---@field message string
+
+---@class kznllm.VmOptions
+---@field dialect? "lox"|"lox+"
+---@field host_access? boolean
+---Enables clock, read_file and input
+---@field fuel? integer
+---Instructions each run may execute
+---@field timeout_ms? integer
+
+---@class kznllm.StreamOptions
+---@field format? "sse"|"ndjson"
+
+---@class kznllm.StreamEvent
+---@field event? string
+---The event type, "message" unless given. Nil for NDJSON
+---@field data string
+---@field id? string
+---The last event id seen
+---@field text? string
+---The text delta, for OpenAI, Anthropic, Gemini and Ollama responses
+---@field done boolean
+---Whether this ends the response, e.g. data: [DONE]
+
+---Collects text in Rust, e.g. a streamed response, until it's taken out
+---@class kznllm.Buffer
+---@operator len: integer
+---@field [integer] integer?
+---The byte at a 1-based position, like string.byte
+---@field push fun(self: kznllm.Buffer, text: string)
+---Appends text
+---@field take fun(self: kznllm.Buffer): string
+---Returns the contents and empties the buffer
+---@field clear fun(self: kznllm.Buffer)
+
+---Lexes Lox source and hands out its tokens in order
+---@class kznllm.TokenStream
+---@operator len: integer
+---@field [integer] kznllm.Token?
+---The token at a 1-based position
+---@field next fun(self: kznllm.TokenStream): kznllm.Token?
+---Returns the next token and moves past it, or nil at the end
+---@field peek fun(self: kznllm.TokenStream): kznllm.Token?
+---Returns the next token without moving past it
+---@field reset fun(self: kznllm.TokenStream)
+---Goes back to the first token
+---@field text fun(self: kznllm.TokenStream, token: kznllm.Token): string
+---Returns the source text of a token
+
+---A Lox VM whose globals persist between calls
+---@class kznllm.LoxVm
+---@field eval fun(self: kznllm.LoxVm, source: string): string, string?
+---Runs source, returning what it printed and the error, if any
+---@field call fun(self: kznllm.LoxVm, name: string, ...: any): any, string, string?
+---Calls a global function, returning its result, what it printed and the error, if any
+---@field get fun(self: kznllm.LoxVm, name: string): any
+---@field set fun(self: kznllm.LoxVm, name: string, value: any)
+
+---Decodes a streamed response fed in chunks of any size
+---@class kznllm.StreamDecoder
+---@field feed fun(self: kznllm.StreamDecoder, chunk: string): kznllm.StreamEvent[], string
+---Returns the events the chunk completes and their text joined. Errors if one holds
+---JSON nested too deeply to decode, dropping the chunk's events
+---@field finish fun(self: kznllm.StreamDecoder): kznllm.StreamEvent[], string
+---Ends the stream, returning a last unterminated NDJSON line, and resets the decoder
+
+---@type kznllm
+local kznllm_c
+return kznllm_c